@@ -0,0 +1,113 @@
+use crate::web_api;
+use librespot::playback::config::Bitrate;
+use log::info;
+use rspotify::spotify::oauth2::TokenInfo as RspotifyToken;
+use std::{
+    collections::VecDeque,
+    sync::mpsc::{channel, Receiver, Sender},
+    time::{Duration, Instant},
+};
+
+/// This many stalls within `STALL_WINDOW` step the bitrate down.
+const STALLS: usize = 2;
+const STALL_WINDOW: Duration = Duration::from_secs(120);
+/// Playing this long without a stall steps the bitrate back up.
+const STABLE: Duration = Duration::from_secs(15 * 60);
+
+/// Steps the bitrate down when playback keeps stalling and back up once it
+/// has been smooth for a while, for `bitrate = auto`.
+///
+/// Stalls are reported by a `StallDetectingSink`. librespot only takes the
+/// bitrate when the player is created, so a change restarts the player.
+pub struct AdaptiveBitrate {
+    bitrate: Bitrate,
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+    stalls: VecDeque<Instant>,
+    last_change: Instant,
+    /// Whether playback has to be resumed after a restart of the player.
+    resume_pending: bool,
+}
+
+impl AdaptiveBitrate {
+    pub fn new(bitrate: Bitrate) -> AdaptiveBitrate {
+        let (sender, receiver) = channel();
+        AdaptiveBitrate {
+            bitrate,
+            sender,
+            receiver,
+            stalls: VecDeque::new(),
+            last_change: Instant::now(),
+            resume_pending: false,
+        }
+    }
+
+    /// Where sinks report stalls.
+    pub fn stalls(&self) -> Sender<()> {
+        self.sender.clone()
+    }
+
+    /// Returns the new bitrate when it should change.
+    pub fn poll(&mut self, playing: bool) -> Option<Bitrate> {
+        let now = Instant::now();
+        while let Ok(()) = self.receiver.try_recv() {
+            self.stalls.push_back(now);
+        }
+        while self
+            .stalls
+            .front()
+            .map_or(false, |&s| now.duration_since(s) > STALL_WINDOW)
+        {
+            self.stalls.pop_front();
+        }
+
+        let next = if self.stalls.len() >= STALLS {
+            match self.bitrate {
+                Bitrate::Bitrate320 => Bitrate::Bitrate160,
+                _ => Bitrate::Bitrate96,
+            }
+        } else if playing && self.stalls.is_empty() && now.duration_since(self.last_change) > STABLE
+        {
+            match self.bitrate {
+                Bitrate::Bitrate96 => Bitrate::Bitrate160,
+                _ => Bitrate::Bitrate320,
+            }
+        } else {
+            return None;
+        };
+        self.stalls.clear();
+        self.last_change = now;
+        if kbps(next) == kbps(self.bitrate) {
+            return None;
+        }
+        info!(
+            "Switching bitrate from {} to {} kbps",
+            kbps(self.bitrate),
+            kbps(next)
+        );
+        self.bitrate = next;
+        self.resume_pending = playing;
+        Some(next)
+    }
+
+    /// Whether a Web API token is needed to resume playback.
+    pub fn is_resume_pending(&self) -> bool {
+        self.resume_pending
+    }
+
+    /// Hands back the playback the restart of the player took away.
+    pub fn resume(&mut self, token: RspotifyToken, device_name: String) {
+        if self.resume_pending {
+            self.resume_pending = false;
+            web_api::transfer_playback(token, device_name, true);
+        }
+    }
+}
+
+fn kbps(bitrate: Bitrate) -> u32 {
+    match bitrate {
+        Bitrate::Bitrate96 => 96,
+        Bitrate::Bitrate160 => 160,
+        Bitrate::Bitrate320 => 320,
+    }
+}
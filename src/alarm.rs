@@ -0,0 +1,212 @@
+use crate::{config::AlarmConfig, web_api};
+use chrono::{DateTime, Datelike, Local, Timelike};
+use futures::{Async, Stream};
+use log::{info, warn};
+use rspotify::spotify::oauth2::TokenInfo as RspotifyToken;
+use std::{
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+use tokio_core::reactor::{Handle, Interval};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// An alarm that couldn't go off for this long, e.g. because spotifyd wasn't
+/// logged in, is dropped rather than going off late.
+const MAX_DELAY: Duration = Duration::from_secs(300);
+/// Volume steps of the fade in.
+const FADE_STEPS: u32 = 10;
+
+/// When an alarm goes off, in the five fields of cron: minute, hour, day of
+/// the month, month and day of the week, e.g. `30 6 * * 1-5`. Each field is
+/// `*`, a number, a range `a-b`, a list of those separated by commas, or any
+/// of them with a step `/n`. Sunday is 0 or 7.
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day of the month or the week is restricted; cron then
+    /// takes either.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// The values a field of a schedule takes, as bits.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, ()> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(i) => (&part[..i], part[i + 1..].parse::<u32>().map_err(|_| ())?),
+            None => (part, 1),
+        };
+        let (first, last) = if range == "*" {
+            (min, max)
+        } else if let Some(i) = range.find('-') {
+            (
+                range[..i].parse().map_err(|_| ())?,
+                range[i + 1..].parse().map_err(|_| ())?,
+            )
+        } else {
+            let value = range.parse().map_err(|_| ())?;
+            // `5/15` means from 5 on.
+            (value, if part.contains('/') { max } else { value })
+        };
+        if step == 0 || first < min || last > max || first > last {
+            return Err(());
+        }
+        for value in (first..=last).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for Schedule {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Schedule, ()> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(());
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Schedule {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+impl Schedule {
+    fn matches(&self, time: &DateTime<Local>) -> bool {
+        let has = |bits: u64, value: u32| bits & (1 << value) != 0;
+        let day = has(self.days, time.day());
+        let weekday = has(self.weekdays, time.weekday().num_days_from_sunday());
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        has(self.minutes, time.minute())
+            && has(self.hours, time.hour())
+            && has(self.months, time.month())
+            && day
+    }
+}
+
+/// Starts playback on this device when an alarm goes off, turning spotifyd
+/// into an alarm clock. Playback is started through the Web API, so it needs
+/// a session.
+pub struct Alarms {
+    alarms: Vec<AlarmConfig>,
+    device_name: String,
+    interval: Interval,
+    /// The minute last checked, so each alarm goes off once, and none in the
+    /// minute spotifyd starts or reloads in.
+    last_minute: (i32, u32, u32, u32),
+    /// Alarms that went off, by index, waiting for a Web API token.
+    pending: Vec<(usize, Instant)>,
+}
+
+impl Alarms {
+    pub fn new(alarms: Vec<AlarmConfig>, device_name: String, handle: &Handle) -> Alarms {
+        Alarms {
+            alarms,
+            device_name,
+            interval: Interval::new(CHECK_INTERVAL, handle).unwrap(),
+            last_minute: minute(&Local::now()),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Whether a Web API token is needed to start an alarm.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    pub fn poll(&mut self, token: Option<RspotifyToken>) {
+        while let Ok(Async::Ready(Some(()))) = self.interval.poll() {}
+        let now = Local::now();
+        if self.last_minute != minute(&now) {
+            self.last_minute = minute(&now);
+            for (i, alarm) in self.alarms.iter().enumerate() {
+                if alarm.schedule.matches(&now) {
+                    info!("Alarm {} goes off", alarm.name);
+                    self.pending.push((i, Instant::now()));
+                }
+            }
+        }
+        let alarms = &self.alarms;
+        self.pending.retain(|&(i, since)| {
+            let late = since.elapsed() > MAX_DELAY;
+            if late {
+                warn!("Alarm {} couldn't start playback in time", alarms[i].name);
+            }
+            !late
+        });
+        let token = match token {
+            Some(token) => token,
+            None => return,
+        };
+        for (i, _) in self.pending.drain(..) {
+            start(&self.alarms[i], &self.device_name, token.clone());
+        }
+    }
+}
+
+/// The minute of a time, as the year, day of the year, hour and minute.
+fn minute(time: &DateTime<Local>) -> (i32, u32, u32, u32) {
+    (time.year(), time.ordinal(), time.hour(), time.minute())
+}
+
+/// Starts the alarm's playback on the device, fading the volume in.
+fn start(alarm: &AlarmConfig, device_name: &str, token: RspotifyToken) {
+    let name = alarm.name.clone();
+    let uri = alarm.uri.clone();
+    let volume = alarm.volume;
+    let fade_in = alarm.fade_in;
+    let device_name = device_name.to_string();
+    thread::spawn(move || {
+        let client = web_api::client(&token);
+        let device_id = match web_api::find_device(&client, &device_name) {
+            Some(id) => id,
+            None => return,
+        };
+        let fading = fade_in > Duration::from_secs(0);
+        let target = volume.unwrap_or(100);
+        if fading || volume.is_some() {
+            let start = if fading { 0 } else { target };
+            let _ = client.volume(start, Some(device_id.clone()));
+        }
+        // Tracks are played by themselves, anything else as a context.
+        let started = if uri.starts_with("spotify:track:") {
+            client.start_playback(Some(device_id.clone()), None, Some(vec![uri]), None)
+        } else {
+            client.start_playback(Some(device_id.clone()), Some(uri), None, None)
+        };
+        if let Err(e) = started {
+            warn!("Alarm {} couldn't start playback: {}", name, e);
+            return;
+        }
+        if !fading {
+            return;
+        }
+        for step in 1..=FADE_STEPS {
+            thread::sleep(fade_in / FADE_STEPS);
+            let volume = (u32::from(target) * step / FADE_STEPS) as u8;
+            if let Err(e) = client.volume(volume, Some(device_id.clone())) {
+                warn!("Alarm {} couldn't fade in: {}", name, e);
+                return;
+            }
+        }
+    });
+}
@@ -1,33 +1,107 @@
-use alsa;
+use alsa::{
+    self,
+    mixer::{MilliBel, Round, Selem, SelemChannelId, SelemId},
+};
 use librespot::playback::mixer::{AudioFilter, Mixer};
 use log::error;
 use std::error::Error;
 
+/// Up to this range, in hundredths of a dB, volume maps linearly onto dB.
+/// Wider ranges are mapped like alsamixer does, so that the usable part of
+/// the range isn't squeezed into the top of the slider.
+const MAX_LINEAR_DB_SCALE: f64 = 2400.0;
+
+#[derive(Clone, Copy)]
+pub enum Scaling {
+    Logarithmic,
+    Linear,
+    /// Follows the control's dB range.
+    Db,
+}
+
 pub struct AlsaMixer {
     pub device: String,
     pub mixer: String,
-    pub linear_scaling: bool,
+    pub index: u32,
+    pub scaling: Scaling,
 }
 
 impl AlsaMixer {
-    fn set_volume_with_err(&self, volume: u16) -> Result<(), Box<Error>> {
+    fn with_selem<T, F>(&self, f: F) -> Result<T, Box<Error>>
+    where
+        F: FnOnce(&Selem) -> Result<T, Box<Error>>,
+    {
         let mixer = alsa::mixer::Mixer::new(&self.device, false)?;
-
-        let selem_id = alsa::mixer::SelemId::new(&*self.mixer, 0);
+        let selem_id = SelemId::new(&*self.mixer, self.index);
         let elem = mixer.find_selem(&selem_id).ok_or("Couldn't find selem.")?;
+        f(&elem)
+    }
+
+    fn set_volume_with_err(&self, volume: u16) -> Result<(), Box<Error>> {
+        let scaling = self.scaling;
+        self.with_selem(|elem| {
+            if let Scaling::Db = scaling {
+                let (min, max) = elem.get_playback_db_range();
+                let db = volume_to_db(volume, min.0 as f64, max.0 as f64);
+                elem.set_playback_db_all(MilliBel(db as i64), Round::Floor)?;
+                return Ok(());
+            }
 
-        let (min, max) = elem.get_playback_volume_range();
+            let (min, max) = elem.get_playback_volume_range();
+            let volume_steps = (max - min) as f64;
+            let normalised_volume = if let Scaling::Linear = scaling {
+                ((f64::from(volume) / f64::from(u16::max_value())) * volume_steps) as i64 + min
+            } else {
+                (f64::from(volume).log(f64::from(u16::max_value())) * volume_steps).floor() as i64
+                    + min
+            };
 
-        let volume_steps = (max - min) as f64;
-        let normalised_volume = if self.linear_scaling {
-            ((f64::from(volume) / f64::from(u16::max_value())) * volume_steps) as i64 + min
-        } else {
-            (f64::from(volume).log(f64::from(u16::max_value())) * volume_steps).floor() as i64 + min
-        };
+            elem.set_playback_volume_all(normalised_volume)?;
+            Ok(())
+        })
+    }
+
+    fn volume_with_err(&self) -> Result<u16, Box<Error>> {
+        let scaling = self.scaling;
+        self.with_selem(|elem| {
+            if let Scaling::Db = scaling {
+                let (min, max) = elem.get_playback_db_range();
+                let db = elem.get_playback_vol_db(SelemChannelId::mono())?;
+                return Ok(db_to_volume(db.0 as f64, min.0 as f64, max.0 as f64));
+            }
+
+            let (min, max) = elem.get_playback_volume_range();
+            let volume = elem.get_playback_volume(SelemChannelId::mono())?;
+            let volume_steps = max - min + 1;
+            Ok(((volume - min) * (0xFFFF / volume_steps)) as u16)
+        })
+    }
+}
 
-        elem.set_playback_volume_all(normalised_volume)?;
-        Ok(())
+/// Maps Connect's volume onto a dB value in `min..=max`, in hundredths of a
+/// dB.
+fn volume_to_db(volume: u16, min: f64, max: f64) -> f64 {
+    let volume = f64::from(volume) / f64::from(u16::max_value());
+    if max - min <= MAX_LINEAR_DB_SCALE {
+        return min + volume * (max - min);
+    }
+    if volume <= 0.0 {
+        return min;
     }
+    let min_norm = 10f64.powf((min - max) / 6000.0);
+    let norm = volume * (1.0 - min_norm) + min_norm;
+    (6000.0 * norm.log10() + max).max(min)
+}
+
+/// The inverse of `volume_to_db`.
+fn db_to_volume(db: f64, min: f64, max: f64) -> u16 {
+    let volume = if max - min <= MAX_LINEAR_DB_SCALE {
+        (db - min) / (max - min)
+    } else {
+        let min_norm = 10f64.powf((min - max) / 6000.0);
+        (10f64.powf((db - max) / 6000.0) - min_norm) / (1.0 - min_norm)
+    };
+    (volume.max(0.0).min(1.0) * f64::from(u16::max_value())) as u16
 }
 
 impl Mixer for AlsaMixer {
@@ -35,7 +109,8 @@ impl Mixer for AlsaMixer {
         AlsaMixer {
             device: "default".to_string(),
             mixer: "Master".to_string(),
-            linear_scaling: false,
+            index: 0,
+            scaling: Scaling::Logarithmic,
         }
     }
 
@@ -44,25 +119,12 @@ impl Mixer for AlsaMixer {
     fn stop(&self) {}
 
     fn volume(&self) -> u16 {
-        let selem_id = alsa::mixer::SelemId::new(&*self.mixer, 0);
-        match alsa::mixer::Mixer::new(&self.device, false)
-            .ok()
-            .as_ref()
-            .and_then(|mixer| mixer.find_selem(&selem_id))
-            .and_then(|elem| {
-                let (min, max) = elem.get_playback_volume_range();
-                elem.get_playback_volume(alsa::mixer::SelemChannelId::mono())
-                    .ok()
-                    .map(|volume| {
-                        let volume_steps = max - min + 1;
-                        ((volume - min) * (0xFFFF / volume_steps)) as u16
-                    })
-            }) {
-            Some(vol) => vol,
-            _ => {
+        match self.volume_with_err() {
+            Ok(vol) => vol,
+            Err(e) => {
                 error!(
-                    "Couldn't read volume from alsa device with name \"{}\".",
-                    self.device
+                    "Couldn't read volume from alsa device with name \"{}\": {}",
+                    self.device, e
                 );
                 0
             },
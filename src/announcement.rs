@@ -0,0 +1,118 @@
+use crate::backends::SinkBuilder;
+use futures::sync::oneshot;
+use librespot::audio::VorbisDecoder;
+use log::info;
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+/// How long the player gets to let go of the audio device after pausing.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// Plays local sound files, like a doorbell chime or a spoken announcement,
+/// through the backend and device Spotify plays on, while it is paused. The
+/// audio is 44.1 kHz, as the sinks expect: 16-bit PCM WAV, mono or stereo,
+/// or stereo Ogg Vorbis. It skips the software mixer, so the volume slider
+/// doesn't apply.
+#[derive(Clone)]
+pub struct Announcer {
+    backend: SinkBuilder,
+    device: Option<String>,
+    /// How long after pausing the device is free, fades included.
+    settle: Duration,
+}
+
+// Only D-Bus plays announcements so far.
+#[cfg_attr(not(feature = "dbus_mpris"), allow(dead_code))]
+impl Announcer {
+    pub fn new(backend: SinkBuilder, device: Option<String>, fade: Option<Duration>) -> Announcer {
+        Announcer {
+            backend,
+            device,
+            settle: fade.unwrap_or_default() + SETTLE_TIME,
+        }
+    }
+
+    /// Plays the file in a thread of its own, once playback had time to
+    /// stop, and tells when it is done.
+    pub fn play(&self, path: PathBuf) -> oneshot::Receiver<Result<(), String>> {
+        let (sender, receiver) = oneshot::channel();
+        let announcer = self.clone();
+        thread::spawn(move || {
+            let played = decode(&path).and_then(|samples| {
+                thread::sleep(announcer.settle);
+                info!("Playing {:?}", path);
+                let mut sink = (announcer.backend)(announcer.device.clone());
+                sink.start()
+                    .and_then(|_| sink.write(&samples))
+                    .and_then(|_| sink.stop())
+                    .map_err(|e| e.to_string())
+            });
+            let _ = sender.send(played.map_err(|e| format!("Couldn't play {:?}: {}", path, e)));
+        });
+        receiver
+    }
+}
+
+fn decode(path: &Path) -> Result<Vec<i16>, String> {
+    let is_ogg = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map_or(false, |e| e.eq_ignore_ascii_case("ogg"));
+    if is_ogg {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        let mut decoder =
+            VorbisDecoder::new(BufReader::new(file)).map_err(|e| format!("{:?}", e))?;
+        let mut samples = Vec::new();
+        while let Some(packet) = decoder.next_packet().map_err(|e| format!("{:?}", e))? {
+            samples.extend_from_slice(packet.data());
+        }
+        Ok(samples)
+    } else {
+        decode_wav(&fs::read(path).map_err(|e| e.to_string())?)
+    }
+}
+
+/// The samples of a WAV file, as interleaved stereo.
+fn decode_wav(wav: &[u8]) -> Result<Vec<i16>, String> {
+    if wav.len() < 12 || &wav[0..4] != b"RIFF" || &wav[8..12] != b"WAVE" {
+        return Err("not a WAV file".to_string());
+    }
+    let u16_at = |i: usize| u16::from(wav[i]) | u16::from(wav[i + 1]) << 8;
+    let u32_at = |i: usize| u32::from(u16_at(i)) | u32::from(u16_at(i + 2)) << 16;
+    let mut channels = None;
+    let mut i = 12;
+    while i + 8 <= wav.len() {
+        let size = u32_at(i + 4) as usize;
+        let body = &wav[i + 8..wav.len().min(i + 8 + size)];
+        match &wav[i..i + 4] {
+            b"fmt " if body.len() >= 16 => {
+                let (format, rate, bits) = (u16_at(i + 8), u32_at(i + 12), u16_at(i + 22));
+                if format != 1 || bits != 16 || rate != 44100 {
+                    return Err("not 16-bit PCM at 44.1 kHz".to_string());
+                }
+                channels = Some(u16_at(i + 10));
+            },
+            b"data" => {
+                let samples = body
+                    .chunks(2)
+                    .filter(|s| s.len() == 2)
+                    .map(|s| i16::from(s[0]) | i16::from(s[1] as i8) << 8);
+                return match channels {
+                    Some(1) => Ok(samples.flat_map(|s| vec![s, s]).collect()),
+                    Some(2) => Ok(samples.collect()),
+                    Some(_) => Err("neither mono nor stereo".to_string()),
+                    None => Err("no format before the data".to_string()),
+                };
+            },
+            _ => (),
+        }
+        // Chunks are padded to an even size.
+        i += 8 + size + size % 2;
+    }
+    Err("no audio data".to_string())
+}
@@ -0,0 +1,97 @@
+use crate::config::ApPolicyConfig;
+use log::{info, warn};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long an access point is avoided after the connection to it failed.
+const AVOID_FOR: Duration = Duration::from_secs(60 * 60);
+/// The access point librespot falls back to when the resolver can't be
+/// reached, on any port.
+const FALLBACK_AP: &str = "ap.spotify.com";
+
+struct ApPolicy {
+    config: ApPolicyConfig,
+    /// Access points the connection to failed, and when.
+    failed: Vec<(String, Instant)>,
+    /// The access point librespot connected to last.
+    last: Option<String>,
+    /// The access points the resolver suggested last.
+    offered: Vec<String>,
+}
+
+/// Which of the access points the resolver suggests librespot may connect
+/// to, and in which order. The relay librespot connects through applies it to
+/// the resolver's answer and tells it which access point was picked.
+#[derive(Clone)]
+pub struct SharedApPolicy(Arc<Mutex<ApPolicy>>);
+
+impl SharedApPolicy {
+    pub fn new(config: ApPolicyConfig) -> SharedApPolicy {
+        SharedApPolicy(Arc::new(Mutex::new(ApPolicy {
+            config,
+            failed: Vec::new(),
+            last: None,
+            offered: Vec::new(),
+        })))
+    }
+
+    /// Drops blacklisted access points and those that failed recently, and
+    /// puts the preferred ones first. When that would leave none, the list
+    /// is kept as it is.
+    pub fn apply(&self, access_points: Vec<String>) -> Vec<String> {
+        let mut policy = self.0.lock().unwrap();
+        policy.failed.retain(|&(_, at)| at.elapsed() < AVOID_FOR);
+        policy.offered = access_points.clone();
+        let allowed: Vec<String> = access_points
+            .iter()
+            .filter(|ap| {
+                !policy
+                    .config
+                    .blacklist
+                    .iter()
+                    .any(|b| ap.contains(b.as_str()))
+                    && !policy.failed.iter().any(|&(ref f, _)| f == *ap)
+            })
+            .cloned()
+            .collect();
+        if allowed.is_empty() {
+            warn!("All access points are blacklisted or failed recently, trying them anyway.");
+            return access_points;
+        }
+        let (mut preferred, others): (Vec<String>, Vec<String>) = allowed
+            .into_iter()
+            .partition(|ap| policy.config.prefer.iter().any(|p| ap.contains(p.as_str())));
+        preferred.extend(others);
+        preferred
+    }
+
+    /// Whether librespot may connect to `access_point`, `host:port`: one the
+    /// resolver suggested, or the one librespot falls back to.
+    pub fn is_access_point(&self, access_point: &str) -> bool {
+        let host = access_point.rsplitn(2, ':').last().unwrap_or(access_point);
+        host == FALLBACK_AP
+            || self
+                .0
+                .lock()
+                .unwrap()
+                .offered
+                .iter()
+                .any(|ap| ap == access_point)
+    }
+
+    pub fn connecting_to(&self, access_point: &str) {
+        self.0.lock().unwrap().last = Some(access_point.to_string());
+    }
+
+    /// Avoids the access point connected to last for a while, after
+    /// connecting to it failed or the connection dropped.
+    pub fn last_failed(&self) {
+        let mut policy = self.0.lock().unwrap();
+        if let Some(ap) = policy.last.take() {
+            info!("Avoiding access point {} for an hour", ap);
+            policy.failed.push((ap, Instant::now()));
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use crate::{config::ArtSource, track_metadata::TrackMetadata};
+use futures::{sync::oneshot, Future};
+use librespot::core::mercury::MercuryError;
+use std::{
+    error::Error, fs, io::Read, os::unix::fs::PermissionsExt, path::PathBuf, sync::Arc, thread,
+};
+use url::{
+    percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET},
+    Url,
+};
+
+const EXTENSIONS: &[&str] = &["jpg", "jpeg", "png"];
+
+/// A source of album art. Providers are asked in the configured order and
+/// the first one that knows the track wins.
+pub trait ArtProvider: Send + Sync {
+    /// Returns the URL of the art for the track, `file://` for local files.
+    /// May block.
+    fn find(&self, metadata: &TrackMetadata) -> Option<String>;
+}
+
+/// The album cover from Spotify.
+struct Spotify;
+
+impl ArtProvider for Spotify {
+    fn find(&self, metadata: &TrackMetadata) -> Option<String> {
+        metadata.cover_url.clone()
+    }
+}
+
+/// Images in a local folder, named `ARTIST - ALBUM.jpg`, `ALBUM.jpg` or
+/// `TRACK_ID.jpg` (`.jpeg` and `.png` work too).
+struct Folder(PathBuf);
+
+impl ArtProvider for Folder {
+    fn find(&self, metadata: &TrackMetadata) -> Option<String> {
+        let names = [
+            format!("{} - {}", metadata.artist(), metadata.album),
+            metadata.album.clone(),
+            metadata.track_id.to_base62(),
+        ];
+        names
+            .iter()
+            .map(|name| name.replace('/', "_"))
+            .flat_map(|name| {
+                EXTENSIONS
+                    .iter()
+                    .map(move |ext| self.0.join(format!("{}.{}", name, ext)))
+            })
+            .find(|path| path.is_file())
+            .and_then(|path| Url::from_file_path(path).ok())
+            .map(String::from)
+    }
+}
+
+/// A URL template, e.g. for an image service. `{artist}`, `{album}`,
+/// `{title}` and `{track_id}` are substituted, and the result is used if the
+/// server has an image there.
+struct Template(String);
+
+impl ArtProvider for Template {
+    fn find(&self, metadata: &TrackMetadata) -> Option<String> {
+        let encode = |s: &str| utf8_percent_encode(s, PATH_SEGMENT_ENCODE_SET).to_string();
+        let url = self
+            .0
+            .replace("{artist}", &encode(metadata.artist()))
+            .replace("{album}", &encode(&metadata.album))
+            .replace("{title}", &encode(&metadata.title))
+            .replace("{track_id}", &metadata.track_id.to_base62());
+        match reqwest::Client::new().head(&url).send() {
+            Ok(ref response) if response.status().is_success() => Some(url),
+            _ => None,
+        }
+    }
+}
+
+/// The configured art providers, in order of priority.
+#[derive(Clone)]
+pub struct ArtProviders(Arc<Vec<Box<ArtProvider>>>);
+
+impl ArtProviders {
+    pub fn new(sources: &[ArtSource]) -> ArtProviders {
+        let providers = sources
+            .iter()
+            .map(|source| match *source {
+                ArtSource::Spotify => Box::new(Spotify) as Box<ArtProvider>,
+                ArtSource::Folder(ref path) => Box::new(Folder(path.clone())),
+                ArtSource::Template(ref template) => Box::new(Template(template.clone())),
+            })
+            .collect();
+        ArtProviders(Arc::new(providers))
+    }
+
+    /// Sets the cover of the track to the art of the first provider that has
+    /// any. Providers may block, so they are asked on a separate thread.
+    pub fn resolve(
+        &self,
+        mut metadata: TrackMetadata,
+    ) -> Box<Future<Item = TrackMetadata, Error = MercuryError>> {
+        let providers = self.0.clone();
+        let (sender, receiver) = oneshot::channel();
+        thread::spawn(move || {
+            metadata.cover_url = providers.iter().find_map(|p| p.find(&metadata));
+            let _ = sender.send(metadata);
+        });
+        Box::new(receiver.map_err(|_| MercuryError))
+    }
+}
+
+/// The URL of the art for programs that fetch it themselves, possibly as
+/// another user: a local file only if anyone may read it, or else the cover
+/// on Spotify's servers.
+pub fn shared_url(metadata: &TrackMetadata) -> Option<String> {
+    let url = metadata.cover_url.as_ref()?;
+    let path = match Url::parse(url) {
+        Ok(ref parsed) if parsed.scheme() == "file" => parsed.to_file_path().ok(),
+        _ => return Some(url.clone()),
+    };
+    match path.and_then(|path| fs::metadata(path).ok()) {
+        Some(ref file) if file.permissions().mode() & 0o004 != 0 => Some(url.clone()),
+        _ => metadata.spotify_cover_url.clone(),
+    }
+}
+
+/// Reads the image behind an art URL, local or remote.
+pub fn load(url: &str) -> Result<Vec<u8>, Box<Error>> {
+    let parsed = Url::parse(url)?;
+    if parsed.scheme() == "file" {
+        let path = parsed.to_file_path().map_err(|_| "Invalid file URL")?;
+        return Ok(fs::read(path)?);
+    }
+    let mut picture = Vec::new();
+    reqwest::get(url)?
+        .error_for_status()?
+        .read_to_end(&mut picture)?;
+    Ok(picture)
+}
@@ -0,0 +1,172 @@
+use crate::web_api;
+use librespot::{core::spotify_id::SpotifyId, playback::player::PlayerEvent};
+use log::{info, warn};
+use rspotify::spotify::{oauth2::TokenInfo as RspotifyToken, senum::RepeatState};
+use serde_json::Map;
+use std::{
+    collections::VecDeque,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The number of recently played tracks recommendations are based on, the
+/// most the Web API takes as seeds.
+const SEEDS: usize = 5;
+const RECOMMENDATIONS: u32 = 50;
+/// How long to wait after playback stopped before checking why, so the
+/// Web API has caught up.
+const CHECK_DELAY: Duration = Duration::from_secs(2);
+/// How long a playback stopping may come after the track changed and still
+/// be part of the change, as when the player is rewound at the end.
+const STOPPED_WITH_CHANGE: Duration = Duration::from_secs(1);
+/// How close to its end a track has to have played for its change to be the
+/// end of it rather than a skip.
+const END_MARGIN_MS: u32 = 5000;
+
+/// Keeps the music going with recommendations based on the last played
+/// tracks once a playlist or album has finished, like the official clients.
+///
+/// librespot doesn't tell the end of a context from a pause, so the end is
+/// taken to be the player changing tracks and stopping at once, after the
+/// track before played to its end, with the Web API telling its length. A
+/// pause, or a skip while paused, is neither.
+pub struct Autoplay {
+    device_name: String,
+    recent: VecDeque<SpotifyId>,
+    stopped: Option<Instant>,
+    /// The track loaded, and when it would have started had it not been
+    /// paused, so how far it got can be told.
+    playing: Option<(SpotifyId, Instant)>,
+    paused: Option<Instant>,
+    /// The track last changed from, how long it played and when it changed.
+    finished: Option<(SpotifyId, Duration, Instant)>,
+}
+
+impl Autoplay {
+    pub fn new(device_name: String) -> Autoplay {
+        Autoplay {
+            device_name,
+            recent: VecDeque::with_capacity(SEEDS),
+            stopped: None,
+            playing: None,
+            paused: None,
+            finished: None,
+        }
+    }
+
+    /// Whether a Web API token is needed to check on stopped playback.
+    pub fn is_pending(&self) -> bool {
+        self.stopped
+            .map_or(false, |stopped| stopped.elapsed() >= CHECK_DELAY)
+    }
+
+    pub fn handle_event(&mut self, event: &PlayerEvent) {
+        let now = Instant::now();
+        let track_id = match *event {
+            PlayerEvent::Started { track_id } => {
+                let start = match (self.playing, self.paused.take()) {
+                    (Some((id, start)), Some(paused)) if id == track_id => start + paused.elapsed(),
+                    _ => now,
+                };
+                self.playing = Some((track_id, start));
+                track_id
+            },
+            PlayerEvent::Changed {
+                old_track_id,
+                new_track_id,
+            } => {
+                self.finished = match self.playing {
+                    Some((id, start)) if id == old_track_id => {
+                        Some((id, self.paused.unwrap_or(now) - start, now))
+                    },
+                    _ => None,
+                };
+                self.playing = Some((new_track_id, now));
+                self.paused = None;
+                new_track_id
+            },
+            PlayerEvent::Stopped { .. } => {
+                self.paused = self.paused.or(Some(now));
+                self.stopped = Some(now);
+                return;
+            },
+        };
+        self.stopped = None;
+        if self.recent.contains(&track_id) {
+            return;
+        }
+        if self.recent.len() == SEEDS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(track_id);
+    }
+
+    pub fn poll(&mut self, token: Option<RspotifyToken>) {
+        let token = match token {
+            Some(ref token) if self.is_pending() => token.clone(),
+            _ => return,
+        };
+        let stopped = self.stopped.take().unwrap();
+        let (finished, played) = match self.finished {
+            Some((id, played, changed)) if stopped - changed <= STOPPED_WITH_CHANGE => {
+                (id.to_base62(), played)
+            },
+            _ => return,
+        };
+        if self.recent.is_empty() {
+            return;
+        }
+        let seeds: Vec<String> = self.recent.iter().map(SpotifyId::to_base62).collect();
+        let device_name = self.device_name.clone();
+        thread::spawn(move || {
+            let client = web_api::client(&token);
+            let playback = match client.current_playback(None) {
+                Ok(Some(playback)) => playback,
+                _ => return,
+            };
+            let ended = playback.device.name == device_name
+                && !playback.is_playing
+                && playback.repeat_state == RepeatState::Off;
+            if !ended {
+                return;
+            }
+            let length_ms = match client.track(&finished) {
+                Ok(track) => track.duration_ms,
+                Err(e) => {
+                    warn!("Couldn't tell whether the playlist ran out: {}", e);
+                    return;
+                },
+            };
+            let played_ms = played.as_millis() as u32;
+            if played_ms.saturating_add(END_MARGIN_MS) < length_ms {
+                return;
+            }
+            let uris: Vec<String> = match client.recommendations(
+                None,
+                None,
+                Some(seeds),
+                RECOMMENDATIONS,
+                None,
+                &Map::new(),
+            ) {
+                Ok(recommendations) => recommendations
+                    .tracks
+                    .into_iter()
+                    .map(|track| track.uri)
+                    .collect(),
+                Err(e) => {
+                    warn!("Couldn't get recommendations to autoplay: {}", e);
+                    return;
+                },
+            };
+            if uris.is_empty() {
+                return;
+            }
+            info!("Autoplaying {} recommended tracks", uris.len());
+            if let Err(e) = client.start_playback(Some(playback.device.id), None, Some(uris), None)
+            {
+                warn!("Couldn't autoplay: {}", e);
+            }
+        });
+    }
+}
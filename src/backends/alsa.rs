@@ -0,0 +1,155 @@
+use super::format::{self, Converter, Output};
+use crate::config::AudioFormat;
+use alsa::{
+    pcm::{Access, Format, HwParams, IO, PCM},
+    Direction, ValueOr,
+};
+use librespot::playback::audio_backend::Sink;
+use log::info;
+use std::io;
+
+const DEFAULT_DEVICE: &str = "default";
+/// How much audio the device buffers, in microseconds.
+const BUFFER_TIME: u32 = 500_000;
+
+/// Plays through an ALSA PCM in the configured `audio_format`, `sample_rate`
+/// and `channel_map`, converting the decoded audio itself, so that devices
+/// which only take e.g. 48 kHz work without a `plug` layer. The PCM is only
+/// held open while playing.
+pub struct AlsaSink {
+    device: String,
+    output: Output,
+    converter: Converter,
+    pcm: Option<PCM>,
+}
+
+pub fn open(device: Option<String>) -> Box<Sink> {
+    let device = device.unwrap_or_else(|| DEFAULT_DEVICE.to_string());
+    info!("Using ALSA sink with device {}", device);
+    let output = format::output();
+    Box::new(AlsaSink {
+        device,
+        output,
+        converter: Converter::new(output),
+        pcm: None,
+    })
+}
+
+fn open_pcm(device: &str, output: Output) -> io::Result<PCM> {
+    let pcm = PCM::new(device, Direction::Playback, false).map_err(alsa_error)?;
+    {
+        let hwp = HwParams::any(&pcm).map_err(alsa_error)?;
+        hwp.set_access(Access::RWInterleaved).map_err(alsa_error)?;
+        hwp.set_format(match output.format {
+            AudioFormat::S16 => Format::S16LE,
+            // ALSA takes 24 bits in the low three bytes of four.
+            AudioFormat::S24 => Format::S24LE,
+            AudioFormat::S32 => Format::S32LE,
+            AudioFormat::F32 => Format::FloatLE,
+        })
+        .map_err(alsa_error)?;
+        hwp.set_channels(output.channels() as u32)
+            .map_err(alsa_error)?;
+        hwp.set_rate(output.rate, ValueOr::Nearest)
+            .map_err(alsa_error)?;
+        hwp.set_buffer_time_near(BUFFER_TIME, ValueOr::Nearest)
+            .map_err(alsa_error)?;
+        pcm.hw_params(&hwp).map_err(alsa_error)?;
+        let rate = hwp.get_rate().map_err(alsa_error)?;
+        if rate != output.rate {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} doesn't play at {} Hz, only at {} Hz",
+                    device, output.rate, rate
+                ),
+            ));
+        }
+    }
+    Ok(pcm)
+}
+
+fn alsa_error(e: alsa::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// Writes interleaved samples whole, recovering from underruns.
+fn write_all<S: Copy>(
+    pcm: &PCM,
+    io: alsa::Result<IO<S>>,
+    samples: &[S],
+    channels: usize,
+) -> io::Result<()> {
+    let io = io.map_err(alsa_error)?;
+    let mut rest = samples;
+    while !rest.is_empty() {
+        match io.writei(rest) {
+            Ok(frames) => rest = &rest[frames * channels..],
+            Err(e) => pcm.try_recover(e, true).map_err(alsa_error)?,
+        }
+    }
+    Ok(())
+}
+
+impl Sink for AlsaSink {
+    fn start(&mut self) -> io::Result<()> {
+        if self.pcm.is_none() {
+            self.pcm = Some(open_pcm(&self.device, self.output)?);
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        if let Some(pcm) = self.pcm.take() {
+            pcm.drain().map_err(alsa_error)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let pcm = match self.pcm {
+            Some(ref pcm) => pcm,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "The ALSA device isn't open",
+                ))
+            },
+        };
+        let bytes = self.converter.convert(data);
+        let channels = self.output.channels();
+        match self.output.format {
+            AudioFormat::S16 => {
+                let samples: Vec<i16> = bytes
+                    .chunks(2)
+                    .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                    .collect();
+                write_all(pcm, pcm.io_i16(), &samples, channels)
+            },
+            AudioFormat::S24 => {
+                let samples: Vec<i32> = bytes
+                    .chunks(3)
+                    .map(|b| {
+                        let sign = if b[2] & 0x80 == 0 { 0 } else { 0xff };
+                        i32::from_le_bytes([b[0], b[1], b[2], sign])
+                    })
+                    .collect();
+                write_all(pcm, pcm.io_i32(), &samples, channels)
+            },
+            AudioFormat::S32 => {
+                let samples: Vec<i32> = bytes
+                    .chunks(4)
+                    .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect();
+                write_all(pcm, pcm.io_i32(), &samples, channels)
+            },
+            AudioFormat::F32 => {
+                let samples: Vec<f32> = bytes
+                    .chunks(4)
+                    .map(|b| f32::from_bits(u32::from_le_bytes([b[0], b[1], b[2], b[3]])))
+                    .collect();
+                write_all(pcm, pcm.io_f32(), &samples, channels)
+            },
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use librespot::playback::audio_backend::Sink;
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+enum Message {
+    Start,
+    Stop,
+    Data(Vec<i16>),
+}
+
+struct Queue {
+    messages: VecDeque<Message>,
+    /// Samples waiting in `messages`.
+    samples: usize,
+    /// The last error of the device, reported on the next call.
+    error: Option<io::Error>,
+    /// Whether the sink is gone, and the thread should end.
+    closed: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    /// Signalled whenever messages are added or taken.
+    changed: Condvar,
+}
+
+/// Lets the player run ahead of the device by up to `size` samples, so
+/// audio that arrives in bursts, e.g. over a slow link, plays smoothly.
+///
+/// The device is opened and written to on a thread of their own. Starting
+/// and stopping are queued with the audio, so nothing already buffered gets
+/// lost, which also means that pausing takes effect once it played.
+pub struct BufferedSink {
+    shared: Arc<Shared>,
+    size: usize,
+}
+
+impl BufferedSink {
+    pub fn new<F>(build: F, size: usize) -> BufferedSink
+    where
+        F: FnOnce() -> Box<Sink> + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                messages: VecDeque::new(),
+                samples: 0,
+                error: None,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let played = shared.clone();
+        thread::spawn(move || run(build(), &played));
+        BufferedSink { shared, size }
+    }
+
+    fn push(&mut self, message: Message) -> io::Result<()> {
+        let samples = match message {
+            Message::Data(ref data) => data.len(),
+            _ => 0,
+        };
+        let mut queue = self.shared.queue.lock().unwrap();
+        // A packet larger than the whole buffer still has to go through.
+        while queue.samples > 0 && queue.samples + samples > self.size {
+            queue = self.shared.changed.wait(queue).unwrap();
+        }
+        if let Some(e) = queue.error.take() {
+            return Err(e);
+        }
+        queue.samples += samples;
+        queue.messages.push_back(message);
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+}
+
+impl Drop for BufferedSink {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Sink for BufferedSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.push(Message::Start)
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.push(Message::Stop)
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        self.push(Message::Data(data.to_vec()))
+    }
+}
+
+fn run(mut sink: Box<Sink>, shared: &Shared) {
+    loop {
+        let message = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                if let Some(message) = queue.messages.pop_front() {
+                    break message;
+                }
+                if queue.closed {
+                    return;
+                }
+                queue = shared.changed.wait(queue).unwrap();
+            }
+        };
+        let (result, samples) = match message {
+            Message::Start => (sink.start(), 0),
+            Message::Stop => (sink.stop(), 0),
+            Message::Data(data) => (sink.write(&data), data.len()),
+        };
+        let mut queue = shared.queue.lock().unwrap();
+        queue.samples -= samples;
+        if let Err(e) = result {
+            queue.error = Some(e);
+        }
+        shared.changed.notify_all();
+    }
+}
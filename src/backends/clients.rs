@@ -0,0 +1,86 @@
+use log::info;
+use std::{
+    io::Write,
+    net::TcpStream,
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Writes a client may fall behind before it is dropped.
+const BACKLOG: usize = 64;
+/// How long writing to a client may take before it is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The clients a stream goes out to. Each is written to by a thread of its
+/// own, so a slow one holds up neither playback nor the others, and those
+/// that fall behind are dropped.
+#[derive(Clone)]
+pub struct Clients {
+    senders: Arc<Mutex<Vec<SyncSender<Arc<Vec<u8>>>>>>,
+    /// What the clients are, for the log.
+    kind: &'static str,
+}
+
+impl Clients {
+    pub fn new(kind: &'static str) -> Clients {
+        Clients {
+            senders: Arc::new(Mutex::new(Vec::new())),
+            kind,
+        }
+    }
+
+    /// Adds a client, which gets what is sent from now on.
+    pub fn add(&self, stream: TcpStream) {
+        self.add_after(stream, Vec::new());
+    }
+
+    /// Adds a client, which gets `first` and then what is sent from now on.
+    pub fn add_after(&self, stream: TcpStream, first: Vec<u8>) {
+        let (sender, receiver) = sync_channel(BACKLOG);
+        if !first.is_empty() {
+            let _ = sender.try_send(Arc::new(first));
+        }
+        let kind = self.kind;
+        thread::spawn(move || serve(stream, &receiver, kind));
+        self.senders.lock().unwrap().push(sender);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.lock().unwrap().is_empty()
+    }
+
+    /// Queues `data` for every client without waiting for any.
+    pub fn send(&self, data: Vec<u8>) {
+        let data = Arc::new(data);
+        let kind = self.kind;
+        self.senders
+            .lock()
+            .unwrap()
+            .retain(|sender| match sender.try_send(data.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    info!("Dropping a {} client that fell behind", kind);
+                    false
+                },
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+fn serve(mut stream: TcpStream, receiver: &Receiver<Arc<Vec<u8>>>, kind: &str) {
+    let address = stream.peer_addr();
+    if let Err(e) = stream.set_write_timeout(Some(WRITE_TIMEOUT)) {
+        info!("{} client {:?} went away: {}", kind, address, e);
+        return;
+    }
+    for data in receiver {
+        if let Err(e) = stream.write_all(&data) {
+            info!("{} client {:?} went away: {}", kind, address, e);
+            return;
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use crate::player_event_handler::run_program;
+use librespot::playback::audio_backend::Sink;
+use log::{error, info};
+use std::{collections::HashMap, io, thread, time::Duration};
+
+/// Holds back audio for a while whenever playback starts after having been
+/// stopped, so equipment that is just waking up, like an amplifier's input
+/// relay, doesn't swallow the beginning. A command can be run first to wake
+/// it.
+pub struct DelayedStartSink {
+    inner: Box<Sink>,
+    delay: Duration,
+    command: Option<String>,
+}
+
+impl DelayedStartSink {
+    pub fn new(inner: Box<Sink>, delay: Duration, command: Option<String>) -> DelayedStartSink {
+        DelayedStartSink {
+            inner,
+            delay,
+            command,
+        }
+    }
+}
+
+impl Sink for DelayedStartSink {
+    fn start(&mut self) -> io::Result<()> {
+        if let Some(ref command) = self.command {
+            let command = command.clone();
+            thread::spawn(move || {
+                if let Err(e) = run_program(&command, HashMap::new()).and_then(|mut c| c.wait()) {
+                    error!("Couldn't run {}: {}", command, e);
+                }
+            });
+        }
+        info!("Delaying playback by {} ms.", self.delay.as_millis());
+        // Blocking the player thread holds back the audio.
+        thread::sleep(self.delay);
+        self.inner.start()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.inner.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        self.inner.write(data)
+    }
+}
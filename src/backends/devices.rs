@@ -0,0 +1,122 @@
+use crate::errors::{self, ErrorCode, Failure};
+use std::{error::Error, process::Command};
+
+/// An output device of a backend.
+#[derive(Default)]
+pub struct Device {
+    /// The name, as `device` takes it.
+    pub name: String,
+    /// What the device is, possibly over several lines.
+    pub description: String,
+}
+
+/// Prints the output devices of the backend for `--list-devices`, or of the
+/// default backend without one.
+pub fn list_devices(backend: Option<&str>) {
+    let backend = match backend {
+        Some(name) if super::find(name).is_none() => {
+            eprintln!("{}", errors::message(ErrorCode::UnknownBackend, &[&name]));
+            ErrorCode::UnknownBackend.failure().exit()
+        },
+        Some(name) => name,
+        None => match super::default() {
+            Some((name, _)) => name,
+            None => {
+                eprintln!("{}", errors::message(ErrorCode::NoBackends, &[]));
+                ErrorCode::NoBackends.failure().exit()
+            },
+        },
+    };
+    let devices = match backend {
+        #[cfg(feature = "alsa_backend")]
+        "alsa" => alsa(),
+        #[cfg(feature = "jack_backend")]
+        "jack" => super::jack::devices(),
+        "oss" => super::oss::devices(),
+        "pipewire" => super::pipewire::devices(),
+        "pulseaudio" => pulseaudio(),
+        // librespot lists the devices of these itself when the device is `?`,
+        // and exits.
+        "portaudio" | "rodio" => {
+            let open = super::find(backend).unwrap();
+            open(Some("?".to_string()));
+            return;
+        },
+        _ => {
+            println!(
+                "The {} backend has no devices to choose from, its device is described in the \
+                 README.",
+                backend
+            );
+            return;
+        },
+    };
+    match devices {
+        Ok(devices) => {
+            println!("Devices of the {} backend:", backend);
+            for device in devices {
+                println!("- {}", device.name);
+                for line in device.description.lines() {
+                    println!("    {}", line);
+                }
+            }
+        },
+        Err(e) => {
+            eprintln!(
+                "Couldn't list the devices of the {} backend: {}",
+                backend, e
+            );
+            Failure::AudioBackend.exit()
+        },
+    }
+}
+
+/// The standard output of a command that lists devices, in English so it
+/// can be parsed.
+pub fn command_output(command: &mut Command) -> Result<String, Box<Error>> {
+    let output = command.env("LC_ALL", "C").output()?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The PCMs ALSA knows that can play, as `aplay -L` shows them.
+#[cfg(feature = "alsa_backend")]
+fn alsa() -> Result<Vec<Device>, Box<Error>> {
+    use alsa::{device_name::HintIter, Direction};
+    use std::ffi::CString;
+
+    let hints = HintIter::new(None, &CString::new("pcm").unwrap())?;
+    Ok(hints
+        .filter(|hint| match hint.direction {
+            Some(Direction::Capture) => false,
+            _ => true,
+        })
+        .filter_map(|hint| {
+            hint.name.map(|name| Device {
+                name,
+                description: hint.desc.unwrap_or_default(),
+            })
+        })
+        .collect())
+}
+
+/// The sinks of the PulseAudio server, from `pactl`.
+fn pulseaudio() -> Result<Vec<Device>, Box<Error>> {
+    let listing = command_output(Command::new("pactl").args(&["list", "sinks"]))?;
+    let mut devices: Vec<Device> = Vec::new();
+    for line in listing.lines().map(str::trim) {
+        if line.starts_with("Name: ") {
+            devices.push(Device {
+                name: line["Name: ".len()..].to_string(),
+                description: String::new(),
+            });
+        } else if line.starts_with("Description: ") {
+            if let Some(device) = devices.last_mut() {
+                device.description = line["Description: ".len()..].to_string();
+            }
+        }
+    }
+    Ok(devices)
+}
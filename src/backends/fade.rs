@@ -0,0 +1,76 @@
+use librespot::playback::audio_backend::Sink;
+use std::{collections::VecDeque, io, time::Duration};
+
+/// librespot always plays 44.1 kHz stereo.
+const SAMPLES_PER_SECOND: u64 = 44100 * 2;
+
+/// Ramps the volume up when playback starts and down when it stops, for
+/// playing, pausing and shutting down alike, instead of cutting the audio
+/// hard, which pops on some amplifiers.
+///
+/// The player only says that it stops once the audio has been written, so
+/// the last fade's worth of audio is held back to have something to fade out.
+pub struct FadeSink {
+    inner: Box<Sink>,
+    /// The length of a fade, in samples.
+    length: usize,
+    held: VecDeque<i16>,
+    /// Samples played since the start, until the fade in is done.
+    faded_in: usize,
+}
+
+impl FadeSink {
+    pub fn new(inner: Box<Sink>, length: Duration) -> FadeSink {
+        let samples = length.as_millis() as u64 * SAMPLES_PER_SECOND / 1000;
+        // Keep to whole frames.
+        let length = (samples - samples % 2) as usize;
+        FadeSink {
+            inner,
+            length,
+            held: VecDeque::with_capacity(length),
+            faded_in: length,
+        }
+    }
+}
+
+impl Sink for FadeSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.faded_in = 0;
+        self.inner.start()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        let length = self.held.len();
+        let tail: Vec<i16> = self
+            .held
+            .drain(..)
+            .enumerate()
+            .map(|(i, s)| {
+                let gain = (length - (i - i % 2)) as f32 / length as f32;
+                (f32::from(s) * gain) as i16
+            })
+            .collect();
+        if !tail.is_empty() {
+            self.inner.write(&tail)?;
+        }
+        self.inner.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        self.held.extend(data);
+        if self.held.len() <= self.length {
+            return Ok(());
+        }
+        let count = self.held.len() - self.length;
+        let mut out: Vec<i16> = self.held.drain(..count).collect();
+        for (i, sample) in out.iter_mut().enumerate() {
+            let position = self.faded_in + i - i % 2;
+            if position >= self.length {
+                break;
+            }
+            *sample = (f32::from(*sample) * position as f32 / self.length as f32) as i16;
+        }
+        self.faded_in = (self.faded_in + count).min(self.length);
+        self.inner.write(&out)
+    }
+}
@@ -0,0 +1,175 @@
+use crate::{
+    config::{AudioFormat, ChannelMap},
+    dither::Quantizer,
+};
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+/// librespot always decodes to this rate.
+const DECODED_RATE: u32 = 44100;
+const CHANNELS: usize = 2;
+
+lazy_static! {
+    static ref OUTPUT: Mutex<Output> = Mutex::new(Output {
+        format: AudioFormat::S16,
+        rate: DECODED_RATE,
+        channel_map: None,
+    });
+}
+
+/// The sample format and rate raw PCM backends write.
+#[derive(Clone, Copy)]
+pub struct Output {
+    pub format: AudioFormat,
+    pub rate: u32,
+    /// Where on a multichannel device the audio goes, if it has more than
+    /// two channels.
+    pub channel_map: Option<ChannelMap>,
+}
+
+/// Sets the output format for backends opened from now on.
+pub fn set_output(format: AudioFormat, rate: u32, channel_map: Option<ChannelMap>) {
+    *OUTPUT.lock().unwrap() = Output {
+        format,
+        rate,
+        channel_map,
+    };
+}
+
+pub fn output() -> Output {
+    *OUTPUT.lock().unwrap()
+}
+
+impl Output {
+    pub fn channels(self) -> usize {
+        self.channel_map.map_or(CHANNELS, |map| map.channels)
+    }
+
+    /// The format as `pw-cat --format` takes it.
+    pub fn pipewire_format(self) -> &'static str {
+        match self.format {
+            AudioFormat::S16 => "s16",
+            AudioFormat::S24 => "s24",
+            AudioFormat::S32 => "s32",
+            AudioFormat::F32 => "f32",
+        }
+    }
+
+    /// The format in GStreamer caps.
+    pub fn gstreamer_format(self) -> &'static str {
+        match self.format {
+            AudioFormat::S16 => "S16LE",
+            AudioFormat::S24 => "S24LE",
+            AudioFormat::S32 => "S32LE",
+            AudioFormat::F32 => "F32LE",
+        }
+    }
+}
+
+/// Turns decoded audio into little endian bytes in the output format,
+/// resampling it to the output rate by linear interpolation.
+pub struct Converter {
+    output: Output,
+    /// Where the next output frame falls, in input frames counted from
+    /// `previous`.
+    position: f64,
+    /// The last input frame of the previous write.
+    previous: [f64; CHANNELS],
+    /// Only resampled 16-bit output needs it, other samples stay whole.
+    quantizer: Quantizer,
+}
+
+impl Converter {
+    pub fn new(output: Output) -> Converter {
+        Converter {
+            output,
+            position: 1.0,
+            previous: [0.0; CHANNELS],
+            quantizer: Quantizer::new(),
+        }
+    }
+
+    pub fn convert(&mut self, data: &[i16]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(data.len() * 4);
+        if self.output.rate == DECODED_RATE {
+            for frame in data.chunks(CHANNELS) {
+                if frame.len() == CHANNELS {
+                    let frame = [f64::from(frame[0]), f64::from(frame[1])];
+                    self.encode_frame(frame, false, &mut bytes);
+                }
+            }
+            return bytes;
+        }
+
+        let frames = data.len() / CHANNELS;
+        let frame = |i: usize, channel: usize, previous: &[f64; CHANNELS]| {
+            if i == 0 {
+                previous[channel]
+            } else {
+                f64::from(data[(i - 1) * CHANNELS + channel])
+            }
+        };
+        let step = f64::from(DECODED_RATE) / f64::from(self.output.rate);
+        while self.position < frames as f64 {
+            let i = self.position as usize;
+            let fraction = self.position - i as f64;
+            let mut interpolated = [0.0; CHANNELS];
+            for (channel, sample) in interpolated.iter_mut().enumerate() {
+                let a = frame(i, channel, &self.previous);
+                let b = frame(i + 1, channel, &self.previous);
+                *sample = a + (b - a) * fraction;
+            }
+            self.encode_frame(interpolated, true, &mut bytes);
+            self.position += step;
+        }
+        if frames > 0 {
+            self.position -= frames as f64;
+            for channel in 0..CHANNELS {
+                self.previous[channel] = frame(frames, channel, &self.previous);
+            }
+        }
+        bytes
+    }
+
+    /// Appends a stereo frame, spread over the device's channels if mapped,
+    /// with silence on the others. Only `resampled` frames have fractions of
+    /// a 16-bit sample to dither.
+    fn encode_frame(&mut self, frame: [f64; CHANNELS], resampled: bool, bytes: &mut Vec<u8>) {
+        match self.output.channel_map {
+            Some(map) => {
+                for channel in 0..map.channels {
+                    let sample = if channel == map.left {
+                        frame[0]
+                    } else if channel == map.right {
+                        frame[1]
+                    } else {
+                        0.0
+                    };
+                    self.encode(sample, resampled, bytes);
+                }
+            },
+            None => {
+                self.encode(frame[0], resampled, bytes);
+                self.encode(frame[1], resampled, bytes);
+            },
+        }
+    }
+
+    /// Appends a sample, given in the range of an `i16`.
+    fn encode(&mut self, sample: f64, resampled: bool, bytes: &mut Vec<u8>) {
+        match self.output.format {
+            AudioFormat::S16 if resampled => {
+                bytes.extend_from_slice(&self.quantizer.quantize(sample).to_le_bytes())
+            },
+            AudioFormat::S16 => bytes.extend_from_slice(&(sample as i16).to_le_bytes()),
+            AudioFormat::S24 => {
+                bytes.extend_from_slice(&((sample * 256.0) as i32).to_le_bytes()[..3])
+            },
+            AudioFormat::S32 => bytes.extend_from_slice(&((sample * 65536.0) as i32).to_le_bytes()),
+            AudioFormat::F32 => {
+                let sample = (sample / 32768.0) as f32;
+                bytes.extend_from_slice(&sample.to_bits().to_le_bytes())
+            },
+        }
+    }
+}
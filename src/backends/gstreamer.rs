@@ -0,0 +1,72 @@
+use super::format::{self, Converter};
+use librespot::playback::audio_backend::Sink;
+use log::info;
+use std::{
+    io::{self, Write},
+    process::{Child, Command, Stdio},
+};
+
+const DEFAULT_PIPELINE: &str = "audioconvert ! audioresample ! autoaudiosink";
+
+/// Plays through a GStreamer pipeline given as the device, in `gst-launch`
+/// syntax. The pipeline is fed raw stereo PCM in the output format, 16 bit at
+/// 44.1 kHz by default, e.g.
+/// `audioconvert ! opusenc ! rtpopuspay ! udpsink host=10.0.0.2 port=5004`.
+pub struct GstreamerSink {
+    child: Child,
+    converter: Converter,
+}
+
+pub fn open(device: Option<String>) -> Box<Sink> {
+    let pipeline = device.unwrap_or_else(|| DEFAULT_PIPELINE.to_string());
+    info!("Using GStreamer sink with pipeline {}", pipeline);
+    let output = format::output();
+    let source = format!(
+        "fdsrc fd=0 ! audio/x-raw,format={},layout=interleaved,rate={},channels={}{} ! queue !",
+        output.gstreamer_format(),
+        output.rate,
+        output.channels(),
+        // More than two channels have no positions GStreamer could guess.
+        if output.channels() > 2 {
+            ",channel-mask=(bitmask)0"
+        } else {
+            ""
+        }
+    );
+    let child = Command::new("gst-launch-1.0")
+        .arg("-q")
+        // gst-launch joins its arguments into one description anyway, so
+        // quoting in the pipeline works as it does on the command line.
+        .arg(source)
+        .arg(&pipeline)
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| panic!("Couldn't start gst-launch-1.0: {}", e));
+    Box::new(GstreamerSink {
+        child,
+        converter: Converter::new(output),
+    })
+}
+
+impl Sink for GstreamerSink {
+    fn start(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.child.stdin.as_mut().unwrap().flush()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let bytes = self.converter.convert(data);
+        self.child.stdin.as_mut().unwrap().write_all(&bytes)
+    }
+}
+
+impl Drop for GstreamerSink {
+    fn drop(&mut self) {
+        // Ends the stream, so the pipeline shuts down cleanly.
+        drop(self.child.stdin.take());
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,249 @@
+use super::{
+    devices::Device,
+    format::{Converter, Output},
+};
+use crate::config::AudioFormat;
+use jack::prelude::{
+    client_options, port_flags, AsyncClient, AudioOutPort, AudioOutSpec, Client, JackControl, Port,
+    ProcessHandler, ProcessScope,
+};
+use librespot::playback::audio_backend::Sink;
+use log::{error, info};
+use std::{
+    error::Error,
+    io,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+const DEFAULT_CLIENT_NAME: &str = "spotifyd";
+/// How many of JACK's periods of audio are kept ready.
+const PERIODS: usize = 4;
+/// How long writing waits for JACK to take audio when the buffer is full.
+const WAIT: Duration = Duration::from_millis(1);
+
+/// Plays through a JACK client with the ports `out_l` and `out_r`.
+///
+/// The device is `[CLIENT_NAME][@PORTS]`. By default the client is called
+/// `spotifyd` and its ports are connected to the first two physical playback
+/// ports. `PORTS` lists the ports to connect to instead, separated by commas,
+/// and `none` leaves the routing to the user. The audio is resampled to
+/// the rate the JACK server runs at.
+pub struct JackSink {
+    frames: Arc<Ring>,
+    converter: Converter,
+    // Keeps the client running.
+    _client: AsyncClient<(), JackData>,
+}
+
+struct JackData {
+    frames: Arc<Ring>,
+    port_l: Port<AudioOutSpec>,
+    port_r: Port<AudioOutSpec>,
+}
+
+impl ProcessHandler for JackData {
+    fn process(&mut self, _: &Client, ps: &ProcessScope) -> JackControl {
+        let mut out_l = AudioOutPort::new(&mut self.port_l, ps);
+        let mut out_r = AudioOutPort::new(&mut self.port_r, ps);
+        let buf_l: &mut [f32] = &mut out_l;
+        let buf_r: &mut [f32] = &mut out_r;
+        let read = self.frames.read(buf_l, buf_r);
+        // Silence while the player is behind or paused.
+        for sample in buf_l[read..].iter_mut().chain(buf_r[read..].iter_mut()) {
+            *sample = 0.0;
+        }
+        JackControl::Continue
+    }
+}
+
+/// Stereo frames on their way from the player to JACK's process callback,
+/// which mustn't wait for a lock or allocate. Only the player writes, and
+/// only the callback reads.
+struct Ring {
+    /// Interleaved samples, as the bits of `f32`s.
+    samples: Vec<AtomicU32>,
+    /// The frames written and read so far, wrapping around the buffer.
+    written: AtomicUsize,
+    read: AtomicUsize,
+}
+
+impl Ring {
+    fn new(frames: usize) -> Ring {
+        Ring {
+            samples: (0..frames * 2).map(|_| AtomicU32::new(0)).collect(),
+            written: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.samples.len() / 2
+    }
+
+    /// Writes as many of the interleaved `samples` as fit, and tells how
+    /// many frames that was.
+    fn write(&self, samples: &[f32]) -> usize {
+        let written = self.written.load(Ordering::Relaxed);
+        let free = self.capacity() - written.wrapping_sub(self.read.load(Ordering::Acquire));
+        let frames = free.min(samples.len() / 2);
+        for (i, frame) in samples[..frames * 2].chunks(2).enumerate() {
+            let at = (written.wrapping_add(i) % self.capacity()) * 2;
+            self.samples[at].store(frame[0].to_bits(), Ordering::Relaxed);
+            self.samples[at + 1].store(frame[1].to_bits(), Ordering::Relaxed);
+        }
+        self.written
+            .store(written.wrapping_add(frames), Ordering::Release);
+        frames
+    }
+
+    /// Reads as many frames as there are, up to the length of the channels,
+    /// and tells how many that was.
+    fn read(&self, left: &mut [f32], right: &mut [f32]) -> usize {
+        let read = self.read.load(Ordering::Relaxed);
+        let available = self.written.load(Ordering::Acquire).wrapping_sub(read);
+        let frames = available.min(left.len()).min(right.len());
+        for i in 0..frames {
+            let at = (read.wrapping_add(i) % self.capacity()) * 2;
+            left[i] = f32::from_bits(self.samples[at].load(Ordering::Relaxed));
+            right[i] = f32::from_bits(self.samples[at + 1].load(Ordering::Relaxed));
+        }
+        self.read
+            .store(read.wrapping_add(frames), Ordering::Release);
+        frames
+    }
+}
+
+pub fn open(device: Option<String>) -> Box<Sink> {
+    let device = device.unwrap_or_default();
+    let (client_name, ports) = match device.find('@') {
+        Some(i) => (&device[..i], Some(&device[i + 1..])),
+        None => (&device[..], None),
+    };
+    let client_name = if client_name.is_empty() {
+        DEFAULT_CLIENT_NAME
+    } else {
+        client_name
+    };
+
+    let (client, _) = Client::new(client_name, client_options::NO_START_SERVER)
+        .unwrap_or_else(|e| panic!("Couldn't connect to JACK: {:?}", e));
+    let port_l = client
+        .register_port("out_l", AudioOutSpec::default())
+        .expect("Couldn't register JACK port");
+    let port_r = client
+        .register_port("out_r", AudioOutSpec::default())
+        .expect("Couldn't register JACK port");
+
+    let targets: Vec<String> = match ports {
+        Some("none") => Vec::new(),
+        Some(ports) => ports.split(',').map(|p| p.trim().to_string()).collect(),
+        None => client.ports(
+            None,
+            Some("audio"),
+            port_flags::IS_PHYSICAL | port_flags::IS_INPUT,
+        ),
+    };
+    let sources = [
+        format!("{}:out_l", client.name()),
+        format!("{}:out_r", client.name()),
+    ];
+    info!(
+        "Using JACK sink as client {} at {} Hz",
+        client.name(),
+        client.sample_rate()
+    );
+
+    let converter = Converter::new(Output {
+        format: AudioFormat::F32,
+        rate: client.sample_rate() as u32,
+        channel_map: None,
+    });
+    let frames = Arc::new(Ring::new(client.buffer_size() as usize * PERIODS));
+    let client = AsyncClient::new(
+        client,
+        (),
+        JackData {
+            frames: frames.clone(),
+            port_l,
+            port_r,
+        },
+    )
+    .unwrap_or_else(|e| panic!("Couldn't activate JACK client: {:?}", e));
+
+    for (source, target) in sources.iter().zip(targets.iter()) {
+        if let Err(e) = client.as_client().connect_ports_by_name(source, target) {
+            error!("Couldn't connect {} to {}: {:?}", source, target, e);
+        }
+    }
+
+    Box::new(JackSink {
+        frames,
+        converter,
+        _client: client,
+    })
+}
+
+/// The ports that can be played into, for `--list-devices`. The device
+/// takes them after an `@`, separated by commas.
+pub fn devices() -> Result<Vec<Device>, Box<Error>> {
+    let (client, _) = Client::new(DEFAULT_CLIENT_NAME, client_options::NO_START_SERVER)
+        .map_err(|e| format!("Couldn't connect to JACK: {:?}", e))?;
+    let physical = client.ports(
+        None,
+        Some("audio"),
+        port_flags::IS_PHYSICAL | port_flags::IS_INPUT,
+    );
+    Ok(client
+        .ports(None, Some("audio"), port_flags::IS_INPUT)
+        .into_iter()
+        .map(|port| Device {
+            description: if physical.contains(&port) {
+                "Physical playback port".to_string()
+            } else {
+                String::new()
+            },
+            name: port,
+        })
+        .collect())
+}
+
+impl Sink for JackSink {
+    fn start(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let samples: Vec<f32> = self
+            .converter
+            .convert(data)
+            .chunks(4)
+            .map(|b| f32::from_bits(u32::from_le_bytes([b[0], b[1], b[2], b[3]])))
+            .collect();
+        let mut rest = &samples[..];
+        while !rest.is_empty() {
+            // The process callback holds the other reference while the
+            // client runs.
+            if Arc::strong_count(&self.frames) == 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "The JACK client stopped",
+                ));
+            }
+            let written = self.frames.write(rest);
+            rest = &rest[written * 2..];
+            if !rest.is_empty() {
+                thread::sleep(WAIT);
+            }
+        }
+        Ok(())
+    }
+}
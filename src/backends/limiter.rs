@@ -0,0 +1,72 @@
+use crate::dither::Quantizer;
+use librespot::playback::audio_backend::Sink;
+use std::{io, time::Duration};
+
+/// librespot always plays 44.1 kHz stereo.
+const FRAMES_PER_SECOND: f64 = 44100.0;
+
+/// Keeps peaks below a threshold: the gain drops at once when a frame would
+/// go above it, and recovers over the release time. librespot applies the
+/// volume and normalisation and rounds to 16 bits before this sees the
+/// audio, so what a pregain clipped there stays clipped; this only leaves
+/// the device headroom.
+pub struct LimiterSink {
+    inner: Box<Sink>,
+    threshold: f64,
+    /// How much of the way back to full gain each frame goes.
+    release: f64,
+    gain: f64,
+    quantizer: Quantizer,
+}
+
+impl LimiterSink {
+    pub fn new(inner: Box<Sink>, threshold_db: f64, release: Duration) -> LimiterSink {
+        let release_frames = release.as_millis() as f64 / 1000.0 * FRAMES_PER_SECOND;
+        LimiterSink {
+            inner,
+            threshold: f64::from(i16::max_value()) * 10f64.powf(threshold_db / 20.0),
+            release: if release_frames < 1.0 {
+                1.0
+            } else {
+                1.0 - (-1.0 / release_frames).exp()
+            },
+            gain: 1.0,
+            quantizer: Quantizer::new(),
+        }
+    }
+}
+
+impl Sink for LimiterSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.gain = 1.0;
+        self.inner.start()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.inner.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let mut out = Vec::with_capacity(data.len());
+        for frame in data.chunks(2) {
+            let peak = frame
+                .iter()
+                .map(|&s| f64::from(s).abs())
+                .fold(0.0, f64::max);
+            let target = if peak > self.threshold {
+                self.threshold / peak
+            } else {
+                1.0
+            };
+            if target < self.gain {
+                self.gain = target;
+            } else {
+                self.gain += (target - self.gain) * self.release;
+            }
+            for &sample in frame {
+                out.push(self.quantizer.quantize(f64::from(sample) * self.gain));
+            }
+        }
+        self.inner.write(&out)
+    }
+}
@@ -0,0 +1,158 @@
+use librespot::playback::audio_backend::{self, Sink};
+use std::{
+    env, thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "alsa_backend")]
+mod alsa;
+mod buffered;
+mod clients;
+mod delay;
+mod devices;
+mod fade;
+mod format;
+mod gstreamer;
+mod http;
+#[cfg(feature = "jack_backend")]
+mod jack;
+mod limiter;
+mod multiroom;
+mod offset;
+mod oss;
+mod pipe;
+mod pipewire;
+mod recovering;
+mod silence;
+mod stall;
+mod stdout;
+mod tcp;
+mod tee;
+mod underrun;
+
+pub use buffered::BufferedSink;
+pub use delay::DelayedStartSink;
+pub use devices::list_devices;
+pub use fade::FadeSink;
+pub use format::set_output;
+pub use limiter::LimiterSink;
+pub use multiroom::{MultiroomLeader, MultiroomSink};
+pub use offset::LatencyOffsetSink;
+pub use recovering::{DeviceEvent, RecoveringSink};
+pub use silence::SilenceTrimSink;
+pub use stall::StallDetectingSink;
+pub use tee::TeeSink;
+pub use underrun::{UnderrunCause, UnderrunSink};
+
+const SAMPLES_PER_SECOND: u64 = 44_100 * 2;
+
+pub type SinkBuilder = fn(Option<String>) -> Box<Sink>;
+
+/// Backends implemented by spotifyd itself. These take precedence over
+/// librespot's backends of the same name.
+const BACKENDS: &[(&str, SinkBuilder)] = &[
+    #[cfg(feature = "alsa_backend")]
+    ("alsa", alsa::open),
+    ("gstreamer", gstreamer::open),
+    ("http", http::open),
+    #[cfg(feature = "jack_backend")]
+    ("jack", jack::open),
+    ("oss", oss::open),
+    ("pipe", pipe::open),
+    ("pipewire", pipewire::open),
+    ("stdout", stdout::open),
+    ("tcp", tcp::open),
+];
+
+/// Backends that write the configured `audio_format` and `sample_rate`,
+/// the others always play S16 at 44.1 kHz.
+pub const CONVERTING: &[&str] = &["alsa", "gstreamer", "pipe", "pipewire", "stdout", "tcp"];
+
+/// All available backends, spotifyd's own first. The first librespot backend
+/// is the default.
+pub fn all() -> Vec<(&'static str, SinkBuilder)> {
+    let mut backends = BACKENDS.to_vec();
+    for &(name, builder) in audio_backend::BACKENDS {
+        if !backends.iter().any(|&(n, _)| n == name) {
+            backends.push((name, builder));
+        }
+    }
+    backends
+}
+
+pub fn find(name: &str) -> Option<SinkBuilder> {
+    all()
+        .into_iter()
+        .find(|&(n, _)| n == name)
+        .map(|(_, builder)| builder)
+}
+
+/// Describes the stream to PulseAudio, which reads client properties from
+/// `PULSE_PROP_*` environment variables. Streams then show up in mixers
+/// like pavucontrol as Spotifyd, with an icon and the music role, whether
+/// they come from the pulseaudio backend or through ALSA's pulse plugin.
+/// Properties set by the user are kept.
+pub fn set_pulseaudio_properties() {
+    let properties = [
+        ("application.name", "Spotifyd"),
+        ("application.id", "rs.spotifyd"),
+        ("application.icon_name", "spotify"),
+        ("media.role", "music"),
+    ];
+    for &(key, value) in &properties {
+        let variable = format!("PULSE_PROP_{}", key);
+        if env::var_os(&variable).is_none() {
+            env::set_var(variable, value);
+        }
+    }
+}
+
+/// The backend librespot would default to, spotifyd's own if it has one of
+/// that name.
+pub fn default() -> Option<(&'static str, SinkBuilder)> {
+    let &(name, _) = audio_backend::BACKENDS.first()?;
+    find(name).map(|builder| (name, builder))
+}
+
+/// Serializes samples as S16LE.
+fn to_bytes(data: &[i16]) -> Vec<u8> {
+    data.iter().flat_map(|s| s.to_le_bytes().to_vec()).collect()
+}
+
+/// Blocks for as long as `data` would take to play, for sinks that drop audio
+/// while nobody is listening but should keep playback going at normal speed.
+fn discard(data: &[i16]) {
+    thread::sleep(Duration::from_micros(
+        data.len() as u64 * 1_000_000 / SAMPLES_PER_SECOND,
+    ));
+}
+
+/// Keeps sinks without backpressure of their own at playback speed.
+struct Pacer {
+    started: Instant,
+    samples: u64,
+}
+
+impl Pacer {
+    fn new() -> Pacer {
+        Pacer {
+            started: Instant::now(),
+            samples: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Pacer::new();
+    }
+
+    /// Blocks until the samples written so far are due, then accounts for
+    /// `samples` more.
+    fn wait(&mut self, samples: usize) {
+        let due = Duration::from_micros(self.samples * 1_000_000 / SAMPLES_PER_SECOND);
+        let elapsed = self.started.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+        self.samples += samples as u64;
+    }
+}
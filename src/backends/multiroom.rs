@@ -0,0 +1,167 @@
+use crate::multiroom::{self, Message};
+use librespot::playback::audio_backend::Sink;
+use log::{error, info, warn};
+use std::{
+    io::{self, Write},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Packets a follower may fall behind before audio is dropped for it.
+const BACKLOG: usize = 64;
+/// How long writing to a follower may take before it is given up on.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// librespot always plays 44.1 kHz stereo.
+const SAMPLES_PER_SECOND: i64 = 44100 * 2;
+
+struct Followers {
+    senders: Vec<SyncSender<Arc<Vec<u8>>>>,
+    /// Whether playback runs, for followers joining in the middle.
+    playing: bool,
+}
+
+/// Where followers connect, for `multiroom_listen`: audio goes out to them
+/// over TCP, and they sync their clocks over UDP on the same port. It lives
+/// as long as spotifyd, across players.
+#[derive(Clone)]
+pub struct MultiroomLeader {
+    followers: Arc<Mutex<Followers>>,
+    /// How far ahead of playing it audio is sent, in microseconds.
+    latency: i64,
+}
+
+impl MultiroomLeader {
+    pub fn new(address: &str, latency: Duration) -> MultiroomLeader {
+        let address = multiroom::with_port(address);
+        let followers = Arc::new(Mutex::new(Followers {
+            senders: Vec::new(),
+            playing: false,
+        }));
+        match UdpSocket::bind(&*address) {
+            Ok(socket) => {
+                thread::spawn(move || multiroom::answer_clock_requests(&socket));
+            },
+            Err(e) => error!("Couldn't listen for clock requests on {}: {}", address, e),
+        }
+        match TcpListener::bind(&*address) {
+            Ok(listener) => {
+                info!("Leading multi-room playback on {}", address);
+                let joining = followers.clone();
+                thread::spawn(move || {
+                    for stream in listener.incoming().filter_map(Result::ok) {
+                        join(stream, &joining);
+                    }
+                });
+            },
+            Err(e) => error!("Couldn't listen for followers on {}: {}", address, e),
+        }
+        MultiroomLeader {
+            followers,
+            latency: latency.as_micros() as i64,
+        }
+    }
+
+    /// Sends to every follower without waiting for any, dropping the audio
+    /// for those behind. Those too far behind to take starting or stopping
+    /// are dropped themselves, they can connect again.
+    fn send(&self, message: &Message) {
+        let bytes = Arc::new(message.encode());
+        let must_arrive = match *message {
+            Message::Data { .. } => false,
+            _ => true,
+        };
+        let mut followers = self.followers.lock().unwrap();
+        followers
+            .senders
+            .retain(|sender| match sender.try_send(bytes.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) if !must_arrive => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Dropping a follower that fell behind");
+                    false
+                },
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+fn join(stream: TcpStream, followers: &Mutex<Followers>) {
+    let address = stream
+        .peer_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_default();
+    info!("{} follows along", address);
+    let _ = stream.set_nodelay(true);
+    let (sender, receiver) = sync_channel(BACKLOG);
+    let mut followers = followers.lock().unwrap();
+    if followers.playing {
+        let _ = sender.try_send(Arc::new(Message::Start.encode()));
+    }
+    followers.senders.push(sender);
+    thread::spawn(move || {
+        if let Err(e) = serve(stream, &receiver) {
+            warn!("{} stopped following: {}", address, e);
+        }
+    });
+}
+
+fn serve(mut stream: TcpStream, receiver: &Receiver<Arc<Vec<u8>>>) -> io::Result<()> {
+    // A follower that stops reading is dropped rather than holding its
+    // messages back forever.
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    for bytes in receiver {
+        stream.write_all(&bytes)?;
+    }
+    Ok(())
+}
+
+/// Plays locally what it sends the followers, stamped with when it plays.
+/// To give the audio time to reach them, the local output is held back by
+/// the leader's latency whenever playback starts.
+pub struct MultiroomSink {
+    inner: Box<Sink>,
+    leader: MultiroomLeader,
+    /// When, on the leader's clock, what is written next plays.
+    next: i64,
+}
+
+impl MultiroomSink {
+    pub fn new(inner: Box<Sink>, leader: MultiroomLeader) -> MultiroomSink {
+        MultiroomSink {
+            inner,
+            leader,
+            next: 0,
+        }
+    }
+}
+
+impl Sink for MultiroomSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.leader.followers.lock().unwrap().playing = true;
+        self.leader.send(&Message::Start);
+        self.inner.start()?;
+        self.next = multiroom::now() + self.leader.latency;
+        let held_back = self.leader.latency * SAMPLES_PER_SECOND / 1_000_000 / 2 * 2;
+        self.inner.write(&vec![0; held_back as usize])
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.leader.followers.lock().unwrap().playing = false;
+        self.leader.send(&Message::Stop);
+        self.inner.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        self.leader.send(&Message::Data {
+            play_at: self.next,
+            samples: data.to_vec(),
+        });
+        self.next += data.len() as i64 * 1_000_000 / SAMPLES_PER_SECOND;
+        self.inner.write(data)
+    }
+}
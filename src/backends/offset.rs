@@ -0,0 +1,60 @@
+use librespot::playback::audio_backend::Sink;
+use std::io;
+
+/// Shifts playback by a fixed amount, to line a zone up with other audio
+/// systems. A positive offset plays silence when playback first starts, which
+/// delays everything after it. A negative one drops as much of the audio
+/// instead, so playback runs ahead. librespot starts the sink at every track
+/// and every resume, so the shift is applied only the first time, once per
+/// player.
+pub struct LatencyOffsetSink {
+    inner: Box<Sink>,
+    /// Samples of silence to play, or to drop if negative.
+    offset: i64,
+    /// Samples still to drop since playback started.
+    skip: usize,
+    applied: bool,
+}
+
+impl LatencyOffsetSink {
+    /// `offset_ms` is at most `config::MAX_LATENCY_OFFSET_MS` either way.
+    pub fn new(inner: Box<Sink>, offset_ms: i64) -> LatencyOffsetSink {
+        // Whole frames, so the channels stay in place.
+        let offset = offset_ms * 44100 / 1000 * 2;
+        LatencyOffsetSink {
+            inner,
+            offset,
+            skip: 0,
+            applied: false,
+        }
+    }
+}
+
+impl Sink for LatencyOffsetSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.inner.start()?;
+        if self.applied {
+            return Ok(());
+        }
+        self.applied = true;
+        if self.offset > 0 {
+            self.inner.write(&vec![0; self.offset as usize])
+        } else {
+            self.skip = -self.offset as usize;
+            Ok(())
+        }
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.inner.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let skipped = self.skip.min(data.len());
+        self.skip -= skipped;
+        if skipped == data.len() {
+            return Ok(());
+        }
+        self.inner.write(&data[skipped..])
+    }
+}
@@ -0,0 +1,97 @@
+use super::{devices::Device, to_bytes};
+use librespot::playback::audio_backend::Sink;
+use log::info;
+use std::{
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::io::AsRawFd,
+};
+
+const DEFAULT_DEVICE: &str = "/dev/dsp";
+// From sys/soundcard.h, the same on FreeBSD and Linux.
+const SNDCTL_DSP_SPEED: libc::c_ulong = 0xc004_5002;
+const SNDCTL_DSP_SETFMT: libc::c_ulong = 0xc004_5005;
+const SNDCTL_DSP_CHANNELS: libc::c_ulong = 0xc004_5006;
+const AFMT_S16_LE: libc::c_int = 0x10;
+
+/// Plays through an Open Sound System device such as `/dev/dsp`, the native
+/// audio interface on FreeBSD. The device is only held open while playing.
+pub struct OssSink {
+    path: String,
+    device: Option<File>,
+}
+
+pub fn open(device: Option<String>) -> Box<Sink> {
+    let path = device.unwrap_or_else(|| DEFAULT_DEVICE.to_string());
+    info!("Using OSS sink with device {}", path);
+    Box::new(OssSink { path, device: None })
+}
+
+/// The `/dev/dsp` devices, for `--list-devices`. FreeBSD describes them in
+/// `/dev/sndstat`, with lines like `pcm0: <Realtek ALC892 (Analog)> default`.
+pub fn devices() -> Result<Vec<Device>, Box<Error>> {
+    let sndstat = fs::read_to_string("/dev/sndstat").unwrap_or_default();
+    let mut devices = fs::read_dir("/dev")?
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.starts_with("dsp"))
+        .map(|name| {
+            let unit = format!("pcm{}:", name[3..].split('.').next().unwrap_or_default());
+            let description = sndstat
+                .lines()
+                .find(|line| line.starts_with(&*unit))
+                .map_or_else(String::new, |line| line[unit.len()..].trim().to_string());
+            Device {
+                name: format!("/dev/{}", name),
+                description,
+            }
+        })
+        .collect::<Vec<_>>();
+    devices.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(devices)
+}
+
+/// Sets a parameter of the device, failing if the device doesn't take the
+/// value as is.
+fn set(device: &File, request: libc::c_ulong, value: libc::c_int) -> io::Result<()> {
+    let mut actual = value;
+    if unsafe { libc::ioctl(device.as_raw_fd(), request, &mut actual) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if actual != value {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("The device doesn't support {} (got {})", value, actual),
+        ));
+    }
+    Ok(())
+}
+
+impl Sink for OssSink {
+    fn start(&mut self) -> io::Result<()> {
+        if self.device.is_none() {
+            let device = OpenOptions::new().write(true).open(&self.path)?;
+            set(&device, SNDCTL_DSP_SETFMT, AFMT_S16_LE)?;
+            set(&device, SNDCTL_DSP_CHANNELS, 2)?;
+            set(&device, SNDCTL_DSP_SPEED, 44100)?;
+            self.device = Some(device);
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.device = None;
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        match self.device {
+            Some(ref mut device) => device.write_all(&to_bytes(data)),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "The OSS device isn't open",
+            )),
+        }
+    }
+}
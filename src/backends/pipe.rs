@@ -0,0 +1,93 @@
+use super::{
+    discard,
+    format::{self, Converter},
+};
+use crate::utils::create_fifo;
+use librespot::playback::audio_backend::Sink;
+use log::info;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    path::PathBuf,
+};
+
+const DEFAULT_PATH: &str = "/tmp/snapfifo";
+
+/// Writes raw stereo PCM in the output format, S16LE at 44.1 kHz by default,
+/// to a named pipe.
+///
+/// The pipe is created if it doesn't exist. While nobody is reading from it,
+/// audio is dropped at playback speed, so playback carries on and picks up
+/// again as soon as a reader connects.
+pub struct PipeSink {
+    path: PathBuf,
+    fifo: Option<File>,
+    converter: Converter,
+}
+
+pub fn open(device: Option<String>) -> Box<Sink> {
+    let path = PathBuf::from(device.unwrap_or_else(|| DEFAULT_PATH.to_string()));
+    if let Err(e) = create_fifo(&path) {
+        panic!("Couldn't create pipe {:?}: {}", path, e);
+    }
+    info!("Using pipe sink with path: {:?}", path);
+    Box::new(PipeSink {
+        path,
+        fifo: None,
+        converter: Converter::new(format::output()),
+    })
+}
+
+impl PipeSink {
+    fn connect(&mut self) {
+        // A non-blocking open fails with ENXIO while there is no reader,
+        // which is exactly what we want to find out without stalling.
+        let fifo = match OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&self.path)
+        {
+            Ok(f) => f,
+            Err(_) => return,
+        };
+        // Writes should block again once connected, so the reader paces us.
+        let fd = fifo.as_raw_fd();
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK);
+        }
+        info!("Reader connected to pipe {:?}", self.path);
+        self.fifo = Some(fifo);
+    }
+}
+
+impl Sink for PipeSink {
+    fn start(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        if self.fifo.is_none() {
+            self.connect();
+        }
+
+        let result = match self.fifo {
+            Some(ref mut fifo) => fifo.write_all(&self.converter.convert(data)),
+            None => {
+                discard(data);
+                return Ok(());
+            },
+        };
+
+        if let Err(e) = result {
+            info!("Reader of pipe {:?} went away: {}", self.path, e);
+            self.fifo = None;
+        }
+        Ok(())
+    }
+}
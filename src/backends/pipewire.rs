@@ -0,0 +1,144 @@
+use super::{
+    devices::{self, Device},
+    format::{self, Converter},
+};
+use crate::errors::Failure;
+use librespot::playback::audio_backend::Sink;
+use log::{error, info};
+use std::{
+    env,
+    error::Error,
+    io::{self, Write},
+    iter, mem,
+    process::{Child, Command, Stdio},
+};
+
+const DEFAULT_NODE_NAME: &str = "spotifyd";
+const PW_CAT: &str = "pw-cat";
+
+/// Plays through PipeWire as a native stream node, by feeding `pw-cat`.
+///
+/// The device is `[NODE_NAME@]TARGET`: the stream shows up as `NODE_NAME`
+/// (`spotifyd` by default) and is linked to the node `TARGET`, or wherever
+/// the session manager routes it if no target is given. Like the PulseAudio
+/// backend, the stream only exists while playing.
+pub struct PipeWireSink {
+    node_name: String,
+    target: Option<String>,
+    child: Option<Child>,
+    converter: Option<Converter>,
+}
+
+pub fn open(device: Option<String>) -> Box<Sink> {
+    let (node_name, target) = match device {
+        Some(ref device) => match device.find('@') {
+            Some(i) => (device[..i].to_string(), Some(device[i + 1..].to_string())),
+            None => (DEFAULT_NODE_NAME.to_string(), Some(device.clone())),
+        },
+        None => (DEFAULT_NODE_NAME.to_string(), None),
+    };
+    info!("Using PipeWire sink as node {}", node_name);
+    if !in_path(PW_CAT) {
+        error!(
+            "The PipeWire backend plays through {}, which wasn't found in PATH. It comes with \
+             PipeWire, often in a package of its own such as pipewire-bin or pipewire-utils.",
+            PW_CAT
+        );
+        Failure::AudioBackend.exit()
+    }
+
+    Box::new(PipeWireSink {
+        node_name,
+        target: target.filter(|t| !t.is_empty()),
+        child: None,
+        converter: None,
+    })
+}
+
+/// Whether an executable of that name is in one of the directories of `PATH`.
+fn in_path(program: &str) -> bool {
+    env::var_os("PATH").map_or(false, |paths| {
+        env::split_paths(&paths).any(|dir| dir.join(program).is_file())
+    })
+}
+
+/// The sinks the stream can be linked to, from `pw-cli`, for
+/// `--list-devices`. Each node is listed as a line `id N, type ...` and then
+/// its properties as `key = "value"`.
+pub fn devices() -> Result<Vec<Device>, Box<Error>> {
+    let listing = devices::command_output(Command::new("pw-cli").args(&["ls", "Node"]))?;
+    let mut devices = Vec::new();
+    let mut node = Device::default();
+    let mut sink = false;
+    // Another `id` at the end lets the last node through.
+    for line in listing.lines().map(str::trim).chain(iter::once("id")) {
+        if line.starts_with("id") {
+            let node = mem::replace(&mut node, Device::default());
+            if sink && !node.name.is_empty() {
+                devices.push(node);
+            }
+            sink = false;
+            continue;
+        }
+        let mut property = line.splitn(2, " = ");
+        let (key, value) = match (property.next(), property.next()) {
+            (Some(key), Some(value)) => (key, value.trim_matches('"').to_string()),
+            _ => continue,
+        };
+        match key {
+            "node.name" => node.name = value,
+            "node.description" => node.description = value,
+            "media.class" => sink = value == "Audio/Sink",
+            _ => (),
+        }
+    }
+    Ok(devices)
+}
+
+impl Sink for PipeWireSink {
+    fn start(&mut self) -> io::Result<()> {
+        if self.child.is_some() {
+            return Ok(());
+        }
+        let properties = format!(
+            "{{ node.name = \"{0}\" node.description = \"{0}\" application.name = \"Spotifyd\" \
+             media.role = \"Music\" }}",
+            self.node_name
+        );
+        let output = format::output();
+        let mut command = Command::new(PW_CAT);
+        command
+            .args(&["--playback", "--raw", "--format", output.pipewire_format()])
+            .args(&["--rate", &output.rate.to_string()])
+            .args(&["--channels", &output.channels().to_string()])
+            .args(&["--properties", &properties]);
+        if let Some(ref target) = self.target {
+            command.args(&["--target", target]);
+        }
+        self.child = Some(command.arg("-").stdin(Stdio::piped()).spawn()?);
+        self.converter = Some(Converter::new(output));
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        if let Some(mut child) = self.child.take() {
+            // Closing standard input lets pw-cat drain and exit.
+            drop(child.stdin.take());
+            child.wait()?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let stdin = self
+            .child
+            .as_mut()
+            .and_then(|c| c.stdin.as_mut())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "pw-cat isn't running"))?;
+        let bytes = match self.converter {
+            Some(ref mut converter) => converter.convert(data),
+            None => return Ok(()),
+        };
+        stdin.write_all(&bytes)
+    }
+}
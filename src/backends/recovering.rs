@@ -0,0 +1,117 @@
+use super::{SinkBuilder, SAMPLES_PER_SECOND};
+use librespot::playback::audio_backend::Sink;
+use log::{info, warn};
+use std::{
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::mpsc::Sender,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often to try opening a device that failed again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Whether the output device is there to play on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceEvent {
+    Lost,
+    Restored,
+}
+
+/// Keeps playback alive when the device fails, e.g. because a USB DAC was
+/// unplugged or another program holds it, instead of taking the player down
+/// with it. The backend is opened anew every few seconds while audio keeps
+/// coming; until then the audio is dropped, at the pace it would have played.
+pub struct RecoveringSink {
+    builder: SinkBuilder,
+    device: Option<String>,
+    /// The backend, opened when first used so that a device which is busy or
+    /// missing at startup is retried like one that fails later.
+    inner: Option<Box<Sink>>,
+    events: Sender<DeviceEvent>,
+    /// When opening the device failed last, while it is gone.
+    failed: Option<Instant>,
+}
+
+impl RecoveringSink {
+    pub fn new(
+        builder: SinkBuilder,
+        device: Option<String>,
+        events: Sender<DeviceEvent>,
+    ) -> RecoveringSink {
+        RecoveringSink {
+            inner: None,
+            builder,
+            device,
+            events,
+            failed: None,
+        }
+    }
+
+    /// Runs `f` on the backend. Some backends panic on device errors, those
+    /// panics are turned into errors.
+    fn guarded<F: FnOnce(&mut Box<Sink>) -> io::Result<()>>(&mut self, f: F) -> io::Result<()> {
+        let (builder, device, inner) = (self.builder, &self.device, &mut self.inner);
+        panic::catch_unwind(AssertUnwindSafe(move || {
+            f(inner.get_or_insert_with(|| builder(device.clone())))
+        }))
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "the backend failed")))
+    }
+
+    fn lost(&mut self, e: &io::Error) {
+        if self.failed.is_none() {
+            warn!("The audio device failed: {}", e);
+            let _ = self.events.send(DeviceEvent::Lost);
+        }
+        self.failed = Some(Instant::now());
+        // Close the device, it is opened anew on the next try.
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            if let Some(mut inner) = self.inner.take() {
+                let _ = inner.stop();
+            }
+        }));
+    }
+}
+
+impl Sink for RecoveringSink {
+    fn start(&mut self) -> io::Result<()> {
+        match self.guarded(|sink| sink.start()) {
+            Ok(()) => {
+                if self.failed.take().is_some() {
+                    info!("The audio device is back");
+                    let _ = self.events.send(DeviceEvent::Restored);
+                }
+            },
+            Err(e) => self.lost(&e),
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        if self.failed.is_none() && self.inner.is_some() {
+            if let Err(e) = self.guarded(|sink| sink.stop()) {
+                self.lost(&e);
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        if let Some(failed) = self.failed {
+            if failed.elapsed() >= RETRY_INTERVAL {
+                self.start()?;
+            }
+        }
+        if self.failed.is_none() {
+            match self.guarded(|sink| sink.write(data)) {
+                Ok(()) => return Ok(()),
+                Err(e) => self.lost(&e),
+            }
+        }
+        thread::sleep(Duration::from_micros(
+            data.len() as u64 * 1_000_000 / SAMPLES_PER_SECOND,
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,64 @@
+use librespot::playback::audio_backend::Sink;
+use std::{io, time::Duration};
+
+/// librespot always plays 44.1 kHz stereo.
+const SAMPLES_PER_SECOND: u64 = 44100 * 2;
+
+/// Shortens silence, like the tail of one track and the lead-in of the next,
+/// to at most the given length. Frames count as silent when no channel is
+/// louder than the threshold.
+///
+/// The sink can't tell where one track ends and the next begins, so long
+/// silence within a track is shortened as well, and the track ends earlier
+/// than Spotify Connect expects.
+pub struct SilenceTrimSink {
+    inner: Box<Sink>,
+    threshold: i32,
+    /// The most silence to keep, in samples.
+    longest: usize,
+    /// Samples of silence written since the last sound.
+    silent: usize,
+}
+
+impl SilenceTrimSink {
+    pub fn new(inner: Box<Sink>, longest: Duration, threshold_db: f64) -> SilenceTrimSink {
+        let samples = longest.as_millis() as u64 * SAMPLES_PER_SECOND / 1000;
+        let threshold = f64::from(i16::max_value()) * 10f64.powf(threshold_db / 20.0);
+        SilenceTrimSink {
+            inner,
+            threshold: threshold as i32,
+            longest: (samples - samples % 2) as usize,
+            silent: 0,
+        }
+    }
+}
+
+impl Sink for SilenceTrimSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.silent = 0;
+        self.inner.start()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.inner.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let mut out = Vec::with_capacity(data.len());
+        for frame in data.chunks(2) {
+            if frame.iter().all(|&s| i32::from(s).abs() <= self.threshold) {
+                self.silent += frame.len();
+                if self.silent > self.longest {
+                    continue;
+                }
+            } else {
+                self.silent = 0;
+            }
+            out.extend_from_slice(frame);
+        }
+        if out.is_empty() {
+            return Ok(());
+        }
+        self.inner.write(&out)
+    }
+}
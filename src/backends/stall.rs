@@ -0,0 +1,65 @@
+use librespot::playback::audio_backend::Sink;
+use std::{
+    io,
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+/// librespot always plays 44.1 kHz stereo.
+const SAMPLES_PER_SECOND: u64 = 44100 * 2;
+/// Audio arriving this much later than it is due means playback stalled.
+const STALL: Duration = Duration::from_secs(1);
+
+/// Reports stalls, when the player runs out of audio while playing, e.g.
+/// because the network can't keep up with the bitrate.
+pub struct StallDetectingSink {
+    inner: Box<Sink>,
+    stalls: Sender<()>,
+    /// Since when audio has been flowing, and how much of it.
+    started: Option<Instant>,
+    samples: u64,
+}
+
+impl StallDetectingSink {
+    pub fn new(inner: Box<Sink>, stalls: Sender<()>) -> StallDetectingSink {
+        StallDetectingSink {
+            inner,
+            stalls,
+            started: None,
+            samples: 0,
+        }
+    }
+}
+
+impl Sink for StallDetectingSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.started = None;
+        self.inner.start()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.started = None;
+        self.inner.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let now = Instant::now();
+        match self.started {
+            Some(started) => {
+                let due =
+                    started + Duration::from_micros(self.samples * 1_000_000 / SAMPLES_PER_SECOND);
+                if now > due + STALL {
+                    let _ = self.stalls.send(());
+                    self.started = Some(now);
+                    self.samples = 0;
+                }
+            },
+            None => {
+                self.started = Some(now);
+                self.samples = 0;
+            },
+        }
+        self.samples += data.len() as u64;
+        self.inner.write(data)
+    }
+}
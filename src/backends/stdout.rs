@@ -0,0 +1,34 @@
+use super::format::{self, Converter};
+use librespot::playback::audio_backend::Sink;
+use std::io::{self, Write};
+
+/// Writes raw stereo PCM in the output format, S16LE at 44.1 kHz by default,
+/// to standard output, for piping
+/// into tools like sox, ffmpeg or aplay. Logging goes to standard error while
+/// this backend is in use.
+pub struct StdoutSink {
+    stdout: io::Stdout,
+    converter: Converter,
+}
+
+pub fn open(_: Option<String>) -> Box<Sink> {
+    Box::new(StdoutSink {
+        stdout: io::stdout(),
+        converter: Converter::new(format::output()),
+    })
+}
+
+impl Sink for StdoutSink {
+    fn start(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.stdout.flush()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let bytes = self.converter.convert(data);
+        self.stdout.lock().write_all(&bytes)
+    }
+}
@@ -0,0 +1,162 @@
+use super::{
+    clients::Clients,
+    format::{self, Converter},
+    Pacer,
+};
+use crate::socket_activation;
+use lazy_static::lazy_static;
+use librespot::playback::audio_backend::Sink;
+use log::{error, info};
+use std::{
+    collections::HashMap,
+    io,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_LISTEN_ADDRESS: &str = "0.0.0.0:4953";
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /// The clients of each address listened on. The player opens a new sink
+    /// whenever it is restarted, and those listening on the same address
+    /// share the listener and its clients rather than bind it again.
+    static ref LISTENING: Mutex<HashMap<String, Clients>> = Mutex::new(HashMap::new());
+}
+
+/// Streams raw stereo PCM in the output format, S16LE at 44.1 kHz by
+/// default, over TCP.
+///
+/// With a device of `listen:ADDRESS` (the default is `listen:0.0.0.0:4953`)
+/// any number of clients can connect and receive the stream. With
+/// `connect:HOST:PORT`, spotifyd connects to the given address itself and
+/// reconnects whenever the connection drops. While nobody receives the
+/// stream, playback carries on and the audio is dropped, and clients that
+/// can't keep up are disconnected.
+pub struct TcpSink {
+    mode: Mode,
+    clients: Clients,
+    converter: Converter,
+    pacer: Pacer,
+}
+
+enum Mode {
+    Listen,
+    Connect {
+        address: String,
+        last_attempt: Option<Instant>,
+    },
+}
+
+pub fn open(device: Option<String>) -> Box<Sink> {
+    let device = device.unwrap_or_else(|| format!("listen:{}", DEFAULT_LISTEN_ADDRESS));
+
+    let (mode, clients) = if device.starts_with("connect:") {
+        let address = device["connect:".len()..].to_string();
+        info!("Using TCP sink, streaming to {}", address);
+        let mode = Mode::Connect {
+            address,
+            last_attempt: None,
+        };
+        (mode, Clients::new("TCP sink"))
+    } else {
+        let address = if device.starts_with("listen:") {
+            &device["listen:".len()..]
+        } else {
+            &device
+        };
+        info!("Using TCP sink, listening on {}", address);
+        (Mode::Listen, listen(address))
+    };
+
+    Box::new(TcpSink {
+        mode,
+        clients,
+        converter: Converter::new(format::output()),
+        pacer: Pacer::new(),
+    })
+}
+
+/// The clients of the address, listening on it unless a sink did before.
+fn listen(address: &str) -> Clients {
+    let mut listening = LISTENING.lock().unwrap();
+    if let Some(clients) = listening.get(address) {
+        return clients.clone();
+    }
+    let listener = match socket_activation::tcp_listener("tcp") {
+        Some(listener) => listener,
+        None => TcpListener::bind(address)
+            .unwrap_or_else(|e| panic!("Couldn't listen on {}: {}", address, e)),
+    };
+    let clients = Clients::new("TCP sink");
+    let accepted = clients.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    info!("TCP sink client connected: {:?}", stream.peer_addr());
+                    let _ = stream.set_nodelay(true);
+                    accepted.add(stream);
+                },
+                Err(e) => error!("Couldn't accept TCP sink client: {}", e),
+            }
+        }
+    });
+    listening.insert(address.to_string(), clients.clone());
+    clients
+}
+
+impl TcpSink {
+    fn connect(&mut self) {
+        if let Mode::Connect {
+            ref address,
+            ref mut last_attempt,
+        } = self.mode
+        {
+            if last_attempt.map_or(false, |t| t.elapsed() < RECONNECT_INTERVAL) {
+                return;
+            }
+            *last_attempt = Some(Instant::now());
+
+            let stream = address
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unresolvable address"))
+                .and_then(|addr| TcpStream::connect_timeout(&addr, RECONNECT_INTERVAL));
+            match stream {
+                Ok(stream) => {
+                    info!("TCP sink connected to {}", address);
+                    let _ = stream.set_nodelay(true);
+                    self.clients.add(stream);
+                },
+                Err(e) => info!("Couldn't connect TCP sink to {}: {}", address, e),
+            }
+        }
+    }
+}
+
+impl Sink for TcpSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.pacer.reset();
+        Ok(())
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        // Nothing waits for the clients, so keep to playback speed.
+        self.pacer.wait(data.len());
+        if self.clients.is_empty() {
+            self.connect();
+        }
+        if !self.clients.is_empty() {
+            self.clients.send(self.converter.convert(data));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+use super::SinkBuilder;
+use librespot::playback::audio_backend::Sink;
+use log::{error, warn};
+use std::{
+    io,
+    sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+    thread,
+};
+
+/// Packets an extra output may fall behind before audio is dropped for it.
+const BACKLOG: usize = 64;
+
+enum Message {
+    Start,
+    Stop,
+    Data(Vec<i16>),
+}
+
+/// Plays through one sink and copies the audio to any number of others.
+///
+/// The first sink sets the pace. The others run on threads of their own and
+/// never hold it up: if one falls behind, it misses audio instead.
+pub struct TeeSink {
+    main: Box<Sink>,
+    others: Vec<SyncSender<Message>>,
+}
+
+impl TeeSink {
+    pub fn new(main: Box<Sink>, others: &[(SinkBuilder, Option<String>)]) -> TeeSink {
+        let others = others
+            .iter()
+            .map(|&(builder, ref device)| {
+                let (sender, receiver) = sync_channel(BACKLOG);
+                let device = device.clone();
+                thread::spawn(move || run(builder(device), &receiver));
+                sender
+            })
+            .collect();
+        TeeSink { main, others }
+    }
+
+    /// Sends audio to the extra outputs, dropping it for those behind.
+    fn send_data(&mut self, data: &[i16]) {
+        self.others
+            .retain(|other| match other.try_send(Message::Data(data.to_vec())) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+
+    /// Starting and stopping must not get lost, so the extra outputs too far
+    /// behind to take them are dropped rather than waited for.
+    fn send_control(&mut self, message: fn() -> Message) {
+        self.others.retain(|other| match other.try_send(message()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("An extra audio output fell behind, continuing without it.");
+                false
+            },
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+fn run(mut sink: Box<Sink>, receiver: &Receiver<Message>) {
+    for message in receiver {
+        let result = match message {
+            Message::Start => sink.start(),
+            Message::Stop => sink.stop(),
+            Message::Data(ref data) => sink.write(data),
+        };
+        if let Err(e) = result {
+            error!("Extra audio output failed: {}", e);
+            return;
+        }
+    }
+}
+
+impl Sink for TeeSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.send_control(|| Message::Start);
+        self.main.start()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.send_control(|| Message::Stop);
+        self.main.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let before = self.others.len();
+        self.send_data(data);
+        if self.others.len() < before {
+            warn!("An extra audio output stopped, continuing without it.");
+        }
+        self.main.write(data)
+    }
+}
@@ -0,0 +1,122 @@
+use librespot::playback::audio_backend::Sink;
+use std::{
+    io,
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+/// librespot always plays 44.1 kHz stereo.
+const SAMPLES_PER_SECOND: u64 = 44100 * 2;
+/// Audio arriving this much later than it is due has left the device
+/// without anything to play.
+const LATE: Duration = Duration::from_millis(200);
+/// How much audio is gathered after an underrun before playing on, so that
+/// a slow stream makes one longer gap rather than many short ones.
+const REFILL: Duration = Duration::from_millis(500);
+
+/// Why the device ran out of audio.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnderrunCause {
+    /// The audio arrived late, e.g. because the network can't keep up.
+    Network,
+    /// The device took longer than the audio lasts to accept it.
+    Device,
+}
+
+impl UnderrunCause {
+    pub fn name(self) -> &'static str {
+        match self {
+            UnderrunCause::Network => "network",
+            UnderrunCause::Device => "device",
+        }
+    }
+}
+
+/// Notices when the device runs out of audio while playing, reports it with
+/// its likely cause, and refills before playing on.
+pub struct UnderrunSink {
+    inner: Box<Sink>,
+    underruns: Sender<UnderrunCause>,
+    /// Since when audio has been flowing, and how much of it.
+    started: Option<Instant>,
+    samples: u64,
+    /// Whether the last write to the device took longer than its audio.
+    slow_device: bool,
+    /// Audio held back after an underrun, if refilling.
+    refill: Option<Vec<i16>>,
+}
+
+impl UnderrunSink {
+    pub fn new(inner: Box<Sink>, underruns: Sender<UnderrunCause>) -> UnderrunSink {
+        UnderrunSink {
+            inner,
+            underruns,
+            started: None,
+            samples: 0,
+            slow_device: false,
+            refill: None,
+        }
+    }
+
+    fn write_through(&mut self, data: &[i16]) -> io::Result<()> {
+        let before = Instant::now();
+        let written = self.inner.write(data);
+        let lasts = Duration::from_micros(data.len() as u64 * 1_000_000 / SAMPLES_PER_SECOND);
+        self.slow_device = before.elapsed() > lasts + LATE;
+        self.samples += data.len() as u64;
+        written
+    }
+}
+
+impl Sink for UnderrunSink {
+    fn start(&mut self) -> io::Result<()> {
+        self.started = None;
+        self.refill = None;
+        self.inner.start()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.started = None;
+        // What was gathered so far is played rather than dropped.
+        if let Some(held) = self.refill.take() {
+            self.inner.write(&held)?;
+        }
+        self.inner.stop()
+    }
+
+    fn write(&mut self, data: &[i16]) -> io::Result<()> {
+        let now = Instant::now();
+        if let Some(mut held) = self.refill.take() {
+            held.extend_from_slice(data);
+            let refill = REFILL.as_millis() as u64 * SAMPLES_PER_SECOND / 1000;
+            if (held.len() as u64) < refill {
+                self.refill = Some(held);
+                return Ok(());
+            }
+            self.started = Some(now);
+            self.samples = 0;
+            return self.write_through(&held);
+        }
+        match self.started {
+            Some(started) => {
+                let due =
+                    started + Duration::from_micros(self.samples * 1_000_000 / SAMPLES_PER_SECOND);
+                if now > due + LATE {
+                    let cause = if self.slow_device {
+                        UnderrunCause::Device
+                    } else {
+                        UnderrunCause::Network
+                    };
+                    let _ = self.underruns.send(cause);
+                    self.refill = Some(data.to_vec());
+                    return Ok(());
+                }
+            },
+            None => {
+                self.started = Some(now);
+                self.samples = 0;
+            },
+        }
+        self.write_through(data)
+    }
+}
@@ -0,0 +1,62 @@
+use futures::{Async, Future};
+use log::info;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_core::reactor::{Handle, Timeout};
+
+const FIRST_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Spaces out attempts to connect again after losing the connection,
+/// doubling the delay after each failed attempt, up to five minutes. The
+/// delays are jittered so devices that lost their connection together don't
+/// come back all at once.
+pub struct Backoff {
+    handle: Handle,
+    attempts: u32,
+    timeout: Option<Timeout>,
+}
+
+impl Backoff {
+    pub fn new(handle: Handle) -> Backoff {
+        Backoff {
+            handle,
+            attempts: 0,
+            timeout: None,
+        }
+    }
+
+    /// Schedules the next attempt.
+    pub fn schedule(&mut self) {
+        let delay = FIRST_DELAY
+            .checked_mul(1u32 << self.attempts.min(16))
+            .map_or(MAX_DELAY, |d| d.min(MAX_DELAY));
+        // Between half and all of the delay. The clock's nanoseconds are
+        // random enough for this.
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let delay = delay / 2 + delay / 2 * (nanos % 1000) / 1000;
+        info!("Connecting again in {} s", delay.as_secs());
+        self.attempts += 1;
+        self.timeout = Some(Timeout::new(delay, &self.handle).unwrap());
+    }
+
+    /// Whether it is time for the scheduled attempt.
+    pub fn poll(&mut self) -> bool {
+        let ready = match self.timeout {
+            Some(ref mut timeout) => timeout.poll().map(|a| a.is_ready()).unwrap_or(true),
+            None => false,
+        };
+        if ready {
+            self.timeout = None;
+        }
+        ready
+    }
+
+    /// Starts over with short delays, once connected.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+        self.timeout = None;
+    }
+}
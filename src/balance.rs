@@ -0,0 +1,141 @@
+use crate::{config::BalanceConfig, dither::Quantizer};
+use librespot::playback::mixer::{softmixer::SoftMixer, AudioFilter, Mixer};
+use log::info;
+use std::sync::{Arc, Mutex};
+
+struct Balance {
+    /// From -1, only the left channel, to 1, only the right one.
+    balance: f64,
+    /// The gain of the left and the right channel, in dB.
+    gains_db: [f64; 2],
+    /// What the samples of each channel are multiplied by.
+    factors: [f64; 2],
+    quantizer: Quantizer,
+}
+
+impl Balance {
+    fn update(&mut self) {
+        let linear = |db: f64| 10f64.powf(db / 20.0);
+        self.factors = [
+            linear(self.gains_db[0]) * (1.0 - self.balance.max(0.0)),
+            linear(self.gains_db[1]) * (1.0 + self.balance.min(0.0)),
+        ];
+    }
+
+    fn process(&mut self, data: &mut [i16]) {
+        if self.factors == [1.0, 1.0] {
+            return;
+        }
+        for (i, sample) in data.iter_mut().enumerate() {
+            *sample = self
+                .quantizer
+                .quantize(f64::from(*sample) * self.factors[i % 2]);
+        }
+    }
+}
+
+/// The balance between the left and right channel and a gain for each, for
+/// speakers placed unevenly in a room. Applied through the mixer's audio
+/// filter, and shared so it can be adjusted while playing.
+#[derive(Clone)]
+pub struct SharedBalance(Arc<Mutex<Balance>>);
+
+// Only D-Bus adjusts the balance so far.
+#[cfg_attr(not(feature = "dbus_mpris"), allow(dead_code))]
+impl SharedBalance {
+    pub fn new(config: BalanceConfig) -> SharedBalance {
+        let mut balance = Balance {
+            balance: config.balance,
+            gains_db: config.gains_db,
+            factors: [1.0, 1.0],
+            quantizer: Quantizer::new(),
+        };
+        balance.update();
+        SharedBalance(Arc::new(Mutex::new(balance)))
+    }
+
+    pub fn balance(&self) -> f64 {
+        self.0.lock().unwrap().balance
+    }
+
+    pub fn gains_db(&self) -> [f64; 2] {
+        self.0.lock().unwrap().gains_db
+    }
+
+    /// Clamped to -1, left only, to 1, right only.
+    pub fn set_balance(&self, value: f64) {
+        let mut balance = self.0.lock().unwrap();
+        balance.balance = value.max(-1.0).min(1.0);
+        info!("Balance set to {:.2}", balance.balance);
+        balance.update();
+    }
+
+    pub fn set_gains_db(&self, left: f64, right: f64) {
+        let mut balance = self.0.lock().unwrap();
+        balance.gains_db = [left, right];
+        info!("Channel gains set to {} dB and {} dB", left, right);
+        balance.update();
+    }
+
+    pub fn mixer(&self, mixer: Box<Mixer>) -> Box<Mixer> {
+        Box::new(BalanceMixer {
+            inner: mixer,
+            balance: Some(self.clone()),
+        })
+    }
+}
+
+struct BalanceMixer {
+    inner: Box<Mixer>,
+    balance: Option<SharedBalance>,
+}
+
+impl Mixer for BalanceMixer {
+    fn open() -> BalanceMixer {
+        BalanceMixer {
+            inner: Box::new(SoftMixer::open()),
+            balance: None,
+        }
+    }
+
+    fn start(&self) {
+        self.inner.start()
+    }
+
+    fn stop(&self) {
+        self.inner.stop()
+    }
+
+    fn volume(&self) -> u16 {
+        self.inner.volume()
+    }
+
+    fn set_volume(&self, volume: u16) {
+        self.inner.set_volume(volume)
+    }
+
+    fn get_audio_filter(&self) -> Option<Box<AudioFilter + Send>> {
+        let inner = self.inner.get_audio_filter();
+        match self.balance {
+            Some(ref balance) => Some(Box::new(BalanceAudioFilter {
+                balance: balance.clone(),
+                inner,
+            })),
+            None => inner,
+        }
+    }
+}
+
+struct BalanceAudioFilter {
+    balance: SharedBalance,
+    inner: Option<Box<AudioFilter + Send>>,
+}
+
+impl AudioFilter for BalanceAudioFilter {
+    fn modify_stream(&self, data: &mut [i16]) {
+        if let Some(ref inner) = self.inner {
+            inner.modify_stream(data);
+        }
+        self.balance.0.lock().unwrap().process(data);
+    }
+}
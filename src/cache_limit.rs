@@ -0,0 +1,119 @@
+use futures::{Async, Stream};
+use log::info;
+use std::{
+    env, fs, io,
+    os::unix::fs::{DirBuilderExt, MetadataExt},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio_core::reactor::{Handle, Interval};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where the cache goes with `cache_in_memory`: the user's runtime directory
+/// or, lacking one, a private directory in /dev/shm, both of which live in
+/// RAM. Its contents survive restarts, but not reboots.
+pub fn memory_cache_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("spotifyd"),
+        None => PathBuf::from(format!("/dev/shm/spotifyd-{}", unsafe { libc::getuid() })),
+    }
+}
+
+/// Creates the directory of the memory cache for no one but its user to
+/// enter, or checks that the one there is such: a directory rather than a
+/// link, owned by one of `owners` and closed to everyone else. The
+/// credentials are kept there too, and anyone can create a directory in
+/// /dev/shm first.
+pub fn create_private_dir(path: &Path, owners: &[libc::uid_t]) -> io::Result<()> {
+    match fs::DirBuilder::new().mode(0o700).create(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+        result => return result,
+    }
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.file_type().is_dir() {
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "it exists, but isn't a directory",
+        ))
+    } else if !owners.contains(&metadata.uid()) {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("it belongs to the user with the ID {}", metadata.uid()),
+        ))
+    } else if metadata.mode() & 0o077 != 0 {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "other users have access to it",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Keeps the cached audio below a size, deleting the files that were
+/// downloaded first, as librespot's cache grows without bounds.
+pub struct CacheLimit {
+    files: PathBuf,
+    max_size: u64,
+    interval: Interval,
+}
+
+impl CacheLimit {
+    pub fn new(cache_path: &Path, max_size: u64, handle: &Handle) -> CacheLimit {
+        CacheLimit {
+            files: cache_path.join("files"),
+            max_size,
+            interval: Interval::new(CHECK_INTERVAL, handle).unwrap(),
+        }
+    }
+
+    pub fn poll(&mut self) {
+        let mut elapsed = false;
+        while let Ok(Async::Ready(Some(()))) = self.interval.poll() {
+            elapsed = true;
+        }
+        if !elapsed {
+            return;
+        }
+
+        let mut files = Vec::new();
+        collect_files(&self.files, &mut files);
+        let mut size: u64 = files.iter().map(|f| f.1).sum();
+        if size <= self.max_size {
+            return;
+        }
+        files.sort_by_key(|f| f.0);
+        let mut removed = 0;
+        for (_, file_size, path) in files {
+            if size <= self.max_size {
+                break;
+            }
+            if fs::remove_file(&path).is_ok() {
+                size -= file_size;
+                removed += 1;
+            }
+        }
+        info!("Removed {} files from the audio cache", removed);
+    }
+}
+
+/// Adds the files below `dir` with their modification times and sizes.
+fn collect_files(dir: &Path, files: &mut Vec<(SystemTime, u64, PathBuf)>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if metadata.is_dir() {
+            collect_files(&entry.path(), files);
+        } else {
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((modified, metadata.len(), entry.path()));
+        }
+    }
+}
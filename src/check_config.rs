@@ -0,0 +1,386 @@
+use crate::{
+    backends, cli,
+    config::{
+        self, SpotifydConfig, VolumeController, ALARM_PREFIX, EQUALIZER_PRESET_PREFIX,
+        INSTANCE_SECTION_PREFIX, UNSUPPORTED_KEYS,
+    },
+    errors::{self, ErrorCode},
+    toml_config,
+};
+use getopts::{Fail, Matches};
+use ini::Ini;
+use librespot::playback::config::Bitrate;
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs,
+    os::unix::ffi::OsStrExt,
+    panic,
+    path::{Path, PathBuf},
+    process::exit,
+    str::FromStr,
+};
+
+/// Checks the configuration without logging in or opening audio devices, for
+/// `--check-config`: every problem is reported with the line of the setting
+/// causing it, and spotifyd exits with 1 if any is an error.
+pub fn run(config_path: Option<PathBuf>, matches: &Matches) -> ! {
+    let mut check = Check::default();
+    let (ini, lines) = match config_path {
+        Some(ref path) => match fs::read_to_string(path) {
+            Ok(contents) => {
+                let ini = if toml_config::is_toml(path) {
+                    toml_config::to_ini(&contents)
+                        .map(|ini| (ini, toml_config::line_numbers(&contents)))
+                        .map_err(|e| (e.line, e.msg))
+                } else {
+                    Ini::load_from_str(&contents)
+                        .map(|ini| (ini, line_numbers(&contents)))
+                        .map_err(|e| (e.line + 1, e.msg))
+                };
+                match ini {
+                    Ok(ini) => ini,
+                    Err((line, msg)) => {
+                        check.error(line, &msg);
+                        check.finish(path);
+                    },
+                }
+            },
+            Err(e) => {
+                check.error(0, &format!("Couldn't read the config file: {}", e));
+                check.finish(path);
+            },
+        },
+        None => {
+            println!("No config file found, checking the command line only.");
+            (Ini::new(), HashMap::new())
+        },
+    };
+    // The sections settings are taken from, the one that takes precedence
+    // first.
+    let sections: Vec<String> = matches
+        .opt_str("instance")
+        .map(|name| format!("{}{}", INSTANCE_SECTION_PREFIX, name))
+        .into_iter()
+        .chain(vec!["spotifyd".to_string(), "global".to_string()])
+        .collect();
+    let line = |key: &str| {
+        sections
+            .iter()
+            .filter_map(|section| lines.get(&(section.clone(), key.to_string())))
+            .next()
+            .cloned()
+            .unwrap_or(0)
+    };
+
+    config::set_dry_run();
+    // Reading the config reports a bad setting by panicking, which is caught
+    // here instead.
+    panic::set_hook(Box::new(|_| ()));
+
+    // Each setting on its own first, so that every bad one is found along
+    // with its line, then all of them together.
+    let opts = cli::command_line_argument_options();
+    let no_options = opts.parse(Vec::<String>::new()).unwrap();
+    for (section, properties) in ini.iter() {
+        let section = match *section {
+            Some(ref s)
+                if s == "global" || s == "spotifyd" || s.starts_with(INSTANCE_SECTION_PREFIX) =>
+            {
+                s
+            },
+            Some(ref s) => {
+                check.warning(
+                    lines.get(&(s.clone(), String::new())).cloned().unwrap_or(0),
+                    &format!("Unknown section [{}], it is ignored.", s),
+                );
+                continue;
+            },
+            None => continue,
+        };
+        for (key, value) in properties.iter() {
+            let at = lines
+                .get(&(section.clone(), key.clone()))
+                .cloned()
+                .unwrap_or(0);
+            if !is_known(key) {
+                check.warning(at, &format!("Unknown setting {}, it is ignored.", key));
+                continue;
+            }
+            // Settings of instances are checked as if they were in
+            // [spotifyd], whichever instance is chosen.
+            let mut single = Ini::new();
+            single
+                .with_section(Some(if section.starts_with(INSTANCE_SECTION_PREFIX) {
+                    "spotifyd".to_string()
+                } else {
+                    section.clone()
+                }))
+                .set(key.clone(), value.clone());
+            if let Err(message) = read_config(&single, &no_options) {
+                check.error(at, &message);
+            }
+        }
+    }
+    let config = match read_config(&ini, matches) {
+        Ok(config) => config,
+        Err(message) => {
+            if check.errors == 0 {
+                check.error(0, &message);
+            }
+            return check.finish(config_path.as_ref().map_or(Path::new(""), PathBuf::as_path));
+        },
+    };
+    let value = |key: &str| {
+        matches.opt_str(key).or_else(|| {
+            sections
+                .iter()
+                .filter_map(|section| ini.get_from(Some(section.as_str()), key))
+                .next()
+                .map(str::to_string)
+        })
+    };
+
+    // Settings spotifyd silently falls back from.
+    if let Some(control) = value("volume-control") {
+        if VolumeController::from_str(&control).is_err() {
+            check.error(
+                line("volume-control"),
+                &errors::message(ErrorCode::InvalidSetting, &[&"volume-control", &control]),
+            );
+        }
+    }
+    if let Some(bitrate) = value("bitrate") {
+        if bitrate != "auto" && Bitrate::from_str(&bitrate).is_err() {
+            check.error(
+                line("bitrate"),
+                &errors::message(ErrorCode::InvalidSetting, &[&"bitrate", &bitrate]),
+            );
+        }
+    }
+
+    // The audio output, as far as it can be resolved without opening it.
+    match config.backend {
+        Some(ref name) if backends::find(name).is_none() => check.error(
+            line("backend"),
+            &errors::message(ErrorCode::UnknownBackend, &[name]),
+        ),
+        Some(ref name) => println!("Backend: {}", name),
+        None => match backends::default() {
+            Some((name, _)) => println!("Backend: {} (the default)", name),
+            None => check.error(0, &errors::message(ErrorCode::NoBackends, &[])),
+        },
+    }
+    for &(ref name, _) in &config.tee_backends {
+        if backends::find(name).is_none() {
+            check.error(
+                line("tee_backends"),
+                &errors::message(ErrorCode::UnknownBackend, &[name]),
+            );
+        }
+    }
+    println!(
+        "Device: {}",
+        config
+            .audio_device
+            .as_ref()
+            .map_or("the default", String::as_str)
+    );
+    match config.volume_controller {
+        VolumeController::Alsa { .. } | VolumeController::AlsaDb => {
+            if cfg!(feature = "alsa_backend") {
+                println!(
+                    "Mixer: {}",
+                    config.mixer.as_ref().map_or("Master", String::as_str)
+                );
+            } else {
+                check.warning(
+                    line("volume-control"),
+                    "ALSA volume control needs the alsa_backend feature, the software mixer is \
+                     used instead.",
+                );
+            }
+        },
+        VolumeController::SoftVol => println!("Mixer: software"),
+        VolumeController::Fixed => println!("Mixer: none, playing at full volume"),
+    }
+
+    check_credentials(&mut check, &config, &value, &line);
+
+    // Paths spotifyd creates or writes to.
+    if let Some(ref cache_path) = config.cache_path {
+        check.directory(line("cache_path"), cache_path);
+    }
+    if let Some(audio_cache_path) = value("audio_cache_path") {
+        check.directory(line("audio_cache_path"), Path::new(&audio_cache_path));
+    }
+    if let Some(ref log_file) = config.log_file {
+        check.file(line("log_file"), &log_file.path);
+    }
+    if let Some(ref pid) = config.pid {
+        check.file(line("pid").max(line("pid-file")), pid);
+    }
+
+    check.finish(config_path.as_ref().map_or(Path::new(""), PathBuf::as_path))
+}
+
+fn check_credentials<V, L>(check: &mut Check, config: &SpotifydConfig, value: &V, line: &L)
+where
+    V: Fn(&str) -> Option<String>,
+    L: Fn(&str) -> usize,
+{
+    if let Some(ref username) = config.username {
+        if username.trim().is_empty() || username.contains(char::is_whitespace) {
+            check.error(
+                line("username"),
+                "username mustn't be empty or contain spaces.",
+            );
+        }
+    }
+    for &key in &[
+        "password",
+        "password_cmd",
+        "oauth_token",
+        "oauth_refresh_token",
+    ] {
+        if value(key).map_or(false, |v| v.trim().is_empty()) {
+            check.error(line(key), &format!("{} is empty.", key));
+        }
+    }
+    let has_password = config.password.is_some()
+        || config.use_keyring
+        || value("password_cmd").is_some()
+        || value("password-fd").is_some()
+        || std::env::var_os("CREDENTIALS_DIRECTORY").is_some();
+    if config.username.is_some() && !has_password && config.cache_path.is_none() {
+        check.warning(
+            line("username"),
+            "username is set, but there is no password or cache_path with saved credentials; only \
+             Spotify Connect discovery can log in.",
+        );
+    }
+    if config.username.is_none()
+        && has_password
+        && config.oauth_token.is_none()
+        && config.oauth_refresh_token.is_none()
+    {
+        check.warning(
+            line("password"),
+            "A password is set, but no username; it isn't used.",
+        );
+    }
+}
+
+/// Whether the setting is one spotifyd reads: every option of the command
+/// line, which the config file mirrors, and a few only the file has.
+fn is_known(key: &str) -> bool {
+    if key.starts_with(EQUALIZER_PRESET_PREFIX)
+        || key.starts_with(ALARM_PREFIX)
+        || UNSUPPORTED_KEYS.contains(&key)
+        || key == "use_keyring"
+    {
+        return true;
+    }
+    match cli::command_line_argument_options().parse(&[format!("--{}", key)]) {
+        Err(Fail::UnrecognizedOption(_)) => false,
+        _ => true,
+    }
+}
+
+fn read_config(ini: &Ini, matches: &Matches) -> Result<SpotifydConfig, String> {
+    panic::catch_unwind(panic::AssertUnwindSafe(|| config::from_ini(ini, matches))).map_err(
+        |payload| match (
+            payload.downcast_ref::<String>(),
+            payload.downcast_ref::<&str>(),
+        ) {
+            (Some(s), _) => s.clone(),
+            (_, Some(&s)) => s.to_string(),
+            _ => "Invalid configuration.".to_string(),
+        },
+    )
+}
+
+/// The line of every section and setting, by section and key. Sections
+/// themselves have an empty key.
+pub fn line_numbers(contents: &str) -> HashMap<(String, String), usize> {
+    let mut lines = HashMap::new();
+    let mut section = String::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len() - 1].trim().to_string();
+            lines.insert((section.clone(), String::new()), i + 1);
+        } else if let Some(end) = line.find(|c| c == '=' || c == ':') {
+            lines
+                .entry((section.clone(), line[..end].trim().to_string()))
+                .or_insert(i + 1);
+        }
+    }
+    lines
+}
+
+#[derive(Default)]
+struct Check {
+    problems: Vec<(usize, &'static str, String)>,
+    errors: usize,
+}
+
+impl Check {
+    fn error(&mut self, line: usize, message: &str) {
+        self.errors += 1;
+        self.problems.push((line, "error", message.to_string()));
+    }
+
+    fn warning(&mut self, line: usize, message: &str) {
+        self.problems.push((line, "warning", message.to_string()));
+    }
+
+    /// Checks that the directory can be created or written to.
+    fn directory(&mut self, line: usize, path: &Path) {
+        let existing = path.ancestors().find(|p| p.exists()).unwrap_or(path);
+        if !existing.is_dir() {
+            self.error(line, &format!("{} isn't a directory.", existing.display()));
+        } else if !writable(existing) {
+            self.error(line, &format!("{} isn't writable.", existing.display()));
+        }
+    }
+
+    /// Checks that the file can be created or written to.
+    fn file(&mut self, line: usize, path: &Path) {
+        if path.is_dir() {
+            self.error(line, &format!("{} is a directory.", path.display()));
+            return;
+        }
+        let parent = path.parent().unwrap_or(path);
+        if !parent.is_dir() {
+            self.error(line, &format!("{} doesn't exist.", parent.display()));
+        } else if !writable(if path.exists() { path } else { parent }) {
+            self.error(line, &format!("{} isn't writable.", path.display()));
+        }
+    }
+
+    fn finish(&mut self, path: &Path) -> ! {
+        self.problems.sort_by_key(|&(line, _, _)| line);
+        for &(line, kind, ref message) in &self.problems {
+            if line == 0 {
+                println!("{}: {}: {}", path.display(), kind, message);
+            } else {
+                println!("{}:{}: {}: {}", path.display(), line, kind, message);
+            }
+        }
+        if self.errors > 0 {
+            println!("{} error(s) found.", self.errors);
+            exit(1);
+        }
+        println!("The configuration is valid.");
+        exit(0)
+    }
+}
+
+fn writable(path: &Path) -> bool {
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
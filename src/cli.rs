@@ -1,5 +1,5 @@
+use crate::backends;
 use getopts::Options;
-use librespot::playback::audio_backend::BACKENDS;
 
 pub fn usage(program: &str, opts: &Options) -> String {
     let brief = format!("Usage: {} [options]", program);
@@ -8,7 +8,7 @@ pub fn usage(program: &str, opts: &Options) -> String {
 
 pub fn print_backends() {
     println!("Available backends:");
-    for &(name, _) in BACKENDS {
+    for (name, _) in backends::all() {
         println!("- {}", name);
     }
 }
@@ -16,15 +16,277 @@ pub fn print_backends() {
 pub fn command_line_argument_options() -> Options {
     let mut opts = Options::new();
     opts.optopt("c", "config", "Path to a config file.", "CONFIG");
+    opts.optopt(
+        "",
+        "instance",
+        "Use the settings of the [spotifyd.INSTANCE] section of the config file.",
+        "INSTANCE",
+    );
     opts.optopt("u", "username", "Spotify user name.", "USERNAME");
     opts.optopt("p", "password", "Spotify password.", "PASSWORD");
-    opts.optopt("", "device", "Audio device, given by aplay -L.", "DEVICE");
+    opts.optopt(
+        "",
+        "device",
+        "Audio device, given by --list-devices.",
+        "DEVICE",
+    );
     opts.optopt("", "mixer", "Audio mixer", "DEVICE");
-    opts.optopt("", "bitrate", "Any of 96, 160, and 320.", "DEVICE");
+    opts.optopt(
+        "",
+        "audio_format",
+        "Any of S16, S24, S32, and F32.",
+        "FORMAT",
+    );
+    opts.optopt("", "sample_rate", "Output sample rate in Hz.", "RATE");
+    opts.optopt(
+        "",
+        "channel_map",
+        "Channels of a multichannel device to play left and right on, e.g. 3,4.",
+        "CHANNELS",
+    );
+    opts.optopt(
+        "",
+        "channels",
+        "Channels of the device channel_map plays on, if it has more.",
+        "COUNT",
+    );
+    opts.optopt(
+        "",
+        "balance",
+        "Balance from -1, left only, to 1, right only.",
+        "BALANCE",
+    );
+    opts.optopt("", "gain_left", "Gain of the left channel in dB.", "DB");
+    opts.optopt("", "gain_right", "Gain of the right channel in dB.", "DB");
+    opts.optopt(
+        "",
+        "dither",
+        "Dithering when processed audio goes back to 16 bits: tpdf or none.",
+        "DITHER",
+    );
+    opts.optopt(
+        "",
+        "mixer_card",
+        "ALSA card of the mixer, if it isn't the audio device.",
+        "CARD",
+    );
+    opts.optopt("", "mixer_index", "Index of the mixer control.", "INDEX");
+    opts.optopt("", "bitrate", "Any of 96, 160, 320, and auto.", "DEVICE");
     opts.optopt("", "pid", "Path to PID file.", "PID-FILE");
+    opts.optopt("", "pid-file", "Same as --pid.", "PID-FILE");
+    opts.optopt(
+        "",
+        "recently_played",
+        "How many of the tracks played last to list, kept in the cache.",
+        "COUNT",
+    );
+    opts.optopt("", "user", "User to run as when started as root.", "USER");
+    opts.optopt(
+        "",
+        "group",
+        "Group to run as when started as root, the user's own by default.",
+        "GROUP",
+    );
+    opts.optopt("", "log-format", "Any of text and json.", "FORMAT");
+    opts.optopt(
+        "",
+        "log_filter",
+        "What to log, by module, e.g. info,librespot=debug.",
+        "FILTER",
+    );
+    opts.optopt("", "log_file", "Write the log to this file.", "PATH");
+    opts.optopt(
+        "",
+        "log_file_max_size",
+        "Start a new log file at this size, 0 for no limit.",
+        "MB",
+    );
+    opts.optopt("", "log_file_rotate", "Any of daily and weekly.", "PERIOD");
+    opts.optopt(
+        "",
+        "log_file_keep",
+        "How many old log files to keep.",
+        "COUNT",
+    );
+    opts.optopt(
+        "",
+        "play_history",
+        "Write every track played to this file.",
+        "PATH",
+    );
+    opts.optopt("", "play_history_format", "Any of csv and jsonl.", "FORMAT");
+    opts.optopt(
+        "",
+        "play_history_max_size",
+        "Start a new play history file at this size.",
+        "MB",
+    );
+    opts.optopt(
+        "",
+        "play_history_rotate",
+        "Any of daily and weekly.",
+        "PERIOD",
+    );
+    opts.optopt(
+        "",
+        "play_history_keep",
+        "How many old play history files to keep.",
+        "COUNT",
+    );
     opts.optopt("", "device_name", "Name of this Spotify device.", "DEVICE");
+    opts.optopt(
+        "",
+        "device_id",
+        "ID of this Spotify device, if not the one kept in the cache.",
+        "ID",
+    );
     opts.optopt("", "backend", "Audio backend.", "BACKEND");
+    opts.optopt(
+        "",
+        "fade_ms",
+        "Milliseconds to fade in and out when playback starts and stops.",
+        "MILLISECONDS",
+    );
+    opts.optopt(
+        "",
+        "trim_silence_ms",
+        "Cut silence between and within tracks down to this many milliseconds.",
+        "MILLISECONDS",
+    );
+    opts.optopt(
+        "",
+        "trim_silence_threshold",
+        "Level in dBFS below which audio counts as silence, -60 by default.",
+        "DB",
+    );
+    opts.optopt(
+        "",
+        "playback_start_delay_ms",
+        "Milliseconds to hold back audio when playback starts, e.g. for an amplifier to wake up.",
+        "MILLISECONDS",
+    );
+    opts.optopt(
+        "",
+        "playback_start_command",
+        "Command run right before the playback_start_delay_ms wait.",
+        "COMMAND",
+    );
+    opts.optopt(
+        "",
+        "latency_offset_ms",
+        "Milliseconds to delay playback by, or to advance it by if negative.",
+        "MILLISECONDS",
+    );
+    opts.optopt(
+        "",
+        "multiroom_listen",
+        "Address to lead multi-room playback on, port 5030 by default.",
+        "ADDRESS",
+    );
+    opts.optopt(
+        "",
+        "multiroom_leader",
+        "Play in sync with the spotifyd instance at this address instead of being a Connect \
+         device.",
+        "ADDRESS",
+    );
+    opts.optopt(
+        "",
+        "multiroom_latency_ms",
+        "How far ahead of playing it the leader sends audio, 300 by default.",
+        "MILLISECONDS",
+    );
+    opts.optopt(
+        "",
+        "audio_buffer",
+        "How far playback may run ahead of the audio device, e.g. 5s or 1M.",
+        "SIZE",
+    );
+    opts.optopt(
+        "",
+        "tee_backends",
+        "Further outputs playing the same audio, as BACKEND[:DEVICE],...",
+        "BACKENDS",
+    );
     opts.optopt("", "cache_path", "Path to cache location.", "PATH");
+    opts.optopt(
+        "",
+        "discovery",
+        "Set to false to not announce the device to Spotify apps on the network.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "zeroconf_port",
+        "Port of the server Spotify apps log in through, instead of a random one.",
+        "PORT",
+    );
+    opts.optopt(
+        "",
+        "ap_port",
+        "Access point ports to try in turn, e.g. 4070,443,80, or any. Defaults to 443.",
+        "PORTS",
+    );
+    opts.optopt(
+        "",
+        "ap_blacklist",
+        "Access points not to connect to, as parts of their names, e.g. gae2,ap-gue1.",
+        "NAMES",
+    );
+    opts.optopt(
+        "",
+        "ap_prefer",
+        "Access points to try first, as parts of their names, e.g. a region like gew1.",
+        "NAMES",
+    );
+    opts.optopt(
+        "",
+        "proxy",
+        "HTTP or SOCKS5 proxy to connect to Spotify through, as a URL.",
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "audio_cache_path",
+        "Path to cache audio in, if not in cache_path.",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "cache_size",
+        "The most audio to keep in the cache, in megabytes.",
+        "MB",
+    );
+    opts.optopt(
+        "",
+        "cache_in_memory",
+        "Set to true to keep the cache in RAM instead of cache_path.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "no_audio_cache",
+        "Set to true to cache only the credentials, not audio.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "error_catalog",
+        "File with translated error messages.",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "snapcast",
+        "Set to true to feed Snapcast through the pipe backend and accept its player controls.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "snapcast_socket",
+        "Socket the Snapcast control script connects to.",
+        "PATH",
+    );
     opts.optflag(
         "",
         "volume-normalisation",
@@ -36,6 +298,18 @@ pub fn command_line_argument_options() -> Options {
         "dB of pregain for volume normalisation",
         "PREGAIN",
     );
+    opts.optopt(
+        "",
+        "normalisation-threshold",
+        "Limit peaks to this many dBFS, e.g. -1.",
+        "DB",
+    );
+    opts.optopt(
+        "",
+        "normalisation-release",
+        "Milliseconds the limiter takes to recover. Defaults to 100.",
+        "MS",
+    );
     opts.optopt(
         "",
         "onevent",
@@ -43,20 +317,366 @@ pub fn command_line_argument_options() -> Options {
          passed to the command.",
         "COMMAND",
     );
+    opts.optopt(
+        "",
+        "metadata_pipe",
+        "Write shairport-sync style metadata to this named pipe.",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "control_socket",
+        "Answer local control commands, like spotifyd status, on this Unix socket.",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "cover_art_file",
+        "Keep the cover of the track playing in this file.",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "cover_art_size",
+        "Scale the cover art file down to at most this many pixels wide and high.",
+        "PIXELS",
+    );
+    opts.optopt(
+        "",
+        "art_providers",
+        "Where album art comes from, in order: spotify, folder:PATH, url:TEMPLATE.",
+        "PROVIDERS",
+    );
+    opts.optopt(
+        "",
+        "desktop_notifications",
+        "Set to true to show a desktop notification for every new track.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "plugins",
+        "Lua scripts to run as plugins, separated by commas.",
+        "PATHS",
+    );
+    opts.optopt(
+        "",
+        "display_driver",
+        "Display showing what's playing: hd44780:BUS:ADDRESS or command:PROGRAM.",
+        "DRIVER",
+    );
+    opts.optopt(
+        "",
+        "display_size",
+        "Columns and rows of the display, e.g. 20x4.",
+        "SIZE",
+    );
+    opts.optopt(
+        "",
+        "display_refresh",
+        "Milliseconds between display updates while scrolling.",
+        "MILLISECONDS",
+    );
+    opts.optopt(
+        "",
+        "rotary_encoder",
+        "GPIOs of a rotary encoder controlling the volume: A,B[,BUTTON].",
+        "PINS",
+    );
+    opts.optopt(
+        "",
+        "rotary_encoder_steps",
+        "Pin transitions per detent of the rotary encoder.",
+        "STEPS",
+    );
+    opts.optopt(
+        "",
+        "gpio_buttons",
+        "Push buttons on GPIOs and what they do, e.g. 22:play_pause,23:next.",
+        "BUTTONS",
+    );
+    opts.optopt(
+        "",
+        "lirc",
+        "Set to true to control playback with an IR remote through lircd.",
+        "BOOL",
+    );
+    opts.optopt("", "lirc_socket", "Path to lircd's socket.", "PATH");
+    opts.optopt(
+        "",
+        "lirc_buttons",
+        "What the remote's buttons do, e.g. KEY_PLAY:play_pause,KEY_NEXT:next.",
+        "BUTTONS",
+    );
+    opts.optopt(
+        "",
+        "amp_serial_port",
+        "Serial port of an amplifier to control.",
+        "PORT",
+    );
+    opts.optopt(
+        "",
+        "amp_baud_rate",
+        "Baud rate of the amplifier's serial port.",
+        "BAUD",
+    );
+    opts.optopt(
+        "",
+        "amp_power_on",
+        "Command that powers on the amplifier.",
+        "COMMAND",
+    );
+    opts.optopt(
+        "",
+        "amp_input",
+        "Command that selects the amplifier's input.",
+        "COMMAND",
+    );
+    opts.optopt(
+        "",
+        "amp_power_off",
+        "Command that powers off the amplifier.",
+        "COMMAND",
+    );
+    opts.optopt(
+        "",
+        "amp_power_off_delay",
+        "Seconds after playback stopped until the amplifier is powered off.",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "amp_volume",
+        "Command that sets the amplifier's volume, {volume} is replaced by the volume.",
+        "COMMAND",
+    );
+    opts.optopt(
+        "",
+        "amp_volume_max",
+        "Highest volume the amplifier accepts.",
+        "VOLUME",
+    );
+    opts.optopt(
+        "",
+        "power_on_command",
+        "Command run when playback starts while powered off.",
+        "COMMAND",
+    );
+    opts.optopt(
+        "",
+        "power_off_command",
+        "Command run after power_off_delay seconds without playback.",
+        "COMMAND",
+    );
+    opts.optopt(
+        "",
+        "power_gpio",
+        "GPIO pin switched on while playing, e.g. for a relay or 12V trigger.",
+        "PIN",
+    );
+    opts.optopt(
+        "",
+        "power_off_delay",
+        "Seconds without playback until the power is switched off.",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "save_points",
+        "Set to true to remember the position in long tracks, like audiobook chapters.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "save_points_min_duration",
+        "Seconds a track has to last to get a save-point.",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "reconnect_to_controller",
+        "Set to true to become the active device again after a restart during playback.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "restore_playback",
+        "Set to true to bring back, paused, what played when spotifyd last ran.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "resume_after_reconnect",
+        "Set to true to resume playback when the connection to Spotify is back after a drop.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "data_usage",
+        "Set to true to count the data received from Spotify, by session and by day.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "data_saver",
+        "Set to true to stream at 96 kbps and count the data used, for metered links.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "wait_for_network",
+        "Set to true to wait at startup until Spotify can be reached, retrying with backoff.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "pause_on_device_loss",
+        "Set to true to pause while the audio device is gone.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "resume_on_device_return",
+        "Set to true to resume playback paused by pause_on_device_loss.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "sigusr1",
+        "What SIGUSR1 does: play_pause, play, pause, next, previous or none.",
+        "ACTION",
+    );
+    opts.optopt(
+        "",
+        "sigusr2",
+        "What SIGUSR2 does: play_pause, play, pause, next, previous or none.",
+        "ACTION",
+    );
+    opts.optopt(
+        "",
+        "inhibit_sleep",
+        "Keep the system awake while playing, and pause before it sleeps.",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "exit_after_idle_secs",
+        "Exit after this many seconds without playback.",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "health_listen",
+        "Answer health checks over HTTP on this address or Unix socket path.",
+        "ADDRESS",
+    );
+    opts.optopt(
+        "",
+        "autoplay",
+        "Set to true to continue with recommendations when a playlist or album ends.",
+        "BOOL",
+    );
     opts.optopt(
         "",
         "volume-control",
-        "Possible values are alsa, alsa_linear, and softvol.",
+        "Possible values are alsa, alsa_linear, alsa_db, softvol, and none.",
         "CONTROLLER",
     );
+    opts.optopt(
+        "",
+        "volume_curve",
+        "Possible values are linear, logarithmic, and cubic.",
+        "CURVE",
+    );
+    opts.optopt(
+        "",
+        "initial_volume",
+        "Volume to start at, in percent.",
+        "PERCENT",
+    );
+    opts.optopt("", "equalizer", "Equalizer preset to start with.", "PRESET");
+    opts.optopt("", "volume_min", "Lowest volume, in percent.", "PERCENT");
+    opts.optopt("", "volume_max", "Highest volume, in percent.", "PERCENT");
+    opts.optopt(
+        "",
+        "volume_db_range",
+        "dB range of the logarithmic volume curve.",
+        "DB",
+    );
     opts.optflag("v", "verbose", "Add debug information to log.");
+    opts.optopt(
+        "",
+        "verbosity",
+        "How much to log, from 0, the default, to 3, everything like -v.",
+        "LEVEL",
+    );
     opts.optflag(
         "",
         "use-keyring",
         "Use the system's keyring to retrieve the password",
     );
+    opts.optopt(
+        "",
+        "oauth_token",
+        "OAuth access token with the streaming scope to log in with.",
+        "TOKEN",
+    );
+    opts.optopt(
+        "",
+        "oauth_refresh_token",
+        "OAuth refresh token to get access tokens to log in with.",
+        "TOKEN",
+    );
+    opts.optflag(
+        "",
+        "oauth-login",
+        "Log in through a browser instead of with a password.",
+    );
+    opts.optflag(
+        "",
+        "oauth-device-login",
+        "Log in by entering a code on another device instead of with a password.",
+    );
+    opts.optopt(
+        "",
+        "password_cmd",
+        "Command printing the password, if it isn't set.",
+        "COMMAND",
+    );
+    opts.optopt(
+        "",
+        "password-fd",
+        "File descriptor to read the password from, if it isn't set.",
+        "FD",
+    );
+    opts.optopt(
+        "",
+        "keyring_store",
+        "Set to true to store the configured password in the keyring.",
+        "BOOL",
+    );
     opts.optflag("", "no-daemon", "Don't detach from console.");
+    opts.optflag(
+        "",
+        "sandbox",
+        "Restrict file access and syscalls once started, on Linux.",
+    );
     opts.optflag("", "backends", "List available audio backends.");
+    opts.optflag(
+        "",
+        "list-devices",
+        "List the output devices of the backend, named as device takes them.",
+    );
+    opts.optflag(
+        "",
+        "check-config",
+        "Check the configuration for problems, without logging in or opening audio devices.",
+    );
+    opts.optflag(
+        "",
+        "simulate",
+        "Play synthetic tracks instead of logging in, for developing integrations.",
+    );
     opts.optflag("h", "help", "Print this help text.");
     opts.optflag("V", "version", "Print version number");
     opts
@@ -0,0 +1,266 @@
+use crate::{backends, cli};
+use getopts::{Fail, Options};
+use std::process::exit;
+
+/// Subcommands, which come before any option.
+const SUBCOMMANDS: &[&str] = &[
+    "completions",
+    "generate-unit",
+    "install-launchd",
+    "migrate-config",
+    "next",
+    "pause",
+    "play",
+    "previous",
+    "seek",
+    "snapcast-control",
+    "status",
+    "volume",
+];
+
+/// Value names of options whose value is a path.
+const PATH_HINTS: &[&str] = &["CONFIG", "PATH", "PID-FILE"];
+
+/// An option of the command line, as far as completions need to know it.
+struct Opt {
+    short: Option<String>,
+    long: String,
+    /// The name of its value, if it takes one.
+    hint: Option<String>,
+    description: String,
+}
+
+impl Opt {
+    /// The values completed for the option, if they are known.
+    fn values(&self) -> Option<Vec<String>> {
+        match self.hint.as_ref().map(String::as_str) {
+            Some("BACKEND") => Some(
+                backends::all()
+                    .into_iter()
+                    .map(|(name, _)| name.to_string())
+                    .collect(),
+            ),
+            Some("BOOL") => Some(vec!["true".to_string(), "false".to_string()]),
+            _ => None,
+        }
+    }
+
+    fn takes_path(&self) -> bool {
+        self.hint
+            .as_ref()
+            .map_or(false, |h| PATH_HINTS.contains(&h.as_str()))
+    }
+}
+
+/// Prints the completion script for the shell, for
+/// `spotifyd completions bash|zsh|fish`.
+pub fn run(args: &[String]) -> ! {
+    let options = options(&cli::command_line_argument_options());
+    let script = match args.get(0).map(String::as_str) {
+        Some("bash") => bash(&options),
+        Some("zsh") => zsh(&options),
+        Some("fish") => fish(&options),
+        _ => {
+            eprintln!("Usage: spotifyd completions bash|zsh|fish");
+            exit(1);
+        },
+    };
+    print!("{}", script);
+    exit(0)
+}
+
+/// The options, read from the usage text, as getopts doesn't list them
+/// otherwise. Whether an option takes a value is asked of getopts itself.
+fn options(opts: &Options) -> Vec<Opt> {
+    let mut options: Vec<Opt> = Vec::new();
+    for line in opts.usage("").lines() {
+        let text = line.trim_start();
+        let indent = line.len() - text.len();
+        if text.is_empty() {
+            continue;
+        }
+        // Descriptions too long for one line go on in the next, indented
+        // further than any option.
+        if indent > 8 || !text.starts_with('-') {
+            if let Some(last) = options.last_mut() {
+                if !last.description.is_empty() {
+                    last.description.push(' ');
+                }
+                last.description.push_str(text);
+            }
+            continue;
+        }
+        let mut rest = text;
+        let mut short = None;
+        if !rest.starts_with("--") {
+            let end = rest.find(", ").unwrap_or_else(|| rest.len());
+            short = Some(rest[1..end].to_string());
+            rest = rest[end..].trim_start_matches(", ");
+        }
+        let end = rest.find(' ').unwrap_or_else(|| rest.len());
+        let long = rest[2..end].to_string();
+        rest = rest[end..].trim_start();
+        let hint = match opts.parse(&[format!("--{}", long)]) {
+            Err(Fail::ArgumentMissing(_)) => {
+                let end = rest.find(' ').unwrap_or_else(|| rest.len());
+                let hint = rest[..end].to_string();
+                rest = rest[end..].trim_start();
+                Some(hint)
+            },
+            _ => None,
+        };
+        options.push(Opt {
+            short,
+            long,
+            hint,
+            description: rest.to_string(),
+        });
+    }
+    options
+}
+
+fn bash(options: &[Opt]) -> String {
+    let mut words = Vec::new();
+    let mut paths = Vec::new();
+    let mut values = Vec::new();
+    let mut arguments = Vec::new();
+    for opt in options {
+        let mut names = vec![format!("--{}", opt.long)];
+        if let Some(ref short) = opt.short {
+            names.push(format!("-{}", short));
+        }
+        words.extend(names.iter().cloned());
+        if opt.takes_path() {
+            paths.extend(names);
+        } else if let Some(v) = opt.values() {
+            values.push((names.join("|"), v.join(" ")));
+        } else if opt.hint.is_some() {
+            arguments.extend(names);
+        }
+    }
+    let mut script = vec![
+        "_spotifyd() {".to_string(),
+        "    local cur prev".to_string(),
+        "    cur=\"${COMP_WORDS[COMP_CWORD]}\"".to_string(),
+        "    prev=\"${COMP_WORDS[COMP_CWORD-1]}\"".to_string(),
+        String::new(),
+        "    case \"$prev\" in".to_string(),
+        format!("        {})", paths.join("|")),
+        "            COMPREPLY=($(compgen -f -- \"$cur\"))".to_string(),
+        "            return".to_string(),
+        "            ;;".to_string(),
+    ];
+    for (names, v) in values {
+        script.push(format!("        {})", names));
+        script.push(format!(
+            "            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))",
+            v
+        ));
+        script.push("            return".to_string());
+        script.push("            ;;".to_string());
+    }
+    script.extend(vec![
+        format!("        {})", arguments.join("|")),
+        "            return".to_string(),
+        "            ;;".to_string(),
+        "    esac".to_string(),
+        String::new(),
+        "    if [[ $COMP_CWORD -eq 1 && $cur != -* ]]; then".to_string(),
+        format!(
+            "        COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))",
+            SUBCOMMANDS.join(" ")
+        ),
+        "        return".to_string(),
+        "    fi".to_string(),
+        "    if [[ ${COMP_WORDS[1]} == completions && $COMP_CWORD -eq 2 ]]; then".to_string(),
+        "        COMPREPLY=($(compgen -W \"bash zsh fish\" -- \"$cur\"))".to_string(),
+        "        return".to_string(),
+        "    fi".to_string(),
+        String::new(),
+        format!(
+            "    COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))",
+            words.join(" ")
+        ),
+        "}".to_string(),
+        String::new(),
+        "complete -F _spotifyd spotifyd".to_string(),
+        String::new(),
+    ]);
+    script.join("\n")
+}
+
+fn zsh(options: &[Opt]) -> String {
+    let mut script = String::from("#compdef spotifyd\n\n_arguments -s \\\n");
+    for opt in options {
+        let action = if opt.takes_path() {
+            ":_files".to_string()
+        } else if let Some(v) = opt.values() {
+            format!(":({})", v.join(" "))
+        } else {
+            ": ".to_string()
+        };
+        let argument = match opt.hint {
+            Some(ref hint) => format!(":{}{}", hint.to_lowercase(), action),
+            None => String::new(),
+        };
+        let description = zsh_escape(&opt.description);
+        let repeat = if opt.long == "verbose" { "*" } else { "" };
+        match opt.short {
+            Some(ref short) => script.push_str(&format!(
+                "    '{r}(-{s} --{l})'{{-{s},--{l}}}'[{d}]{a}' \\\n",
+                r = repeat,
+                s = short,
+                l = opt.long,
+                d = description,
+                a = argument
+            )),
+            None => script.push_str(&format!(
+                "    '--{}[{}]{}' \\\n",
+                opt.long, description, argument
+            )),
+        }
+    }
+    script.push_str(&format!(
+        "    '1::command:({})' \\\n    '*::shell:(bash zsh fish)'\n",
+        SUBCOMMANDS.join(" ")
+    ));
+    script
+}
+
+fn zsh_escape(description: &str) -> String {
+    description
+        .replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+fn fish(options: &[Opt]) -> String {
+    let mut script = format!(
+        "complete -c spotifyd -n __fish_use_subcommand -f -a '{}'\n",
+        SUBCOMMANDS.join(" ")
+    );
+    script.push_str(
+        "complete -c spotifyd -n '__fish_seen_subcommand_from completions' -f -a 'bash zsh fish'\n",
+    );
+    for opt in options {
+        let mut line = String::from("complete -c spotifyd");
+        if let Some(ref short) = opt.short {
+            line.push_str(&format!(" -s {}", short));
+        }
+        line.push_str(&format!(" -l {}", opt.long));
+        if opt.takes_path() {
+            line.push_str(" -r -F");
+        } else if let Some(v) = opt.values() {
+            line.push_str(&format!(" -x -a '{}'", v.join(" ")));
+        } else if opt.hint.is_some() {
+            line.push_str(" -x");
+        }
+        line.push_str(&format!(
+            " -d '{}'\n",
+            opt.description.replace('\\', "\\\\").replace('\'', "\\'")
+        ));
+        script.push_str(&line);
+    }
+    script
+}
@@ -1,3 +1,17 @@
+use crate::{
+    alarm::Schedule,
+    cache_limit,
+    cover_art_file::CoverArtFileConfig,
+    errors::{self, ErrorCode, Failure},
+    lirc::{self, RemoteAction},
+    logging::{Filter, LogFile, LogFormat, RotationPeriod},
+    multiroom,
+    play_history::{HistoryFormat, PlayHistoryConfig},
+    player_event_handler::split_command,
+    privileges,
+    signals::SignalAction,
+    snapcast, toml_config,
+};
 use crypto::{digest::Digest, sha1::Sha1};
 use getopts::Matches;
 use hostname;
@@ -6,30 +20,179 @@ use librespot::{
     core::{cache::Cache, config::SessionConfig, version},
     playback::config::{Bitrate, PlayerConfig},
 };
-use log::info;
+use log::{error, info, warn};
 use std::{
+    collections::HashMap,
     convert::From,
+    env,
     error::Error,
-    fs::metadata,
+    fs::{self, metadata, File},
+    io::Read,
     mem::swap,
+    net::UdpSocket,
+    os::unix::{
+        self,
+        io::{FromRawFd, RawFd},
+    },
     path::{Path, PathBuf},
+    process::{self, Command, Stdio},
     str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use url::Url;
 use xdg;
 
-const CONFIG_FILE: &str = "spotifyd.conf";
+/// Config files looked for, the TOML one first so that it takes over once a
+/// config is migrated.
+const CONFIG_FILES: &[&str] = &["spotifyd.toml", "spotifyd.conf"];
+pub const EQUALIZER_PRESET_PREFIX: &str = "equalizer.";
+pub const ALARM_PREFIX: &str = "alarm.";
+/// Sections of instances chosen with `--instance`, e.g. `[spotifyd.office]`.
+pub const INSTANCE_SECTION_PREFIX: &str = "spotifyd.";
+/// RAM is scarce on the appliances `cache_in_memory` is meant for.
+const DEFAULT_MEMORY_CACHE_SIZE: u64 = 100 * 1024 * 1024;
+const DEFAULT_LOG_FILE_SIZE: u64 = 10 * 1024 * 1024;
+/// Settings of newer librespot versions. This one always normalises by track
+/// gain, only loads a track when the previous one has ended, always decodes,
+/// and runs discovery on every interface with its own mDNS responder.
+pub const UNSUPPORTED_KEYS: &[&str] = &[
+    "normalisation-type",
+    "normalisation-attack",
+    "gapless",
+    "preload",
+    "passthrough",
+    "zeroconf_interface",
+    "zeroconf_backend",
+];
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
 
 pub enum VolumeController {
-    Alsa { linear: bool },
+    Alsa {
+        linear: bool,
+    },
+    /// ALSA, with volume following the control's dB range.
+    AlsaDb,
     SoftVol,
+    /// Always full volume, Connect's volume is ignored.
+    Fixed,
+}
+
+/// How the volume from Connect is mapped onto the mixer.
+#[derive(Clone, Copy, PartialEq)]
+pub enum VolumeCurve {
+    Linear,
+    /// Spreads the volume evenly over a range of dB.
+    Logarithmic,
+    Cubic,
+}
+
+impl FromStr for VolumeCurve {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "linear" => Ok(VolumeCurve::Linear),
+            "logarithmic" | "log" => Ok(VolumeCurve::Logarithmic),
+            "cubic" => Ok(VolumeCurve::Cubic),
+            _ => Err(()),
+        }
+    }
 }
 
+/// The range the logarithmic curve spans unless `volume_db_range` says
+/// otherwise, the same as Connect's own curve.
+const DEFAULT_VOLUME_DB_RANGE: f64 = 60.0;
+/// Quieter than this, in dBFS, is silence to `trim_silence_ms`.
+const DEFAULT_SILENCE_THRESHOLD: f64 = -60.0;
+/// How long the limiter takes to recover, as in newer librespot versions.
+const DEFAULT_LIMITER_RELEASE_MS: u64 = 100;
+/// How far `latency_offset_ms` may shift playback either way.
+pub const MAX_LATENCY_OFFSET_MS: i64 = 10_000;
+/// The `verbosity` that logs everything, as `-v` does.
+pub const MAX_VERBOSITY: usize = 3;
+
 fn device_id(name: &str) -> String {
     let mut h = Sha1::new();
     h.input_str(name);
     h.result_str()
 }
 
+/// Fills in the placeholders of `device_name`, so that devices sharing a
+/// config file show up under names of their own: `{hostname}`, `{ip}` for the
+/// address other devices on the network reach this one at, and `{mac}` for
+/// the hardware address of the interface with the default route.
+fn expand_device_name(template: &str) -> String {
+    let mut name = template.to_string();
+    let placeholders: [(&str, fn() -> Option<String>); 3] = [
+        ("{hostname}", hostname::get_hostname),
+        ("{ip}", local_ip),
+        ("{mac}", mac_address),
+    ];
+    for &(placeholder, value) in &placeholders {
+        if name.contains(placeholder) {
+            let value = value().unwrap_or_else(|| {
+                warn!("Couldn't find out {} for device_name", placeholder);
+                "unknown".to_string()
+            });
+            name = name.replace(placeholder, &value);
+        }
+    }
+    name
+}
+
+/// The address connections to the internet go out from. Connecting a UDP
+/// socket only picks the route, nothing is sent.
+fn local_ip() -> Option<String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("8.8.8.8:80").ok()?;
+    Some(socket.local_addr().ok()?.ip().to_string())
+}
+
+fn mac_address() -> Option<String> {
+    // The interface is the first column of the default route, the one with
+    // destination 00000000.
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+    let interface = routes.lines().skip(1).find_map(|line| {
+        let mut columns = line.split_whitespace();
+        let interface = columns.next()?;
+        if columns.next()? == "00000000" {
+            Some(interface.to_string())
+        } else {
+            None
+        }
+    })?;
+    let address = fs::read_to_string(format!("/sys/class/net/{}/address", interface)).ok()?;
+    Some(address.trim().replace(':', ""))
+}
+
+/// The device ID kept in the cache, made up and stored if there is none yet.
+fn stored_device_id(cache_path: &Path, device_name: &str) -> Option<String> {
+    let path = cache_path.join("device_id");
+    if let Ok(id) = fs::read_to_string(&path) {
+        if !id.trim().is_empty() {
+            return Some(id.trim().to_string());
+        }
+    }
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let id = device_id(&format!(
+        "{}{}{:?}",
+        device_name,
+        process::id(),
+        since_epoch
+    ));
+    match fs::create_dir_all(cache_path).and_then(|_| fs::write(&path, &id)) {
+        Ok(()) => Some(id),
+        Err(e) => {
+            warn!("Couldn't store the device ID in {:?}: {}", path, e);
+            None
+        },
+    }
+}
+
 impl FromStr for VolumeController {
     type Err = ();
 
@@ -37,26 +200,449 @@ impl FromStr for VolumeController {
         match &*s.to_uppercase() {
             "ALSA" => Ok(VolumeController::Alsa { linear: false }),
             "ALSA_LINEAR" => Ok(VolumeController::Alsa { linear: true }),
+            "ALSA_DB" => Ok(VolumeController::AlsaDb),
             "SOFTVOL" => Ok(VolumeController::SoftVol),
+            "NONE" => Ok(VolumeController::Fixed),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The balance, from -1 for left only to 1 for right only, and the gain of
+/// the left and the right channel in dB.
+pub struct BalanceConfig {
+    pub balance: f64,
+    pub gains_db: [f64; 2],
+}
+
+/// Which channels of a multichannel device the left and right channel go to,
+/// counted from 0, and how many channels the device has.
+#[derive(Clone, Copy, PartialEq)]
+pub struct ChannelMap {
+    pub channels: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+#[derive(Clone, Copy)]
+pub struct SilenceTrimConfig {
+    /// Silence longer than this is cut down to it.
+    pub longest: Duration,
+    pub threshold_db: f64,
+}
+
+/// The limiter after normalisation: the ceiling in dBFS, and how long the
+/// gain takes to recover.
+#[derive(Clone, Copy)]
+pub struct LimiterConfig {
+    pub threshold_db: f64,
+    pub release: Duration,
+}
+
+/// Which access points to stay away from and which to try first, as parts
+/// of their host names, e.g. `gae2` or `gew1` for a region.
+pub struct ApPolicyConfig {
+    pub blacklist: Vec<String>,
+    pub prefer: Vec<String>,
+}
+
+pub struct SerialAmpConfig {
+    pub port: String,
+    pub baud_rate: u32,
+    pub power_on: Option<Vec<u8>>,
+    pub input: Option<Vec<u8>>,
+    pub power_off: Option<Vec<u8>>,
+    pub power_off_delay: Duration,
+    /// Command template, `{volume}` is replaced by the volume scaled to
+    /// `0..=volume_max`.
+    pub volume: Option<String>,
+    pub volume_max: u32,
+}
+
+pub struct PowerConfig {
+    pub on_command: Option<String>,
+    pub off_command: Option<String>,
+    pub gpio: Option<u32>,
+    pub off_delay: Duration,
+    pub state_file: Option<PathBuf>,
+}
+
+/// Where album art comes from, see `art::ArtProvider`.
+pub enum ArtSource {
+    Spotify,
+    Folder(PathBuf),
+    Template(String),
+}
+
+impl FromStr for ArtSource {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<ArtSource, ()> {
+        let s = s.trim();
+        if s == "spotify" {
+            Ok(ArtSource::Spotify)
+        } else if s.starts_with("folder:") {
+            Ok(ArtSource::Folder(PathBuf::from(&s["folder:".len()..])))
+        } else if s.starts_with("url:") {
+            Ok(ArtSource::Template(s["url:".len()..].to_string()))
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// Sample formats of raw PCM output, all little endian. `S24` is packed into
+/// three bytes.
+#[derive(Clone, Copy, PartialEq)]
+pub enum AudioFormat {
+    S16,
+    S24,
+    S32,
+    F32,
+}
+
+impl FromStr for AudioFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<AudioFormat, ()> {
+        match &*s.to_uppercase() {
+            "S16" => Ok(AudioFormat::S16),
+            "S24" => Ok(AudioFormat::S24),
+            "S32" => Ok(AudioFormat::S32),
+            "F32" => Ok(AudioFormat::F32),
             _ => Err(()),
         }
     }
 }
 
+#[derive(Clone, Copy)]
+pub enum EqualizerFilter {
+    LowShelf,
+    HighShelf,
+    Peak,
+}
+
+/// One band of an equalizer preset, `FILTER FREQUENCY GAIN [Q]`, e.g.
+/// `lowshelf 100 +6` or `peak 2500 -3 1.4`.
+#[derive(Clone, Copy)]
+pub struct EqualizerBand {
+    pub filter: EqualizerFilter,
+    /// In Hz.
+    pub frequency: f64,
+    /// In dB.
+    pub gain: f64,
+    pub q: f64,
+}
+
+impl FromStr for EqualizerBand {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<EqualizerBand, ()> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let filter = match fields
+            .get(0)
+            .map(|f| f.to_lowercase())
+            .as_ref()
+            .map(String::as_str)
+        {
+            Some("lowshelf") => EqualizerFilter::LowShelf,
+            Some("highshelf") => EqualizerFilter::HighShelf,
+            Some("peak") => EqualizerFilter::Peak,
+            _ => return Err(()),
+        };
+        let number = |i: usize| {
+            fields
+                .get(i)
+                .and_then(|f| f.trim_start_matches('+').parse().ok())
+        };
+        let band = EqualizerBand {
+            filter,
+            frequency: number(1).ok_or(())?,
+            gain: number(2).ok_or(())?,
+            q: if fields.len() > 3 {
+                number(3).ok_or(())?
+            } else {
+                std::f64::consts::FRAC_1_SQRT_2
+            },
+        };
+        if fields.len() > 4 || band.frequency <= 0.0 || band.q <= 0.0 {
+            return Err(());
+        }
+        Ok(band)
+    }
+}
+
+pub struct EqualizerConfig {
+    /// Presets and their bands, by name.
+    pub presets: Vec<(String, Vec<EqualizerBand>)>,
+    pub preset: Option<String>,
+}
+
+pub enum DisplayDriver {
+    /// A character LCD on an I2C bus.
+    Hd44780 { bus: PathBuf, address: u16 },
+    /// A program that draws the display itself.
+    Command(String),
+}
+
+impl FromStr for DisplayDriver {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<DisplayDriver, ()> {
+        if s.starts_with("command:") {
+            let command = &s["command:".len()..];
+            if split_command(command).is_empty() {
+                return Err(());
+            }
+            return Ok(DisplayDriver::Command(command.to_string()));
+        }
+        let mut parts = s.splitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("hd44780"), bus, address) => Ok(DisplayDriver::Hd44780 {
+                bus: PathBuf::from(bus.unwrap_or("/dev/i2c-1")),
+                address: match address {
+                    Some(a) => {
+                        u16::from_str_radix(a.trim_start_matches("0x"), 16).map_err(|_| ())?
+                    },
+                    None => 0x27,
+                },
+            }),
+            _ => Err(()),
+        }
+    }
+}
+
+pub struct DisplayConfig {
+    pub driver: DisplayDriver,
+    pub columns: usize,
+    pub rows: usize,
+    pub refresh: Duration,
+}
+
+pub struct RotaryEncoderConfig {
+    pub pin_a: u32,
+    pub pin_b: u32,
+    pub button: Option<u32>,
+    /// Pin transitions between two detents of the encoder.
+    pub steps_per_detent: i32,
+}
+
+/// The IR remote buttons, as lircd names them, and what they do.
+pub struct LircConfig {
+    pub socket: PathBuf,
+    pub buttons: Vec<(String, RemoteAction)>,
+}
+
+/// The buttons of remotes set up with the kernel's key names.
+const DEFAULT_LIRC_BUTTONS: &str = "KEY_PLAYPAUSE:play_pause, KEY_PLAY:play, KEY_PAUSE:pause, \
+                                    KEY_NEXT:next, KEY_PREVIOUS:previous, KEY_VOLUMEUP:volume_up, \
+                                    KEY_VOLUMEDOWN:volume_down";
+
+/// An alarm, `alarm.NAME = SCHEDULE URI [VOLUME [FADE_IN]]`: when it goes
+/// off, as five cron fields, what to play, the volume in percent to play it
+/// at and the seconds to fade in over, e.g.
+/// `30 6 * * 1-5 spotify:playlist:37i9dQZF1DX0yEZaMOXna3 40 60`.
+pub struct AlarmConfig {
+    pub name: String,
+    pub schedule: Schedule,
+    pub uri: String,
+    pub volume: Option<u8>,
+    pub fade_in: Duration,
+}
+
+impl AlarmConfig {
+    fn parse(name: &str, value: &str) -> Result<AlarmConfig, ()> {
+        let fields: Vec<&str> = value.split_whitespace().collect();
+        if fields.len() < 6 || fields.len() > 8 || !fields[5].starts_with("spotify:") {
+            return Err(());
+        }
+        let volume = match fields.get(6) {
+            Some(v) => Some(v.parse::<u8>().map_err(|_| ())?).filter(|&v| v <= 100),
+            None => None,
+        };
+        if fields.len() > 6 && volume.is_none() {
+            return Err(());
+        }
+        Ok(AlarmConfig {
+            name: name.to_string(),
+            schedule: Schedule::from_str(&fields[..5].join(" "))?,
+            uri: fields[5].to_string(),
+            volume,
+            fade_in: match fields.get(7) {
+                Some(f) => Duration::from_secs(f.parse().map_err(|_| ())?),
+                None => Duration::from_secs(0),
+            },
+        })
+    }
+}
+
+/// How many of the tracks that played last to keep, and where.
+pub struct RecentlyPlayedConfig {
+    pub count: usize,
+    pub file: Option<PathBuf>,
+}
+
+pub struct DataUsageConfig {
+    /// Where the day's count is kept, if there is a cache.
+    pub file: Option<PathBuf>,
+}
+
+pub struct SavePointConfig {
+    pub file: PathBuf,
+    /// Only tracks at least this long get save-points.
+    pub min_duration: Duration,
+}
+
+/// Turns the escape sequences `\r`, `\n`, `\t`, `\\` and `\xNN` into the
+/// bytes they stand for, so binary commands can be written in the config file.
+pub fn unescape(s: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('r') => bytes.push(b'\r'),
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(b) => bytes.push(b),
+                    Err(_) => bytes.extend_from_slice(format!("\\x{}", hex).as_bytes()),
+                }
+            },
+            Some(c) => {
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            },
+            None => bytes.push(b'\\'),
+        }
+    }
+    bytes
+}
+
 pub struct SpotifydConfig {
     pub username: Option<String>,
     pub password: Option<String>,
+    /// An OAuth access token to log in with instead of a password.
+    pub oauth_token: Option<String>,
+    /// An OAuth refresh token to get access tokens with.
+    pub oauth_refresh_token: Option<String>,
     pub use_keyring: bool,
-    pub cache: Option<Cache>,
+    /// Whether to store the configured password in the keyring.
+    pub keyring_store: bool,
+    pub cache_path: Option<PathBuf>,
+    /// Where to keep the cached audio instead of below `cache_path`.
+    pub audio_cache_path: Option<PathBuf>,
+    pub use_audio_cache: bool,
+    /// The most audio to keep in the cache, in bytes.
+    pub cache_size: Option<u64>,
+    /// Whether `cache_path` is the memory cache, which is checked to be
+    /// private before it is used.
+    pub cache_in_memory: bool,
     pub backend: Option<String>,
+    /// Backends, with their devices, that play the same audio as `backend`.
+    pub tee_backends: Vec<(String, Option<String>)>,
+    pub playback_start_delay: Option<Duration>,
+    pub playback_start_command: Option<String>,
+    pub fade: Option<Duration>,
+    pub trim_silence: Option<SilenceTrimConfig>,
+    pub limiter: Option<LimiterConfig>,
+    /// How far playback may run ahead of the device, in samples.
+    pub audio_buffer: Option<usize>,
+    /// Milliseconds to delay playback by, or to advance it by if negative.
+    pub latency_offset_ms: i64,
+    /// Where to lead multi-room playback, or the leader to follow.
+    pub multiroom_listen: Option<String>,
+    pub multiroom_leader: Option<String>,
+    /// How far ahead of playing it the leader sends audio.
+    pub multiroom_latency: Duration,
+    /// Sample format and rate of backends writing raw PCM.
+    pub audio_format: AudioFormat,
+    pub sample_rate: u32,
+    pub channel_map: Option<ChannelMap>,
+    /// Whether to dither what is processed back to 16 bits.
+    pub dither: bool,
+    pub balance: BalanceConfig,
     pub audio_device: Option<String>,
     pub mixer: Option<String>,
+    /// Card the mixer belongs to, when it differs from the audio device.
+    pub mixer_card: Option<String>,
+    pub mixer_index: u32,
     pub volume_controller: VolumeController,
+    pub volume_curve: VolumeCurve,
+    pub volume_db_range: f64,
+    /// Limits, in percent, to the volume Connect may set.
+    pub volume_min: u8,
+    pub volume_max: u8,
+    /// Volume, in percent, to start at instead of the mixer's.
+    pub initial_volume: Option<u8>,
     pub device_name: String,
     pub player_config: PlayerConfig,
+    /// Whether the bitrate follows network conditions, see `bitrate = auto`.
+    pub adaptive_bitrate: bool,
+    pub pause_on_device_loss: bool,
+    pub resume_on_device_return: bool,
+    /// How long to wait for playback before exiting, if at all.
+    pub exit_after_idle: Option<Duration>,
+    /// Where to answer health checks, a Unix socket path or a TCP address.
+    pub health_listen: Option<String>,
+    pub inhibit_sleep: bool,
+    /// What SIGUSR1 and SIGUSR2 do to playback.
+    pub signal_actions: Vec<(i32, SignalAction)>,
     pub session_config: SessionConfig,
+    /// The access point ports to try in turn, none for any port.
+    pub ap_ports: Vec<u16>,
+    pub ap_policy: Option<ApPolicyConfig>,
+    /// The port of the zeroconf server, any free one if 0.
+    pub zeroconf_port: u16,
+    pub discovery: bool,
+    /// Whether to hold startup until Spotify can be reached.
+    pub wait_for_network: bool,
     pub onevent: Option<String>,
-    pub pid: Option<String>,
+    pub metadata_pipe: Option<PathBuf>,
+    pub control_socket: Option<PathBuf>,
+    pub cover_art_file: Option<CoverArtFileConfig>,
+    pub art_providers: Vec<ArtSource>,
+    pub display: Option<DisplayConfig>,
+    pub desktop_notifications: bool,
+    /// Lua scripts to load as plugins.
+    pub plugins: Vec<PathBuf>,
+    pub equalizer: Option<EqualizerConfig>,
+    pub alarms: Vec<AlarmConfig>,
+    pub rotary_encoder: Option<RotaryEncoderConfig>,
+    pub lirc: Option<LircConfig>,
+    /// Push buttons, by their GPIO, and what they do.
+    pub gpio_buttons: Vec<(u32, RemoteAction)>,
+    pub serial_amp: Option<SerialAmpConfig>,
+    pub power: Option<PowerConfig>,
+    pub snapcast_socket: Option<PathBuf>,
+    pub save_points: Option<SavePointConfig>,
+    /// Marker file for reconnecting to the last controller, if enabled.
+    pub reconnect_marker: Option<PathBuf>,
+    /// File recording playback to restore on startup, if enabled.
+    pub restore_playback: Option<PathBuf>,
+    pub resume_after_reconnect: bool,
+    pub autoplay: bool,
+    pub pid: Option<PathBuf>,
+    /// Who to run as when started as root.
+    pub user: Option<String>,
+    pub group: Option<String>,
+    /// Whether to confine spotifyd with Landlock and seccomp once started.
+    pub sandbox: bool,
+    pub log_format: LogFormat,
+    pub log_file: Option<LogFile>,
+    pub play_history: Option<PlayHistoryConfig>,
+    pub recently_played: Option<RecentlyPlayedConfig>,
+    /// Whether to count the data received from Spotify.
+    pub data_usage: Option<DataUsageConfig>,
+    /// What to log, from `log_filter` or `verbosity`, unless `-v` says.
+    pub log_filter: Option<Filter>,
+    pub simulate: bool,
 }
 
 impl Default for SpotifydConfig {
@@ -64,55 +650,327 @@ impl Default for SpotifydConfig {
         SpotifydConfig {
             username: None,
             password: None,
+            oauth_token: None,
+            oauth_refresh_token: None,
             use_keyring: false,
-            cache: None,
+            keyring_store: false,
+            cache_path: None,
+            audio_cache_path: None,
+            use_audio_cache: true,
+            cache_size: None,
+            cache_in_memory: false,
             backend: None,
+            tee_backends: Vec::new(),
+            playback_start_delay: None,
+            playback_start_command: None,
+            fade: None,
+            trim_silence: None,
+            limiter: None,
+            audio_buffer: None,
+            latency_offset_ms: 0,
+            multiroom_listen: None,
+            multiroom_leader: None,
+            multiroom_latency: multiroom::DEFAULT_LATENCY,
+            audio_format: AudioFormat::S16,
+            sample_rate: 44100,
+            channel_map: None,
+            dither: false,
+            balance: BalanceConfig {
+                balance: 0.0,
+                gains_db: [0.0, 0.0],
+            },
             audio_device: None,
             mixer: None,
+            mixer_card: None,
+            mixer_index: 0,
             volume_controller: VolumeController::SoftVol,
+            volume_curve: VolumeCurve::Logarithmic,
+            volume_db_range: DEFAULT_VOLUME_DB_RANGE,
+            volume_min: 0,
+            volume_max: 100,
+            initial_volume: None,
             device_name: "Spotifyd".to_string(),
             player_config: PlayerConfig {
                 bitrate: Bitrate::Bitrate160,
                 normalisation: false,
                 normalisation_pregain: 0.0,
             },
+            adaptive_bitrate: false,
+            pause_on_device_loss: false,
+            resume_on_device_return: false,
+            exit_after_idle: None,
+            health_listen: None,
+            inhibit_sleep: false,
+            signal_actions: vec![
+                (libc::SIGUSR1, SignalAction::PlayPause),
+                (libc::SIGUSR2, SignalAction::Next),
+            ],
             session_config: SessionConfig {
                 user_agent: version::version_string(),
                 device_id: device_id("Spotifyd"),
                 proxy: None,
                 ap_port: Some(443),
             },
+            ap_ports: vec![443],
+            ap_policy: None,
+            zeroconf_port: 0,
+            discovery: true,
+            wait_for_network: false,
             onevent: None,
+            metadata_pipe: None,
+            control_socket: None,
+            cover_art_file: None,
+            art_providers: vec![ArtSource::Spotify],
+            display: None,
+            desktop_notifications: false,
+            plugins: Vec::new(),
+            equalizer: None,
+            alarms: Vec::new(),
+            rotary_encoder: None,
+            lirc: None,
+            gpio_buttons: Vec::new(),
+            serial_amp: None,
+            power: None,
+            snapcast_socket: None,
+            save_points: None,
+            reconnect_marker: None,
+            restore_playback: None,
+            resume_after_reconnect: false,
+            autoplay: false,
             pid: None,
+            user: None,
+            group: None,
+            sandbox: false,
+            log_format: LogFormat::Text,
+            log_file: None,
+            play_history: None,
+            recently_played: None,
+            data_usage: None,
+            log_filter: None,
+            simulate: false,
         }
     }
 }
 
 pub fn get_config_file() -> Result<PathBuf, Box<Error>> {
-    let etc_conf = format!("/etc/{}", CONFIG_FILE);
     let xdg_dirs = xdg::BaseDirectories::with_prefix("spotifyd")?;
-    xdg_dirs
-        .find_config_file(CONFIG_FILE)
+    CONFIG_FILES
+        .iter()
+        .filter_map(|name| xdg_dirs.find_config_file(name))
+        .next()
         .or_else(|| {
-            metadata(&*etc_conf).ok().and_then(|meta| {
-                if meta.is_file() {
-                    Some(etc_conf.into())
-                } else {
-                    None
-                }
-            })
+            CONFIG_FILES
+                .iter()
+                .map(|name| PathBuf::from(format!("/etc/{}", name)))
+                .find(|path| metadata(path).map_or(false, |meta| meta.is_file()))
         })
         .ok_or_else(|| From::from("Couldn't find a config file."))
 }
 
+/// Parses a setting, exiting with an error naming the setting and `kind`,
+/// what it has to be, if it doesn't parse.
+fn parse<T: FromStr>(field: &str, value: &str, kind: &str) -> T {
+    value.trim().parse().unwrap_or_else(|_| {
+        panic!(
+            "{}",
+            errors::message(ErrorCode::InvalidValue, &[&field, &kind])
+        )
+    })
+}
+
+/// Checks that a setting which is a command names a program to run, exiting
+/// with an error naming the setting if it doesn't.
+fn command(field: &str, value: String) -> String {
+    if split_command(&value).is_empty() {
+        panic!(
+            "{}",
+            errors::message(ErrorCode::InvalidValue, &[&field, &"a program to run"])
+        )
+    }
+    value
+}
+
+/// Parses `audio_buffer`, a duration like `5s` or `500ms`, or a size in bytes
+/// of 16-bit PCM like `1M`, `256K` or `65536`, into samples.
+fn buffer_samples(value: &str) -> usize {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or_else(|| value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number
+        .parse::<f64>()
+        .unwrap_or_else(|_| invalid_setting("audio_buffer", value));
+    let samples = match &*unit.trim().to_lowercase() {
+        "s" => number * 44100.0 * 2.0,
+        "ms" => number * 44.1 * 2.0,
+        "" | "b" => number / 2.0,
+        "k" | "kb" => number * 1024.0 / 2.0,
+        "m" | "mb" => number * 1024.0 * 1024.0 / 2.0,
+        _ => invalid_setting("audio_buffer", value),
+    };
+    samples as usize
+}
+
+fn invalid_setting(field: &str, value: &str) -> ! {
+    panic!(
+        "{}",
+        errors::message(ErrorCode::InvalidSetting, &[&field, &value])
+    )
+}
+
+/// Runs `password_cmd` in a shell and takes the first line it prints as the
+/// password, like `pass` prints it.
+fn password_from_command(command: &str) -> String {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stderr(Stdio::inherit())
+        .output();
+    let failure = match output {
+        Ok(ref output) if output.status.success() => {
+            return first_line(&String::from_utf8_lossy(&output.stdout));
+        },
+        Ok(output) => output.status.to_string(),
+        Err(e) => e.to_string(),
+    };
+    panic!(
+        "{}",
+        errors::message(ErrorCode::PasswordSourceFailed, &[&command, &failure])
+    )
+}
+
+/// Reads the password from a file descriptor inherited from the parent, so
+/// it never shows up in arguments or the environment.
+fn password_from_fd(fd: RawFd) -> String {
+    let mut password = String::new();
+    // The descriptor is only ever used here.
+    if let Err(e) = unsafe { File::from_raw_fd(fd) }.read_to_string(&mut password) {
+        panic!(
+            "{}",
+            errors::message(
+                ErrorCode::PasswordSourceFailed,
+                &[&format!("file descriptor {}", fd), &e]
+            )
+        )
+    }
+    first_line(&password)
+}
+
+/// Reads the `password` credential systemd passes with `LoadCredential=`.
+fn password_from_credentials_directory() -> Option<String> {
+    let path = PathBuf::from(env::var_os("CREDENTIALS_DIRECTORY")?).join("password");
+    if !path.exists() {
+        return None;
+    }
+    match fs::read_to_string(&path) {
+        Ok(password) => Some(first_line(&password)),
+        Err(e) => panic!(
+            "{}",
+            errors::message(ErrorCode::PasswordSourceFailed, &[&path.display(), &e])
+        ),
+    }
+}
+
+/// Replaces `${VAR}` in a value of the config file with the environment
+/// variable `VAR`, exiting with an error naming the setting if it isn't set.
+/// `$${` is a literal `${`.
+fn expand_variables(field: &str, value: &str) -> String {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(i) = rest.find("${") {
+        if rest[..i].ends_with('$') {
+            expanded.push_str(&rest[..i]);
+            expanded.push('{');
+            rest = &rest[i + 2..];
+            continue;
+        }
+        let end = match rest[i..].find('}') {
+            Some(end) => i + end,
+            None => break,
+        };
+        let name = &rest[i + 2..end];
+        expanded.push_str(&rest[..i]);
+        match env::var(name) {
+            Ok(v) => expanded.push_str(&v),
+            Err(_) => panic!(
+                "{}",
+                errors::message(ErrorCode::UnsetVariable, &[&field, &name])
+            ),
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+fn first_line(s: &str) -> String {
+    s.lines().next().unwrap_or("").to_string()
+}
+
 fn update<T>(r: &mut T, val: Option<T>) {
     if let Some(mut v) = val {
         swap(r, &mut v);
     }
 }
 
+/// Opens the cache, once spotifyd starts rather than whenever the config is
+/// read, with the audio cache linked into it first.
+pub fn open_cache(config: &SpotifydConfig) -> Option<Cache> {
+    let cache_path = config.cache_path.clone()?;
+    if config.cache_in_memory {
+        // Switching users hands the cache over to the user.
+        let mut owners = vec![unsafe { libc::getuid() }];
+        owners.extend(config.user.as_ref().and_then(|u| privileges::uid_of(u)));
+        if let Err(e) = cache_limit::create_private_dir(&cache_path, &owners) {
+            error!("Couldn't keep the cache in {:?}: {}", cache_path, e);
+            Failure::System.exit();
+        }
+    }
+    if let Some(ref audio_cache_path) = config.audio_cache_path {
+        link_audio_cache(&cache_path, audio_cache_path);
+    }
+    Some(Cache::new(cache_path, config.use_audio_cache))
+}
+
+/// Keeps the audio files of the cache in `audio_cache_path` instead of next
+/// to the credentials. librespot caches both below one directory, so its
+/// `files` directory is made a link to the audio cache.
+fn link_audio_cache(cache_path: &Path, audio_cache_path: &Path) {
+    let files = cache_path.join("files");
+    if fs::read_link(&files).map_or(false, |target| target.as_path() == audio_cache_path) {
+        return;
+    }
+    match fs::symlink_metadata(&files) {
+        Ok(ref m) if m.file_type().is_symlink() => {
+            let _ = fs::remove_file(&files);
+        },
+        Ok(_) => {
+            warn!(
+                "{:?} already holds cached audio, remove it to cache audio in {:?}.",
+                files, audio_cache_path
+            );
+            return;
+        },
+        Err(_) => (),
+    }
+    let linked = fs::create_dir_all(audio_cache_path)
+        .and_then(|_| fs::create_dir_all(cache_path))
+        .and_then(|_| unix::fs::symlink(audio_cache_path, &files));
+    if let Err(e) = linked {
+        warn!("Couldn't cache audio in {:?}: {}", audio_cache_path, e);
+    }
+}
+
+/// Makes reading the configuration free of side effects, for checking it:
+/// no password commands are run or descriptors read, and no device ID is
+/// stored in the cache.
+pub fn set_dry_run() {
+    DRY_RUN.store(true, Ordering::SeqCst);
+}
+
 pub fn get_config<P: AsRef<Path>>(config_path: Option<P>, matches: &Matches) -> SpotifydConfig {
-    let mut config = SpotifydConfig::default();
+    let config = SpotifydConfig::default();
 
     let config_path = match config_path {
         Some(c) => c,
@@ -122,7 +980,15 @@ pub fn get_config<P: AsRef<Path>>(config_path: Option<P>, matches: &Matches) ->
         },
     };
 
-    let config_file = match Ini::load_from_file(config_path) {
+    let config_path = config_path.as_ref();
+    let config_file = if toml_config::is_toml(config_path) {
+        fs::read_to_string(config_path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| toml_config::to_ini(&contents).map_err(|e| e.to_string()))
+    } else {
+        Ini::load_from_file(config_path).map_err(|e| e.to_string())
+    };
+    let config_file = match config_file {
         Ok(c) => c,
         Err(e) => {
             info!(
@@ -132,68 +998,649 @@ pub fn get_config<P: AsRef<Path>>(config_path: Option<P>, matches: &Matches) ->
             return config;
         },
     };
+    from_ini(&config_file, matches)
+}
 
+/// The configuration the config file and the command line give.
+pub fn from_ini(config_file: &Ini, matches: &Matches) -> SpotifydConfig {
+    let mut config = SpotifydConfig::default();
+
+    let dry_run = DRY_RUN.load(Ordering::SeqCst);
     let global = config_file.section(Some("global".to_owned()));
     let spotifyd = config_file.section(Some("spotifyd".to_owned()));
-
-    let lookup = |field| {
-        matches.opt_str(field).or_else(|| {
-            spotifyd
-                .and_then(|s| s.get(field).map(String::clone))
-                .or_else(|| global.and_then(|s| s.get(field).map(String::clone)))
-        })
+    let instance = matches.opt_str("instance").map(|name| {
+        config_file
+            .section(Some(format!("{}{}", INSTANCE_SECTION_PREFIX, name)))
+            .unwrap_or_else(|| invalid_setting("instance", &name))
+    });
+    // The sections of the config file, the one that takes precedence first.
+    let sections: Vec<_> = instance.into_iter().chain(spotifyd).chain(global).collect();
+    let from_file = |field: &str| {
+        sections
+            .iter()
+            .filter_map(|s| s.get(field))
+            .next()
+            .map(|value| expand_variables(field, value))
     };
 
-    update(
-        &mut config.cache,
-        lookup("cache_path")
-            .map(PathBuf::from)
-            .and_then(|p| Some(Cache::new(p, true)))
-            .map(Some),
-    );
+    let lookup = |field| matches.opt_str(field).or_else(|| from_file(field));
+
+    if let Some(catalog) = lookup("error_catalog") {
+        errors::load_catalog(Path::new(&catalog));
+    }
+
+    config.cache_path = lookup("cache_path").map(PathBuf::from);
+    config.cache_size =
+        lookup("cache_size").map(|mb| parse::<u64>("cache_size", &mb, "an integer") * 1024 * 1024);
+    if lookup("cache_in_memory").map_or(false, |s| s == "true") {
+        if config.cache_path.is_some() {
+            warn!("cache_path is ignored, cache_in_memory is set.");
+        }
+        config.cache_path = Some(cache_limit::memory_cache_path());
+        config.cache_in_memory = true;
+        config.cache_size = config.cache_size.or(Some(DEFAULT_MEMORY_CACHE_SIZE));
+    }
+    config.use_audio_cache = lookup("no_audio_cache").map_or(true, |s| s != "true");
+    if let Some(audio_cache_path) = lookup("audio_cache_path") {
+        match config.cache_path {
+            Some(_) if config.use_audio_cache => {
+                config.audio_cache_path = Some(PathBuf::from(audio_cache_path))
+            },
+            Some(_) => warn!("audio_cache_path is ignored, no_audio_cache is set."),
+            None => warn!("audio_cache_path needs a cache_path for the credentials."),
+        }
+    }
 
     config.username = lookup("username");
+    config.oauth_token = lookup("oauth_token");
+    config.oauth_refresh_token = lookup("oauth_refresh_token");
+    let password_fd =
+        lookup("password-fd").map(|fd| parse("password-fd", &fd, "a file descriptor"));
     config.password = lookup("password");
-    if let Some(ref value) = lookup("use-keyring") {
-        if value == "true" {
-            config.use_keyring = true;
-        }
+    if !dry_run {
+        config.password = config
+            .password
+            .or_else(|| lookup("password_cmd").map(|command| password_from_command(&command)))
+            .or_else(|| password_fd.map(password_from_fd))
+            .or_else(password_from_credentials_directory);
     }
+    // A flag on the command line, either spelling in the config file.
+    config.use_keyring = matches.opt_present("use-keyring")
+        || ["use-keyring", "use_keyring"]
+            .iter()
+            .any(|&key| from_file(key).map_or(false, |v| v == "true"));
+    config.keyring_store = lookup("keyring_store").map_or(false, |s| s == "true");
     config.backend = lookup("backend");
+    if let Some(tee) = lookup("tee_backends") {
+        config.tee_backends = tee
+            .split(',')
+            .map(|b| {
+                let b = b.trim();
+                match b.find(':') {
+                    Some(i) => (b[..i].to_string(), Some(b[i + 1..].to_string())),
+                    None => (b.to_string(), None),
+                }
+            })
+            .collect();
+    }
+    if lookup("snapcast").map_or(false, |s| s == "true") {
+        config.snapcast_socket = Some(PathBuf::from(
+            lookup("snapcast_socket").unwrap_or_else(|| snapcast::DEFAULT_SOCKET.to_string()),
+        ));
+        config.backend = config.backend.or_else(|| Some("pipe".to_string()));
+    }
+    config.playback_start_delay = lookup("playback_start_delay_ms")
+        .map(|d| Duration::from_millis(parse("playback_start_delay_ms", &d, "an integer")));
+    config.playback_start_command =
+        lookup("playback_start_command").map(|c| command("playback_start_command", c));
+    config.fade = lookup("fade_ms")
+        .map(|d| Duration::from_millis(parse("fade_ms", &d, "an integer")))
+        .filter(|d| *d > Duration::from_millis(0));
+    config.trim_silence = lookup("trim_silence_ms").map(|d| SilenceTrimConfig {
+        longest: Duration::from_millis(parse("trim_silence_ms", &d, "an integer")),
+        threshold_db: lookup("trim_silence_threshold")
+            .map(|t| parse("trim_silence_threshold", &t, "a number"))
+            .unwrap_or(DEFAULT_SILENCE_THRESHOLD),
+    });
+    config.audio_buffer = lookup("audio_buffer")
+        .map(|b| buffer_samples(&b))
+        .filter(|&samples| samples > 0);
+    config.latency_offset_ms =
+        lookup("latency_offset_ms").map_or(0, |o| parse("latency_offset_ms", &o, "an integer"));
+    if config.latency_offset_ms > MAX_LATENCY_OFFSET_MS
+        || config.latency_offset_ms < -MAX_LATENCY_OFFSET_MS
+    {
+        invalid_setting(
+            "latency_offset_ms",
+            &format!("more than {} ms either way", MAX_LATENCY_OFFSET_MS),
+        );
+    }
+    config.multiroom_listen = lookup("multiroom_listen");
+    config.multiroom_leader = lookup("multiroom_leader");
+    if config.multiroom_listen.is_some() && config.multiroom_leader.is_some() {
+        warn!("multiroom_leader makes this a follower, ignoring multiroom_listen.");
+        config.multiroom_listen = None;
+    }
+    if let Some(l) = lookup("multiroom_latency_ms") {
+        config.multiroom_latency =
+            Duration::from_millis(parse("multiroom_latency_ms", &l, "an integer"));
+    }
     config.audio_device = lookup("device");
+    if let Some(f) = lookup("audio_format") {
+        config.audio_format =
+            AudioFormat::from_str(&f).unwrap_or_else(|_| invalid_setting("audio_format", &f));
+    }
+    if let Some(r) = lookup("sample_rate") {
+        config.sample_rate = parse("sample_rate", &r, "an integer");
+        if config.sample_rate == 0 {
+            invalid_setting("sample_rate", &r);
+        }
+    }
+    // Channels are counted from 1 in the config, as on audio interfaces.
+    if let Some(map) = lookup("channel_map") {
+        let channels: Vec<usize> = map
+            .split(',')
+            .map(|c| parse("channel_map", c, "two channels, e.g. 3,4"))
+            .collect();
+        if channels.len() != 2 || channels.contains(&0) || channels[0] == channels[1] {
+            invalid_setting("channel_map", &map);
+        }
+        let highest = channels[0].max(channels[1]);
+        let count =
+            lookup("channels").map_or(highest, |n| parse("channels", &n, "a number of channels"));
+        if count < highest {
+            invalid_setting("channels", "fewer than channel_map uses");
+        }
+        config.channel_map = Some(ChannelMap {
+            channels: count,
+            left: channels[0] - 1,
+            right: channels[1] - 1,
+        });
+    }
+    config.dither = match lookup("dither").as_ref().map(String::as_str) {
+        None | Some("none") | Some("false") => false,
+        Some("tpdf") | Some("true") => true,
+        Some(other) => invalid_setting("dither", other),
+    };
+    if let Some(b) = lookup("balance") {
+        config.balance.balance = parse("balance", &b, "a number");
+        if config.balance.balance < -1.0 || config.balance.balance > 1.0 {
+            invalid_setting("balance", &b);
+        }
+    }
+    for (i, &key) in ["gain_left", "gain_right"].iter().enumerate() {
+        if let Some(db) = lookup(key) {
+            config.balance.gains_db[i] = parse(key, &db, "a number of dB");
+        }
+    }
     config.mixer = lookup("mixer");
+    config.mixer_card = lookup("mixer_card");
+    if let Some(i) = lookup("mixer_index") {
+        config.mixer_index = parse("mixer_index", &i, "an integer");
+    }
     update(
         &mut config.volume_controller,
         lookup("volume-control").and_then(|s| VolumeController::from_str(&*s).ok()),
     );
-    config.device_name = lookup("device_name").unwrap_or_else(|| {
-        if let Some(h) = hostname::get_hostname() {
-            format!("Spotifyd@{}", h)
-        } else {
-            "Spotifyd".to_string()
+    config.volume_curve = match lookup("volume_curve") {
+        Some(c) => {
+            VolumeCurve::from_str(&c).unwrap_or_else(|_| invalid_setting("volume_curve", &c))
+        },
+        // Mixers that already follow loudness get the volume unchanged.
+        None => match config.volume_controller {
+            VolumeController::Alsa { linear: true } | VolumeController::AlsaDb => {
+                VolumeCurve::Linear
+            },
+            _ => VolumeCurve::Logarithmic,
+        },
+    };
+    if let Some(r) = lookup("volume_db_range") {
+        config.volume_db_range = parse("volume_db_range", &r, "a number");
+        if config.volume_db_range <= 0.0 {
+            invalid_setting("volume_db_range", &r);
         }
+    }
+    if let Some(v) = lookup("volume_min") {
+        config.volume_min = parse("volume_min", &v, "a percentage");
+    }
+    if let Some(v) = lookup("volume_max") {
+        config.volume_max = parse("volume_max", &v, "a percentage");
+    }
+    config.initial_volume = lookup("initial_volume").map(|v| {
+        let volume = parse("initial_volume", &v, "a percentage");
+        if volume > 100 {
+            invalid_setting("initial_volume", &v);
+        }
+        volume
     });
-    config.onevent = lookup("onevent");
+    if config.volume_max > 100 || config.volume_min > config.volume_max {
+        invalid_setting(
+            "volume limits",
+            &format!("{}..{}", config.volume_min, config.volume_max),
+        );
+    }
+    config.device_name = lookup("device_name").map_or_else(
+        || {
+            if let Some(h) = hostname::get_hostname() {
+                format!("Spotifyd@{}", h)
+            } else {
+                "Spotifyd".to_string()
+            }
+        },
+        |name| expand_device_name(&name),
+    );
+    config.onevent = lookup("onevent").map(|c| command("onevent", c));
+    config.metadata_pipe = lookup("metadata_pipe").map(PathBuf::from);
+    config.control_socket = lookup("control_socket").map(PathBuf::from);
+    config.cover_art_file = lookup("cover_art_file").map(|path| CoverArtFileConfig {
+        path: PathBuf::from(path),
+        size: lookup("cover_art_size").map(|s| parse("cover_art_size", &s, "a size in pixels")),
+    });
+    if let Some(providers) = lookup("art_providers") {
+        config.art_providers = providers
+            .split(',')
+            .map(|p| {
+                ArtSource::from_str(p).unwrap_or_else(|_| invalid_setting("art provider", p.trim()))
+            })
+            .collect();
+    }
+    if let Some(driver) = lookup("display_driver") {
+        let size = lookup("display_size").unwrap_or_else(|| "16x2".to_string());
+        let (columns, rows) = match size.find('x') {
+            Some(i) => (size[..i].parse().ok(), size[i + 1..].parse().ok()),
+            None => (None, None),
+        };
+        config.display = Some(DisplayConfig {
+            driver: DisplayDriver::from_str(&driver)
+                .unwrap_or_else(|_| invalid_setting("display_driver", &driver)),
+            columns: columns.unwrap_or_else(|| invalid_setting("display_size", &size)),
+            rows: rows.unwrap_or_else(|| invalid_setting("display_size", &size)),
+            refresh: Duration::from_millis(
+                lookup("display_refresh")
+                    .map(|r| parse("display_refresh", &r, "an integer"))
+                    .unwrap_or(500),
+            ),
+        });
+    }
+    config.desktop_notifications = lookup("desktop_notifications").map_or(false, |s| s == "true");
+    if let Some(plugins) = lookup("plugins") {
+        config.plugins = plugins
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from)
+            .collect();
+    }
+    // Presets are `equalizer.NAME` keys, which can't be given on the command
+    // line. Those in [spotifyd] replace those of the same name in [global],
+    // and those of the instance both.
+    let mut presets: HashMap<String, Vec<EqualizerBand>> = HashMap::new();
+    for section in sections.iter().rev() {
+        for (key, value) in section.iter() {
+            if key.starts_with(EQUALIZER_PRESET_PREFIX) {
+                let bands = value
+                    .split(',')
+                    .map(|b| EqualizerBand::from_str(b).unwrap_or_else(|_| invalid_setting(key, b)))
+                    .collect();
+                presets.insert(key[EQUALIZER_PRESET_PREFIX.len()..].to_string(), bands);
+            }
+        }
+    }
+    let preset = lookup("equalizer");
+    if let Some(ref p) = preset {
+        if !presets.contains_key(p) {
+            invalid_setting("equalizer", p);
+        }
+    }
+    if !presets.is_empty() {
+        let mut presets: Vec<_> = presets.into_iter().collect();
+        presets.sort_by(|a, b| a.0.cmp(&b.0));
+        config.equalizer = Some(EqualizerConfig { presets, preset });
+    }
+    // Alarms are `alarm.NAME` keys, like equalizer presets.
+    let mut alarms: HashMap<String, AlarmConfig> = HashMap::new();
+    for section in sections.iter().rev() {
+        for (key, value) in section.iter() {
+            if key.starts_with(ALARM_PREFIX) {
+                let name = &key[ALARM_PREFIX.len()..];
+                let alarm = AlarmConfig::parse(name, &expand_variables(key, value))
+                    .unwrap_or_else(|_| invalid_setting(key, value));
+                alarms.insert(name.to_string(), alarm);
+            }
+        }
+    }
+    config.alarms = alarms.into_iter().map(|(_, alarm)| alarm).collect();
+    config.alarms.sort_by(|a, b| a.name.cmp(&b.name));
+    config.rotary_encoder = lookup("rotary_encoder").map(|pins| {
+        let pins: Vec<u32> = pins
+            .split(',')
+            .map(|p| parse("rotary_encoder", p, "a list of GPIO numbers"))
+            .collect();
+        if pins.len() < 2 {
+            panic!(
+                "{}",
+                errors::message(
+                    ErrorCode::InvalidValue,
+                    &[
+                        &"rotary_encoder",
+                        &"two GPIOs and, optionally, one for the button"
+                    ]
+                )
+            );
+        }
+        RotaryEncoderConfig {
+            pin_a: pins[0],
+            pin_b: pins[1],
+            button: pins.get(2).cloned(),
+            steps_per_detent: lookup("rotary_encoder_steps")
+                .map(|s| match parse("rotary_encoder_steps", &s, "an integer") {
+                    0 => invalid_setting("rotary_encoder_steps", &s),
+                    steps => steps,
+                })
+                .unwrap_or(4),
+        }
+    });
+    if let Some(buttons) = lookup("gpio_buttons") {
+        config.gpio_buttons = buttons
+            .split(',')
+            .map(|b| {
+                let mut parts = b.trim().splitn(2, ':');
+                let pin = parts.next().and_then(|p| p.trim().parse().ok());
+                let action = parts
+                    .next()
+                    .and_then(|a| RemoteAction::from_str(a.trim()).ok());
+                match (pin, action) {
+                    (Some(pin), Some(action)) => (pin, action),
+                    _ => invalid_setting("gpio_buttons", b.trim()),
+                }
+            })
+            .collect();
+    }
+    if lookup("lirc").map_or(false, |s| s == "true") {
+        let buttons = lookup("lirc_buttons").unwrap_or_else(|| DEFAULT_LIRC_BUTTONS.to_string());
+        config.lirc = Some(LircConfig {
+            socket: PathBuf::from(
+                lookup("lirc_socket").unwrap_or_else(|| lirc::DEFAULT_SOCKET.to_string()),
+            ),
+            buttons: buttons
+                .split(',')
+                .map(|b| {
+                    let mut parts = b.trim().splitn(2, ':');
+                    let button = parts.next().unwrap_or("");
+                    let action = parts
+                        .next()
+                        .and_then(|a| RemoteAction::from_str(a.trim()).ok())
+                        .unwrap_or_else(|| invalid_setting("lirc_buttons", b.trim()));
+                    (button.to_string(), action)
+                })
+                .collect(),
+        });
+    }
+    config.serial_amp = lookup("amp_serial_port").map(|port| SerialAmpConfig {
+        port,
+        baud_rate: lookup("amp_baud_rate")
+            .map(|b| parse("amp_baud_rate", &b, "an integer"))
+            .unwrap_or(9600),
+        power_on: lookup("amp_power_on").map(|c| unescape(&c)),
+        input: lookup("amp_input").map(|c| unescape(&c)),
+        power_off: lookup("amp_power_off").map(|c| unescape(&c)),
+        power_off_delay: Duration::from_secs(
+            lookup("amp_power_off_delay")
+                .map(|d| parse("amp_power_off_delay", &d, "an integer"))
+                .unwrap_or(300),
+        ),
+        volume: lookup("amp_volume"),
+        volume_max: lookup("amp_volume_max")
+            .map(|m| parse("amp_volume_max", &m, "an integer"))
+            .unwrap_or(100),
+    });
+    let power_on_command = lookup("power_on_command").map(|c| command("power_on_command", c));
+    let power_off_command = lookup("power_off_command").map(|c| command("power_off_command", c));
+    let power_gpio = lookup("power_gpio").map(|p| parse("power_gpio", &p, "an integer"));
+    if power_on_command.is_some() || power_off_command.is_some() || power_gpio.is_some() {
+        config.power = Some(PowerConfig {
+            on_command: power_on_command,
+            off_command: power_off_command,
+            gpio: power_gpio,
+            off_delay: Duration::from_secs(
+                lookup("power_off_delay")
+                    .map(|d| parse("power_off_delay", &d, "an integer"))
+                    .unwrap_or(600),
+            ),
+            state_file: config.cache_path.as_ref().map(|p| p.join("power_state")),
+        });
+    }
+    config.recently_played = lookup("recently_played")
+        .map(|n| parse::<usize>("recently_played", &n, "a number of tracks"))
+        .filter(|&count| count > 0)
+        .map(|count| RecentlyPlayedConfig {
+            count,
+            file: config
+                .cache_path
+                .as_ref()
+                .map(|p| p.join("recently_played.json")),
+        });
+    if lookup("save_points").map_or(false, |s| s == "true") {
+        match config.cache_path {
+            Some(ref cache_path) => {
+                config.save_points = Some(SavePointConfig {
+                    file: cache_path.join("savepoints.json"),
+                    min_duration: Duration::from_secs(
+                        lookup("save_points_min_duration")
+                            .map(|d| parse("save_points_min_duration", &d, "an integer"))
+                            .unwrap_or(900),
+                    ),
+                })
+            },
+            None => warn!("save_points needs a cache_path to store the positions in."),
+        }
+    }
+    if lookup("reconnect_to_controller").map_or(false, |s| s == "true") {
+        match config.cache_path {
+            Some(ref cache_path) => config.reconnect_marker = Some(cache_path.join("was_playing")),
+            None => warn!("reconnect_to_controller needs a cache_path to remember playback in."),
+        }
+    }
+    if lookup("restore_playback").map_or(false, |s| s == "true") {
+        match config.cache_path {
+            Some(ref cache_path) => {
+                config.restore_playback = Some(cache_path.join("last_playback.json"))
+            },
+            None => warn!("restore_playback needs a cache_path to remember playback in."),
+        }
+    }
+    config.resume_after_reconnect = lookup("resume_after_reconnect").map_or(false, |s| s == "true");
+    config.pause_on_device_loss = lookup("pause_on_device_loss").map_or(false, |s| s == "true");
+    config.resume_on_device_return =
+        lookup("resume_on_device_return").map_or(false, |s| s == "true");
+    if config.resume_on_device_return && !config.pause_on_device_loss {
+        warn!("resume_on_device_return needs pause_on_device_loss, ignoring it.");
+    }
+    config.exit_after_idle = lookup("exit_after_idle_secs")
+        .map(|s| Duration::from_secs(parse("exit_after_idle_secs", &s, "an integer")));
+    config.health_listen = lookup("health_listen");
+    for &(field, signal) in &[("sigusr1", libc::SIGUSR1), ("sigusr2", libc::SIGUSR2)] {
+        if let Some(action) = lookup(field) {
+            config.signal_actions.retain(|&(s, _)| s != signal);
+            if action != "none" {
+                let action = SignalAction::from_str(&action)
+                    .unwrap_or_else(|_| invalid_setting(field, &action));
+                config.signal_actions.push((signal, action));
+            }
+        }
+    }
+    config.inhibit_sleep = lookup("inhibit_sleep").map_or(false, |s| s == "true");
+    config.autoplay = lookup("autoplay").map_or(false, |s| s == "true");
     config.player_config.normalisation = matches.opt_present("volume-normalisation")
-        || spotifyd
-            .and_then(|s| s.get("volume-normalisation").map(String::clone))
-            .or_else(|| global.and_then(|g| g.get("volume-normalisation").map(String::clone)))
-            .unwrap_or_else(|| "false".to_string())
-            == "true";
+        || from_file("volume-normalisation").map_or(false, |v| v == "true");
 
     config.player_config.normalisation_pregain = lookup("normalisation-pregain")
-        .map(|db| {
-            db.parse::<f32>()
-                .expect("volume-normalisation must be a floating point number.")
-        })
+        .map(|db| parse::<f32>("normalisation-pregain", &db, "a floating point number"))
         .unwrap_or(PlayerConfig::default().normalisation_pregain);
+    config.limiter = lookup("normalisation-threshold").map(|t| LimiterConfig {
+        threshold_db: parse("normalisation-threshold", &t, "a number of dBFS"),
+        release: Duration::from_millis(
+            lookup("normalisation-release")
+                .map(|r| parse("normalisation-release", &r, "an integer"))
+                .unwrap_or(DEFAULT_LIMITER_RELEASE_MS),
+        ),
+    });
+    if let Some(LimiterConfig { threshold_db, .. }) = config.limiter {
+        if threshold_db > 0.0 {
+            invalid_setting("normalisation-threshold", "above 0 dBFS");
+        }
+    }
+    for &key in UNSUPPORTED_KEYS {
+        // Not command line options, so only the config file is checked.
+        if from_file(key).is_some() {
+            warn!(
+                "{} isn't supported by this version of spotifyd, ignoring it.",
+                key
+            );
+        }
+    }
 
+    let bitrate = lookup("bitrate");
+    if bitrate.as_ref().map_or(false, |b| b == "auto") {
+        config.adaptive_bitrate = true;
+        config.player_config.bitrate = Bitrate::Bitrate320;
+    } else {
+        update(
+            &mut config.player_config.bitrate,
+            bitrate.and_then(|s| Bitrate::from_str(&*s).ok()),
+        );
+    }
+    // Saving data leaves no room to step the bitrate up, and is the point of
+    // counting it.
+    let data_saver = lookup("data_saver").map_or(false, |s| s == "true");
+    if data_saver {
+        config.adaptive_bitrate = false;
+        config.player_config.bitrate = Bitrate::Bitrate96;
+    }
+    if data_saver || lookup("data_usage").map_or(false, |s| s == "true") {
+        config.data_usage = Some(DataUsageConfig {
+            file: config
+                .cache_path
+                .as_ref()
+                .map(|p| p.join("data_usage.json")),
+        });
+    }
     update(
-        &mut config.player_config.bitrate,
-        lookup("bitrate").and_then(|s| Bitrate::from_str(&*s).ok()),
+        &mut config.session_config.device_id,
+        lookup("device_name").map(|_| config.device_name.clone()),
     );
-    update(&mut config.session_config.device_id, lookup("device_name"));
+    // The apps remember the volume and more by device, so the device keeps
+    // its ID when it is renamed or restarted.
+    if let Some(id) = lookup("device_id") {
+        config.session_config.device_id = id;
+    } else if let (Some(ref cache_path), false) = (config.cache_path.as_ref(), dry_run) {
+        if let Some(id) = stored_device_id(cache_path, &config.device_name) {
+            config.session_config.device_id = id;
+        }
+    }
+    if let Some(ports) = lookup("ap_port") {
+        config.ap_ports = if ports.trim() == "any" {
+            Vec::new()
+        } else {
+            ports
+                .split(',')
+                .map(|p| parse("ap_port", p, "a list of ports or any"))
+                .collect()
+        };
+        config.session_config.ap_port = config.ap_ports.first().cloned();
+    }
+    let ap_list = |key| -> Vec<String> {
+        lookup(key).map_or_else(Vec::new, |list: String| {
+            list.split(',')
+                .map(|ap| ap.trim().to_string())
+                .filter(|ap| !ap.is_empty())
+                .collect()
+        })
+    };
+    let (blacklist, prefer) = (ap_list("ap_blacklist"), ap_list("ap_prefer"));
+    if !blacklist.is_empty() || !prefer.is_empty() {
+        config.ap_policy = Some(ApPolicyConfig { blacklist, prefer });
+    }
+    config.discovery = lookup("discovery").map_or(true, |s| s != "false");
+    config.wait_for_network = lookup("wait_for_network").map_or(false, |s| s == "true");
+    config.zeroconf_port = lookup("zeroconf_port")
+        .map(|p| parse("zeroconf_port", &p, "a port"))
+        .unwrap_or(0);
+    if let Some(proxy) = lookup("proxy") {
+        match Url::parse(&proxy) {
+            Ok(ref url)
+                if ["http", "socks5", "socks5h"].contains(&url.scheme())
+                    && url.host_str().is_some() =>
+            {
+                config.session_config.proxy = Some(url.clone())
+            },
+            // Not the value itself, which may contain a password.
+            _ => invalid_setting("proxy", "not an http:// or socks5:// address"),
+        }
+    }
 
-    config.pid = lookup("pid");
+    if let Some(format) = lookup("log-format") {
+        config.log_format =
+            LogFormat::from_str(&format).unwrap_or_else(|_| invalid_setting("log-format", &format));
+    }
+    config.log_filter = lookup("log_filter").map(|filter| {
+        Filter::from_str(&filter).unwrap_or_else(|_| invalid_setting("log_filter", &filter))
+    });
+    if let Some(verbosity) = lookup("verbosity") {
+        if config.log_filter.is_some() {
+            warn!("verbosity is ignored, log_filter is set.");
+        } else {
+            match verbosity.parse() {
+                Ok(level) if level <= MAX_VERBOSITY => {
+                    config.log_filter = Some(Filter::verbosity(level))
+                },
+                _ => invalid_setting("verbosity", &verbosity),
+            }
+        }
+    }
+    // Detaching changes the working directory, which the paths mustn't
+    // depend on.
+    config.log_file = lookup("log_file").map(|path| LogFile {
+        path: env::current_dir().unwrap_or_default().join(path),
+        max_size: match lookup("log_file_max_size") {
+            Some(ref size) if size == "0" => None,
+            Some(size) => {
+                Some(parse::<u64>("log_file_max_size", &size, "a size in MB") * 1024 * 1024)
+            },
+            None => Some(DEFAULT_LOG_FILE_SIZE),
+        },
+        period: lookup("log_file_rotate").map(|period| {
+            RotationPeriod::from_str(&period)
+                .unwrap_or_else(|_| invalid_setting("log_file_rotate", &period))
+        }),
+        keep: lookup("log_file_keep").map_or(5, |n| parse("log_file_keep", &n, "an integer")),
+    });
+    // Unlike the log, the history is only ever rotated when asked to.
+    config.play_history = lookup("play_history").map(|path| PlayHistoryConfig {
+        file: LogFile {
+            path: env::current_dir().unwrap_or_default().join(path),
+            max_size: lookup("play_history_max_size").map(|size| {
+                parse::<u64>("play_history_max_size", &size, "a size in MB") * 1024 * 1024
+            }),
+            period: lookup("play_history_rotate").map(|period| {
+                RotationPeriod::from_str(&period)
+                    .unwrap_or_else(|_| invalid_setting("play_history_rotate", &period))
+            }),
+            keep: lookup("play_history_keep")
+                .map_or(5, |n| parse("play_history_keep", &n, "an integer")),
+        },
+        format: lookup("play_history_format").map_or(HistoryFormat::Csv, |format| {
+            HistoryFormat::from_str(&format)
+                .unwrap_or_else(|_| invalid_setting("play_history_format", &format))
+        }),
+    });
+    config.user = lookup("user");
+    config.group = lookup("group");
+    config.sandbox =
+        matches.opt_present("sandbox") || lookup("sandbox").map_or(false, |s| s == "true");
+    config.pid = lookup("pid")
+        .or_else(|| lookup("pid-file"))
+        .map(|p| env::current_dir().unwrap_or_default().join(p));
+    config.simulate = matches.opt_present("simulate");
     config
 }
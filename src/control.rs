@@ -0,0 +1,447 @@
+use crate::{
+    cli, config,
+    data_usage::SharedDataUsage,
+    device_state::{DeviceState, SharedDeviceState},
+    errors::{self, ErrorCode},
+    recently_played::{PlayedTrack, SharedRecentlyPlayed},
+    socket_activation,
+    track_metadata::TrackMetadata,
+    web_api,
+};
+use futures::{
+    sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    task, Async, Stream,
+};
+use getopts::Options;
+use librespot::{
+    connect::spirc::Spirc,
+    playback::mixer::{softmixer::SoftMixer, AudioFilter, Mixer},
+};
+use log::{error, info, warn};
+use rspotify::spotify::oauth2::TokenInfo as RspotifyToken;
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    process::exit,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// The commands that control playback, as subcommands of spotifyd.
+pub const COMMANDS: &[&str] = &["play", "pause", "next", "previous", "seek", "volume"];
+
+/// Playback commands received on the control socket.
+#[derive(Clone, Copy)]
+enum Command {
+    Play,
+    Pause,
+    Next,
+    Previous,
+    /// To this many milliseconds into the track.
+    Seek(u32),
+    /// To this many percent.
+    Volume(u8),
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let argument = words.next();
+        let number = |max: f64| {
+            argument
+                .and_then(|a| a.parse::<f64>().ok())
+                .filter(|&n| n >= 0.0 && n <= max)
+                .ok_or_else(|| format!("{} needs a number up to {}", command, max))
+        };
+        match command {
+            "play" => Ok(Command::Play),
+            "pause" => Ok(Command::Pause),
+            "next" => Ok(Command::Next),
+            "previous" => Ok(Command::Previous),
+            "seek" => Ok(Command::Seek((number(86_400.0)? * 1000.0) as u32)),
+            "volume" => Ok(Command::Volume(number(100.0)?.round() as u8)),
+            _ => Err(format!("Unknown command {}", command)),
+        }
+    }
+}
+
+/// What spotifyd is playing, as the control socket reports it.
+struct Status {
+    device_name: String,
+    /// The volume slider, in percent.
+    volume: u8,
+    track: Option<TrackMetadata>,
+    /// Why spotifyd isn't logged in, if it gave up.
+    error: Option<ErrorCode>,
+}
+
+/// The status, shared between the main loop, the mixer and the control
+/// socket.
+#[derive(Clone)]
+pub struct SharedStatus {
+    status: Arc<Mutex<Status>>,
+    state: SharedDeviceState,
+    recently_played: Option<SharedRecentlyPlayed>,
+    data_usage: Option<SharedDataUsage>,
+}
+
+impl SharedStatus {
+    pub fn new(
+        device_name: String,
+        state: SharedDeviceState,
+        recently_played: Option<SharedRecentlyPlayed>,
+        data_usage: Option<SharedDataUsage>,
+    ) -> SharedStatus {
+        SharedStatus {
+            status: Arc::new(Mutex::new(Status {
+                device_name,
+                volume: 0,
+                track: None,
+                error: None,
+            })),
+            state,
+            recently_played,
+            data_usage,
+        }
+    }
+
+    pub fn set_device_name(&self, name: &str) {
+        self.status.lock().unwrap().device_name = name.to_string();
+    }
+
+    /// Records why logging in failed, or that it succeeded with `None`.
+    pub fn set_error(&self, error: Option<ErrorCode>) {
+        self.status.lock().unwrap().error = error;
+    }
+
+    /// Wraps the outermost mixer, which sees the volume slider as Connect
+    /// sets it.
+    pub fn mixer(&self, mixer: Box<Mixer>) -> Box<Mixer> {
+        Box::new(StatusMixer {
+            inner: mixer,
+            status: Some(self.clone()),
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        let status = self.status.lock().unwrap();
+        let state = self.state.get();
+        let playback = match (state, &status.track) {
+            (DeviceState::Active, _) => "playing",
+            (DeviceState::Registered, &Some(_)) => "paused",
+            _ => "stopped",
+        };
+        json!({
+            "device_name": status.device_name,
+            "state": state.to_string(),
+            "playback": playback,
+            "volume": status.volume,
+            "track": status.track.as_ref().map(|track| json!({
+                "id": format!("spotify:track:{}", track.track_id.to_base62()),
+                "title": track.title,
+                "artists": track.artists,
+                "album": track.album,
+                "duration_ms": track.duration_ms,
+            })),
+            "recently_played": self.recently_played.as_ref().map(|recent| {
+                recent.tracks().iter().map(PlayedTrack::to_json).collect::<Vec<_>>()
+            }),
+            "data_usage": self.data_usage.as_ref().map(SharedDataUsage::to_json),
+            "error": status.error.map(|code| errors::message(code, &[])),
+        })
+    }
+}
+
+/// A Unix socket local scripts control spotifyd through, one command per
+/// line, each answered with a line of JSON. `status` tells what is playing,
+/// and the others control playback. Spirc can't seek or set the volume, so
+/// those go through the Web API.
+pub struct Control {
+    status: SharedStatus,
+    commands: UnboundedReceiver<Command>,
+    /// Commands waiting for a Web API token.
+    pending: Vec<Command>,
+}
+
+impl Control {
+    pub fn new(socket: PathBuf, status: SharedStatus) -> Control {
+        let (sender, commands) = unbounded();
+        let listener = match socket_activation::unix_listener("control") {
+            Some(listener) => Ok(listener),
+            None => {
+                let _ = fs::remove_file(&socket);
+                UnixListener::bind(&socket)
+            },
+        };
+        match listener {
+            Ok(listener) => {
+                info!("Listening for control commands on {:?}", socket);
+                let status = status.clone();
+                thread::spawn(move || accept(&listener, &status, &sender));
+            },
+            Err(e) => error!(
+                "Couldn't listen for control commands on {:?}: {}",
+                socket, e
+            ),
+        }
+        Control {
+            status,
+            commands,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Whether a Web API token is needed to carry out a command.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    pub fn poll(&mut self, spirc: Option<&Spirc>, token: Option<RspotifyToken>) {
+        while let Ok(Async::Ready(Some(command))) = self.commands.poll() {
+            let spirc = match spirc {
+                Some(spirc) => spirc,
+                None => continue,
+            };
+            match command {
+                Command::Play => spirc.play(),
+                Command::Pause => spirc.pause(),
+                Command::Next => spirc.next(),
+                Command::Previous => spirc.prev(),
+                Command::Seek(_) | Command::Volume(_) => {
+                    // The main loop asks for a token on its next turn.
+                    if self.pending.is_empty() {
+                        task::current().notify();
+                    }
+                    self.pending.push(command);
+                },
+            }
+        }
+        let token = match token {
+            Some(ref token) if !self.pending.is_empty() => token.clone(),
+            _ => return,
+        };
+        let commands: Vec<Command> = self.pending.drain(..).collect();
+        let device_name = self.status.status.lock().unwrap().device_name.clone();
+        thread::spawn(move || {
+            let client = web_api::client(&token);
+            let device_id = match web_api::find_device(&client, &device_name) {
+                Some(id) => id,
+                None => return,
+            };
+            for command in commands {
+                let done = match command {
+                    Command::Seek(position_ms) => {
+                        client.seek_track(position_ms, Some(device_id.clone()))
+                    },
+                    Command::Volume(volume) => client.volume(volume, Some(device_id.clone())),
+                    _ => Ok(()),
+                };
+                if let Err(e) = done {
+                    warn!("Couldn't carry out a control command: {}", e);
+                }
+            }
+        });
+    }
+
+    pub fn handle_metadata(&self, metadata: &TrackMetadata) {
+        self.status.status.lock().unwrap().track = Some(metadata.clone());
+    }
+}
+
+fn accept(listener: &UnixListener, status: &SharedStatus, sender: &UnboundedSender<Command>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let status = status.clone();
+                let sender = sender.clone();
+                thread::spawn(move || serve(&stream, &status, &sender));
+            },
+            Err(e) => error!("Couldn't accept control connection: {}", e),
+        }
+    }
+}
+
+fn serve(stream: &UnixStream, status: &SharedStatus, sender: &UnboundedSender<Command>) {
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let response = match line.trim() {
+            "status" => status.to_json(),
+            "" => continue,
+            line => match Command::parse(line) {
+                Ok(command) => {
+                    let _ = sender.unbounded_send(command);
+                    json!({ "ok": true })
+                },
+                Err(e) => json!({ "error": e }),
+            },
+        };
+        if writeln!(&*stream, "{}", response).is_err() {
+            break;
+        }
+    }
+}
+
+/// Sends a command to the running spotifyd, and returns its answer.
+fn request(socket: &Path, command: &str) -> Result<Value, String> {
+    let stream = UnixStream::connect(socket)
+        .map_err(|e| format!("Couldn't connect to spotifyd at {:?}: {}", socket, e))?;
+    writeln!(&stream, "{}", command).map_err(|e| e.to_string())?;
+    let mut response = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut response)
+        .map_err(|e| e.to_string())?;
+    let response: Value = serde_json::from_str(&response).map_err(|e| e.to_string())?;
+    match response["error"].as_str() {
+        Some(error) => Err(error.to_string()),
+        None => Ok(response),
+    }
+}
+
+/// Entry point of `spotifyd status`: prints what the running spotifyd plays,
+/// as text or, with `--json`, as it comes from the control socket.
+pub fn run_status(args: &[String]) -> ! {
+    let mut opts = Options::new();
+    opts.optopt("c", "config", "Path to the config file.", "CONFIG");
+    opts.optopt("", "socket", "Path to the control socket.", "PATH");
+    opts.optflag("", "json", "Print the status as JSON.");
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(f) => usage(&opts, "status", &f.to_string()),
+    };
+    let socket = socket(&matches).unwrap_or_else(|| {
+        usage(
+            &opts,
+            "status",
+            "No control socket, set control_socket or give --socket.",
+        )
+    });
+    let status = match request(&socket, "status") {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            exit(1)
+        },
+    };
+    if matches.opt_present("json") {
+        println!("{}", status);
+        exit(0)
+    }
+    let text = |value: &Value| value.as_str().unwrap_or("").to_string();
+    println!("Device:   {}", text(&status["device_name"]));
+    println!("State:    {}", text(&status["playback"]));
+    println!("Volume:   {}%", status["volume"]);
+    let track = &status["track"];
+    if !track.is_null() {
+        let artists: Vec<String> = track["artists"]
+            .as_array()
+            .map_or_else(Vec::new, |a| a.iter().map(text).collect());
+        println!("Track:    {}", text(&track["title"]));
+        println!("Artist:   {}", artists.join(", "));
+        println!("Album:    {}", text(&track["album"]));
+    }
+    if !status["error"].is_null() {
+        println!("Error:    {}", text(&status["error"]));
+    }
+    exit(0)
+}
+
+/// Entry point of `spotifyd play`, `pause`, `next`, `previous`, `seek SECS`
+/// and `volume PERCENT`: passes the command on to the running spotifyd.
+pub fn run_command(args: &[String]) -> ! {
+    let mut opts = Options::new();
+    opts.optopt("c", "config", "Path to the config file.", "CONFIG");
+    opts.optopt("", "socket", "Path to the control socket.", "PATH");
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => usage(&opts, &args[0], &f.to_string()),
+    };
+    let command = std::iter::once(args[0].as_str())
+        .chain(matches.free.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if let Err(e) = Command::parse(&command) {
+        usage(&opts, &args[0], &e);
+    }
+    let socket = socket(&matches).unwrap_or_else(|| {
+        usage(
+            &opts,
+            &args[0],
+            "No control socket, set control_socket or give --socket.",
+        )
+    });
+    if let Err(e) = request(&socket, &command) {
+        eprintln!("Error: {}", e);
+        exit(1)
+    }
+    exit(0)
+}
+
+/// The control socket given on the command line or in the config file.
+fn socket(matches: &getopts::Matches) -> Option<PathBuf> {
+    if let Some(socket) = matches.opt_str("socket") {
+        return Some(PathBuf::from(socket));
+    }
+    let config_path = matches
+        .opt_str("config")
+        .map(PathBuf::from)
+        .or_else(|| config::get_config_file().ok());
+    config::set_dry_run();
+    let no_options = cli::command_line_argument_options()
+        .parse(Vec::<String>::new())
+        .unwrap();
+    config::get_config(config_path.as_ref(), &no_options).control_socket
+}
+
+fn usage(opts: &Options, command: &str, error: &str) -> ! {
+    eprintln!(
+        "Error: {}\n{}",
+        error,
+        opts.usage(&format!("Usage: spotifyd {} [options]", command))
+    );
+    exit(1)
+}
+
+struct StatusMixer {
+    inner: Box<Mixer>,
+    status: Option<SharedStatus>,
+}
+
+impl Mixer for StatusMixer {
+    fn open() -> StatusMixer {
+        StatusMixer {
+            inner: Box::new(SoftMixer::open()),
+            status: None,
+        }
+    }
+
+    fn start(&self) {
+        self.inner.start()
+    }
+
+    fn stop(&self) {
+        self.inner.stop()
+    }
+
+    fn volume(&self) -> u16 {
+        self.inner.volume()
+    }
+
+    fn set_volume(&self, volume: u16) {
+        self.inner.set_volume(volume);
+        if let Some(ref status) = self.status {
+            let percent = (u32::from(volume) * 100 + 0x7fff) / u32::from(u16::max_value());
+            status.status.lock().unwrap().volume = percent as u8;
+        }
+    }
+
+    fn get_audio_filter(&self) -> Option<Box<AudioFilter + Send>> {
+        self.inner.get_audio_filter()
+    }
+}
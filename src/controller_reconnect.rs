@@ -0,0 +1,57 @@
+use crate::web_api;
+use librespot::playback::player::PlayerEvent;
+use log::warn;
+use rspotify::spotify::oauth2::TokenInfo as RspotifyToken;
+use std::{fs, path::PathBuf};
+
+/// Makes this device the active one again after a restart if it was playing
+/// when spotifyd went down, so controllers show it right away instead of
+/// after a manual refresh.
+///
+/// Whether the device was playing is kept in a marker file, which exists
+/// from playback start until it stops.
+pub struct ControllerReconnect {
+    marker: PathBuf,
+    device_name: String,
+    pending: bool,
+}
+
+impl ControllerReconnect {
+    pub fn new(marker: PathBuf, device_name: String) -> ControllerReconnect {
+        let pending = marker.exists();
+        ControllerReconnect {
+            marker,
+            device_name,
+            pending,
+        }
+    }
+
+    /// Whether a Web API token is needed to reconnect.
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    pub fn handle_event(&mut self, event: &PlayerEvent) {
+        // Once something plays, there is nothing to restore anymore.
+        self.pending = false;
+        match *event {
+            PlayerEvent::Started { .. } | PlayerEvent::Changed { .. } => {
+                if let Err(e) = fs::write(&self.marker, "") {
+                    warn!("Couldn't write {:?}: {}", self.marker, e);
+                }
+            },
+            PlayerEvent::Stopped { .. } => {
+                let _ = fs::remove_file(&self.marker);
+            },
+        }
+    }
+
+    pub fn poll(&mut self, token: Option<RspotifyToken>) {
+        let token = match token {
+            Some(ref token) if self.pending => token.clone(),
+            _ => return,
+        };
+        self.pending = false;
+        web_api::transfer_playback(token, self.device_name.clone(), false);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::{art, track_metadata::TrackMetadata};
+use image::{self, FilterType};
+use librespot::core::spotify_id::SpotifyId;
+use log::warn;
+use std::{
+    cell::Cell,
+    error::Error,
+    fs,
+    path::PathBuf,
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
+
+pub struct CoverArtFileConfig {
+    pub path: PathBuf,
+    /// The most pixels it may be wide and high, if it is scaled.
+    pub size: Option<u32>,
+}
+
+/// Keeps the cover of the track playing in a file, for e-ink displays, conky
+/// and the like to show. The file is replaced in one go, so readers never
+/// see half of a picture, and removed for tracks without a cover.
+pub struct CoverArtFile {
+    sender: Sender<TrackMetadata>,
+    /// The track whose cover is in the file, so resuming it doesn't load it
+    /// again.
+    track_id: Cell<Option<SpotifyId>>,
+}
+
+impl CoverArtFile {
+    pub fn new(config: CoverArtFileConfig) -> CoverArtFile {
+        let (sender, receiver) = channel();
+        thread::spawn(move || run(&config, &receiver));
+        CoverArtFile {
+            sender,
+            track_id: Cell::new(None),
+        }
+    }
+
+    pub fn handle_metadata(&self, metadata: &TrackMetadata) {
+        if self.track_id.replace(Some(metadata.track_id)) == Some(metadata.track_id) {
+            return;
+        }
+        let _ = self.sender.send(metadata.clone());
+    }
+}
+
+fn run(config: &CoverArtFileConfig, receiver: &Receiver<TrackMetadata>) {
+    for metadata in receiver.iter() {
+        let url = match metadata.cover_url {
+            Some(ref url) => url,
+            None => {
+                let _ = fs::remove_file(&config.path);
+                continue;
+            },
+        };
+        if let Err(e) = write(config, url) {
+            warn!(
+                "Couldn't write the cover from {} to {:?}: {}",
+                url, config.path, e
+            );
+        }
+    }
+}
+
+fn write(config: &CoverArtFileConfig, url: &str) -> Result<(), Box<Error>> {
+    let picture = art::load(url)?;
+    // A hidden file next to it keeps its extension, which tells the format
+    // to save a scaled picture in.
+    let file_name = config.path.file_name().ok_or("No file name")?;
+    let temporary = config
+        .path
+        .with_file_name(format!(".{}", file_name.to_string_lossy()));
+    match config.size {
+        Some(size) => image::load_from_memory(&picture)?
+            .resize(size, size, FilterType::Triangle)
+            .save(&temporary)?,
+        None => fs::write(&temporary, picture)?,
+    }
+    fs::rename(&temporary, &config.path)?;
+    Ok(())
+}
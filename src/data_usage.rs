@@ -0,0 +1,117 @@
+use chrono::Local;
+use log::{error, info};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How often the day's count is written to the cache while data flows.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+struct DataUsage {
+    /// Bytes received since the current session connected.
+    session: u64,
+    /// Bytes received on `day`, as `YYYY-MM-DD` in local time.
+    today: u64,
+    day: String,
+    /// Bytes received since spotifyd started.
+    total: u64,
+    file: Option<PathBuf>,
+    saved: Instant,
+}
+
+impl DataUsage {
+    /// Starts counting a new day once the date changed.
+    fn roll_over(&mut self) {
+        let day = today();
+        if day != self.day {
+            info!(
+                "Received {} MB from Spotify on {}",
+                self.today >> 20,
+                self.day
+            );
+            self.day = day;
+            self.today = 0;
+        }
+    }
+
+    fn save(&mut self) {
+        self.saved = Instant::now();
+        let file = match self.file {
+            Some(ref file) => file,
+            None => return,
+        };
+        let value = json!({ "day": self.day, "bytes": self.today });
+        if let Err(e) = fs::write(file, value.to_string()) {
+            error!("Couldn't save the data usage to {:?}: {}", file, e);
+        }
+    }
+}
+
+fn today() -> String {
+    Local::now().format("%Y-%m-%d").to_string()
+}
+
+/// Counts the bytes librespot receives from Spotify, which the proxy relay
+/// passes on, by session and by day. The day's count is kept in the cache,
+/// so it survives restarts.
+#[derive(Clone)]
+pub struct SharedDataUsage(Arc<Mutex<DataUsage>>);
+
+impl SharedDataUsage {
+    pub fn new(file: Option<PathBuf>) -> SharedDataUsage {
+        let day = today();
+        let today = file
+            .as_ref()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .and_then(|s| serde_json::from_str::<Value>(&s).ok())
+            .filter(|value| value["day"].as_str() == Some(&*day))
+            .and_then(|value| value["bytes"].as_u64())
+            .unwrap_or(0);
+        SharedDataUsage(Arc::new(Mutex::new(DataUsage {
+            session: 0,
+            today,
+            day,
+            total: 0,
+            file,
+            saved: Instant::now(),
+        })))
+    }
+
+    /// Counts `bytes` more, from any thread.
+    pub fn received(&self, bytes: u64) {
+        let mut usage = self.0.lock().unwrap();
+        usage.roll_over();
+        usage.session += bytes;
+        usage.today += bytes;
+        usage.total += bytes;
+        if usage.saved.elapsed() > SAVE_INTERVAL {
+            usage.save();
+        }
+    }
+
+    /// Starts counting for a session that just connected.
+    pub fn new_session(&self) {
+        self.0.lock().unwrap().session = 0;
+    }
+
+    /// Bytes received this session, today and since spotifyd started.
+    pub fn counts(&self) -> (u64, u64, u64) {
+        let mut usage = self.0.lock().unwrap();
+        usage.roll_over();
+        (usage.session, usage.today, usage.total)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let (session, today, total) = self.counts();
+        json!({ "session": session, "today": today, "total": total })
+    }
+
+    /// Keeps the day's count for the next start.
+    pub fn save(&self) {
+        self.0.lock().unwrap().save();
+    }
+}
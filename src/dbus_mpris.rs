@@ -1,70 +1,110 @@
-use chrono::prelude::*;
+use crate::{
+    announcement::Announcer,
+    balance::SharedBalance,
+    device_state::{DeviceState, SharedDeviceState},
+    equalizer::SharedEqualizer,
+    recently_played::{PlayedTrack, SharedRecentlyPlayed},
+    save_points::SharedSavePoints,
+    sleep_timer::SharedSleepTimer,
+    web_api::{self, TokenProvider},
+};
 use dbus::{
     arg::{RefArg, Variant},
     tree::{Access, MethodErr},
-    BusType, Connection, MessageItem, NameFlag,
+    BusType, Connection, Message, MessageItem, NameFlag, Path,
 };
 use dbus_tokio::{
     tree::{AFactory, ATree, ATreeServer},
     AConnection,
 };
-use futures::{sync::oneshot, Async, Future, Poll, Stream};
-use librespot::{
-    connect::spirc::Spirc,
-    core::{
-        keymaster::{get_token, Token as LibrespotToken},
-        mercury::MercuryError,
-        session::Session,
+use futures::{
+    future,
+    sync::{
+        mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+        oneshot,
     },
+    Async, Future, Poll, Stream,
 };
+use librespot::{connect::spirc::Spirc, core::session::Session};
 use log::{info, warn};
-use rspotify::spotify::{
-    client::Spotify, oauth2::TokenInfo as RspotifyToken, senum::*, util::datetime_to_timestamp,
+use reqwest::header::AUTHORIZATION;
+use rspotify::spotify::{client::Spotify, oauth2::TokenInfo as RspotifyToken, senum::*};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    rc::Rc,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
 };
-use std::{collections::HashMap, rc::Rc, thread};
 use tokio_core::reactor::Handle;
 
 pub struct DbusServer {
-    session: Session,
     handle: Handle,
     spirc: Rc<Spirc>,
-    api_token: RspotifyToken,
-    token_request: Option<Box<Future<Item = LibrespotToken, Error = MercuryError>>>,
+    token_provider: TokenProvider,
+    api_token: Option<RspotifyToken>,
     dbus_future: Option<Box<Future<Item = (), Error = ()>>>,
+    connection: Option<Rc<Connection>>,
+    /// Names of devices taking playback over, for the TakenOver signal.
+    takeovers: UnboundedReceiver<String>,
+    /// The URIs of the tracks that start, to fetch their metadata.
+    track_changes: UnboundedReceiver<String>,
+    queue: SharedTrackQueue,
+    metadata: SharedMetadataCache,
+    /// Told when the metadata is of another track, for PropertiesChanged.
+    metadata_changed: UnboundedSender<()>,
+    metadata_changes: UnboundedReceiver<()>,
     device_name: String,
+    save_points: Option<SharedSavePoints>,
+    state: SharedDeviceState,
+    equalizer: Option<SharedEqualizer>,
+    balance: SharedBalance,
+    sleep_timer: SharedSleepTimer,
+    recently_played: Option<SharedRecentlyPlayed>,
+    announcer: Announcer,
 }
 
-const CLIENT_ID: &str = "2c1ea588dfbc4a989e2426f8385297c3";
-const SCOPE: &str = "user-read-playback-state,user-read-private,user-read-birthdate,\
-                     user-read-email,playlist-read-private,user-library-read,user-library-modify,\
-                     user-top-read,playlist-read-collaborative,playlist-modify-public,\
-                     playlist-modify-private,user-follow-read,user-follow-modify,\
-                     user-read-currently-playing,user-modify-playback-state,\
-                     user-read-recently-played";
-
 impl DbusServer {
     pub fn new(
         session: Session,
         handle: Handle,
         spirc: Rc<Spirc>,
         device_name: String,
+        save_points: Option<SharedSavePoints>,
+        state: SharedDeviceState,
+        equalizer: Option<SharedEqualizer>,
+        balance: SharedBalance,
+        sleep_timer: SharedSleepTimer,
+        recently_played: Option<SharedRecentlyPlayed>,
+        announcer: Announcer,
+        takeovers: UnboundedReceiver<String>,
+        track_changes: UnboundedReceiver<String>,
     ) -> DbusServer {
+        let (metadata_changed, metadata_changes) = unbounded();
         DbusServer {
-            session,
             handle,
             spirc,
-            api_token: RspotifyToken::default(),
-            token_request: None,
+            token_provider: TokenProvider::new(session),
+            api_token: None,
             dbus_future: None,
+            connection: None,
+            takeovers,
+            track_changes,
+            queue: SharedTrackQueue::default(),
+            metadata: SharedMetadataCache::default(),
+            metadata_changed,
+            metadata_changes,
             device_name,
-        }
-    }
-
-    fn is_token_expired(&self) -> bool {
-        let now: DateTime<Utc> = Utc::now();
-        match self.api_token.expires_at {
-            Some(expires_at) => now.timestamp() > expires_at - 100,
-            None => true,
+            save_points,
+            state,
+            equalizer,
+            balance,
+            sleep_timer,
+            recently_played,
+            announcer,
         }
     }
 }
@@ -74,39 +114,580 @@ impl Future for DbusServer {
     type Item = ();
 
     fn poll(&mut self) -> Poll<(), ()> {
-        let mut got_new_token = false;
-        if self.is_token_expired() {
-            if let Some(ref mut fut) = self.token_request {
-                if let Async::Ready(token) = fut.poll().unwrap() {
-                    self.api_token = RspotifyToken::default()
-                        .access_token(&token.access_token)
-                        .expires_in(token.expires_in)
-                        .expires_at(datetime_to_timestamp(token.expires_in));
-                    self.dbus_future = Some(create_dbus_server(
-                        self.handle.clone(),
-                        self.api_token.clone(),
-                        self.spirc.clone(),
-                        self.device_name.clone(),
-                    ));
-                    got_new_token = true;
-                }
-            } else {
-                self.token_request = Some(get_token(&self.session, CLIENT_ID, SCOPE));
+        while let Ok(Async::Ready(Some(device))) = self.takeovers.poll() {
+            if let Some(ref c) = self.connection {
+                let signal =
+                    Message::new_signal(CONTROLS_PATH, CONTROLS_INTERFACE, "TakenOver").unwrap();
+                let _ = c.send(signal.append1(device));
+            }
+        }
+
+        while let Ok(Async::Ready(Some(track))) = self.track_changes.poll() {
+            if let Some(ref token) = self.api_token {
+                track_started(&self.queue, token, &track);
+                refresh_metadata(&self.metadata, token, &self.metadata_changed, Some(track));
+            }
+        }
+
+        while let Ok(Async::Ready(Some(()))) = self.metadata_changes.poll() {
+            if let Some(ref c) = self.connection {
+                let metadata = self
+                    .metadata
+                    .lock()
+                    .unwrap()
+                    .current
+                    .as_ref()
+                    .map(TrackMetadata::to_map)
+                    .unwrap_or_default();
+                let mut changed = HashMap::new();
+                changed.insert("Metadata".to_string(), Variant(metadata));
+                let signal = Message::new_signal(
+                    "/org/mpris/MediaPlayer2",
+                    "org.freedesktop.DBus.Properties",
+                    "PropertiesChanged",
+                )
+                .unwrap();
+                let _ = c.send(signal.append3(
+                    "org.mpris.MediaPlayer2.Player",
+                    changed,
+                    Vec::<String>::new(),
+                ));
+            }
+        }
+
+        if let Some(token) = self.token_provider.poll() {
+            let renewed = self
+                .api_token
+                .as_ref()
+                .map_or(true, |t| t.access_token != token.access_token);
+            if renewed {
+                let (connection, server) = create_dbus_server(
+                    self.handle.clone(),
+                    token.clone(),
+                    self.spirc.clone(),
+                    self.device_name.clone(),
+                    self.save_points.clone(),
+                    self.state.clone(),
+                    self.equalizer.clone(),
+                    self.balance.clone(),
+                    self.sleep_timer.clone(),
+                    self.recently_played.clone(),
+                    self.announcer.clone(),
+                    self.queue.clone(),
+                    self.metadata.clone(),
+                    self.metadata_changed.clone(),
+                );
+                sync_queue(&self.queue, &token);
+                self.connection = Some(connection);
+                self.dbus_future = Some(server);
+                self.api_token = Some(token);
             }
-        } else if let Some(ref mut fut) = self.dbus_future {
-            return fut.poll();
+        } else {
+            // The server waits for a token, or a new one once it expired.
+            return Ok(Async::NotReady);
         }
 
-        if got_new_token {
-            self.token_request = None;
+        match self.dbus_future {
+            Some(ref mut fut) => fut.poll(),
+            None => Ok(Async::NotReady),
         }
+    }
+}
+
+const CONTROLS_PATH: &str = "/rs/spotifyd/Controls";
+const CONTROLS_INTERFACE: &str = "rs.spotifyd.Controls";
+
+const PLAYER_URL: &str = "https://api.spotify.com/v1/me/player";
+/// The MPRIS track ID that stands for no track, as in `AddTrack`'s
+/// `AfterTrack` to add a track first.
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+/// Actions the current playback context disallows, e.g. skipping during ads.
+#[derive(Default)]
+struct Restrictions {
+    skipping_next: bool,
+    skipping_prev: bool,
+    seeking: bool,
+}
+
+/// Fetches the restrictions of the current context. rspotify doesn't know
+/// about the `actions` object yet, so the player state is requested directly.
+fn restrictions(token: &RspotifyToken) -> Restrictions {
+    let player = reqwest::Client::new()
+        .get(PLAYER_URL)
+        .header(AUTHORIZATION, format!("Bearer {}", token.access_token))
+        .send()
+        .and_then(|mut response| response.json::<Value>());
+    let disallows = match player {
+        Ok(ref player) => &player["actions"]["disallows"],
+        Err(_) => return Restrictions::default(),
+    };
+    let disallowed = |action: &str| disallows[action].as_bool().unwrap_or(false);
+    Restrictions {
+        skipping_next: disallowed("skipping_next"),
+        skipping_prev: disallowed("skipping_prev"),
+        seeking: disallowed("seeking"),
+    }
+}
+
+/// Adds a track to the user's queue. Queued tracks play after the current
+/// one, in the order they were added, and before the rest of the context.
+/// rspotify has no call for this yet.
+fn add_to_queue(token: &RspotifyToken, uri: &str) -> bool {
+    let result = reqwest::Client::new()
+        .post(&format!("{}/queue", PLAYER_URL))
+        .query(&[("uri", uri)])
+        .header(AUTHORIZATION, format!("Bearer {}", token.access_token))
+        .send()
+        .and_then(|response| response.error_for_status());
+    if let Err(ref e) = result {
+        warn!("Couldn't queue {}: {}", uri, e);
+    }
+    result.is_ok()
+}
+
+/// The URIs of the tracks that play next: the queued ones first, then the
+/// rest of the context. rspotify has no call for this yet.
+fn queue(token: &RspotifyToken) -> Option<Vec<String>> {
+    let queue = reqwest::Client::new()
+        .get(&format!("{}/queue", PLAYER_URL))
+        .header(AUTHORIZATION, format!("Bearer {}", token.access_token))
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|mut response| response.json::<Value>());
+    match queue {
+        Ok(queue) => Some(
+            queue["queue"]
+                .as_array()
+                .map(|tracks| {
+                    tracks
+                        .iter()
+                        .filter_map(|t| t["uri"].as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        ),
+        Err(e) => {
+            warn!("Couldn't fetch the queue: {}", e);
+            None
+        },
+    }
+}
+
+/// The tracks queued over D-Bus. Spotify's queue only grows at its end, so
+/// they wait here and are handed to Spotify one at a time, each once the one
+/// before started. Until then they can still be put first or taken out.
+#[derive(Default)]
+struct TrackQueue {
+    /// The tracks waiting to be handed to Spotify, the next first.
+    waiting: VecDeque<String>,
+    /// The track handed to Spotify last, until it starts.
+    handed: Option<String>,
+    /// What plays next as the Web API listed it last.
+    upcoming: Vec<String>,
+}
+
+type SharedTrackQueue = Arc<Mutex<TrackQueue>>;
+
+impl TrackQueue {
+    /// The URIs of the tracks that play next, with the waiting ones after
+    /// the one handed to Spotify, or first if there is none.
+    fn list(&self) -> Vec<String> {
+        let mut tracks = self.upcoming.clone();
+        let at = self
+            .handed
+            .as_ref()
+            .and_then(|handed| tracks.iter().position(|t| t == handed))
+            .map_or(0, |i| i + 1);
+        for (i, uri) in self.waiting.iter().enumerate() {
+            tracks.insert(at + i, uri.clone());
+        }
+        tracks
+    }
+
+    /// Takes the first waiting track with `uri` out, if there is one.
+    fn remove(&mut self, uri: &str) -> bool {
+        match self.waiting.iter().position(|t| t == uri) {
+            Some(i) => {
+                self.waiting.remove(i);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Takes the first waiting track with the MPRIS track ID `path` out, if
+    /// there is one.
+    fn remove_path(&mut self, path: &Path) -> bool {
+        match self
+            .waiting
+            .iter()
+            .position(|t| track_path(t).as_ref() == Some(path))
+        {
+            Some(i) => {
+                self.waiting.remove(i);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Where a track added after the track with the MPRIS track ID `after`
+    /// waits: after that track if it is waiting, first if it is the current
+    /// track, the one handed over or `NoTrack`, and last otherwise.
+    fn position_after(&self, after: &Path, current: Option<&str>) -> usize {
+        let is = |uri: Option<&str>| uri.and_then(track_path).as_ref() == Some(after);
+        if let Some(i) = self.waiting.iter().position(|t| is(Some(t.as_str()))) {
+            i + 1
+        } else if &**after == NO_TRACK || is(current) || is(self.handed.as_ref().map(|h| &**h)) {
+            0
+        } else {
+            self.waiting.len()
+        }
+    }
+}
+
+/// Queues a track, to play next or after the tracks waiting already.
+fn enqueue(queue: &SharedTrackQueue, token: &RspotifyToken, uri: String, next: bool) {
+    let at = if next {
+        0
+    } else {
+        queue.lock().unwrap().waiting.len()
+    };
+    enqueue_at(queue, token, uri, at);
+}
+
+/// Queues a track at `at` among those waiting.
+fn enqueue_at(queue: &SharedTrackQueue, token: &RspotifyToken, uri: String, at: usize) {
+    {
+        let mut queue = queue.lock().unwrap();
+        let at = at.min(queue.waiting.len());
+        queue.waiting.insert(at, uri);
+    }
+    sync_queue(queue, token);
+}
+
+/// Tells the queue that the track with `uri` started, so the next waiting
+/// track can be handed over once the one handed last plays.
+fn track_started(queue: &SharedTrackQueue, token: &RspotifyToken, uri: &str) {
+    {
+        let mut queue = queue.lock().unwrap();
+        if queue.handed.as_ref().map_or(false, |handed| handed == uri) {
+            queue.handed = None;
+        }
+    }
+    sync_queue(queue, token);
+}
+
+/// Hands the next waiting track to Spotify if none is handed over yet, and
+/// lists what plays next again, on a thread of its own.
+fn sync_queue(queue: &SharedTrackQueue, token: &RspotifyToken) {
+    let hand = {
+        let mut queue = queue.lock().unwrap();
+        if queue.handed.is_none() {
+            queue.handed = queue.waiting.pop_front();
+            queue.handed.clone()
+        } else {
+            None
+        }
+    };
+    let queue = queue.clone();
+    let token = token.clone();
+    thread::spawn(move || {
+        if let Some(uri) = hand {
+            if !add_to_queue(&token, &uri) {
+                let mut queue = queue.lock().unwrap();
+                queue.handed = None;
+                queue.waiting.push_front(uri);
+            }
+        }
+        if let Some(upcoming) = self::queue(&token) {
+            queue.lock().unwrap().upcoming = upcoming;
+        }
+    });
+}
+
+/// The MPRIS track ID of a track. Local files have no ID that would make for
+/// a valid path.
+fn track_path(uri: &str) -> Option<Path<'static>> {
+    let id = uri.rsplit(':').next()?;
+    if id.is_empty() || !id.chars().all(|c: char| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    Path::new(format!("/rs/spotifyd/Track/{}", id)).ok()
+}
+
+/// What `SearchAndPlay` searches for.
+#[derive(Clone, Copy)]
+enum SearchType {
+    Track,
+    Album,
+    Artist,
+    Playlist,
+}
+
+impl FromStr for SearchType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "track" => Ok(SearchType::Track),
+            "album" => Ok(SearchType::Album),
+            "artist" => Ok(SearchType::Artist),
+            "playlist" => Ok(SearchType::Playlist),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Searches the catalogue and plays the top result on this device: a track
+/// on its own, or an album, artist or playlist from its start. Fails rather
+/// than play on another device when this one isn't listed.
+fn search_and_play(
+    token: &RspotifyToken,
+    device_name: &str,
+    query: &str,
+    kind: SearchType,
+) -> Result<(), String> {
+    let sp = web_api::client(token);
+    let result = match kind {
+        SearchType::Track => sp
+            .search_track(query, 1, 0, None)
+            .map(|r| r.tracks.items.into_iter().next().map(|t| t.uri)),
+        SearchType::Album => sp
+            .search_album(query, 1, 0, None)
+            .map(|r| r.albums.items.into_iter().next().map(|a| a.uri)),
+        SearchType::Artist => sp
+            .search_artist(query, 1, 0, None)
+            .map(|r| r.artists.items.into_iter().next().map(|a| a.uri)),
+        SearchType::Playlist => sp
+            .search_playlist(query, 1, 0, None)
+            .map(|r| r.playlists.items.into_iter().next().map(|p| p.uri)),
+    };
+    let uri = match result {
+        Ok(Some(uri)) => uri,
+        Ok(None) => {
+            info!("Nothing found for {:?}", query);
+            return Err(format!("Nothing found for {:?}", query));
+        },
+        Err(e) => {
+            warn!("Couldn't search for {:?}: {}", query, e);
+            return Err(format!("Couldn't search for {:?}: {}", query, e));
+        },
+    };
+    let device = web_api::find_device(&sp, device_name)
+        .ok_or_else(|| format!("{} isn't listed as a Connect device", device_name))?;
+    info!("Playing {} for {:?}", uri, query);
+    let result = match kind {
+        SearchType::Track => sp.start_playback(Some(device), None, Some(vec![uri]), None),
+        _ => sp.start_playback(Some(device), Some(uri), None, None),
+    };
+    result.map_err(|e| {
+        warn!("Couldn't play the result for {:?}: {}", query, e);
+        format!("Couldn't play the result for {:?}: {}", query, e)
+    })
+}
+
+fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<RefArg>> {
+    Variant(Box::new(value))
+}
 
-        Ok(Async::NotReady)
+fn strings(values: Vec<String>) -> Vec<Box<RefArg>> {
+    values
+        .into_iter()
+        .map(|v| Box::new(MessageItem::Str(v)) as Box<RefArg>)
+        .collect()
+}
+
+/// The metadata of a track as fetched from the Web API, kept apart from its
+/// D-Bus form since that can't be sent between threads.
+#[derive(Clone, Default)]
+struct TrackMetadata {
+    uri: String,
+    url: Option<String>,
+    art_url: Option<String>,
+    length_ms: u32,
+    title: String,
+    album: String,
+    artists: Vec<String>,
+    album_artists: Vec<String>,
+    genres: Vec<String>,
+    track_number: u32,
+    disc_number: i32,
+    saved: bool,
+    context_uri: Option<String>,
+    context: Option<String>,
+}
+
+impl TrackMetadata {
+    /// The metadata as the MPRIS `Metadata` property has it.
+    fn to_map(&self) -> HashMap<String, Variant<Box<RefArg>>> {
+        let mut m = HashMap::new();
+        if let Some(path) = track_path(&self.uri) {
+            m.insert("mpris:trackid".to_string(), variant(path));
+        }
+        m.insert(
+            "mpris:length".to_string(),
+            variant(i64::from(self.length_ms) * 1000),
+        );
+        if let Some(ref url) = self.art_url {
+            m.insert("mpris:artUrl".to_string(), variant(url.clone()));
+        }
+        if let Some(ref url) = self.url {
+            m.insert("xesam:url".to_string(), variant(url.clone()));
+        }
+        m.insert("xesam:title".to_string(), variant(self.title.clone()));
+        m.insert("xesam:album".to_string(), variant(self.album.clone()));
+        m.insert(
+            "xesam:artist".to_string(),
+            variant(strings(self.artists.clone())),
+        );
+        // Not in the spec, but what Spotifyd has always set, and clients read.
+        m.insert(
+            "xesam:artists".to_string(),
+            variant(strings(self.artists.clone())),
+        );
+        m.insert(
+            "xesam:albumArtist".to_string(),
+            variant(strings(self.album_artists.clone())),
+        );
+        m.insert(
+            "xesam:genre".to_string(),
+            variant(strings(self.genres.clone())),
+        );
+        m.insert(
+            "xesam:trackNumber".to_string(),
+            variant(self.track_number as i32),
+        );
+        m.insert("xesam:discNumber".to_string(), variant(self.disc_number));
+        m.insert("rs.spotifyd:saved".to_string(), variant(self.saved));
+        if let Some(ref context) = self.context {
+            m.insert("rs.spotifyd:context".to_string(), variant(context.clone()));
+        }
+        m
     }
 }
 
-fn create_spotify_api(token: &RspotifyToken) -> Spotify {
-    Spotify::default().access_token(&token.access_token).build()
+/// How often metadata is fetched for a track that just started, since the
+/// Web API takes a moment to know about it.
+const METADATA_ATTEMPTS: u32 = 5;
+const METADATA_RETRY: Duration = Duration::from_secs(1);
+
+/// The metadata of the current track as last fetched. The `Metadata` property
+/// answers from it, so reading it never waits for the Web API.
+#[derive(Default)]
+struct MetadataCache {
+    current: Option<TrackMetadata>,
+    /// The URI of the track that started last, until the metadata is of it.
+    wanted: Option<String>,
+    fetching: bool,
+    /// Whether to fetch again once the fetch under way is done.
+    stale: bool,
+}
+
+type SharedMetadataCache = Arc<Mutex<MetadataCache>>;
+
+/// Fetches the metadata of the current track. The playing track only comes
+/// with its album and artists in short, so their genres, whether the track is
+/// saved and the name of the playlist, album or artist it plays from are
+/// looked up separately, unless `known` has them for the same track and
+/// context already.
+fn fetch_metadata(sp: &Spotify, known: Option<&TrackMetadata>) -> Option<TrackMetadata> {
+    let playback = match sp.current_playback(None) {
+        Ok(Some(playback)) => playback,
+        Ok(None) => return None,
+        Err(e) => {
+            info!("Couldn't fetch metadata from spotify: {:?}", e);
+            return known.cloned();
+        },
+    };
+    let track = playback.item?;
+    let context_uri = playback.context.map(|c| c.uri);
+    let known = known.filter(|k| k.uri == track.uri);
+    let (genres, saved) = match known {
+        Some(k) => (k.genres.clone(), k.saved),
+        None => (
+            web_api::genres(sp, &track),
+            web_api::is_saved(sp, &track.uri),
+        ),
+    };
+    let context = match known {
+        Some(k) if k.context_uri == context_uri => k.context.clone(),
+        _ => context_uri
+            .as_ref()
+            .and_then(|uri| web_api::context_name(sp, uri)),
+    };
+    Some(TrackMetadata {
+        url: track.external_urls.get("spotify").cloned(),
+        art_url: track.album.images.first().map(|i| i.url.clone()),
+        length_ms: track.duration_ms,
+        title: track.name.clone(),
+        album: track.album.name.clone(),
+        artists: track.artists.iter().map(|a| a.name.clone()).collect(),
+        album_artists: track.album.artists.iter().map(|a| a.name.clone()).collect(),
+        genres,
+        track_number: track.track_number,
+        disc_number: track.disc_number,
+        saved,
+        context_uri,
+        context,
+        uri: track.uri,
+    })
+}
+
+/// Fetches the metadata of the current track on a thread of its own, and
+/// tells `changed` once it is of another track than before. If `track` just
+/// started, it is fetched until it is of that track, or gave up on after a
+/// few attempts. While a fetch is under way, another one follows it.
+fn refresh_metadata(
+    cache: &SharedMetadataCache,
+    token: &RspotifyToken,
+    changed: &UnboundedSender<()>,
+    track: Option<String>,
+) {
+    {
+        let mut cache = cache.lock().unwrap();
+        if track.is_some() {
+            cache.wanted = track;
+        }
+        if cache.fetching {
+            cache.stale = true;
+            return;
+        }
+        cache.fetching = true;
+    }
+    let cache = cache.clone();
+    let token = token.clone();
+    let changed = changed.clone();
+    thread::spawn(move || {
+        let client = web_api::client(&token);
+        let uri = |m: &Option<TrackMetadata>| m.as_ref().map(|m| m.uri.clone());
+        let mut attempts = 0;
+        loop {
+            let known = cache.lock().unwrap().current.clone();
+            let metadata = fetch_metadata(&client, known.as_ref());
+            let mut cache = cache.lock().unwrap();
+            if uri(&metadata) != uri(&cache.current) {
+                let _ = changed.unbounded_send(());
+            }
+            cache.current = metadata;
+            attempts += 1;
+            if cache.wanted.is_some() && cache.wanted == uri(&cache.current) {
+                cache.wanted = None;
+            }
+            if cache.stale {
+                cache.stale = false;
+                attempts = 0;
+            } else if cache.wanted.is_some() && attempts < METADATA_ATTEMPTS {
+                drop(cache);
+                thread::sleep(METADATA_RETRY);
+            } else {
+                cache.wanted = None;
+                cache.fetching = false;
+                return;
+            }
+        }
+    });
 }
 
 fn create_dbus_server(
@@ -114,7 +695,17 @@ fn create_dbus_server(
     api_token: RspotifyToken,
     spirc: Rc<Spirc>,
     device_name: String,
-) -> Box<Future<Item = (), Error = ()>> {
+    save_points: Option<SharedSavePoints>,
+    state: SharedDeviceState,
+    equalizer: Option<SharedEqualizer>,
+    balance: SharedBalance,
+    sleep_timer: SharedSleepTimer,
+    recently_played: Option<SharedRecentlyPlayed>,
+    announcer: Announcer,
+    queue: SharedTrackQueue,
+    metadata: SharedMetadataCache,
+    metadata_changed: UnboundedSender<()>,
+) -> (Rc<Connection>, Box<Future<Item = (), Error = ()>>) {
     let c = Rc::new(Connection::get_private(BusType::Session).unwrap());
 
     macro_rules! spotify_api_method {
@@ -128,7 +719,7 @@ fn create_dbus_server(
                     let device_name = device_name.clone();
                     $(let $m: Result<$t,_> = m.msg.read1();)*
                     thread::spawn(move || {
-                        let $sp = create_spotify_api(&token);
+                        let $sp = web_api::client(&token);
                         let $device = Some(device_name);
                         let _ = $f;
                         let _ = p.send(());
@@ -145,7 +736,7 @@ fn create_dbus_server(
             let device_name = device_name.clone();
             let token = api_token.clone();
             move |i, _| {
-                let $sp = create_spotify_api(&token);
+                let $sp = web_api::client(&token);
                 let $device = Some(device_name.clone());
                 let v = $f;
                 i.append(v);
@@ -154,16 +745,264 @@ fn create_dbus_server(
         }};
     }
 
+    macro_rules! restrictions_property {
+        ($action:ident) => {{
+            let token = api_token.clone();
+            move |i, _| {
+                i.append(!restrictions(&token).$action);
+                Ok(())
+            }
+        }};
+    }
+
     c.register_name(
         "org.mpris.MediaPlayer2.spotifyd",
         NameFlag::ReplaceExisting as u32,
     )
     .unwrap();
 
+    let sleep_timer_set = sleep_timer.clone();
+    let sleep_timer_cancel = sleep_timer.clone();
+    let equalizer_select = equalizer.clone();
+    let balance_set = balance.clone();
+    let balance_gains = balance.clone();
+    let balance_set_gains = balance.clone();
+    let equalizer_presets = equalizer.clone();
+    let save_points_get = save_points.clone();
+    let save_points_clear = save_points;
     let spirc_quit = spirc.clone();
     let spirc_play_pause = spirc.clone();
+    let spirc_announce = spirc.clone();
+    let announce_state = state.clone();
+    let queue_token = api_token.clone();
+    let play_next_token = api_token.clone();
+    let add_track_token = api_token.clone();
+    let search_token = api_token.clone();
+    let queue_add = queue.clone();
+    let queue_next = queue.clone();
+    let queue_remove = queue.clone();
+    let queue_list = queue.clone();
+    let queue_add_track = queue.clone();
+    let queue_remove_track = queue.clone();
+    let add_track_metadata = metadata.clone();
+    let queue_tracks = queue;
+    let metadata_token = api_token.clone();
+    let search_device_name = device_name.clone();
 
     let f = AFactory::new_afn::<()>();
+    let controls = f.object_path(CONTROLS_PATH, ()).introspectable().add(
+        f.interface(CONTROLS_INTERFACE, ())
+            .add_s(f.signal("TakenOver", ()).sarg::<&str, _>("device"))
+            .add_m(f.amethod("AddToQueue", (), move |m| {
+                let uri: String = m.msg.read1()?;
+                enqueue(&queue_add, &queue_token, uri, false);
+                Ok(vec![m.msg.method_return()])
+            }))
+            .add_m(f.amethod("PlayNext", (), move |m| {
+                let uri: String = m.msg.read1()?;
+                enqueue(&queue_next, &play_next_token, uri, true);
+                Ok(vec![m.msg.method_return()])
+            }))
+            .add_m(f.amethod("RemoveFromQueue", (), move |m| {
+                let uri: String = m.msg.read1()?;
+                if queue_remove.lock().unwrap().remove(&uri) {
+                    Ok(vec![m.msg.method_return()])
+                } else {
+                    Err(MethodErr::failed(&format!(
+                        "{} isn't waiting in the queue",
+                        uri
+                    )))
+                }
+            }))
+            .add_m(f.amethod("SearchAndPlay", (), move |m| {
+                let args = m
+                    .msg
+                    .read2::<String, String>()
+                    .map_err(MethodErr::from)
+                    .and_then(|(query, kind)| match kind.parse::<SearchType>() {
+                        Ok(kind) => Ok((query, kind)),
+                        Err(()) => Err(MethodErr::invalid_arg(&kind)),
+                    });
+                let token = search_token.clone();
+                let device_name = search_device_name.clone();
+                let mret = m.msg.method_return();
+                future::result(args)
+                    .and_then(move |(query, kind)| {
+                        let (p, c) = oneshot::channel();
+                        thread::spawn(move || {
+                            let _ = p.send(search_and_play(&token, &device_name, &query, kind));
+                        });
+                        c.map_err(|e| MethodErr::failed(&e))
+                            .and_then(|played| played.map_err(|e| MethodErr::failed(&e)))
+                    })
+                    .map(|_| vec![mret])
+            }))
+            .add_m(f.amethod("PlayLocalFile", (), move |m| {
+                let path = m.msg.read1::<String>().map_err(MethodErr::from);
+                let announcer = announcer.clone();
+                let spirc = spirc_announce.clone();
+                let state = announce_state.clone();
+                let mret = m.msg.method_return();
+                future::result(path)
+                    .and_then(move |path| {
+                        let was_playing = state.get() == DeviceState::Active;
+                        if was_playing {
+                            spirc.pause();
+                        }
+                        announcer
+                            .play(PathBuf::from(path))
+                            .map_err(|e| MethodErr::failed(&e))
+                            .and_then(move |played| {
+                                if was_playing {
+                                    spirc.play();
+                                }
+                                played.map_err(|e| MethodErr::failed(&e))
+                            })
+                    })
+                    .map(|_| vec![mret])
+            }))
+            .add_m(f.amethod("ClearSavePoints", (), move |m| {
+                if let Some(ref save_points) = save_points_clear {
+                    save_points.lock().unwrap().clear();
+                }
+                let mret = m.msg.method_return();
+                Ok(vec![mret])
+            }))
+            .add_m(f.amethod("SetEqualizerPreset", (), move |m| {
+                let name: String = m.msg.read1()?;
+                let selected = equalizer_select.as_ref().map_or(false, |e| e.select(&name));
+                if !selected {
+                    return Err(MethodErr::invalid_arg(&name));
+                }
+                let mret = m.msg.method_return();
+                Ok(vec![mret])
+            }))
+            .add_m(f.amethod("SetSleepTimer", (), move |m| {
+                let minutes: u32 = m.msg.read1()?;
+                if minutes == 0 {
+                    return Err(MethodErr::invalid_arg(&minutes));
+                }
+                sleep_timer_set.set(Duration::from_secs(u64::from(minutes) * 60));
+                let mret = m.msg.method_return();
+                Ok(vec![mret])
+            }))
+            .add_m(f.amethod("CancelSleepTimer", (), move |m| {
+                sleep_timer_cancel.cancel();
+                let mret = m.msg.method_return();
+                Ok(vec![mret])
+            }))
+            .add_p(
+                f.property::<u32, _>("SleepTimerRemaining", ())
+                    .access(Access::Read)
+                    .on_get(move |i, _| {
+                        i.append(
+                            sleep_timer
+                                .remaining()
+                                .map_or(0, |left| left.as_secs() as u32),
+                        );
+                        Ok(())
+                    }),
+            )
+            .add_m(f.amethod("SetBalance", (), move |m| {
+                let value: f64 = m.msg.read1()?;
+                balance_set.set_balance(value);
+                let mret = m.msg.method_return();
+                Ok(vec![mret])
+            }))
+            .add_m(f.amethod("SetChannelGains", (), move |m| {
+                let (left, right): (f64, f64) = m.msg.read2()?;
+                balance_set_gains.set_gains_db(left, right);
+                let mret = m.msg.method_return();
+                Ok(vec![mret])
+            }))
+            .add_p(
+                f.property::<f64, _>("Balance", ())
+                    .access(Access::Read)
+                    .on_get(move |i, _| {
+                        i.append(balance.balance());
+                        Ok(())
+                    }),
+            )
+            .add_p(
+                f.property::<Vec<f64>, _>("ChannelGains", ())
+                    .access(Access::Read)
+                    .on_get(move |i, _| {
+                        i.append(balance_gains.gains_db().to_vec());
+                        Ok(())
+                    }),
+            )
+            .add_p(
+                f.property::<String, _>("EqualizerPreset", ())
+                    .access(Access::Read)
+                    .on_get(move |i, _| {
+                        i.append(
+                            equalizer
+                                .as_ref()
+                                .and_then(SharedEqualizer::preset)
+                                .unwrap_or_default(),
+                        );
+                        Ok(())
+                    }),
+            )
+            .add_p(
+                f.property::<Vec<String>, _>("EqualizerPresets", ())
+                    .access(Access::Read)
+                    .on_get(move |i, _| {
+                        i.append(
+                            equalizer_presets
+                                .as_ref()
+                                .map(SharedEqualizer::presets)
+                                .unwrap_or_default(),
+                        );
+                        Ok(())
+                    }),
+            )
+            .add_p(
+                f.property::<Vec<String>, _>("Queue", ())
+                    .access(Access::Read)
+                    .on_get(move |i, _| {
+                        i.append(queue_list.lock().unwrap().list());
+                        Ok(())
+                    }),
+            )
+            .add_p(
+                f.property::<Vec<HashMap<String, String>>, _>("RecentlyPlayed", ())
+                    .access(Access::Read)
+                    .on_get(move |i, _| {
+                        i.append(
+                            recently_played
+                                .as_ref()
+                                .map(|recent| {
+                                    recent.tracks().iter().map(PlayedTrack::to_map).collect()
+                                })
+                                .unwrap_or_else(Vec::new),
+                        );
+                        Ok(())
+                    }),
+            )
+            .add_p(
+                f.property::<String, _>("State", ())
+                    .access(Access::Read)
+                    .on_get(move |i, _| {
+                        i.append(state.get().to_string());
+                        Ok(())
+                    }),
+            )
+            .add_p(
+                f.property::<HashMap<String, u32>, _>("SavePoints", ())
+                    .access(Access::Read)
+                    .on_get(move |i, _| {
+                        i.append(
+                            save_points_get
+                                .as_ref()
+                                .map(|s| s.lock().unwrap().all())
+                                .unwrap_or_default(),
+                        );
+                        Ok(())
+                    }),
+            ),
+    );
+
     let tree = f.tree(ATree::new()).add(
         f.object_path("/org/mpris/MediaPlayer2", ())
             .introspectable()
@@ -310,55 +1149,27 @@ fn create_dbus_server(
                     .add_p(
                         f.property::<HashMap<String, Variant<Box<RefArg>>>, _>("Metadata", ())
                             .access(Access::Read)
-                            .on_get(spotify_api_property!([sp, _device] {
-                                let mut m = HashMap::new();
-                                let v = sp.current_user_playing_track();
-                                if let Ok(Some(playing)) = v {
-                                    if let Some(track) = playing.item {
-                                        m.insert("xesam:title".to_string(),
-                                                 Variant(
-                                                     Box::new(
-                                                         MessageItem::Str(track.name)
-                                                         ) as Box<RefArg>));
-                                        m.insert("xesam:album".to_string(),
-                                                 Variant(Box::new(
-                                                         MessageItem::Str(track.album.name))
-                                                         as Box<RefArg>));
-                                        m.insert("xesam:artist".to_string(),
-                                                 Variant(
-                                                     Box::new(
-                                                         MessageItem::Str(
-                                                             track.artists
-                                                                 .iter()
-                                                                 .next()
-                                                                 .map_or("", |a| &a.name)
-                                                                 .to_string()
-                                                         )) as Box<RefArg>));
-                                        m.insert("xesam:artists".to_string(),
-                                                 Variant(Box::new(track.artists
-                                                                  .iter()
-                                                                  .map(|a|
-                                                                       Box::new(
-                                                                           MessageItem::Str(
-                                                                               a.name.to_string())
-                                                                           ) as Box<RefArg>)
-                                                                  .collect::<Vec<Box<RefArg>>>())));
-                                        m.insert("mpris:length".to_string(), Variant(Box::new(
-                                                    MessageItem::Int64(i64::from(track.duration_ms)))
-                                                as Box<RefArg>));
-                                        m.insert("mpris:artUrl".to_string(), Variant(Box::new(
-                                                    MessageItem::Str(track.album.images.first()
-                                                                     .unwrap().url.clone()))
-                                                as Box<RefArg>));
-                                        m.insert("xesam:trackNumber".to_string(), Variant(Box::new(
-                                                    MessageItem::UInt32(track.track_number)
-                                                    ) as Box<RefArg>));
-                                    }
-                                } else {
-                                    info!("Couldn't fetch metadata from spotify: {:?}", v);
+                            .on_get(move |i, _| {
+                                // Answers with what is known. Tracks that
+                                // start are fetched as they do, and told of
+                                // with PropertiesChanged.
+                                let known = metadata
+                                    .lock()
+                                    .unwrap()
+                                    .current
+                                    .as_ref()
+                                    .map(TrackMetadata::to_map);
+                                if known.is_none() {
+                                    refresh_metadata(
+                                        &metadata,
+                                        &metadata_token,
+                                        &metadata_changed,
+                                        None,
+                                    );
                                 }
-                                m
-                            })),
+                                i.append(known.unwrap_or_default());
+                                Ok(())
+                            }),
                     )
                     .add_p(
                         f.property::<bool, _>("CanPlay", ())
@@ -376,16 +1187,99 @@ fn create_dbus_server(
                                 Ok(())
                             }),
                     )
+                    .add_p(
+                        f.property::<bool, _>("CanGoNext", ())
+                            .access(Access::Read)
+                            .on_get(restrictions_property!(skipping_next)),
+                    )
+                    .add_p(
+                        f.property::<bool, _>("CanGoPrevious", ())
+                            .access(Access::Read)
+                            .on_get(restrictions_property!(skipping_prev)),
+                    )
                     .add_p(
                         f.property::<bool, _>("CanSeek", ())
+                            .access(Access::Read)
+                            .on_get(restrictions_property!(seeking)),
+                    )
+                    .add_p(
+                        f.property::<bool, _>("CanControl", ())
                             .access(Access::Read)
                             .on_get(|i, _| {
                                 i.append(true);
                                 Ok(())
                             }),
+                    ),
+            )
+            .add(
+                f.interface("org.mpris.MediaPlayer2.TrackList", ())
+                    .add_m(f.amethod("AddTrack", (), move |m| {
+                        let args = m
+                            .msg
+                            .read3::<String, Path, bool>()
+                            .map_err(MethodErr::from);
+                        let queue = queue_add_track.clone();
+                        let metadata = add_track_metadata.clone();
+                        let token = add_track_token.clone();
+                        let mret = m.msg.method_return();
+                        future::result(args)
+                            .and_then(move |(uri, after, set_as_current)| {
+                                let (p, c) = oneshot::channel();
+                                if set_as_current {
+                                    thread::spawn(move || {
+                                        let _ = web_api::client(&token).start_playback(
+                                            None,
+                                            None,
+                                            Some(vec![uri]),
+                                            None,
+                                        );
+                                        let _ = p.send(());
+                                    });
+                                } else {
+                                    let current = metadata
+                                        .lock()
+                                        .unwrap()
+                                        .current
+                                        .as_ref()
+                                        .map(|m| m.uri.clone());
+                                    let at = queue
+                                        .lock()
+                                        .unwrap()
+                                        .position_after(&after, current.as_ref().map(|c| &**c));
+                                    enqueue_at(&queue, &token, uri, at);
+                                    let _ = p.send(());
+                                }
+                                c.map_err(|e| MethodErr::failed(&e))
+                            })
+                            .map(|_| vec![mret])
+                    }))
+                    .add_m(f.amethod("RemoveTrack", (), move |m| {
+                        let path: Path = m.msg.read1()?;
+                        if queue_remove_track.lock().unwrap().remove_path(&path) {
+                            Ok(vec![m.msg.method_return()])
+                        } else {
+                            Err(MethodErr::failed(&format!(
+                                "{} isn't waiting in the queue",
+                                &*path
+                            )))
+                        }
+                    }))
+                    .add_p(
+                        f.property::<Vec<Path>, _>("Tracks", ())
+                            .access(Access::Read)
+                            .on_get(move |i, _| {
+                                let tracks = queue_tracks.lock().unwrap().list();
+                                i.append(
+                                    tracks
+                                        .iter()
+                                        .filter_map(|uri| track_path(uri))
+                                        .collect::<Vec<_>>(),
+                                );
+                                Ok(())
+                            }),
                     )
                     .add_p(
-                        f.property::<bool, _>("CanControl", ())
+                        f.property::<bool, _>("CanEditTracks", ())
                             .access(Access::Read)
                             .on_get(|i, _| {
                                 i.append(true);
@@ -424,7 +1318,7 @@ fn create_dbus_server(
                         f.property::<bool, _>("HasTrackList", ())
                             .access(Access::Read)
                             .on_get(|i, _| {
-                                i.append(false);
+                                i.append(true);
                                 Ok(())
                             }),
                     )
@@ -445,13 +1339,15 @@ fn create_dbus_server(
                             }),
                     ),
             ),
-    );
+    )
+    .add(controls);
 
     tree.set_registered(&c, true).unwrap();
     let aconn = AConnection::new(c.clone(), handle).unwrap();
     let server = ATreeServer::new(c.clone(), Box::new(tree), aconn.messages().unwrap());
-    Box::new(server.for_each(|m| {
+    let server = server.for_each(|m| {
         warn!("Unhandled dbus message: {:?}", m);
         Ok(())
-    }))
+    });
+    (c, Box::new(server))
 }
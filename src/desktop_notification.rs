@@ -0,0 +1,103 @@
+use crate::{art, track_metadata::TrackMetadata};
+use librespot::core::spotify_id::SpotifyId;
+use log::warn;
+use notify_rust::Notification;
+use std::{
+    cell::Cell,
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    process,
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
+
+/// How long a notification stays, in milliseconds.
+const TIMEOUT: i32 = 5000;
+
+/// Pops up a desktop notification with the title, artists and cover of
+/// every new track, for laptops running spotifyd instead of Spotify's app.
+pub struct DesktopNotifier {
+    sender: Sender<TrackMetadata>,
+    /// The track last notified, so resuming it doesn't notify again.
+    track_id: Cell<Option<SpotifyId>>,
+}
+
+impl DesktopNotifier {
+    pub fn new() -> DesktopNotifier {
+        let (sender, receiver) = channel();
+        thread::spawn(move || run(&receiver));
+        DesktopNotifier {
+            sender,
+            track_id: Cell::new(None),
+        }
+    }
+
+    pub fn handle_metadata(&self, metadata: &TrackMetadata) {
+        if self.track_id.replace(Some(metadata.track_id)) == Some(metadata.track_id) {
+            return;
+        }
+        let _ = self.sender.send(metadata.clone());
+    }
+}
+
+/// Shows the notifications, each replacing the one before.
+fn run(receiver: &Receiver<TrackMetadata>) {
+    let cover_path = env::temp_dir().join(format!("spotifyd-cover-{}", process::id()));
+    let mut id = None;
+    for metadata in receiver.iter() {
+        let mut notification = Notification::new();
+        notification
+            .appname("Spotifyd")
+            .summary(&metadata.title)
+            .body(&format!(
+                "{}\n{}",
+                metadata.artists.join(", "),
+                metadata.album
+            ))
+            .timeout(TIMEOUT);
+        match cover(&metadata, &cover_path) {
+            Some(path) => notification.icon(&path),
+            None => notification.icon("audio-x-generic"),
+        };
+        if let Some(id) = id {
+            notification.id(id);
+        }
+        match notification.show() {
+            Ok(handle) => id = Some(handle.id()),
+            Err(e) => warn!("Couldn't show a desktop notification: {}", e),
+        }
+    }
+    let _ = fs::remove_file(&cover_path);
+}
+
+/// Saves the track's cover where the notification server can read it.
+fn cover(metadata: &TrackMetadata, path: &Path) -> Option<String> {
+    let url = metadata.cover_url.as_ref()?;
+    let picture = match art::load(url) {
+        Ok(picture) => picture,
+        Err(e) => {
+            warn!("Couldn't download cover art from {}: {}", url, e);
+            return None;
+        },
+    };
+    if let Err(e) = write_new(path, &picture) {
+        warn!("Couldn't save the cover art to {:?}: {}", path, e);
+        return None;
+    }
+    Some(path.to_string_lossy().into_owned())
+}
+
+/// Replaces the file at `path` with a new one of our own. The temporary
+/// directory is shared, so whatever else is found there, a link in
+/// particular, is never written to.
+fn write_new(path: &Path, contents: &[u8]) -> io::Result<()> {
+    // If someone else's file is in the way, creating ours fails below.
+    let _ = fs::remove_file(path);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?
+        .write_all(contents)
+}
@@ -0,0 +1,155 @@
+use crate::backends::{DeviceEvent, SinkBuilder};
+use futures::{Async, Stream};
+use librespot::connect::spirc::Spirc;
+use log::info;
+use std::{
+    panic::{self, AssertUnwindSafe},
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use tokio_core::reactor::{Handle, Interval};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reacts to the output device going away and coming back.
+///
+/// The player's `RecoveringSink` reports devices that fail. A PulseAudio
+/// sink that goes away doesn't fail the stream, which PulseAudio moves to
+/// another sink, so configured PulseAudio sinks are also looked for with
+/// `pactl`. While playback is paused because the device is gone, nothing
+/// is written to it, so the device is tried every few seconds to notice it
+/// coming back.
+pub struct DeviceLoss {
+    sender: Sender<DeviceEvent>,
+    receiver: Receiver<DeviceEvent>,
+    /// Whether to pause while the device is gone, and to resume after.
+    pause: bool,
+    resume: bool,
+    backend: SinkBuilder,
+    device: Option<String>,
+    /// The PulseAudio sink to look for, if any.
+    pulse_sink: Option<String>,
+    interval: Interval,
+    lost: bool,
+    paused: bool,
+    /// Whether a check runs in the background.
+    checking: Arc<AtomicBool>,
+}
+
+impl DeviceLoss {
+    pub fn new(
+        pause: bool,
+        resume: bool,
+        backend: SinkBuilder,
+        device: Option<String>,
+        pulse_sink: Option<String>,
+        handle: &Handle,
+    ) -> DeviceLoss {
+        let (sender, receiver) = channel();
+        DeviceLoss {
+            sender,
+            receiver,
+            pause,
+            resume,
+            backend,
+            device,
+            pulse_sink,
+            interval: Interval::new(CHECK_INTERVAL, handle).unwrap(),
+            lost: false,
+            paused: false,
+            checking: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Whether the device is gone.
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+
+    /// Where sinks report the device.
+    pub fn events(&self) -> Sender<DeviceEvent> {
+        self.sender.clone()
+    }
+
+    pub fn poll(&mut self, spirc: Option<&Spirc>) {
+        while let Ok(event) = self.receiver.try_recv() {
+            match event {
+                DeviceEvent::Lost if !self.lost => {
+                    self.lost = true;
+                    if let (true, Some(spirc)) = (self.pause, spirc) {
+                        info!("Pausing until the audio device is back");
+                        spirc.pause();
+                        self.paused = true;
+                    }
+                },
+                DeviceEvent::Restored if self.lost => {
+                    self.lost = false;
+                    if let (true, true, Some(spirc)) = (self.paused, self.resume, spirc) {
+                        info!("Resuming, the audio device is back");
+                        spirc.play();
+                    }
+                    self.paused = false;
+                },
+                _ => (),
+            }
+        }
+
+        let mut elapsed = false;
+        while let Ok(Async::Ready(Some(()))) = self.interval.poll() {
+            elapsed = true;
+        }
+        if !elapsed || self.checking.load(Ordering::SeqCst) {
+            return;
+        }
+        if self.lost && (self.paused || self.pulse_sink.is_some()) {
+            self.check(DeviceEvent::Restored);
+        } else if !self.lost && self.pulse_sink.is_some() {
+            self.check(DeviceEvent::Lost);
+        }
+    }
+
+    /// Finds out in the background whether the device is there, and reports
+    /// `event` if that changed.
+    fn check(&self, event: DeviceEvent) {
+        let (sender, checking) = (self.sender.clone(), self.checking.clone());
+        let (backend, device) = (self.backend, self.device.clone());
+        let pulse_sink = self.pulse_sink.clone();
+        checking.store(true, Ordering::SeqCst);
+        thread::spawn(move || {
+            let present = match pulse_sink {
+                Some(ref sink) => has_pulse_sink(sink),
+                // Opening the device shows whether it is back. Some backends
+                // panic when they can't.
+                None => panic::catch_unwind(AssertUnwindSafe(|| {
+                    let mut sink = backend(device);
+                    sink.start().and_then(|_| sink.stop()).is_ok()
+                }))
+                .unwrap_or(false),
+            };
+            if present == (event == DeviceEvent::Restored) {
+                let _ = sender.send(event);
+            }
+            checking.store(false, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Whether PulseAudio has the named sink. Without `pactl`, it is assumed
+/// to be there.
+fn has_pulse_sink(name: &str) -> bool {
+    match Command::new("pactl")
+        .args(&["list", "short", "sinks"])
+        .output()
+    {
+        Ok(ref output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .any(|line| line.split_whitespace().nth(1) == Some(name)),
+        _ => true,
+    }
+}
@@ -0,0 +1,80 @@
+use librespot::playback::player::PlayerEvent;
+use log::info;
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// What spotifyd is doing, as far as Spotify Connect is concerned.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceState {
+    /// Not logged in, waiting for credentials through discovery.
+    Disconnected,
+    /// Logging in to Spotify.
+    Connecting,
+    /// Logged in and listed as a Connect device, but not playing.
+    Registered,
+    /// Playing.
+    Active,
+    /// Shutting down, waiting for Spotify to let go of the device.
+    Draining,
+}
+
+impl fmt::Display for DeviceState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            DeviceState::Disconnected => "disconnected",
+            DeviceState::Connecting => "connecting",
+            DeviceState::Registered => "registered",
+            DeviceState::Active => "active",
+            DeviceState::Draining => "draining",
+        };
+        f.write_str(name)
+    }
+}
+
+impl DeviceState {
+    /// Whether the device may move on to `next`. Once draining, it only ever
+    /// shuts down.
+    fn can_become(self, next: DeviceState) -> bool {
+        match (self, next) {
+            (DeviceState::Draining, _) => false,
+            (DeviceState::Disconnected, DeviceState::Registered)
+            | (DeviceState::Disconnected, DeviceState::Active) => false,
+            _ => true,
+        }
+    }
+}
+
+/// The device state, shared with the interfaces that report it.
+#[derive(Clone)]
+pub struct SharedDeviceState(Arc<Mutex<DeviceState>>);
+
+impl SharedDeviceState {
+    pub fn new(state: DeviceState) -> SharedDeviceState {
+        info!("Device state: {}", state);
+        SharedDeviceState(Arc::new(Mutex::new(state)))
+    }
+
+    pub fn get(&self) -> DeviceState {
+        *self.0.lock().unwrap()
+    }
+
+    /// Moves to `next` and logs the transition, unless it isn't allowed.
+    pub fn set(&self, next: DeviceState) {
+        let mut state = self.0.lock().unwrap();
+        if *state != next && state.can_become(next) {
+            info!("Device state: {} -> {}", *state, next);
+            *state = next;
+        }
+    }
+
+    pub fn handle_event(&self, event: &PlayerEvent) {
+        match *event {
+            PlayerEvent::Started { .. } | PlayerEvent::Changed { .. } => {
+                self.set(DeviceState::Active)
+            },
+            PlayerEvent::Stopped { .. } => self.set(DeviceState::Registered),
+        }
+    }
+}
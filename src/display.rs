@@ -0,0 +1,256 @@
+use crate::{
+    config::{DisplayConfig, DisplayDriver},
+    player_event_handler::run_program,
+    track_metadata::TrackMetadata,
+};
+use librespot::playback::player::PlayerEvent;
+use log::{error, info};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::unix::io::AsRawFd,
+    path::Path,
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// `ioctl` selecting the device an I2C bus file talks to.
+const I2C_SLAVE: libc::c_ulong = 0x0703;
+/// Gap between the end and the start of scrolling text.
+const SCROLL_GAP: &str = "   ";
+
+enum Update {
+    Playing(bool),
+    Track(TrackMetadata),
+}
+
+/// Shows what's playing on a small display attached to the device.
+pub struct Display {
+    sender: Sender<Update>,
+}
+
+impl Display {
+    pub fn new(config: DisplayConfig) -> Display {
+        let (sender, receiver) = channel();
+        thread::spawn(move || run(&config, &receiver));
+        Display { sender }
+    }
+
+    pub fn handle_event(&self, event: &PlayerEvent) {
+        let playing = match *event {
+            PlayerEvent::Started { .. } | PlayerEvent::Changed { .. } => true,
+            PlayerEvent::Stopped { .. } => false,
+        };
+        let _ = self.sender.send(Update::Playing(playing));
+    }
+
+    pub fn handle_metadata(&self, metadata: &TrackMetadata) {
+        let _ = self.sender.send(Update::Track(metadata.clone()));
+    }
+}
+
+/// Something that can show now-playing information.
+trait Driver {
+    /// Whether lines longer than the display are scrolled through.
+    fn scrolls(&self) -> bool;
+    fn show(
+        &mut self,
+        lines: &[String],
+        playing: bool,
+        track: Option<&TrackMetadata>,
+    ) -> io::Result<()>;
+}
+
+fn run(config: &DisplayConfig, receiver: &Receiver<Update>) {
+    let mut driver: Box<Driver> = match config.driver {
+        DisplayDriver::Hd44780 { ref bus, address } => {
+            match Hd44780::open(bus, address, config.columns) {
+                Ok(lcd) => {
+                    info!("Using HD44780 display at {:#x} on {:?}", address, bus);
+                    Box::new(lcd)
+                },
+                Err(e) => {
+                    error!(
+                        "Couldn't open display at {:#x} on {:?}: {}",
+                        address, bus, e
+                    );
+                    return;
+                },
+            }
+        },
+        DisplayDriver::Command(ref command) => Box::new(CommandDriver(command.clone())),
+    };
+
+    let mut playing = false;
+    let mut track: Option<TrackMetadata> = None;
+    let mut shown: Option<Vec<String>> = None;
+    let mut scroll = 0;
+    let mut next_refresh = Instant::now();
+    loop {
+        let now = Instant::now();
+        let timeout = if next_refresh > now {
+            next_refresh - now
+        } else {
+            Duration::from_secs(0)
+        };
+        match receiver.recv_timeout(timeout) {
+            Ok(Update::Playing(p)) => playing = p,
+            Ok(Update::Track(t)) => {
+                track = Some(t);
+                scroll = 0;
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                next_refresh = Instant::now() + config.refresh;
+                scroll += 1;
+            },
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        let mut lines = text(track.as_ref(), playing, config.rows);
+        if driver.scrolls() {
+            lines = lines
+                .iter()
+                .map(|line| window(line, config.columns, scroll))
+                .collect();
+        }
+        if shown.as_ref() == Some(&lines) {
+            continue;
+        }
+        match driver.show(&lines, playing, track.as_ref()) {
+            Ok(()) => shown = Some(lines),
+            Err(e) => error!("Couldn't update the display: {}", e),
+        }
+    }
+}
+
+fn text(track: Option<&TrackMetadata>, playing: bool, rows: usize) -> Vec<String> {
+    let state = if playing { "Playing" } else { "Paused" };
+    let mut lines = match track {
+        Some(track) => vec![
+            track.title.clone(),
+            track.artists.join(", "),
+            track.album.clone(),
+            state.to_string(),
+        ],
+        None => vec!["Spotifyd".to_string()],
+    };
+    lines.resize(rows, String::new());
+    lines
+}
+
+/// The part of `line` visible at the given scroll position, padded to the
+/// width of the display so it overwrites what was shown before.
+fn window(line: &str, columns: usize, scroll: usize) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    if chars.len() <= columns {
+        return format!("{:<width$}", line, width = columns);
+    }
+    let looped: Vec<char> = chars.iter().cloned().chain(SCROLL_GAP.chars()).collect();
+    (0..columns)
+        .map(|i| looped[(scroll + i) % looped.len()])
+        .collect()
+}
+
+/// HD44780 compatible character LCD behind a PCF8574 I2C backpack, driven in
+/// 4 bit mode.
+struct Hd44780 {
+    bus: File,
+    columns: u8,
+}
+
+const LCD_BACKLIGHT: u8 = 0x08;
+const LCD_ENABLE: u8 = 0x04;
+const LCD_REGISTER_SELECT: u8 = 0x01;
+const LCD_ROWS: usize = 4;
+
+impl Hd44780 {
+    fn open(bus: &Path, address: u16, columns: usize) -> io::Result<Hd44780> {
+        let bus = OpenOptions::new().read(true).write(true).open(bus)?;
+        if unsafe { libc::ioctl(bus.as_raw_fd(), I2C_SLAVE, libc::c_ulong::from(address)) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut lcd = Hd44780 {
+            bus,
+            columns: columns as u8,
+        };
+        // Falls back to 8 bit mode whatever state the controller is in, then
+        // switches to 4 bit mode.
+        for &nibble in &[0x30, 0x30, 0x30, 0x20] {
+            lcd.write_nibble(nibble, 0)?;
+            thread::sleep(Duration::from_millis(5));
+        }
+        // Two lines, display on without cursor, left to right, clear.
+        for &command in &[0x28, 0x0c, 0x06, 0x01] {
+            lcd.write_byte(command, 0)?;
+        }
+        thread::sleep(Duration::from_millis(2));
+        Ok(lcd)
+    }
+
+    fn write_nibble(&mut self, nibble: u8, mode: u8) -> io::Result<()> {
+        let data = nibble | mode | LCD_BACKLIGHT;
+        self.bus.write_all(&[data | LCD_ENABLE])?;
+        self.bus.write_all(&[data])
+    }
+
+    fn write_byte(&mut self, byte: u8, mode: u8) -> io::Result<()> {
+        self.write_nibble(byte & 0xf0, mode)?;
+        self.write_nibble(byte << 4, mode)
+    }
+}
+
+impl Driver for Hd44780 {
+    fn scrolls(&self) -> bool {
+        true
+    }
+
+    fn show(&mut self, lines: &[String], _: bool, _: Option<&TrackMetadata>) -> io::Result<()> {
+        for (row, line) in lines.iter().enumerate().take(LCD_ROWS) {
+            // Rows three and four continue the memory of rows one and two.
+            let offset = [0x00, 0x40, self.columns, 0x40 + self.columns][row];
+            self.write_byte(0x80 | offset, 0)?;
+            // The character ROM only reliably covers ASCII.
+            for c in line.chars() {
+                let byte = if c.is_ascii() { c as u8 } else { b'?' };
+                self.write_byte(byte, LCD_REGISTER_SELECT)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hands the information to an external program, for displays such as
+/// e-ink panels that are best driven by their vendor's libraries. The
+/// program is run whenever the track or playback state changes.
+struct CommandDriver(String);
+
+impl Driver for CommandDriver {
+    fn scrolls(&self) -> bool {
+        false
+    }
+
+    fn show(
+        &mut self,
+        _: &[String],
+        playing: bool,
+        track: Option<&TrackMetadata>,
+    ) -> io::Result<()> {
+        let mut env_vars = HashMap::new();
+        env_vars.insert(
+            "PLAYER_STATE",
+            if playing { "playing" } else { "paused" }.to_string(),
+        );
+        if let Some(track) = track {
+            env_vars.insert("TRACK_ID", track.track_id.to_base62());
+            env_vars.insert("TITLE", track.title.clone());
+            env_vars.insert("ARTIST", track.artists.join(", "));
+            env_vars.insert("ALBUM", track.album.clone());
+            if let Some(ref url) = track.cover_url {
+                env_vars.insert("COVER_URL", url.clone());
+            }
+        }
+        run_program(&self.0, env_vars)?.wait().map(|_| ())
+    }
+}
@@ -0,0 +1,57 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether audio processed at a higher precision is dithered when it
+/// goes back to 16 bits, for the stages set up from now on.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Turns samples computed as floats back into 16 bits. Dithered, triangular
+/// noise of up to one step either way is added before rounding, which turns
+/// the distortion of quiet passages into a low, even hiss. Otherwise the
+/// samples are cut off as they always were.
+pub struct Quantizer {
+    /// The state of the xorshift generator, if dithering.
+    noise: Option<u32>,
+}
+
+impl Quantizer {
+    pub fn new() -> Quantizer {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |d| d.subsec_nanos() | 1);
+        Quantizer {
+            noise: if ENABLED.load(Ordering::Relaxed) {
+                Some(seed)
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Takes a sample in the range of an `i16`.
+    pub fn quantize(&mut self, sample: f64) -> i16 {
+        let sample = match self.noise {
+            Some(_) => (sample + self.random() - self.random()).round(),
+            None => sample,
+        };
+        sample
+            .max(f64::from(i16::min_value()))
+            .min(f64::from(i16::max_value())) as i16
+    }
+
+    /// Uniform in [0, 1).
+    fn random(&mut self) -> f64 {
+        let mut x = self.noise.unwrap_or(1);
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.noise = Some(x);
+        f64::from(x) / 4_294_967_296.0
+    }
+}
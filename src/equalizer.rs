@@ -0,0 +1,217 @@
+use crate::{
+    config::{EqualizerBand, EqualizerConfig, EqualizerFilter},
+    dither::Quantizer,
+};
+use librespot::playback::mixer::{softmixer::SoftMixer, AudioFilter, Mixer};
+use log::info;
+use std::{
+    f64::consts::PI,
+    sync::{Arc, Mutex},
+};
+
+/// librespot always decodes to this rate.
+const SAMPLE_RATE: f64 = 44100.0;
+const CHANNELS: usize = 2;
+
+/// A second order filter, with coefficients from the Audio EQ Cookbook.
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    /// The last two inputs and outputs, per channel.
+    x: [[f64; 2]; CHANNELS],
+    y: [[f64; 2]; CHANNELS],
+}
+
+impl Biquad {
+    fn new(band: &EqualizerBand) -> Biquad {
+        let a = 10f64.powf(band.gain / 40.0);
+        let w0 = 2.0 * PI * band.frequency / SAMPLE_RATE;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * band.q);
+        let shelf = 2.0 * a.sqrt() * alpha;
+        let (b0, b1, b2, a0, a1, a2) = match band.filter {
+            EqualizerFilter::Peak => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            EqualizerFilter::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                (a + 1.0) + (a - 1.0) * cos + shelf,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - shelf,
+            ),
+            EqualizerFilter::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                (a + 1.0) - (a - 1.0) * cos + shelf,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - shelf,
+            ),
+        };
+        Biquad {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+            x: [[0.0; 2]; CHANNELS],
+            y: [[0.0; 2]; CHANNELS],
+        }
+    }
+
+    fn process(&mut self, channel: usize, input: f64) -> f64 {
+        let (x, y) = (&mut self.x[channel], &mut self.y[channel]);
+        let output =
+            self.b0 * input + self.b1 * x[0] + self.b2 * x[1] - self.a1 * y[0] - self.a2 * y[1];
+        *x = [input, x[0]];
+        *y = [output, y[0]];
+        output
+    }
+}
+
+struct Equalizer {
+    presets: Vec<(String, Vec<EqualizerBand>)>,
+    preset: Option<String>,
+    filters: Vec<Biquad>,
+    quantizer: Quantizer,
+}
+
+impl Equalizer {
+    fn process(&mut self, data: &mut [i16]) {
+        if self.filters.is_empty() {
+            return;
+        }
+        for (i, sample) in data.iter_mut().enumerate() {
+            let channel = i % CHANNELS;
+            let output = self
+                .filters
+                .iter_mut()
+                .fold(f64::from(*sample), |s, filter| filter.process(channel, s));
+            *sample = self.quantizer.quantize(output);
+        }
+    }
+}
+
+/// A multi-band equalizer, applied to the audio through the mixer's audio
+/// filter. It is shared so the preset can be switched while playing.
+#[derive(Clone)]
+pub struct SharedEqualizer(Arc<Mutex<Equalizer>>);
+
+impl SharedEqualizer {
+    pub fn new(config: EqualizerConfig) -> SharedEqualizer {
+        let equalizer = SharedEqualizer(Arc::new(Mutex::new(Equalizer {
+            presets: config.presets,
+            preset: None,
+            filters: Vec::new(),
+            quantizer: Quantizer::new(),
+        })));
+        if let Some(preset) = config.preset {
+            equalizer.select(&preset);
+        }
+        equalizer
+    }
+
+    pub fn presets(&self) -> Vec<String> {
+        let equalizer = self.0.lock().unwrap();
+        equalizer.presets.iter().map(|p| p.0.clone()).collect()
+    }
+
+    /// The preset in use, if any.
+    pub fn preset(&self) -> Option<String> {
+        self.0.lock().unwrap().preset.clone()
+    }
+
+    /// Switches to the named preset, or turns the equalizer off for an empty
+    /// name. Returns false for unknown presets.
+    pub fn select(&self, name: &str) -> bool {
+        let mut equalizer = self.0.lock().unwrap();
+        if name.is_empty() {
+            info!("Equalizer off.");
+            equalizer.preset = None;
+            equalizer.filters.clear();
+            return true;
+        }
+        let filters = match equalizer.presets.iter().find(|p| p.0 == name) {
+            Some(&(_, ref bands)) => bands.iter().map(Biquad::new).collect(),
+            None => return false,
+        };
+        info!("Equalizer preset: {}", name);
+        equalizer.preset = Some(name.to_string());
+        equalizer.filters = filters;
+        true
+    }
+
+    pub fn mixer(&self, mixer: Box<Mixer>) -> Box<Mixer> {
+        Box::new(EqualizerMixer {
+            inner: mixer,
+            equalizer: Some(self.clone()),
+        })
+    }
+}
+
+struct EqualizerMixer {
+    inner: Box<Mixer>,
+    equalizer: Option<SharedEqualizer>,
+}
+
+impl Mixer for EqualizerMixer {
+    fn open() -> EqualizerMixer {
+        EqualizerMixer {
+            inner: Box::new(SoftMixer::open()),
+            equalizer: None,
+        }
+    }
+
+    fn start(&self) {
+        self.inner.start()
+    }
+
+    fn stop(&self) {
+        self.inner.stop()
+    }
+
+    fn volume(&self) -> u16 {
+        self.inner.volume()
+    }
+
+    fn set_volume(&self, volume: u16) {
+        self.inner.set_volume(volume)
+    }
+
+    fn get_audio_filter(&self) -> Option<Box<AudioFilter + Send>> {
+        let inner = self.inner.get_audio_filter();
+        match self.equalizer {
+            Some(ref equalizer) => Some(Box::new(EqualizerAudioFilter {
+                equalizer: equalizer.clone(),
+                inner,
+            })),
+            None => inner,
+        }
+    }
+}
+
+struct EqualizerAudioFilter {
+    equalizer: SharedEqualizer,
+    /// The mixer's own filter, e.g. software volume.
+    inner: Option<Box<AudioFilter + Send>>,
+}
+
+impl AudioFilter for EqualizerAudioFilter {
+    fn modify_stream(&self, data: &mut [i16]) {
+        // Lower volumes leave room for boosts before the samples clip.
+        if let Some(ref inner) = self.inner {
+            inner.modify_stream(data);
+        }
+        self.equalizer.0.lock().unwrap().process(data);
+    }
+}
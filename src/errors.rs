@@ -0,0 +1,221 @@
+use lazy_static::lazy_static;
+use log::warn;
+use std::{collections::HashMap, fmt::Display, fs, path::Path, process, sync::Mutex};
+
+lazy_static! {
+    /// Translated messages by error code, from the error catalog.
+    static ref CATALOG: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// User-facing errors. Each has a stable code, `SPD-` followed by four digits,
+/// that stays the same across releases and translations, so it can be used
+/// to look up or automate the handling of the error.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    NoPassword,
+    PremiumRequired,
+    ConnectionFailed,
+    UnknownBackend,
+    NoBackends,
+    InvalidValue,
+    InvalidSetting,
+    DaemonizeFailed,
+    PasswordSourceFailed,
+    OAuthFailed,
+    UnsetVariable,
+    DropPrivilegesFailed,
+    BadCredentials,
+}
+
+const ALL: &[ErrorCode] = &[
+    ErrorCode::NoPassword,
+    ErrorCode::PremiumRequired,
+    ErrorCode::ConnectionFailed,
+    ErrorCode::UnknownBackend,
+    ErrorCode::NoBackends,
+    ErrorCode::InvalidValue,
+    ErrorCode::InvalidSetting,
+    ErrorCode::DaemonizeFailed,
+    ErrorCode::PasswordSourceFailed,
+    ErrorCode::OAuthFailed,
+    ErrorCode::UnsetVariable,
+    ErrorCode::DropPrivilegesFailed,
+    ErrorCode::BadCredentials,
+];
+
+/// Kinds of failure spotifyd exits on, each with an exit code of its own
+/// from `sysexits.h`, so that service managers can tell whether restarting
+/// helps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+    /// The config file or command line is wrong, restarting won't help.
+    Config,
+    /// Spotify wouldn't log the account in.
+    Auth,
+    /// There is no audio backend to play with.
+    AudioBackend,
+    /// Spotify couldn't be reached, which may well pass.
+    Network,
+    /// The system refused something, like switching users.
+    System,
+    /// Anything else, which is a bug.
+    Internal,
+}
+
+impl Failure {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Failure::Config => 78,
+            Failure::Auth => 77,
+            Failure::AudioBackend => 69,
+            Failure::Network => 75,
+            Failure::System => 71,
+            Failure::Internal => 70,
+        }
+    }
+
+    /// Exits with the failure's exit code.
+    pub fn exit(self) -> ! {
+        process::exit(self.exit_code())
+    }
+
+    /// The failure a message tells of by its error code, e.g. the message
+    /// of a panic, or else by the reason librespot gives for not logging in.
+    pub fn of_message(message: &str) -> Failure {
+        ErrorCode::in_message(message)
+            .or_else(|| ErrorCode::of_login_failure(message))
+            .map_or(Failure::Internal, ErrorCode::failure)
+    }
+}
+
+impl ErrorCode {
+    /// The error a message tells of by its error code.
+    pub fn in_message(message: &str) -> Option<ErrorCode> {
+        ALL.iter()
+            .cloned()
+            .find(|code| message.contains(code.code()))
+    }
+
+    /// The error for the reason librespot gives when the access point won't
+    /// log the account in, as in `Authentication failed with reason:
+    /// BadCredentials`.
+    pub fn of_login_failure(message: &str) -> Option<ErrorCode> {
+        if message.contains("PremiumAccountRequired") {
+            Some(ErrorCode::PremiumRequired)
+        } else if message.contains("BadCredentials") {
+            Some(ErrorCode::BadCredentials)
+        } else {
+            None
+        }
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorCode::NoPassword => "SPD-0001",
+            ErrorCode::PremiumRequired => "SPD-0002",
+            ErrorCode::ConnectionFailed => "SPD-0003",
+            ErrorCode::UnknownBackend => "SPD-0004",
+            ErrorCode::NoBackends => "SPD-0005",
+            ErrorCode::InvalidValue => "SPD-0006",
+            ErrorCode::InvalidSetting => "SPD-0007",
+            ErrorCode::DaemonizeFailed => "SPD-0008",
+            ErrorCode::PasswordSourceFailed => "SPD-0009",
+            ErrorCode::OAuthFailed => "SPD-0010",
+            ErrorCode::UnsetVariable => "SPD-0011",
+            ErrorCode::DropPrivilegesFailed => "SPD-0012",
+            ErrorCode::BadCredentials => "SPD-0013",
+        }
+    }
+
+    pub fn failure(self) -> Failure {
+        match self {
+            ErrorCode::NoPassword
+            | ErrorCode::PremiumRequired
+            | ErrorCode::PasswordSourceFailed
+            | ErrorCode::OAuthFailed
+            | ErrorCode::BadCredentials => Failure::Auth,
+            ErrorCode::ConnectionFailed => Failure::Network,
+            ErrorCode::UnknownBackend | ErrorCode::NoBackends => Failure::AudioBackend,
+            ErrorCode::InvalidValue | ErrorCode::InvalidSetting | ErrorCode::UnsetVariable => {
+                Failure::Config
+            },
+            ErrorCode::DaemonizeFailed | ErrorCode::DropPrivilegesFailed => Failure::System,
+        }
+    }
+
+    /// The English message. `{0}`, `{1}` and so on stand for the arguments.
+    fn default_message(self) -> &'static str {
+        match self {
+            ErrorCode::NoPassword => "No password found.",
+            ErrorCode::PremiumRequired => {
+                "Couldn't log in: Spotifyd requires a Spotify Premium account, free accounts can't \
+                 be used."
+            },
+            ErrorCode::ConnectionFailed => "Couldn't connect to Spotify: {0}",
+            ErrorCode::UnknownBackend => "Unknown backend: {0}.",
+            ErrorCode::NoBackends => "No backends were enabled at build time.",
+            ErrorCode::InvalidValue => "{0} must be {1}.",
+            ErrorCode::InvalidSetting => "Invalid {0}: {1}",
+            ErrorCode::DaemonizeFailed => "Something went wrong while daemonizing: {0}",
+            ErrorCode::PasswordSourceFailed => "Couldn't get the password from {0}: {1}",
+            ErrorCode::OAuthFailed => "Couldn't log in with OAuth: {0}",
+            ErrorCode::UnsetVariable => "{0} uses the environment variable {1}, which isn't set.",
+            ErrorCode::DropPrivilegesFailed => "Couldn't switch to user and group: {0}",
+            ErrorCode::BadCredentials => "Couldn't log in, the username or password is wrong: {0}",
+        }
+    }
+}
+
+/// Loads translated messages. Each line of the catalog is `CODE = MESSAGE`,
+/// with `{0}`, `{1}` and so on for the arguments; lines starting with `#` are
+/// comments. Codes missing from the catalog keep their English message.
+pub fn load_catalog(path: &Path) {
+    let contents = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("Couldn't read error catalog {:?}: {}", path, e);
+            return;
+        },
+    };
+    let mut catalog = CATALOG.lock().unwrap();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(i) = line.find('=') {
+            catalog.insert(
+                line[..i].trim().to_string(),
+                line[i + 1..].trim().to_string(),
+            );
+        }
+    }
+}
+
+/// Formats the error as `CODE: message`, translated if the catalog has it.
+pub fn message(code: ErrorCode, args: &[&Display]) -> String {
+    let mut message = CATALOG
+        .lock()
+        .unwrap()
+        .get(code.code())
+        .cloned()
+        .unwrap_or_else(|| code.default_message().to_string());
+    for (i, arg) in args.iter().enumerate() {
+        message = message.replace(&format!("{{{}}}", i), &arg.to_string());
+    }
+    format!("{}: {}", code.code(), message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejected_credentials_exit_with_77() {
+        // What librespot panics with when the access point rejects the
+        // password, and what spotifyd panics with once it caught that.
+        let rejected = "Authentication failed with reason: BadCredentials";
+        assert_eq!(Failure::of_message(rejected), Failure::Auth);
+        let caught = message(ErrorCode::BadCredentials, &[&rejected]);
+        assert_eq!(Failure::of_message(&caught).exit_code(), 77);
+    }
+}
@@ -0,0 +1,25 @@
+use librespot::playback::mixer::{AudioFilter, Mixer};
+
+/// Always plays at full volume and ignores volume changes from Connect, for
+/// setups where the amplifier controls the volume.
+pub struct FixedMixer;
+
+impl Mixer for FixedMixer {
+    fn open() -> FixedMixer {
+        FixedMixer
+    }
+
+    fn start(&self) {}
+
+    fn stop(&self) {}
+
+    fn volume(&self) -> u16 {
+        u16::max_value()
+    }
+
+    fn set_volume(&self, _volume: u16) {}
+
+    fn get_audio_filter(&self) -> Option<Box<AudioFilter + Send>> {
+        None
+    }
+}
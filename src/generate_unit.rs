@@ -0,0 +1,142 @@
+use crate::{cli, config};
+use getopts::Options;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+/// Prints a systemd unit running spotifyd with the configuration found, for
+/// `spotifyd generate-unit --user|--system`. A system unit runs as the user
+/// given with `--run-as`, or as the one calling it, e.g. through `sudo`.
+pub fn run(args: &[String]) -> ! {
+    let mut opts = Options::new();
+    opts.optflag("", "user", "A unit for the user's systemd instance.");
+    opts.optflag("", "system", "A unit for the system's systemd instance.");
+    opts.optopt("c", "config", "Path to the config file.", "CONFIG");
+    opts.optopt("", "run-as", "User a system unit runs as.", "USER");
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(f) => usage(&opts, &f.to_string()),
+    };
+    if matches.opt_present("user") == matches.opt_present("system") {
+        usage(&opts, "Either --user or --system is needed.");
+    }
+    let system = matches.opt_present("system");
+
+    let config_path = matches
+        .opt_str("config")
+        .map(PathBuf::from)
+        .or_else(|| config::get_config_file().ok())
+        .map(|p| fs::canonicalize(&p).unwrap_or(p));
+    config::set_dry_run();
+    let no_options = cli::command_line_argument_options()
+        .parse(Vec::<String>::new())
+        .unwrap();
+    let config = config::get_config(config_path.as_ref(), &no_options);
+
+    let exe = env::current_exe()
+        .ok()
+        .and_then(|p| fs::canonicalize(p).ok())
+        .unwrap_or_else(|| PathBuf::from("/usr/bin/spotifyd"));
+    let mut exec_start = format!("{} --no-daemon", quote(&exe));
+    if let Some(ref path) = config_path {
+        exec_start.push_str(&format!(" --config {}", quote(path)));
+    }
+    let uses_dbus = cfg!(feature = "dbus_mpris") || config.inhibit_sleep;
+
+    let mut unit = vec![
+        "[Unit]",
+        "Description=A spotify playing daemon",
+        "Documentation=https://github.com/Spotifyd/spotifyd",
+        "Wants=sound.target",
+        "After=sound.target",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect::<Vec<_>>();
+    // User instances have no network-online.target.
+    if system {
+        unit.push("Wants=network-online.target".to_string());
+        unit.push("After=network-online.target".to_string());
+    }
+    if uses_dbus {
+        unit.push("Requires=dbus.socket".to_string());
+        unit.push("After=dbus.socket".to_string());
+    }
+    unit.push(String::new());
+    unit.push("[Service]".to_string());
+    unit.push("Type=notify".to_string());
+    unit.push(format!("ExecStart={}", exec_start));
+    unit.push("ExecReload=/bin/kill -HUP $MAINPID".to_string());
+    unit.push("WatchdogSec=30".to_string());
+    // Spotifyd is only ready once it has waited for the network.
+    if config.wait_for_network {
+        unit.push("TimeoutStartSec=infinity".to_string());
+    }
+    unit.push("Restart=always".to_string());
+    unit.push("RestartSec=12".to_string());
+    // A wrong config or account stays wrong until someone fixes it.
+    unit.push("RestartPreventExitStatus=77 78".to_string());
+    if system {
+        let user = matches
+            .opt_str("run-as")
+            .or_else(|| env::var("SUDO_USER").ok())
+            .or_else(|| env::var("USER").ok())
+            .filter(|u| u != "root");
+        match user {
+            Some(user) => unit.push(format!("User={}", user)),
+            None => eprintln!(
+                "Warning: the unit runs spotifyd as root, give another user with --run-as."
+            ),
+        }
+        // For the sound cards, which belong to the audio group.
+        unit.push("SupplementaryGroups=audio".to_string());
+        if config.backend.as_ref().map(String::as_str) == Some("pulseaudio") {
+            eprintln!(
+                "Warning: PulseAudio usually runs per user, a --user unit may suit the pulseaudio \
+                 backend better."
+            );
+        }
+    }
+    unit.push(String::new());
+    unit.push("[Install]".to_string());
+    unit.push(format!(
+        "WantedBy={}",
+        if system {
+            "multi-user.target"
+        } else {
+            "default.target"
+        }
+    ));
+    println!("{}", unit.join("\n"));
+    eprintln!(
+        "Install it as {}",
+        if system {
+            "/etc/systemd/system/spotifyd.service"
+        } else {
+            "~/.config/systemd/user/spotifyd.service"
+        }
+    );
+    exit(0)
+}
+
+fn usage(opts: &Options, error: &str) -> ! {
+    eprintln!(
+        "Error: {}\n{}",
+        error,
+        opts.usage("Usage: spotifyd generate-unit --user|--system [options]")
+    );
+    exit(1)
+}
+
+/// Quotes a path for `ExecStart=` if it needs it.
+fn quote(path: &Path) -> String {
+    // systemd expands specifiers and variables in command lines.
+    let path = path.to_string_lossy().replace('%', "%%").replace('$', "$$");
+    if path.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        path
+    }
+}
@@ -0,0 +1,131 @@
+use crate::{
+    lirc::RemoteAction,
+    rotary_encoder::{open_input, read_value},
+};
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use log::{error, info};
+use std::{
+    io,
+    os::unix::io::AsRawFd,
+    thread,
+    time::{Duration, Instant},
+};
+
+const DEBOUNCE: Duration = Duration::from_millis(50);
+/// How often a volume button held down changes the volume again.
+const REPEAT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Push buttons on GPIO pins, each doing one thing to playback.
+pub struct GpioButtons {
+    pub commands: UnboundedReceiver<RemoteAction>,
+}
+
+impl GpioButtons {
+    pub fn new(buttons: Vec<(u32, RemoteAction)>) -> GpioButtons {
+        let (sender, commands) = unbounded();
+        thread::spawn(move || {
+            if let Err(e) = run(&buttons, &sender) {
+                error!("Couldn't read GPIO buttons: {}", e);
+            }
+        });
+        GpioButtons { commands }
+    }
+}
+
+struct Button {
+    action: RemoteAction,
+    down: bool,
+    changed: Instant,
+    /// The pin changed while bouncing, so it is read again once it settled.
+    unsettled: bool,
+    /// When the volume last changed for the button held down.
+    repeated: Instant,
+}
+
+impl Button {
+    /// How long until the button needs looking at again, if it does.
+    fn wait(&self) -> Option<Duration> {
+        let until = |start: Instant, interval: Duration| {
+            interval.checked_sub(start.elapsed()).unwrap_or_default()
+        };
+        let settled = if self.unsettled {
+            Some(until(self.changed, DEBOUNCE))
+        } else {
+            None
+        };
+        let repeat = if self.down && self.action.repeats() {
+            Some(until(self.repeated, REPEAT_INTERVAL))
+        } else {
+            None
+        };
+        settled.into_iter().chain(repeat).min()
+    }
+}
+
+fn run(buttons: &[(u32, RemoteAction)], sender: &UnboundedSender<RemoteAction>) -> io::Result<()> {
+    let mut pins = Vec::new();
+    for &(pin, _) in buttons {
+        pins.push(open_input(pin)?);
+    }
+    info!(
+        "Reading buttons on GPIO {}",
+        buttons
+            .iter()
+            .map(|&(pin, _)| pin.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let mut fds: Vec<libc::pollfd> = pins
+        .iter()
+        .map(|f| libc::pollfd {
+            fd: f.as_raw_fd(),
+            events: libc::POLLPRI | libc::POLLERR,
+            revents: 0,
+        })
+        .collect();
+    let mut states: Vec<Button> = buttons
+        .iter()
+        .map(|&(_, action)| Button {
+            action,
+            down: false,
+            changed: Instant::now(),
+            unsettled: false,
+            repeated: Instant::now(),
+        })
+        .collect();
+    loop {
+        // Bouncing buttons are read again, and held volume buttons repeat,
+        // so they need waking up for. The extra millisecond rounds up.
+        let timeout = match states.iter().filter_map(Button::wait).min() {
+            Some(wait) => wait.as_millis() as libc::c_int + 1,
+            None => -1,
+        };
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if ready < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for (pin, button) in pins.iter_mut().zip(states.iter_mut()) {
+            // The buttons pull their pins low.
+            let down = !read_value(pin)?;
+            button.unsettled = down != button.down && button.changed.elapsed() < DEBOUNCE;
+            if down != button.down && !button.unsettled {
+                button.down = down;
+                button.changed = Instant::now();
+                button.repeated = button.changed;
+                if down && sender.unbounded_send(button.action).is_err() {
+                    return Ok(());
+                }
+            } else if button.down
+                && button.action.repeats()
+                && button.repeated.elapsed() >= REPEAT_INTERVAL
+            {
+                button.repeated = Instant::now();
+                if sender.unbounded_send(button.action).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use crate::{device_state::DeviceState, socket_activation};
+use log::{error, info};
+use serde_json::json;
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    os::unix::net::UnixListener,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The main loop wakes up every few seconds at least, so one that hasn't run
+/// for this long is stuck.
+const STUCK_AFTER: Duration = Duration::from_secs(30);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Health {
+    /// When the main loop last ran.
+    heartbeat: Instant,
+    state: DeviceState,
+    session: bool,
+    spirc: bool,
+    device_lost: bool,
+    /// Underruns caused by the network and by the audio device.
+    underruns: (u64, u64),
+    /// Bytes received this session, today and in all, if they are counted.
+    data_usage: Option<(u64, u64, u64)>,
+}
+
+/// Answers health probes over HTTP, for container orchestrators and
+/// monitoring: `/healthz` fails once the main loop is stuck, and `/readyz`
+/// as long as spotifyd isn't logged in, registered and able to play.
+/// `/metrics` has the underrun counts and the data usage for Prometheus.
+pub struct HealthCheck(Arc<Mutex<Health>>);
+
+impl HealthCheck {
+    /// Listens on `address`, a Unix socket if it is a path and a TCP address
+    /// otherwise, unless systemd passes a socket named `health` of that kind.
+    pub fn new(address: &str) -> HealthCheck {
+        let health = Arc::new(Mutex::new(Health {
+            heartbeat: Instant::now(),
+            state: DeviceState::Disconnected,
+            session: false,
+            spirc: false,
+            device_lost: false,
+            underruns: (0, 0),
+            data_usage: None,
+        }));
+        let served = health.clone();
+        let listening = if address.starts_with('/') {
+            match socket_activation::unix_listener("health") {
+                Some(listener) => Ok(listener),
+                None => {
+                    let _ = fs::remove_file(address);
+                    UnixListener::bind(Path::new(address))
+                },
+            }
+            .map(|listener| {
+                thread::spawn(move || serve(listener.incoming(), &served));
+            })
+        } else {
+            match socket_activation::tcp_listener("health") {
+                Some(listener) => Ok(listener),
+                None => TcpListener::bind(address),
+            }
+            .map(|listener| {
+                thread::spawn(move || serve(listener.incoming(), &served));
+            })
+        };
+        match listening {
+            Ok(()) => info!("Answering health checks on {}", address),
+            Err(e) => error!("Couldn't listen for health checks on {}: {}", address, e),
+        }
+        HealthCheck(health)
+    }
+
+    /// Records how spotifyd is doing, on every turn of the main loop.
+    pub fn update(
+        &self,
+        state: DeviceState,
+        session: bool,
+        spirc: bool,
+        device_lost: bool,
+        underruns: (u64, u64),
+        data_usage: Option<(u64, u64, u64)>,
+    ) {
+        let mut health = self.0.lock().unwrap();
+        health.heartbeat = Instant::now();
+        health.state = state;
+        health.session = session;
+        health.spirc = spirc;
+        health.device_lost = device_lost;
+        health.underruns = underruns;
+        health.data_usage = data_usage;
+    }
+}
+
+/// Streams that can time out reading, TCP and Unix sockets alike.
+trait Connection: Read + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Connection for std::net::TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        std::net::TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl Connection for std::os::unix::net::UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+fn serve<C, I>(connections: I, health: &Mutex<Health>)
+where
+    C: Connection,
+    I: Iterator<Item = io::Result<C>>,
+{
+    for connection in connections {
+        if let Ok(mut connection) = connection {
+            let _ = connection.set_read_timeout(Some(READ_TIMEOUT));
+            let _ = answer(&mut connection, health);
+        }
+    }
+}
+
+fn answer<C: Connection>(connection: &mut C, health: &Mutex<Health>) -> io::Result<()> {
+    let mut request = String::new();
+    BufReader::new(&mut *connection).read_line(&mut request)?;
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    let (live, ready, body, metrics) = {
+        let health = health.lock().unwrap();
+        let live = health.heartbeat.elapsed() < STUCK_AFTER;
+        let ready = live && health.session && health.spirc && !health.device_lost;
+        let body = json!({
+            "live": live,
+            "ready": ready,
+            "state": health.state.to_string(),
+            "session": health.session,
+            "spirc": health.spirc,
+            "audio_device": !health.device_lost,
+            "underruns": {
+                "network": health.underruns.0,
+                "device": health.underruns.1,
+            },
+            "data_usage": health.data_usage.map(|(session, today, total)| json!({
+                "session": session,
+                "today": today,
+                "total": total,
+            })),
+        });
+        let mut metrics = format!(
+            "# HELP spotifyd_underruns_total Times the audio device ran out of audio.\n# TYPE \
+             spotifyd_underruns_total counter\nspotifyd_underruns_total{{cause=\"network\"}} \
+             {}\nspotifyd_underruns_total{{cause=\"device\"}} {}\n",
+            health.underruns.0, health.underruns.1
+        );
+        if let Some((session, today, total)) = health.data_usage {
+            metrics += &format!(
+                "# HELP spotifyd_received_bytes Bytes received from Spotify.\n# TYPE \
+                 spotifyd_received_bytes gauge\nspotifyd_received_bytes{{period=\"session\"}} \
+                 {}\nspotifyd_received_bytes{{period=\"today\"}} {}\n",
+                session, today
+            );
+            metrics += &format!(
+                "# HELP spotifyd_received_bytes_total Bytes received from Spotify since spotifyd \
+                 started.\n# TYPE spotifyd_received_bytes_total \
+                 counter\nspotifyd_received_bytes_total {}\n",
+                total
+            );
+        }
+        (live, ready, body.to_string(), metrics)
+    };
+    if path == "/metrics" {
+        return write!(
+            connection,
+            "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: \
+             {}\r\nConnection: close\r\n\r\n{}",
+            metrics.len(),
+            metrics
+        );
+    }
+    let status = match path {
+        "/healthz" | "/livez" if live => "200 OK",
+        "/healthz" | "/livez" => "503 Service Unavailable",
+        "/readyz" if ready => "200 OK",
+        "/readyz" => "503 Service Unavailable",
+        _ => "404 Not Found",
+    };
+    write!(
+        connection,
+        "HTTP/1.0 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: \
+         close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
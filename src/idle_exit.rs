@@ -0,0 +1,33 @@
+use futures::{Async, Stream};
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, Interval};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Tells when nothing has played for a while, for `exit_after_idle_secs`.
+pub struct IdleExit {
+    limit: Duration,
+    idle_since: Option<Instant>,
+    interval: Interval,
+}
+
+impl IdleExit {
+    pub fn new(limit: Duration, handle: &Handle) -> IdleExit {
+        IdleExit {
+            limit,
+            idle_since: None,
+            interval: Interval::new(CHECK_INTERVAL, handle).unwrap(),
+        }
+    }
+
+    /// Whether playback has been idle for longer than the limit.
+    pub fn poll(&mut self, playing: bool) -> bool {
+        while let Ok(Async::Ready(Some(()))) = self.interval.poll() {}
+        if playing {
+            self.idle_since = None;
+            return false;
+        }
+        let idle_since = *self.idle_since.get_or_insert_with(Instant::now);
+        idle_since.elapsed() >= self.limit
+    }
+}
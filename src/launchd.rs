@@ -0,0 +1,122 @@
+use crate::config;
+use getopts::Options;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{exit, Command},
+};
+
+const LABEL: &str = "rs.spotifyd";
+
+/// Sets spotifyd up as a LaunchAgent on macOS, for
+/// `spotifyd install-launchd`: writes its property list to
+/// `~/Library/LaunchAgents` and loads it, so that launchd starts spotifyd on
+/// login and again whenever it exits.
+pub fn run(args: &[String]) -> ! {
+    let mut opts = Options::new();
+    opts.optopt("c", "config", "Path to the config file.", "CONFIG");
+    opts.optflag("", "print", "Print the property list instead.");
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(f) => usage(&opts, &f.to_string()),
+    };
+    let config_path = matches
+        .opt_str("config")
+        .map(PathBuf::from)
+        .or_else(|| config::get_config_file().ok())
+        .map(|p| fs::canonicalize(&p).unwrap_or(p));
+    let home = match env::var_os("HOME") {
+        Some(home) => PathBuf::from(home),
+        None => usage(&opts, "HOME isn't set."),
+    };
+
+    let exe = env::current_exe()
+        .ok()
+        .and_then(|p| fs::canonicalize(p).ok())
+        .unwrap_or_else(|| PathBuf::from("/usr/local/bin/spotifyd"));
+    let mut arguments = vec![exe, PathBuf::from("--no-daemon")];
+    if let Some(path) = config_path {
+        arguments.push(PathBuf::from("--config"));
+        arguments.push(path);
+    }
+    let log = home.join("Library/Logs/spotifyd.log");
+
+    let mut plist = vec![
+        r#"<?xml version="1.0" encoding="UTF-8"?>"#.to_string(),
+        r#"<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">"#.to_string(),
+        r#"<plist version="1.0">"#.to_string(),
+        "<dict>".to_string(),
+        "    <key>Label</key>".to_string(),
+        format!("    <string>{}</string>", LABEL),
+        "    <key>ProgramArguments</key>".to_string(),
+        "    <array>".to_string(),
+    ];
+    for argument in &arguments {
+        plist.push(format!("        <string>{}</string>", escape(argument)));
+    }
+    plist.push("    </array>".to_string());
+    plist.push("    <key>RunAtLoad</key>".to_string());
+    plist.push("    <true/>".to_string());
+    plist.push("    <key>KeepAlive</key>".to_string());
+    plist.push("    <true/>".to_string());
+    // Background agents are throttled, which playback can't afford.
+    plist.push("    <key>ProcessType</key>".to_string());
+    plist.push("    <string>Interactive</string>".to_string());
+    // Without a file to go to, launchd drops the output.
+    for &key in &["StandardOutPath", "StandardErrorPath"] {
+        plist.push(format!("    <key>{}</key>", key));
+        plist.push(format!("    <string>{}</string>", escape(&log)));
+    }
+    plist.push("</dict>".to_string());
+    plist.push("</plist>".to_string());
+    let plist = plist.join("\n") + "\n";
+
+    if matches.opt_present("print") {
+        print!("{}", plist);
+        exit(0)
+    }
+    let agents = home.join("Library/LaunchAgents");
+    let path = agents.join(format!("{}.plist", LABEL));
+    if let Err(e) = fs::create_dir_all(&agents).and_then(|_| fs::write(&path, plist)) {
+        eprintln!("Error: couldn't write {:?}: {}", path, e);
+        exit(1)
+    }
+    println!("Wrote {}", path.display());
+    // Loading it again picks up changes, which needs unloading first.
+    let _ = Command::new("launchctl").arg("unload").arg(&path).status();
+    match Command::new("launchctl")
+        .args(&["load", "-w"])
+        .arg(&path)
+        .status()
+    {
+        Ok(ref status) if status.success() => {
+            println!(
+                "Loaded it, spotifyd now runs on login and logs to {}",
+                log.display()
+            );
+            exit(0)
+        },
+        Ok(_) => exit(1),
+        Err(e) => {
+            eprintln!("Error: couldn't run launchctl: {}", e);
+            exit(1)
+        },
+    }
+}
+
+fn usage(opts: &Options, error: &str) -> ! {
+    eprintln!(
+        "Error: {}\n{}",
+        error,
+        opts.usage("Usage: spotifyd install-launchd [options]")
+    );
+    exit(1)
+}
+
+/// Escapes a path for XML.
+fn escape(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,126 @@
+use crate::config::LircConfig;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use librespot::connect::spirc::Spirc;
+use log::{info, warn};
+use std::{
+    io::{BufRead, BufReader},
+    os::unix::net::UnixStream,
+    str::FromStr,
+    thread,
+    time::Duration,
+};
+
+pub const DEFAULT_SOCKET: &str = "/var/run/lirc/lircd";
+/// How long to wait before connecting to lircd again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What a button of the remote does.
+#[derive(Clone, Copy, Debug)]
+pub enum RemoteAction {
+    PlayPause,
+    Play,
+    Pause,
+    Next,
+    Previous,
+    VolumeUp,
+    VolumeDown,
+}
+
+impl FromStr for RemoteAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "play_pause" | "playpause" => Ok(RemoteAction::PlayPause),
+            "play" => Ok(RemoteAction::Play),
+            "pause" => Ok(RemoteAction::Pause),
+            "next" => Ok(RemoteAction::Next),
+            "previous" | "prev" => Ok(RemoteAction::Previous),
+            "volume_up" => Ok(RemoteAction::VolumeUp),
+            "volume_down" => Ok(RemoteAction::VolumeDown),
+            _ => Err(()),
+        }
+    }
+}
+
+impl RemoteAction {
+    pub fn apply(self, spirc: &Spirc) {
+        match self {
+            RemoteAction::PlayPause => spirc.play_pause(),
+            RemoteAction::Play => spirc.play(),
+            RemoteAction::Pause => spirc.pause(),
+            RemoteAction::Next => spirc.next(),
+            RemoteAction::Previous => spirc.prev(),
+            RemoteAction::VolumeUp => spirc.volume_up(),
+            RemoteAction::VolumeDown => spirc.volume_down(),
+        }
+    }
+
+    /// Whether holding the button down repeats it.
+    pub fn repeats(self) -> bool {
+        match self {
+            RemoteAction::VolumeUp | RemoteAction::VolumeDown => true,
+            _ => false,
+        }
+    }
+}
+
+/// Controls playback with an IR remote, through the buttons lircd reports.
+pub struct Lirc {
+    pub commands: UnboundedReceiver<RemoteAction>,
+}
+
+impl Lirc {
+    pub fn new(config: LircConfig) -> Lirc {
+        let (sender, commands) = unbounded();
+        thread::spawn(move || run(&config, &sender));
+        Lirc { commands }
+    }
+}
+
+/// Reads lircd's socket, and connects again whenever lircd goes away.
+fn run(config: &LircConfig, sender: &UnboundedSender<RemoteAction>) {
+    let mut connected = true;
+    loop {
+        match UnixStream::connect(&config.socket) {
+            Ok(stream) => {
+                info!("Reading IR remote buttons from {:?}", config.socket);
+                connected = true;
+                for line in BufReader::new(stream).lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+                    if let Some(action) = action(config, &line) {
+                        if sender.unbounded_send(action).is_err() {
+                            return;
+                        }
+                    }
+                }
+                warn!("lircd closed {:?}", config.socket);
+            },
+            Err(e) => {
+                // Only the first of a series of failures is worth logging.
+                if connected {
+                    warn!("Couldn't connect to lircd at {:?}: {}", config.socket, e);
+                }
+                connected = false;
+            },
+        }
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// The action for a line from lircd, `CODE REPEAT BUTTON REMOTE`, where the
+/// repeat count is in hex.
+fn action(config: &LircConfig, line: &str) -> Option<RemoteAction> {
+    let mut fields = line.split_whitespace().skip(1);
+    let repeat = u32::from_str_radix(fields.next()?, 16).ok()?;
+    let button = fields.next()?;
+    let &(_, action) = config.buttons.iter().find(|&&(ref b, _)| b == button)?;
+    if repeat == 0 || action.repeats() {
+        Some(action)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,479 @@
+use crate::{config::SpotifydConfig, errors::Failure};
+use chrono::{DateTime, Datelike, Local, NaiveDate, Utc};
+use lazy_static::lazy_static;
+use librespot::playback::player::PlayerEvent;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    mem,
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::Mutex,
+};
+use syslog::{BasicLogger, Facility, Formatter3164};
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// How log records are written out.
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per record, for log shippers.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where to keep the log when it goes to a file, and when to start a new one.
+/// The play history is kept the same way.
+pub struct LogFile {
+    pub path: PathBuf,
+    /// Start a new file once the current one has this many bytes, if at all.
+    pub max_size: Option<u64>,
+    /// Start a new file each day or week, if at all.
+    pub period: Option<RotationPeriod>,
+    /// How many old files to keep, as `spotifyd.log.1` and up.
+    pub keep: u32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RotationPeriod {
+    Daily,
+    Weekly,
+}
+
+impl FromStr for RotationPeriod {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "daily" => Ok(RotationPeriod::Daily),
+            "weekly" => Ok(RotationPeriod::Weekly),
+            _ => Err(()),
+        }
+    }
+}
+
+lazy_static! {
+    /// The track playing, or played last, which log records are about.
+    static ref TRACK_ID: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Keeps track of the track log records are about.
+pub fn handle_event(event: &PlayerEvent) {
+    let track_id = match *event {
+        PlayerEvent::Started { track_id } => track_id,
+        PlayerEvent::Changed { new_track_id, .. } => new_track_id,
+        PlayerEvent::Stopped { .. } => return,
+    };
+    *TRACK_ID.lock().unwrap() = Some(track_id.to_base62());
+}
+
+fn track_id() -> Option<String> {
+    TRACK_ID.lock().unwrap().clone()
+}
+
+/// Which records to log, by the module they come from, in the style of
+/// `RUST_LOG`: `info,librespot=debug` logs everything at info level, and
+/// librespot's records down to debug. The longest module that matches wins.
+#[derive(Clone)]
+pub struct Filter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl FromStr for Filter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = Filter {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        };
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let mut parts = directive.splitn(2, '=');
+            let (name, level) = (parts.next().unwrap_or(""), parts.next());
+            match level {
+                Some(level) => {
+                    let level = level.parse().map_err(|_| ())?;
+                    filter.modules.push((name.to_string(), level));
+                },
+                None => match name.parse() {
+                    Ok(level) => filter.default = level,
+                    // A module on its own logs everything.
+                    Err(_) => filter.modules.push((name.to_string(), LevelFilter::Trace)),
+                },
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl Filter {
+    /// What `verbosity` logs: more of spotifyd's own records first, then
+    /// librespot's and the other libraries' too, up to everything.
+    pub fn verbosity(level: usize) -> Filter {
+        let (own, others) = match level {
+            0 => (LevelFilter::Info, LevelFilter::Info),
+            1 => (LevelFilter::Debug, LevelFilter::Info),
+            2 => (LevelFilter::Trace, LevelFilter::Debug),
+            _ => (LevelFilter::Trace, LevelFilter::Trace),
+        };
+        Filter {
+            default: others,
+            modules: vec![("spotifyd".to_string(), own)],
+        }
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|&&(ref module, _)| {
+                target == module || target.starts_with(&format!("{}::", module))
+            })
+            .max_by_key(|&&(ref module, _)| module.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    /// The most any module logs.
+    fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, ::std::cmp::max)
+    }
+}
+
+/// Sets up logging: to the log file, if there is one, otherwise to syslog
+/// when detaching, or to the journal when systemd runs spotifyd, or else to
+/// standard output.
+pub fn init(config: &SpotifydConfig, filter: Filter, detach: bool) {
+    let logger: Box<Log> = if let Some(ref log_file) = config.log_file {
+        match RotatingFile::open(log_file) {
+            Ok(file) => Box::new(FileLogger {
+                file,
+                format: config.log_format,
+            }),
+            Err(e) => {
+                eprintln!("Couldn't open the log file {:?}: {}", log_file.path, e);
+                Failure::System.exit();
+            },
+        }
+    } else if detach {
+        let formatter = Formatter3164 {
+            facility: Facility::LOG_DAEMON,
+            hostname: None,
+            process: "Spotifyd".to_string(),
+            pid: process::id() as i32,
+        };
+        Box::new(BasicLogger::new(
+            syslog::unix(formatter).expect("Couldn't initialize logger"),
+        ))
+    } else if let (LogFormat::Text, Some(journal)) = (config.log_format, journal()) {
+        Box::new(journal)
+    } else {
+        Box::new(ConsoleLogger {
+            format: config.log_format,
+            // Standard output carries the audio, so nothing else may go there.
+            to_stderr: config.backend.as_ref().map(String::as_str) == Some("stdout"),
+        })
+    };
+    log::set_max_level(filter.max());
+    log::set_boxed_logger(Box::new(Filtered {
+        inner: logger,
+        filter,
+    }))
+    .expect("Couldn't initialize logger");
+}
+
+/// Passes on the records the filter lets through.
+struct Filtered {
+    inner: Box<Log>,
+    filter: Filter,
+}
+
+impl Log for Filtered {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// The record as a line of text with its time, level and module.
+fn to_text(record: &Record) -> String {
+    format!(
+        "{} {} [{}] {}",
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+/// A file that lines are appended to, moved aside for a new one as a
+/// `LogFile` says. Besides the log, it keeps the play history.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    period: Option<RotationPeriod>,
+    keep: u32,
+    current: Mutex<CurrentFile>,
+}
+
+/// The file written to, with its size and the day it was started.
+struct CurrentFile {
+    file: File,
+    size: u64,
+    started: NaiveDate,
+}
+
+impl RotatingFile {
+    pub fn open(log_file: &LogFile) -> io::Result<RotatingFile> {
+        Ok(RotatingFile {
+            path: log_file.path.clone(),
+            max_size: log_file.max_size,
+            period: log_file.period,
+            keep: log_file.keep,
+            current: Mutex::new(open_current(&log_file.path)?),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends a line, which ends in a newline, starting a new file first if
+    /// it's time to. A line is still written when that fails.
+    pub fn append(&self, line: &str) -> io::Result<()> {
+        let mut current = self.current.lock().unwrap();
+        let rotated = if self.is_due(&current, line.len() as u64) {
+            self.rotate(&mut current)
+        } else {
+            Ok(())
+        };
+        current.file.write_all(line.as_bytes())?;
+        current.size += line.len() as u64;
+        rotated
+    }
+
+    fn flush(&self) {
+        let _ = self.current.lock().unwrap().file.flush();
+    }
+
+    /// Whether it's time for a new file before writing `length` bytes.
+    fn is_due(&self, current: &CurrentFile, length: u64) -> bool {
+        let today = Local::today().naive_local();
+        let outgrown = self
+            .max_size
+            .map_or(false, |max| current.size > 0 && current.size + length > max);
+        let expired = match self.period {
+            Some(RotationPeriod::Daily) => today != current.started,
+            Some(RotationPeriod::Weekly) => today.iso_week() != current.started.iso_week(),
+            None => false,
+        };
+        outgrown || expired
+    }
+
+    /// Moves the old files one number up, dropping the oldest, and starts
+    /// a new one.
+    fn rotate(&self, current: &mut CurrentFile) -> io::Result<()> {
+        let numbered = |n| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if self.keep == 0 {
+            let _ = fs::remove_file(&self.path);
+        } else {
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(numbered(n), numbered(n + 1));
+            }
+            let _ = fs::rename(&self.path, numbered(1));
+        }
+        *current = open_current(&self.path)?;
+        Ok(())
+    }
+}
+
+fn open_current(path: &Path) -> io::Result<CurrentFile> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    // A file left from before counts as started when it was last written.
+    let started = metadata
+        .modified()
+        .ok()
+        .map(|time| DateTime::<Local>::from(time).naive_local().date())
+        .unwrap_or_else(|| Local::today().naive_local());
+    Ok(CurrentFile {
+        file,
+        size: metadata.len(),
+        started,
+    })
+}
+
+struct FileLogger {
+    file: RotatingFile,
+    format: LogFormat,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let mut line = match self.format {
+            LogFormat::Text => to_text(record),
+            LogFormat::Json => to_json(record),
+        };
+        line.push('\n');
+        if let Err(e) = self.file.append(&line) {
+            eprintln!("Couldn't write the log file {:?}: {}", self.file.path(), e);
+        }
+    }
+
+    fn flush(&self) {
+        self.file.flush();
+    }
+}
+
+/// The record as a JSON object: its time, level, module and message, and
+/// the track it is about.
+fn to_json(record: &Record) -> String {
+    let mut object = json!({
+        "timestamp": Utc::now().to_rfc3339(),
+        "level": record.level().to_string(),
+        "module": record.target(),
+        "message": record.args().to_string(),
+    });
+    if let Some(track_id) = track_id() {
+        object["track_id"] = json!(track_id);
+    }
+    object.to_string()
+}
+
+/// Writes records to standard output or, if that carries the audio,
+/// standard error.
+struct ConsoleLogger {
+    format: LogFormat,
+    to_stderr: bool,
+}
+
+impl Log for ConsoleLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let line = match self.format {
+            LogFormat::Text => to_text(record),
+            LogFormat::Json => to_json(record),
+        };
+        let _ = if self.to_stderr {
+            writeln!(io::stderr(), "{}", line)
+        } else {
+            writeln!(io::stdout(), "{}", line)
+        };
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+    }
+}
+
+/// The journal, when systemd runs spotifyd with its output going there, as
+/// `JOURNAL_STREAM` tells. The variable is inherited by children whose
+/// output goes elsewhere, so it only counts if it names stderr's device and
+/// inode.
+fn journal() -> Option<JournalLogger> {
+    let stream = env::var("JOURNAL_STREAM").ok()?;
+    let mut ids = stream.splitn(2, ':').map(|id| id.parse::<u64>().ok());
+    let (dev, ino) = (ids.next()??, ids.next()??);
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(libc::STDERR_FILENO, &mut stat) } != 0 {
+        return None;
+    }
+    if stat.st_dev as u64 != dev || stat.st_ino as u64 != ino {
+        return None;
+    }
+    JournalLogger::connect().ok()
+}
+
+/// Writes records to the journal with their priority and where they come
+/// from, using the journal's native protocol, and the ID of the track they
+/// are about as `TRACK_ID`.
+struct JournalLogger {
+    socket: UnixDatagram,
+}
+
+impl JournalLogger {
+    fn connect() -> io::Result<JournalLogger> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNAL_SOCKET)?;
+        Ok(JournalLogger { socket })
+    }
+}
+
+impl Log for JournalLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let mut entry = Vec::new();
+        let priority = match record.level() {
+            Level::Error => "3",
+            Level::Warn => "4",
+            Level::Info => "6",
+            Level::Debug | Level::Trace => "7",
+        };
+        add_field(&mut entry, "PRIORITY", priority);
+        add_field(&mut entry, "MESSAGE", &record.args().to_string());
+        add_field(&mut entry, "SYSLOG_IDENTIFIER", "spotifyd");
+        add_field(&mut entry, "CODE_MODULE", record.target());
+        if let (Some(file), Some(line)) = (record.file(), record.line()) {
+            add_field(&mut entry, "CODE_FILE", file);
+            add_field(&mut entry, "CODE_LINE", &line.to_string());
+        }
+        if let Some(track_id) = track_id() {
+            add_field(&mut entry, "TRACK_ID", &track_id);
+        }
+        let _ = self.socket.send(&entry);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Adds a field as the native protocol has it. Values with line breaks are
+/// preceded by their length instead of an equals sign.
+fn add_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend(value.as_bytes());
+    entry.push(b'\n');
+}
@@ -1,29 +1,137 @@
+use crate::{
+    errors::{ErrorCode, Failure},
+    logging::Filter,
+};
 use daemonize::Daemonize;
-use log::{error, info, LevelFilter};
-use std::{convert::From, error::Error, panic, path::PathBuf, process::exit};
+use log::{error, info, warn};
+use std::{
+    convert::From,
+    fs, panic,
+    path::{Path, PathBuf},
+    process::{self, exit},
+    thread,
+};
 use tokio_core::reactor::Core;
 
+mod adaptive_bitrate;
+mod alarm;
 #[cfg(feature = "alsa_backend")]
 mod alsa_mixer;
+mod announcement;
+mod ap_policy;
+mod art;
+mod autoplay;
+mod backends;
+mod backoff;
+mod balance;
+mod cache_limit;
+mod check_config;
 mod cli;
+mod completions;
 mod config;
+mod control;
+mod controller_reconnect;
+mod cover_art_file;
+mod data_usage;
 #[cfg(feature = "dbus_mpris")]
 mod dbus_mpris;
+#[cfg(feature = "desktop_notifications")]
+mod desktop_notification;
+mod device_loss;
+mod device_state;
+#[cfg(feature = "display")]
+mod display;
+mod dither;
+mod equalizer;
+mod errors;
+mod fixed_mixer;
+mod generate_unit;
+#[cfg(feature = "gpio_buttons")]
+mod gpio_buttons;
+mod health;
+mod idle_exit;
+mod launchd;
+mod lirc;
+mod logging;
 mod main_loop;
+mod metadata_pipe;
+mod migrate_config;
+mod multiroom;
+mod oauth;
+mod play_history;
+mod playback_restore;
 mod player_event_handler;
+#[cfg(feature = "lua_plugins")]
+mod plugins;
+mod power_management;
+mod privileges;
+mod proxy;
+mod recently_played;
+mod reconnect_resume;
+mod rotary_encoder;
+#[cfg(target_os = "linux")]
+mod sandbox;
+mod save_points;
+#[cfg(feature = "serial_amp")]
+mod serial_amp;
 mod setup;
+mod signals;
+mod simulation;
+#[cfg(feature = "dbus_mpris")]
+mod sleep;
+mod sleep_timer;
+mod snapcast;
+mod socket_activation;
+mod systemd;
+mod takeover;
+mod toml_config;
+mod track_metadata;
+mod underruns;
+mod utils;
+mod volume_curve;
+mod wait_for_network;
+mod web_api;
 #[macro_use]
 mod macros;
 
 fn main() {
+    // What went wrong on the main thread, in the end by panicking, is told
+    // by the exit code.
+    if let Err(payload) = panic::catch_unwind(run) {
+        let message = match (
+            payload.downcast_ref::<String>(),
+            payload.downcast_ref::<&str>(),
+        ) {
+            (Some(s), _) => &**s,
+            (_, Some(&s)) => s,
+            _ => "",
+        };
+        Failure::of_message(message).exit();
+    }
+}
+
+fn run() {
     let opts = cli::command_line_argument_options();
     let args: Vec<String> = std::env::args().collect();
 
+    match args.get(1).map(String::as_str) {
+        Some("snapcast-control") => snapcast::run_control_script(&args[2..]),
+        Some("completions") => completions::run(&args[2..]),
+        Some("generate-unit") => generate_unit::run(&args[2..]),
+        Some("install-launchd") => launchd::run(&args[2..]),
+        Some("migrate-config") => migrate_config::run(&args[2..]),
+        Some("status") => control::run_status(&args[2..]),
+        Some(command) if control::COMMANDS.contains(&command) => control::run_command(&args[1..]),
+        _ => (),
+    }
+
+    socket_activation::init();
+
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
             println!("Error: {}\n{}", f.to_string(), cli::usage(&args[0], &opts));
-            exit(1)
+            Failure::Config.exit()
         },
     };
 
@@ -46,30 +154,58 @@ fn main() {
         .opt_str("config")
         .map(PathBuf::from)
         .or_else(|| config::get_config_file().ok());
-    let config = config::get_config(config_file, &matches);
+    if matches.opt_present("check-config") {
+        check_config::run(config_file, &matches);
+    }
+    if matches.opt_present("list-devices") {
+        // Only the backend is wanted, so no password is asked for.
+        config::set_dry_run();
+        let config = config::get_config(config_file, &matches);
+        backends::list_devices(config.backend.as_ref().map(String::as_str));
+        exit(0);
+    }
+    let mut config = config::get_config(config_file.clone(), &matches);
+    let oauth_login = if matches.opt_present("oauth-device-login") {
+        Some(oauth::authorize_device as fn() -> _)
+    } else if matches.opt_present("oauth-login") {
+        Some(oauth::authorize as fn() -> _)
+    } else {
+        None
+    };
+    if let Some(login) = oauth_login {
+        // Before detaching, the user has to follow the instructions.
+        match login() {
+            Ok(tokens) => {
+                if let (Some(ref refresh_token), Some(cache_path)) =
+                    (tokens.refresh_token, config.cache_path.as_ref())
+                {
+                    oauth::store_refresh_token(cache_path, refresh_token);
+                }
+                config.oauth_token = Some(tokens.access_token);
+            },
+            Err(e) => {
+                eprintln!("{}", errors::message(ErrorCode::OAuthFailed, &[&e]));
+                ErrorCode::OAuthFailed.failure().exit();
+            },
+        }
+    }
+    let audio_to_stdout = config.backend.as_ref().map(String::as_str) == Some("stdout");
 
-    if matches.opt_present("no-daemon") {
-        let filter = if matches.opt_present("verbose") {
-            simplelog::LogLevelFilter::Trace
-        } else {
-            simplelog::LogLevelFilter::Info
-        };
+    // -v is for debugging this once, logging everything over what the
+    // config file says.
+    let filter = match config.log_filter.take() {
+        _ if matches.opt_present("verbose") => Filter::verbosity(config::MAX_VERBOSITY),
+        Some(filter) => filter,
+        None => Filter::verbosity(0),
+    };
+    logging::init(&config, filter, !matches.opt_present("no-daemon"));
 
-        simplelog::TermLogger::init(filter, simplelog::Config::default())
-            .map_err(Box::<Error>::from)
-            .or_else(|_| {
-                simplelog::SimpleLogger::init(filter, simplelog::Config::default())
-                    .map_err(Box::<Error>::from)
-            })
-            .expect("Couldn't initialize logger");
-    } else {
-        let filter = if matches.opt_present("verbose") {
-            LevelFilter::Trace
-        } else {
-            LevelFilter::Info
-        };
-        syslog::init(syslog::Facility::LOG_DAEMON, filter, Some("Spotifyd"))
-            .expect("Couldn't initialize logger");
+    if !matches.opt_present("no-daemon") {
+        if audio_to_stdout {
+            warn!(
+                "The stdout backend needs --no-daemon, standard output is closed when detaching."
+            );
+        }
 
         let mut daemonize = Daemonize::new();
         if let Some(pid) = config.pid.as_ref() {
@@ -77,28 +213,109 @@ fn main() {
         }
         match daemonize.start() {
             Ok(_) => info!("Detached from shell, now running in background."),
-            Err(e) => error!("Something went wrong while daemonizing: {}", e),
+            Err(e) => error!("{}", errors::message(ErrorCode::DaemonizeFailed, &[&e])),
         };
     }
+    if let (true, Some(pid)) = (matches.opt_present("no-daemon"), config.pid.as_ref()) {
+        // Detaching writes the PID file, otherwise spotifyd does.
+        if let Err(e) = fs::write(pid, format!("{}\n", process::id())) {
+            warn!("Couldn't write the PID file {:?}: {}", pid, e);
+        }
+    }
 
     panic::set_hook(Box::new(|panic_info| {
-        error!(
-            "Caught panic with message: {}",
-            match (
-                panic_info.payload().downcast_ref::<String>(),
-                panic_info.payload().downcast_ref::<&str>(),
-            ) {
-                (Some(s), _) => &**s,
-                (_, Some(&s)) => s,
-                _ => "Unknown error type, can't produce message.",
+        let message = match (
+            panic_info.payload().downcast_ref::<String>(),
+            panic_info.payload().downcast_ref::<&str>(),
+        ) {
+            (Some(s), _) => &**s,
+            (_, Some(&s)) => s,
+            _ => "Unknown error type, can't produce message.",
+        };
+        error!("Caught panic with message: {}", message);
+        // The main thread's panics end up in main, those of the others would
+        // only end their thread, so an error spotifyd can't go on after
+        // exits here.
+        if thread::current().name() != Some("main") {
+            if let Some(code) = ErrorCode::in_message(message) {
+                code.failure().exit();
             }
-        );
+        }
     }));
 
     let mut core = Core::new().unwrap();
     let handle = core.handle();
 
-    let initial_state = setup::initial_state(handle, config);
+    let pid = config.pid.clone();
+    let (user, group) = (config.user.take(), config.group.take());
+    let cache_path = config.cache_path.clone();
+    let audio_cache_path = config.audio_cache_path.clone();
+    if cfg!(not(target_os = "linux")) && config.sandbox {
+        warn!("sandbox is ignored, it only works on Linux.");
+    }
+    #[cfg(target_os = "linux")]
+    let sandbox = if config.sandbox {
+        Some((config_file.clone(), sandbox::writable_paths(&config)))
+    } else {
+        None
+    };
+    if let Some(leader) = config.multiroom_leader.take() {
+        // A follower doesn't log in, it only plays what the leader sends.
+        let backend = setup::find_backend(config.backend.as_ref().map(String::as_str));
+        backends::set_pulseaudio_properties();
+        backends::set_output(config.audio_format, config.sample_rate, config.channel_map);
+        drop_privileges(user.as_ref(), group.as_ref(), &[]);
+        multiroom::follow(
+            &leader,
+            backend,
+            config.audio_device.take(),
+            config.latency_offset_ms,
+        );
+    }
+    let read_config = Box::new(move || {
+        // Reloading keeps the password from startup: password_cmd isn't run
+        // on the main loop again, and the password descriptor is closed by
+        // now, if not reused.
+        config::set_dry_run();
+        config::get_config(config_file.clone(), &matches)
+    });
+    // Before the components start their threads, which would be left out.
+    // Switching users later still works in it.
+    #[cfg(target_os = "linux")]
+    {
+        if let Some((config_file, writable)) = sandbox {
+            sandbox::apply(config_file.as_ref().map(PathBuf::as_path), &writable);
+        }
+    }
+    let initial_state = setup::initial_state(handle, config, read_config);
+    let owned: Vec<&Path> = cache_path
+        .iter()
+        .chain(audio_cache_path.iter())
+        .map(PathBuf::as_path)
+        .collect();
+    drop_privileges(user.as_ref(), group.as_ref(), &owned);
 
+    let data_usage = initial_state.spotifyd_state.data_usage.clone();
     core.run(initial_state).unwrap();
+
+    if let Some(usage) = data_usage {
+        usage.save();
+    }
+
+    if let Some(pid) = pid {
+        let _ = fs::remove_file(pid);
+    }
+}
+
+/// Switches to `user` and `group`, if given, handing `owned` over to them,
+/// or exits.
+fn drop_privileges(user: Option<&String>, group: Option<&String>, owned: &[&Path]) {
+    if let Err(e) = privileges::drop_to(user.map(String::as_str), group.map(String::as_str), owned)
+    {
+        error!(
+            "{}",
+            errors::message(ErrorCode::DropPrivilegesFailed, &[&e])
+        );
+        ErrorCode::DropPrivilegesFailed.failure().exit();
+    }
 }
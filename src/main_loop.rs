@@ -1,13 +1,76 @@
 #[cfg(feature = "dbus_mpris")]
 use crate::dbus_mpris::DbusServer;
-use crate::player_event_handler::run_program_on_events;
-use futures::{self, Async, Future, Poll, Stream};
+#[cfg(feature = "desktop_notifications")]
+use crate::desktop_notification::DesktopNotifier;
+#[cfg(feature = "display")]
+use crate::display::Display;
+#[cfg(feature = "gpio_buttons")]
+use crate::gpio_buttons::GpioButtons;
+#[cfg(feature = "lua_plugins")]
+use crate::plugins::Plugins;
+#[cfg(feature = "serial_amp")]
+use crate::serial_amp::SerialAmp;
+#[cfg(feature = "dbus_mpris")]
+use crate::sleep::{SleepEvent, SleepInhibitor};
+use crate::{
+    adaptive_bitrate::AdaptiveBitrate,
+    alarm::Alarms,
+    announcement::Announcer,
+    ap_policy::SharedApPolicy,
+    autoplay::Autoplay,
+    backends::{
+        BufferedSink, DelayedStartSink, FadeSink, LatencyOffsetSink, LimiterSink, MultiroomLeader,
+        MultiroomSink, RecoveringSink, SilenceTrimSink, SinkBuilder, StallDetectingSink, TeeSink,
+        UnderrunSink,
+    },
+    backoff::Backoff,
+    balance::SharedBalance,
+    cache_limit::CacheLimit,
+    config::{LimiterConfig, SilenceTrimConfig, SpotifydConfig},
+    control::{Control, SharedStatus},
+    controller_reconnect::ControllerReconnect,
+    cover_art_file::CoverArtFile,
+    data_usage::SharedDataUsage,
+    device_loss::DeviceLoss,
+    device_state::{DeviceState, SharedDeviceState},
+    equalizer::SharedEqualizer,
+    errors::{self, ErrorCode},
+    health::HealthCheck,
+    idle_exit::IdleExit,
+    lirc::Lirc,
+    logging,
+    metadata_pipe::MetadataPipe,
+    play_history::PlayHistory,
+    playback_restore::PlaybackRestore,
+    player_event_handler::{Details, Event, EventProgram},
+    power_management::PowerManager,
+    recently_played::SharedRecentlyPlayed,
+    reconnect_resume::ReconnectResume,
+    rotary_encoder::RotaryEncoder,
+    save_points::{SavePointTracker, SharedSavePoints},
+    signals::PlaybackSignals,
+    simulation::Simulator,
+    sleep_timer::{SharedSleepTimer, SleepTimer},
+    snapcast::Snapcast,
+    systemd::Notifier,
+    takeover::Takeover,
+    track_metadata::MetadataFetcher,
+    underruns::Underruns,
+    volume_curve::VolumeSettings,
+    web_api::TokenProvider,
+};
+use futures::{
+    self,
+    sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    Async, Future, Poll, Stream,
+};
 use librespot::{
     connect::{
-        discovery::DiscoveryStream,
+        discovery::{discovery, DiscoveryStream},
         spirc::{Spirc, SpircTask},
     },
     core::{
+        authentication::Credentials,
         cache::Cache,
         config::{ConnectConfig, DeviceType, SessionConfig},
         session::Session,
@@ -19,26 +82,41 @@ use librespot::{
         player::{Player, PlayerEvent},
     },
 };
-use std::{io, process::Child, rc::Rc};
+use log::{error, info, warn};
+use std::{
+    io,
+    panic::{self, AssertUnwindSafe},
+    rc::Rc,
+    time::Duration,
+};
 use tokio_core::reactor::Handle;
 use tokio_io::IoStream;
 
 pub struct LibreSpotConnection {
     connection: Box<Future<Item = Session, Error = io::Error>>,
+    /// What the last connection logged in with, to connect again.
+    credentials: Option<Credentials>,
     spirc_task: Option<SpircTask>,
     spirc: Option<Rc<Spirc>>,
-    discovery_stream: DiscoveryStream,
+    session: Option<Session>,
+    web_api: Option<TokenProvider>,
+    /// Logins through Spotify apps on the network, unless discovery is off.
+    discovery_stream: Option<DiscoveryStream>,
 }
 
 impl LibreSpotConnection {
     pub fn new(
         connection: Box<Future<Item = Session, Error = io::Error>>,
-        discovery_stream: DiscoveryStream,
+        credentials: Option<Credentials>,
+        discovery_stream: Option<DiscoveryStream>,
     ) -> LibreSpotConnection {
         LibreSpotConnection {
             connection,
+            credentials,
             spirc_task: None,
             spirc: None,
+            session: None,
+            web_api: None,
             discovery_stream,
         }
     }
@@ -48,30 +126,152 @@ pub struct AudioSetup {
     pub mixer: Box<FnMut() -> Box<Mixer>>,
     pub backend: fn(Option<String>) -> Box<Sink>,
     pub audio_device: Option<String>,
+    /// Further backends and their devices that get a copy of the audio.
+    pub tee: Vec<(SinkBuilder, Option<String>)>,
+    /// How long to hold back audio when playback starts, if at all.
+    pub start_delay: Option<Duration>,
+    pub start_command: Option<String>,
+    /// How long volume ramps on starting and stopping take, if there are any.
+    pub fade: Option<Duration>,
+    /// How silence gets shortened, if it does.
+    pub trim_silence: Option<SilenceTrimConfig>,
+    /// Where peaks are limited to, if they are.
+    pub limiter: Option<LimiterConfig>,
+    /// How many samples playback may run ahead of the device, if any.
+    pub buffer: Option<usize>,
+    /// Milliseconds playback is delayed by, advanced if negative.
+    pub latency_offset_ms: i64,
+    /// Where the audio goes out to other instances, if it does.
+    pub multiroom: Option<MultiroomLeader>,
+    /// The volume to start at, used on the first connection only.
+    pub initial_volume: Option<u16>,
+    pub volume: VolumeSettings,
+    /// Records the volume for the control socket, if there is one.
+    pub status: Option<SharedStatus>,
+}
+
+impl AudioSetup {
+    fn new_mixer(&mut self) -> Box<Mixer> {
+        let mixer = self.volume.apply((self.mixer)());
+        match self.status {
+            Some(ref status) => status.mixer(mixer),
+            None => mixer,
+        }
+    }
 }
 
 pub struct SpotifydState {
-    pub ctrl_c_stream: IoStream<()>,
-    pub shutting_down: bool,
+    /// SIGINT and SIGTERM, to shut down.
+    pub shutdown_stream: IoStream<()>,
+    /// SIGHUP, to reload the config file.
+    pub hangup_stream: IoStream<()>,
+    pub playback_signals: PlaybackSignals,
+    pub state: SharedDeviceState,
     pub cache: Option<Cache>,
+    pub cache_limit: Option<CacheLimit>,
     pub device_name: String,
-    pub player_event_channel: Option<futures::sync::mpsc::UnboundedReceiver<PlayerEvent>>,
-    pub player_event_program: Option<String>,
+    pub player_event_channel: Option<UnboundedReceiver<PlayerEvent>>,
+    pub event_program: EventProgram,
+    pub metadata_pipe: Option<MetadataPipe>,
+    pub control: Option<Control>,
+    pub cover_art_file: Option<CoverArtFile>,
+    pub play_history: Option<PlayHistory>,
+    pub recently_played: Option<SharedRecentlyPlayed>,
+    pub power_manager: Option<PowerManager>,
+    pub snapcast: Option<Snapcast>,
+    pub save_points: Option<SharedSavePoints>,
+    pub save_point_tracker: Option<SavePointTracker>,
+    pub equalizer: Option<SharedEqualizer>,
+    pub balance: SharedBalance,
+    pub sleep_timer: SleepTimer,
+    pub takeover: Takeover,
+    /// Told the URI of each track that starts, for the D-Bus metadata.
+    pub track_changes: Option<UnboundedSender<String>>,
+    pub track_metadata: MetadataFetcher,
+    pub rotary_encoder: Option<RotaryEncoder>,
+    #[cfg(feature = "gpio_buttons")]
+    pub gpio_buttons: Option<GpioButtons>,
+    pub lirc: Option<Lirc>,
+    pub controller_reconnect: Option<ControllerReconnect>,
+    pub autoplay: Option<Autoplay>,
+    pub alarms: Option<Alarms>,
+    pub playback_restore: Option<PlaybackRestore>,
+    pub reconnect_resume: Option<ReconnectResume>,
+    pub adaptive_bitrate: Option<AdaptiveBitrate>,
+    pub device_loss: DeviceLoss,
+    pub underruns: Underruns,
+    pub data_usage: Option<SharedDataUsage>,
+    pub idle_exit: Option<IdleExit>,
+    pub health_check: Option<HealthCheck>,
+    pub notifier: Option<Notifier>,
+    pub simulator: Option<Simulator>,
+    #[cfg(feature = "serial_amp")]
+    pub serial_amp: Option<SerialAmp>,
+    #[cfg(feature = "dbus_mpris")]
+    pub sleep_inhibitor: Option<SleepInhibitor>,
+    #[cfg(feature = "display")]
+    pub display: Option<Display>,
+    #[cfg(feature = "desktop_notifications")]
+    pub desktop_notifier: Option<DesktopNotifier>,
+    #[cfg(feature = "lua_plugins")]
+    pub plugins: Option<Plugins>,
     pub dbus_mpris_server: Option<Box<Future<Item = (), Error = ()>>>,
 }
 
+impl SpotifydState {
+    /// Whether anything shows the tracks that play, which needs their
+    /// metadata.
+    fn shows_tracks(&self) -> bool {
+        #[allow(unused_mut)] // mut is needed behind the display and notification flags.
+        let mut shows = self.metadata_pipe.is_some()
+            || self.control.is_some()
+            || self.cover_art_file.is_some()
+            || self.snapcast.is_some()
+            || self.play_history.is_some()
+            || self.recently_played.is_some()
+            || self.notifier.is_some();
+        #[cfg(feature = "display")]
+        {
+            shows |= self.display.is_some();
+        }
+        #[cfg(feature = "desktop_notifications")]
+        {
+            shows |= self.desktop_notifier.is_some();
+        }
+        shows
+    }
+}
+
 #[cfg(feature = "dbus_mpris")]
 fn new_dbus_server(
     session: Session,
     handle: Handle,
     spirc: Rc<Spirc>,
     device_name: String,
+    save_points: Option<SharedSavePoints>,
+    state: SharedDeviceState,
+    equalizer: Option<SharedEqualizer>,
+    balance: SharedBalance,
+    sleep_timer: SharedSleepTimer,
+    recently_played: Option<SharedRecentlyPlayed>,
+    announcer: Announcer,
+    takeovers: UnboundedReceiver<String>,
+    track_changes: UnboundedReceiver<String>,
 ) -> Option<Box<Future<Item = (), Error = ()>>> {
     Some(Box::new(DbusServer::new(
         session,
         handle,
         spirc,
         device_name,
+        save_points,
+        state,
+        equalizer,
+        balance,
+        sleep_timer,
+        recently_played,
+        announcer,
+        takeovers,
+        track_changes,
     )))
 }
 
@@ -81,115 +281,780 @@ fn new_dbus_server(
     _: Handle,
     _: Rc<Spirc>,
     _: String,
+    _: Option<SharedSavePoints>,
+    _: SharedDeviceState,
+    _: Option<SharedEqualizer>,
+    _: SharedBalance,
+    _: SharedSleepTimer,
+    _: Option<SharedRecentlyPlayed>,
+    _: Announcer,
+    _: UnboundedReceiver<String>,
+    _: UnboundedReceiver<String>,
 ) -> Option<Box<Future<Item = (), Error = ()>>> {
     None
 }
 
+/// Polls the connection to Spotify. librespot panics when the access point
+/// won't log the account in, as in `Authentication failed with reason:
+/// PremiumAccountRequired`, so the panic is turned into an error with its
+/// message, like the other reasons connecting fails.
+fn poll_connection(
+    connection: &mut Box<Future<Item = Session, Error = io::Error>>,
+) -> Poll<Session, io::Error> {
+    panic::catch_unwind(AssertUnwindSafe(|| connection.poll())).unwrap_or_else(|payload| {
+        let message = match (
+            payload.downcast_ref::<String>(),
+            payload.downcast_ref::<&str>(),
+        ) {
+            (Some(s), _) => s.clone(),
+            (_, Some(&s)) => s.to_string(),
+            _ => "librespot failed to log in".to_string(),
+        };
+        Err(io::Error::new(io::ErrorKind::Other, message))
+    })
+}
+
+/// The access point rejects free accounts.
+fn is_premium_required(e: &io::Error) -> bool {
+    ErrorCode::of_login_failure(&e.to_string()) == Some(ErrorCode::PremiumRequired)
+}
+
+/// Trying again won't help with credentials the access point rejected.
+fn is_bad_credentials(e: &io::Error) -> bool {
+    ErrorCode::of_login_failure(&e.to_string()) == Some(ErrorCode::BadCredentials)
+}
+
 pub struct MainLoopState {
     pub librespot_connection: LibreSpotConnection,
     pub audio_setup: AudioSetup,
     pub spotifyd_state: SpotifydState,
     pub player_config: PlayerConfig,
     pub session_config: SessionConfig,
+    /// The access point ports to try in turn, none for any port.
+    pub ap_ports: Vec<u16>,
+    /// Which access points the resolver's answer is narrowed down to.
+    pub ap_policy: Option<SharedApPolicy>,
+    /// When to connect again after the connection was lost.
+    pub reconnect: Backoff,
     pub handle: Handle,
-    pub linear_volume: bool,
-    pub running_event_program: Option<Child>,
+    /// Reads the config file and the arguments again.
+    pub read_config: Box<Fn() -> SpotifydConfig>,
 }
 
+impl MainLoopState {
+    /// Shuts the player down, or returns true if there is none and spotifyd
+    /// can exit right away.
+    fn shut_down(&mut self) -> bool {
+        if self.spotifyd_state.state.get() != DeviceState::Draining {
+            match self.librespot_connection.spirc {
+                Some(ref spirc) => {
+                    spirc.shutdown();
+                    self.spotifyd_state.state.set(DeviceState::Draining);
+                    if let Some(ref notifier) = self.spotifyd_state.notifier {
+                        notifier.stopping();
+                    }
+                },
+                None => return true,
+            }
+        }
+        false
+    }
+
+    /// Applies the settings that can change while running from the config
+    /// file, as it is now. The player is created anew if those changed, which
+    /// registers it again with its new name.
+    fn reload(&mut self) {
+        let read_config = &self.read_config;
+        // Invalid settings panic, which shouldn't take spotifyd down.
+        let config = match panic::catch_unwind(AssertUnwindSafe(|| read_config())) {
+            Ok(config) => config,
+            Err(_) => {
+                warn!("Keeping the old settings, the config file has errors.");
+                return;
+            },
+        };
+        info!("Reloading the config file.");
+        self.spotifyd_state.event_program.program = config.onevent.clone();
+
+        let renamed = config.device_name != self.spotifyd_state.device_name;
+        let bitrate_changed = config.player_config.bitrate != self.player_config.bitrate;
+        let volume = VolumeSettings::new(&config);
+        let volume_changed = volume != self.audio_setup.volume;
+        self.spotifyd_state.device_name = config.device_name.clone();
+        if let Some(ref status) = self.audio_setup.status {
+            status.set_device_name(&config.device_name);
+        }
+        self.spotifyd_state
+            .takeover
+            .set_device_name(&config.device_name);
+        self.player_config.bitrate = config.player_config.bitrate;
+        self.audio_setup.volume = volume;
+        self.audio_setup.initial_volume = config
+            .initial_volume
+            .map(|v| (u32::from(v) * u32::from(u16::max_value()) / 100) as u16);
+
+        if renamed && self.librespot_connection.discovery_stream.is_some() {
+            // The old announcement has to go before the new one.
+            self.librespot_connection.discovery_stream = None;
+            let connect_config = ConnectConfig {
+                name: config.device_name.clone(),
+                device_type: DeviceType::default(),
+                volume: self.audio_setup.new_mixer().volume(),
+                linear_volume: true,
+            };
+            match discovery(
+                &self.handle,
+                connect_config,
+                self.session_config.device_id.clone(),
+                config.zeroconf_port,
+            ) {
+                Ok(stream) => self.librespot_connection.discovery_stream = Some(stream),
+                Err(e) => warn!("Couldn't announce the new name, discovery is off: {}", e),
+            }
+        }
+        if renamed || bitrate_changed || volume_changed {
+            if let Some(session) = self.librespot_connection.session.clone() {
+                self.start_player(session);
+            }
+        }
+    }
+
+    /// Drops the session, to connect again after a while.
+    fn reconnect_later(&mut self) {
+        self.librespot_connection.spirc_task = None;
+        self.librespot_connection.spirc = None;
+        self.librespot_connection.session = None;
+        self.librespot_connection.web_api = None;
+        self.spotifyd_state.dbus_mpris_server = None;
+        self.spotifyd_state.state.set(DeviceState::Disconnected);
+        if let Some(ref notifier) = self.spotifyd_state.notifier {
+            notifier.status("Reconnecting");
+        }
+        self.reconnect.schedule();
+    }
+
+    /// Creates the player and registers it with Spotify Connect.
+    fn start_player(&mut self, session: Session) {
+        let mixer = self.audio_setup.new_mixer();
+        let audio_filter = mixer.get_audio_filter();
+        let backend = self.audio_setup.backend;
+        let audio_device = self.audio_setup.audio_device.clone();
+        let tee = self.audio_setup.tee.clone();
+        let start_delay = self.audio_setup.start_delay;
+        let start_command = self.audio_setup.start_command.clone();
+        let fade = self.audio_setup.fade;
+        let trim_silence = self.audio_setup.trim_silence;
+        let limiter = self.audio_setup.limiter;
+        let buffer = self.audio_setup.buffer;
+        let latency_offset_ms = self.audio_setup.latency_offset_ms;
+        let multiroom = self.audio_setup.multiroom.clone();
+        let device_events = self.spotifyd_state.device_loss.events();
+        let underruns = self.spotifyd_state.underruns.reports();
+        let stalls = self
+            .spotifyd_state
+            .adaptive_bitrate
+            .as_ref()
+            .map(AdaptiveBitrate::stalls);
+        let build = move || {
+            let mut sink =
+                Box::new(RecoveringSink::new(backend, audio_device, device_events)) as Box<Sink>;
+            sink = Box::new(UnderrunSink::new(sink, underruns.clone()));
+            if let Some(stalls) = stalls {
+                sink = Box::new(StallDetectingSink::new(sink, stalls));
+            }
+            if !tee.is_empty() {
+                sink = Box::new(TeeSink::new(sink, &tee));
+            }
+            if latency_offset_ms != 0 {
+                sink = Box::new(LatencyOffsetSink::new(sink, latency_offset_ms));
+            }
+            if let Some(leader) = multiroom {
+                sink = Box::new(MultiroomSink::new(sink, leader));
+            }
+            if let Some(limiter) = limiter {
+                sink = Box::new(LimiterSink::new(
+                    sink,
+                    limiter.threshold_db,
+                    limiter.release,
+                ));
+            }
+            if let Some(trim) = trim_silence {
+                sink = Box::new(SilenceTrimSink::new(sink, trim.longest, trim.threshold_db));
+            }
+            if let Some(fade) = fade {
+                sink = Box::new(FadeSink::new(sink, fade));
+            }
+            if let Some(delay) = start_delay {
+                sink = Box::new(DelayedStartSink::new(sink, delay, start_command));
+            }
+            sink
+        };
+        let (player, event_channel) = Player::new(
+            self.player_config.clone(),
+            session.clone(),
+            audio_filter,
+            move || match buffer {
+                Some(size) => Box::new(BufferedSink::new(build, size)) as Box<Sink>,
+                None => build(),
+            },
+        );
+
+        self.spotifyd_state.player_event_channel = Some(event_channel);
+        self.spotifyd_state.simulator = None;
+
+        let (spirc, spirc_task) = Spirc::new(
+            ConnectConfig {
+                name: self.spotifyd_state.device_name.clone(),
+                device_type: DeviceType::default(),
+                volume: self
+                    .audio_setup
+                    .initial_volume
+                    .take()
+                    .unwrap_or_else(|| mixer.volume()),
+                // The mixer applies the volume curve.
+                linear_volume: true,
+            },
+            session.clone(),
+            player,
+            mixer,
+        );
+        self.librespot_connection.spirc_task = Some(spirc_task);
+        let shared_spirc = Rc::new(spirc);
+        self.librespot_connection.spirc = Some(shared_spirc.clone());
+        self.spotifyd_state.state.set(DeviceState::Registered);
+        if let Some(ref mut notifier) = self.spotifyd_state.notifier {
+            notifier.ready(&format!(
+                "Registered as {}",
+                self.spotifyd_state.device_name
+            ));
+        }
+
+        let (track_changes, tracks) = unbounded();
+        self.spotifyd_state.track_changes = Some(track_changes);
+        self.spotifyd_state.dbus_mpris_server = new_dbus_server(
+            session,
+            self.handle.clone(),
+            shared_spirc,
+            self.spotifyd_state.device_name.clone(),
+            self.spotifyd_state.save_points.clone(),
+            self.spotifyd_state.state.clone(),
+            self.spotifyd_state.equalizer.clone(),
+            self.spotifyd_state.balance.clone(),
+            self.spotifyd_state.sleep_timer.shared(),
+            self.spotifyd_state.recently_played.clone(),
+            Announcer::new(
+                self.audio_setup.backend,
+                self.audio_setup.audio_device.clone(),
+                self.audio_setup.fade,
+            ),
+            self.spotifyd_state.takeover.subscribe(),
+            tracks,
+        );
+    }
+}
+
+/// The main loop, as one hand-written future: each turn polls every part of
+/// spotifyd once, and it goes round again only when one of them made
+/// progress that others may act on.
+///
+/// It stays on futures 0.1 for as long as librespot does. Its session,
+/// Spirc, discovery and player are tokio-core futures, as are dbus-tokio and
+/// tokio-signal, and the pinned toolchain predates async/await, so moving to
+/// tasks on tokio 1.x has to wait for a librespot built on it.
 impl Future for MainLoopState {
     type Error = ();
     type Item = ();
 
     fn poll(&mut self) -> Poll<(), ()> {
         loop {
-            if let Async::Ready(Some(creds)) =
-                self.librespot_connection.discovery_stream.poll().unwrap()
-            {
+            let discovered = match self.librespot_connection.discovery_stream {
+                Some(ref mut stream) => match stream.poll().unwrap() {
+                    Async::Ready(Some(creds)) => Some(creds),
+                    _ => None,
+                },
+                None => None,
+            };
+            if let Some(creds) = discovered {
                 if let Some(ref mut spirc) = self.librespot_connection.spirc {
                     spirc.shutdown();
                 }
+                self.session_config.ap_port = self.ap_ports.first().cloned();
+                self.librespot_connection.credentials = Some(creds.clone());
                 let session_config = self.session_config.clone();
                 let cache = self.spotifyd_state.cache.clone();
                 let handle = self.handle.clone();
                 self.librespot_connection.connection =
                     Session::connect(session_config, creds, cache, handle);
+                self.spotifyd_state.state.set(DeviceState::Connecting);
             }
 
-            if let Some(mut child) = self.running_event_program.take() {
-                if let Ok(None) = child.try_wait() {
-                    self.running_event_program = Some(child);
+            if let Some(ref mut simulator) = self.spotifyd_state.simulator {
+                simulator.poll();
+            }
+            if let Some(ref mut cache_limit) = self.spotifyd_state.cache_limit {
+                cache_limit.poll();
+            }
+            #[cfg(feature = "lua_plugins")]
+            let plugins_pending = self
+                .spotifyd_state
+                .plugins
+                .as_ref()
+                .map_or(false, Plugins::is_pending);
+            #[cfg(not(feature = "lua_plugins"))]
+            let plugins_pending = false;
+            // Only ask for a Web API token when something needs one.
+            let token = if plugins_pending
+                || self.spotifyd_state.event_program.is_set()
+                || self.spotifyd_state.takeover.is_pending()
+                || self.spotifyd_state.save_point_tracker.is_some()
+                || self
+                    .spotifyd_state
+                    .controller_reconnect
+                    .as_ref()
+                    .map_or(false, ControllerReconnect::is_pending)
+                || self
+                    .spotifyd_state
+                    .autoplay
+                    .as_ref()
+                    .map_or(false, Autoplay::is_pending)
+                || self
+                    .spotifyd_state
+                    .alarms
+                    .as_ref()
+                    .map_or(false, Alarms::is_pending)
+                || self
+                    .spotifyd_state
+                    .control
+                    .as_ref()
+                    .map_or(false, Control::is_pending)
+                || self
+                    .spotifyd_state
+                    .playback_restore
+                    .as_ref()
+                    .map_or(false, PlaybackRestore::is_pending)
+                || self
+                    .spotifyd_state
+                    .reconnect_resume
+                    .as_ref()
+                    .map_or(false, ReconnectResume::is_pending)
+                || self
+                    .spotifyd_state
+                    .adaptive_bitrate
+                    .as_ref()
+                    .map_or(false, AdaptiveBitrate::is_resume_pending)
+            {
+                self.librespot_connection
+                    .web_api
+                    .as_mut()
+                    .and_then(TokenProvider::poll)
+            } else {
+                None
+            };
+            if let Some(ref mut player_event_channel) = self.spotifyd_state.player_event_channel {
+                while let Async::Ready(Some(event)) = player_event_channel.poll().unwrap() {
+                    self.spotifyd_state.state.handle_event(&event);
+                    self.spotifyd_state.sleep_timer.handle_event(&event);
+                    self.spotifyd_state.takeover.handle_event(&event);
+                    logging::handle_event(&event);
+                    if let Some(ref changes) = self.spotifyd_state.track_changes {
+                        match event {
+                            PlayerEvent::Started { track_id }
+                            | PlayerEvent::Changed {
+                                new_track_id: track_id,
+                                ..
+                            } => {
+                                let uri = format!("spotify:track:{}", track_id.to_base62());
+                                let _ = changes.unbounded_send(uri);
+                            },
+                            PlayerEvent::Stopped { .. } => (),
+                        }
+                    }
+                    if let Some(ref mut tracker) = self.spotifyd_state.save_point_tracker {
+                        tracker.handle_event(&event, token.clone());
+                    }
+                    if let Some(ref mut reconnect) = self.spotifyd_state.controller_reconnect {
+                        reconnect.handle_event(&event);
+                    }
+                    if let Some(ref mut autoplay) = self.spotifyd_state.autoplay {
+                        autoplay.handle_event(&event);
+                    }
+                    if let Some(ref mut restore) = self.spotifyd_state.playback_restore {
+                        restore.handle_event(&event);
+                    }
+                    if let Some(ref mut resume) = self.spotifyd_state.reconnect_resume {
+                        resume.handle_event(&event);
+                    }
+                    if self.spotifyd_state.shows_tracks() {
+                        self.spotifyd_state.track_metadata.handle_event(
+                            &event,
+                            self.librespot_connection.session.as_ref(),
+                            &self.handle,
+                        );
+                    }
+                    if let Some(ref pipe) = self.spotifyd_state.metadata_pipe {
+                        pipe.handle_event(&event);
+                    }
+                    if let Some(ref snapcast) = self.spotifyd_state.snapcast {
+                        snapcast.handle_event(&event);
+                    }
+                    if let Some(ref mut history) = self.spotifyd_state.play_history {
+                        history.handle_event(&event);
+                    }
+                    if let Some(ref recent) = self.spotifyd_state.recently_played {
+                        recent.handle_event(&event);
+                    }
+                    if let Some(ref power_manager) = self.spotifyd_state.power_manager {
+                        power_manager.handle_event(&event);
+                    }
+                    if let Some(ref notifier) = self.spotifyd_state.notifier {
+                        notifier.handle_event(&event);
+                    }
+                    #[cfg(feature = "serial_amp")]
+                    {
+                        if let Some(ref amp) = self.spotifyd_state.serial_amp {
+                            amp.handle_event(&event);
+                        }
+                    }
+                    #[cfg(feature = "display")]
+                    {
+                        if let Some(ref display) = self.spotifyd_state.display {
+                            display.handle_event(&event);
+                        }
+                    }
+                    #[cfg(feature = "lua_plugins")]
+                    {
+                        if let Some(ref plugins) = self.spotifyd_state.plugins {
+                            plugins.handle_event(&event);
+                        }
+                    }
+                    if self.spotifyd_state.event_program.is_set() {
+                        let details = Details::fetch(
+                            token.clone(),
+                            &self.spotifyd_state.device_name,
+                            &event,
+                            &self.handle,
+                        );
+                        self.spotifyd_state
+                            .event_program
+                            .push(Event::Player(event, details));
+                    }
                 }
             }
-            if self.running_event_program.is_none() {
-                if let Some(ref mut player_event_channel) = self.spotifyd_state.player_event_channel
+            // The metadata of a track is fetched once, for all that show it.
+            while let Async::Ready(Some(metadata)) =
+                self.spotifyd_state.track_metadata.poll().unwrap()
+            {
+                if let Some(ref pipe) = self.spotifyd_state.metadata_pipe {
+                    pipe.handle_metadata(&metadata);
+                }
+                if let Some(ref control) = self.spotifyd_state.control {
+                    control.handle_metadata(&metadata);
+                }
+                if let Some(ref cover) = self.spotifyd_state.cover_art_file {
+                    cover.handle_metadata(&metadata);
+                }
+                if let Some(ref snapcast) = self.spotifyd_state.snapcast {
+                    snapcast.handle_metadata(&metadata);
+                }
+                if let Some(ref mut history) = self.spotifyd_state.play_history {
+                    history.handle_metadata(&metadata);
+                }
+                if let Some(ref recent) = self.spotifyd_state.recently_played {
+                    recent.handle_metadata(&metadata);
+                }
+                if let Some(ref notifier) = self.spotifyd_state.notifier {
+                    notifier.handle_metadata(&metadata);
+                }
+                #[cfg(feature = "display")]
+                {
+                    if let Some(ref display) = self.spotifyd_state.display {
+                        display.handle_metadata(&metadata);
+                    }
+                }
+                #[cfg(feature = "desktop_notifications")]
+                {
+                    if let Some(ref notifier) = self.spotifyd_state.desktop_notifier {
+                        notifier.handle_metadata(&metadata);
+                    }
+                }
+            }
+            self.spotifyd_state.event_program.poll();
+
+            for device in self.spotifyd_state.takeover.poll(token.clone()) {
+                self.spotifyd_state
+                    .event_program
+                    .push(Event::TakenOver(device));
+            }
+            if let Some(ref mut reconnect) = self.spotifyd_state.controller_reconnect {
+                reconnect.poll(token.clone());
+            }
+            if let Some(ref mut autoplay) = self.spotifyd_state.autoplay {
+                autoplay.poll(token.clone());
+            }
+            if let Some(ref mut restore) = self.spotifyd_state.playback_restore {
+                restore.poll(token.clone());
+            }
+            if let Some(ref mut resume) = self.spotifyd_state.reconnect_resume {
+                resume.poll(token.clone());
+            }
+            if let Some(ref mut alarms) = self.spotifyd_state.alarms {
+                alarms.poll(token.clone());
+            }
+            if let Some(ref mut control) = self.spotifyd_state.control {
+                control.poll(
+                    self.librespot_connection.spirc.as_ref().map(|s| &**s),
+                    token.clone(),
+                );
+            }
+            #[cfg(feature = "lua_plugins")]
+            {
+                if let Some(ref mut plugins) = self.spotifyd_state.plugins {
+                    plugins.poll(
+                        self.librespot_connection.spirc.as_ref().map(|s| &**s),
+                        token.clone(),
+                        &self.spotifyd_state.device_name,
+                    );
+                }
+            }
+            let bitrate = match self.spotifyd_state.adaptive_bitrate {
+                Some(ref mut adaptive) => {
+                    if let Some(ref token) = token {
+                        adaptive.resume(token.clone(), self.spotifyd_state.device_name.clone());
+                    }
+                    adaptive.poll(self.spotifyd_state.state.get() == DeviceState::Active)
+                },
+                None => None,
+            };
+            if let Some(bitrate) = bitrate {
+                // The player only takes the bitrate when it is created.
+                self.player_config.bitrate = bitrate;
+                if let Some(session) = self.librespot_connection.session.clone() {
+                    self.start_player(session);
+                }
+            }
+            if let Some(ref mut tracker) = self.spotifyd_state.save_point_tracker {
+                tracker.poll(token);
+            }
+            if let Some(ref mut notifier) = self.spotifyd_state.notifier {
+                notifier.poll();
+            }
+            while let Ok(Async::Ready(Some(()))) = self.spotifyd_state.hangup_stream.poll() {
+                self.reload();
+            }
+            #[cfg(feature = "dbus_mpris")]
+            {
+                let playing = self.spotifyd_state.state.get() == DeviceState::Active;
+                match self
+                    .spotifyd_state
+                    .sleep_inhibitor
+                    .as_mut()
+                    .and_then(|inhibitor| inhibitor.poll(playing))
                 {
-                    if let Async::Ready(Some(event)) = player_event_channel.poll().unwrap() {
-                        if let Some(ref program) = self.spotifyd_state.player_event_program {
-                            let child = run_program_on_events(event, program);
-                            self.running_event_program = Some(child);
+                    Some(SleepEvent::Suspending) => {
+                        if let Some(ref spirc) = self.librespot_connection.spirc {
+                            spirc.pause();
+                        }
+                    },
+                    // The connection rarely survives sleep, so it is made anew
+                    // instead of finding out on the next command.
+                    Some(SleepEvent::Resumed) if self.librespot_connection.session.is_some() => {
+                        info!("Connecting again after the system woke up.");
+                        self.reconnect_later();
+                    },
+                    _ => (),
+                }
+            }
+            if self.spotifyd_state.sleep_timer.poll() {
+                if let Some(ref spirc) = self.librespot_connection.spirc {
+                    spirc.pause();
+                }
+            }
+            self.spotifyd_state
+                .device_loss
+                .poll(self.librespot_connection.spirc.as_ref().map(|s| &**s));
+            self.spotifyd_state
+                .playback_signals
+                .poll(self.librespot_connection.spirc.as_ref().map(|s| &**s));
+            for (cause, count) in self.spotifyd_state.underruns.poll() {
+                self.spotifyd_state
+                    .event_program
+                    .push(Event::Underrun(cause, count));
+            }
+            if let Some(ref health_check) = self.spotifyd_state.health_check {
+                health_check.update(
+                    self.spotifyd_state.state.get(),
+                    self.librespot_connection.session.is_some(),
+                    self.librespot_connection.spirc_task.is_some(),
+                    self.spotifyd_state.device_loss.is_lost(),
+                    self.spotifyd_state.underruns.counts(),
+                    self.spotifyd_state
+                        .data_usage
+                        .as_ref()
+                        .map(SharedDataUsage::counts),
+                );
+            }
+
+            if let Some(ref mut snapcast) = self.spotifyd_state.snapcast {
+                while let Async::Ready(Some(command)) = snapcast.commands.poll().unwrap() {
+                    if let Some(ref spirc) = self.librespot_connection.spirc {
+                        command.apply(spirc);
+                    }
+                }
+            }
+
+            if let Some(ref mut encoder) = self.spotifyd_state.rotary_encoder {
+                while let Async::Ready(Some(command)) = encoder.commands.poll().unwrap() {
+                    if let Some(ref spirc) = self.librespot_connection.spirc {
+                        command.apply(spirc);
+                    }
+                }
+            }
+
+            #[cfg(feature = "gpio_buttons")]
+            {
+                if let Some(ref mut buttons) = self.spotifyd_state.gpio_buttons {
+                    while let Async::Ready(Some(action)) = buttons.commands.poll().unwrap() {
+                        if let Some(ref spirc) = self.librespot_connection.spirc {
+                            action.apply(spirc);
                         }
                     }
                 }
             }
 
+            if let Some(ref mut lirc) = self.spotifyd_state.lirc {
+                while let Async::Ready(Some(action)) = lirc.commands.poll().unwrap() {
+                    if let Some(ref spirc) = self.librespot_connection.spirc {
+                        action.apply(spirc);
+                    }
+                }
+            }
+
             if let Some(ref mut fut) = self.spotifyd_state.dbus_mpris_server {
                 let _ = fut.poll();
             }
 
-            if let Async::Ready(session) = self.librespot_connection.connection.poll().unwrap() {
-                let mixer = (self.audio_setup.mixer)();
-                let audio_filter = mixer.get_audio_filter();
-                self.librespot_connection.connection = Box::new(futures::future::empty());
-                let backend = self.audio_setup.backend;
-                let audio_device = self.audio_setup.audio_device.clone();
-                let (player, event_channel) = Player::new(
-                    self.player_config.clone(),
-                    session.clone(),
-                    audio_filter,
-                    move || (backend)(audio_device),
-                );
+            let connection = match poll_connection(&mut self.librespot_connection.connection) {
+                Ok(connection) => connection,
+                Err(ref e) if is_premium_required(e) => {
+                    error!("{}", errors::message(ErrorCode::PremiumRequired, &[]));
+                    self.librespot_connection.connection = Box::new(futures::future::empty());
+                    self.spotifyd_state.state.set(DeviceState::Disconnected);
+                    if let Some(ref status) = self.audio_setup.status {
+                        status.set_error(Some(ErrorCode::PremiumRequired));
+                    }
+                    self.spotifyd_state
+                        .event_program
+                        .push(Event::PremiumRequired);
+                    // Run the onevent program right away.
+                    continue;
+                },
+                Err(e) => {
+                    if let Some(ref policy) = self.ap_policy {
+                        policy.last_failed();
+                    }
+                    let next_port = self.session_config.ap_port.and_then(|port| {
+                        let i = self.ap_ports.iter().position(|&p| p == port)?;
+                        self.ap_ports.get(i + 1).cloned()
+                    });
+                    match (next_port, self.librespot_connection.credentials.clone()) {
+                        // No port or later attempt gets past these.
+                        _ if is_bad_credentials(&e) => {
+                            panic!("{}", errors::message(ErrorCode::BadCredentials, &[&e]))
+                        },
+                        (Some(port), Some(credentials)) => {
+                            warn!(
+                                "{}, trying port {}",
+                                errors::message(ErrorCode::ConnectionFailed, &[&e]),
+                                port
+                            );
+                            self.session_config.ap_port = Some(port);
+                            self.librespot_connection.connection = Session::connect(
+                                self.session_config.clone(),
+                                credentials,
+                                self.spotifyd_state.cache.clone(),
+                                self.handle.clone(),
+                            );
+                            continue;
+                        },
+                        (None, Some(_)) => {
+                            warn!("{}", errors::message(ErrorCode::ConnectionFailed, &[&e]));
+                            self.librespot_connection.connection =
+                                Box::new(futures::future::empty());
+                            self.spotifyd_state.state.set(DeviceState::Disconnected);
+                            self.reconnect.schedule();
+                            continue;
+                        },
+                        _ => panic!("{}", errors::message(ErrorCode::ConnectionFailed, &[&e])),
+                    }
+                },
+            };
 
-                self.spotifyd_state.player_event_channel = Some(event_channel);
+            if self.reconnect.poll() {
+                if let Some(credentials) = self.librespot_connection.credentials.clone() {
+                    self.session_config.ap_port = self.ap_ports.first().cloned();
+                    self.librespot_connection.connection = Session::connect(
+                        self.session_config.clone(),
+                        credentials,
+                        self.spotifyd_state.cache.clone(),
+                        self.handle.clone(),
+                    );
+                    self.spotifyd_state.state.set(DeviceState::Connecting);
+                }
+                continue;
+            }
 
-                let (spirc, spirc_task) = Spirc::new(
-                    ConnectConfig {
-                        name: self.spotifyd_state.device_name.clone(),
-                        device_type: DeviceType::default(),
-                        volume: mixer.volume(),
-                        linear_volume: self.linear_volume,
-                    },
-                    session.clone(),
-                    player,
-                    mixer,
-                );
-                self.librespot_connection.spirc_task = Some(spirc_task);
-                let shared_spirc = Rc::new(spirc);
-                self.librespot_connection.spirc = Some(shared_spirc.clone());
-
-                self.spotifyd_state.dbus_mpris_server = new_dbus_server(
-                    session,
-                    self.handle.clone(),
-                    shared_spirc,
-                    self.spotifyd_state.device_name.clone(),
-                );
-            } else if let Async::Ready(_) = self.spotifyd_state.ctrl_c_stream.poll().unwrap() {
-                if !self.spotifyd_state.shutting_down {
-                    if let Some(ref spirc) = self.librespot_connection.spirc {
-                        spirc.shutdown();
-                        self.spotifyd_state.shutting_down = true;
-                    } else {
-                        return Ok(Async::Ready(()));
-                    }
+            let state = self.spotifyd_state.state.get();
+            if let Async::Ready(session) = connection {
+                self.reconnect.reset();
+                if let Some(ref status) = self.audio_setup.status {
+                    status.set_error(None);
+                }
+                self.librespot_connection.connection = Box::new(futures::future::empty());
+                self.librespot_connection.session = Some(session.clone());
+                self.librespot_connection.web_api = Some(TokenProvider::new(session.clone()));
+                if let Some(ref usage) = self.spotifyd_state.data_usage {
+                    usage.new_session();
+                }
+                self.start_player(session);
+            } else if let Async::Ready(_) = self.spotifyd_state.shutdown_stream.poll().unwrap() {
+                if self.shut_down() {
+                    return Ok(Async::Ready(()));
+                }
+            } else if state != DeviceState::Draining
+                && self
+                    .spotifyd_state
+                    .idle_exit
+                    .as_mut()
+                    .map_or(false, |idle| idle.poll(state == DeviceState::Active))
+            {
+                info!("Nothing has played for a while, exiting.");
+                if self.shut_down() {
+                    return Ok(Async::Ready(()));
                 }
-            } else if let Some(Async::Ready(_)) = self
+            } else if let Some(Ok(Async::NotReady)) | None = self
                 .librespot_connection
                 .spirc_task
                 .as_mut()
-                .map(|ref mut st| st.poll().unwrap())
+                .map(|ref mut st| st.poll())
             {
+                return Ok(Async::NotReady);
+            } else if self.spotifyd_state.state.get() == DeviceState::Draining {
                 return Ok(Async::Ready(()));
+            } else if self.spotifyd_state.state.get() == DeviceState::Connecting {
+                // A login through discovery replaces the session.
+                self.librespot_connection.spirc_task = None;
             } else {
-                return Ok(Async::NotReady);
+                // The session ended without being asked to, so the
+                // connection was lost.
+                warn!("Lost the connection to Spotify.");
+                if let Some(ref policy) = self.ap_policy {
+                    policy.last_failed();
+                }
+                if let Some(ref mut resume) = self.spotifyd_state.reconnect_resume {
+                    resume.connection_lost();
+                }
+                self.spotifyd_state.event_program.push(Event::SessionLost);
+                self.reconnect_later();
             }
         }
     }
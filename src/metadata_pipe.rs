@@ -0,0 +1,128 @@
+use crate::{art, track_metadata::TrackMetadata, utils::create_fifo};
+use librespot::playback::player::PlayerEvent;
+use log::{error, info, warn};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
+
+/// Messages understood by the writer thread.
+enum Item {
+    Metadata(TrackMetadata),
+    Playback(&'static [u8; 4]),
+}
+
+/// Writes now-playing information to a named pipe using the framing of
+/// shairport-sync's metadata pipe, so existing consumers can read it as is.
+///
+/// Every item is written as
+///
+/// ```text
+/// <item><type>636f7265</type><code>6d696e6d</code><length>9</length>
+/// <data encoding="base64">
+/// U29uZyBuYW1l</data></item>
+/// ```
+///
+/// where type and code are four character codes in hex and the payload, if
+/// any, is base64 encoded. Track metadata is bracketed by `ssnc mdst` and
+/// `ssnc mden` and contains `core minm` (title), `core asar` (artist), `core
+/// asal` (album), `core astm` (duration in ms, big endian u32) and `ssnc PICT`
+/// (cover art). Playback state is reported with `ssnc pbeg`, `ssnc prsm` and
+/// `ssnc pend`.
+pub struct MetadataPipe {
+    sender: Sender<Item>,
+}
+
+impl MetadataPipe {
+    pub fn new(path: PathBuf) -> MetadataPipe {
+        let (sender, receiver) = channel();
+        thread::spawn(move || run_writer(&path, &receiver));
+        MetadataPipe { sender }
+    }
+
+    pub fn handle_event(&self, event: &PlayerEvent) {
+        let code = match *event {
+            PlayerEvent::Started { .. } => b"prsm",
+            PlayerEvent::Changed { .. } => b"pbeg",
+            PlayerEvent::Stopped { .. } => b"pend",
+        };
+        let _ = self.sender.send(Item::Playback(code));
+    }
+
+    pub fn handle_metadata(&self, metadata: &TrackMetadata) {
+        let _ = self.sender.send(Item::Metadata(metadata.clone()));
+    }
+}
+
+fn run_writer(path: &Path, receiver: &Receiver<Item>) {
+    if let Err(e) = create_fifo(path) {
+        error!("Couldn't create metadata pipe {:?}: {}", path, e);
+        return;
+    }
+
+    let mut pipe: Option<File> = None;
+    for item in receiver.iter() {
+        if pipe.is_none() {
+            // Opening a FIFO for writing blocks until there is a reader.
+            match OpenOptions::new().write(true).open(path) {
+                Ok(f) => pipe = Some(f),
+                Err(e) => {
+                    error!("Couldn't open metadata pipe {:?}: {}", path, e);
+                    continue;
+                },
+            }
+        }
+
+        let result = match item {
+            Item::Metadata(ref metadata) => write_metadata(pipe.as_mut().unwrap(), metadata),
+            Item::Playback(code) => write_item(pipe.as_mut().unwrap(), b"ssnc", code, &[]),
+        };
+        if let Err(e) = result {
+            info!("Metadata pipe reader went away: {}", e);
+            pipe = None;
+        }
+    }
+}
+
+fn write_metadata<W: Write>(w: &mut W, metadata: &TrackMetadata) -> io::Result<()> {
+    write_item(w, b"ssnc", b"mdst", &[])?;
+    write_item(w, b"core", b"minm", metadata.title.as_bytes())?;
+    write_item(w, b"core", b"asar", metadata.artists.join(", ").as_bytes())?;
+    write_item(w, b"core", b"asal", metadata.album.as_bytes())?;
+    write_item(w, b"core", b"astm", &metadata.duration_ms.to_be_bytes())?;
+    write_item(w, b"ssnc", b"mden", &[])?;
+
+    if let Some(ref url) = metadata.cover_url {
+        match art::load(url) {
+            Ok(picture) => write_item(w, b"ssnc", b"PICT", &picture)?,
+            Err(e) => warn!("Couldn't download cover art from {}: {}", url, e),
+        }
+    }
+    Ok(())
+}
+
+fn write_item<W: Write>(w: &mut W, kind: &[u8; 4], code: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    write!(
+        w,
+        "<item><type>{}</type><code>{}</code><length>{}</length>",
+        hex(kind),
+        hex(code),
+        data.len()
+    )?;
+    if !data.is_empty() {
+        write!(
+            w,
+            "\n<data encoding=\"base64\">\n{}</data>",
+            base64::encode(data)
+        )?;
+    }
+    writeln!(w, "</item>")?;
+    w.flush()
+}
+
+fn hex(code: &[u8; 4]) -> String {
+    code.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,197 @@
+use crate::{
+    check_config::line_numbers,
+    config::{self, ALARM_PREFIX, EQUALIZER_PRESET_PREFIX, INSTANCE_SECTION_PREFIX},
+    errors::Failure,
+    player_event_handler::split_command,
+    toml_config::{self, ALARMS, EQUALIZER_PRESETS},
+};
+use ini::Ini;
+use std::{collections::HashMap, fs, path::PathBuf, process::exit};
+use toml::{value::Table, Value};
+
+/// Settings that are lists separated by commas, which become arrays.
+const LISTS: &[&str] = &[
+    "ap_blacklist",
+    "ap_port",
+    "ap_prefer",
+    "art_providers",
+    "channel_map",
+    "gpio_buttons",
+    "lirc_buttons",
+    "plugins",
+    "rotary_encoder",
+    "tee_backends",
+];
+
+/// Prints the config file given, or the one found, as TOML, for
+/// `spotifyd migrate-config [FILE]`. The settings keep their order, but
+/// comments aren't carried over.
+pub fn run(args: &[String]) -> ! {
+    let path = match args
+        .get(0)
+        .map(PathBuf::from)
+        .or_else(|| config::get_config_file().ok())
+    {
+        Some(path) => path,
+        None => fail("No config file found, give one with spotifyd migrate-config FILE."),
+    };
+    if toml_config::is_toml(&path) {
+        fail(&format!("{} is TOML already.", path.display()));
+    }
+    let contents = fs::read_to_string(&path)
+        .unwrap_or_else(|e| fail(&format!("Couldn't read {}: {}", path.display(), e)));
+    let ini = Ini::load_from_str(&contents)
+        .unwrap_or_else(|e| fail(&format!("{}:{}: {}", path.display(), e.line + 1, e.msg)));
+    match toml::to_string(&to_toml(&ini, &line_numbers(&contents))) {
+        Ok(toml) => print!("{}", toml),
+        Err(e) => fail(&format!("Couldn't write the TOML: {}", e)),
+    }
+    eprintln!(
+        "Save it as {}, which is read instead of {}.",
+        path.with_extension("toml").display(),
+        path.display()
+    );
+    exit(0)
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("Error: {}", message);
+    Failure::Config.exit()
+}
+
+/// The sections of the INI file as tables, in the order of the file, with
+/// the instances in `[spotifyd]`.
+fn to_toml(ini: &Ini, lines: &HashMap<(String, String), usize>) -> Value {
+    let line = |section: &str, key: &str| {
+        lines
+            .get(&(section.to_string(), key.to_string()))
+            .cloned()
+            .unwrap_or(0)
+    };
+    let mut sections = Vec::new();
+    for (name, properties) in ini.iter() {
+        match *name {
+            Some(ref name) => sections.push((name, properties)),
+            None if properties.is_empty() => (),
+            None => eprintln!(
+                "Warning: settings before the first section are ignored by spotifyd, they are \
+                 left out."
+            ),
+        }
+    }
+    sections.sort_by_key(|&(name, _)| line(name, ""));
+
+    let mut root = Table::new();
+    let mut instances = Table::new();
+    for (name, properties) in sections {
+        let mut settings: Vec<(&str, &str)> = properties
+            .iter()
+            .map(|(key, value)| (&**key, &**value))
+            .collect();
+        settings.sort_by_key(|&(key, _)| line(name, key));
+        let table = Value::Table(to_table(&settings));
+        if name.starts_with(INSTANCE_SECTION_PREFIX) {
+            instances.insert(name[INSTANCE_SECTION_PREFIX.len()..].to_string(), table);
+        } else {
+            root.insert(name.clone(), table);
+        }
+    }
+    if !instances.is_empty() {
+        let mut spotifyd = match root.remove("spotifyd") {
+            Some(Value::Table(spotifyd)) => spotifyd,
+            _ => Table::new(),
+        };
+        for (name, instance) in instances {
+            spotifyd.insert(name, instance);
+        }
+        root.insert("spotifyd".to_string(), Value::Table(spotifyd));
+    }
+    Value::Table(root)
+}
+
+/// The settings of a section, with alarms as an array of tables and
+/// equalizer presets in a table of their own.
+fn to_table(settings: &[(&str, &str)]) -> Table {
+    let mut table = Table::new();
+    let mut presets = Table::new();
+    let mut alarms = Vec::new();
+    for &(key, value) in settings {
+        if key.starts_with(ALARM_PREFIX) {
+            alarms.push(alarm(&key[ALARM_PREFIX.len()..], value));
+        } else if key.starts_with(EQUALIZER_PRESET_PREFIX) {
+            presets.insert(
+                key[EQUALIZER_PRESET_PREFIX.len()..].to_string(),
+                list(value),
+            );
+        } else {
+            table.insert(key.to_string(), to_value(key, value));
+        }
+    }
+    if !presets.is_empty() {
+        table.insert(EQUALIZER_PRESETS.to_string(), Value::Table(presets));
+    }
+    if !alarms.is_empty() {
+        table.insert(ALARMS.to_string(), Value::Array(alarms));
+    }
+    table
+}
+
+/// A setting as a TOML value. Booleans and numbers are only taken as such
+/// if they read back the same, so every value means what it did.
+fn to_value(key: &str, value: &str) -> Value {
+    if LISTS.contains(&key) {
+        return list(value);
+    }
+    let command = split_command(value);
+    if key == "onevent" && command.len() > 1 {
+        return Value::Array(command.into_iter().map(Value::String).collect());
+    }
+    match value {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => match (value.parse::<i64>(), value.parse::<f64>()) {
+            (Ok(n), _) if n.to_string() == value => Value::Integer(n),
+            (_, Ok(x)) if x.to_string() == value => Value::Float(x),
+            _ => Value::String(value.to_string()),
+        },
+    }
+}
+
+/// A list separated by commas as an array, of numbers if they all are.
+fn list(value: &str) -> Value {
+    let items: Vec<&str> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .collect();
+    let numbers: Vec<i64> = items
+        .iter()
+        .filter_map(|item| item.parse::<i64>().ok().filter(|n| n.to_string() == *item))
+        .collect();
+    if !items.is_empty() && numbers.len() == items.len() {
+        Value::Array(numbers.into_iter().map(Value::Integer).collect())
+    } else {
+        Value::Array(
+            items
+                .into_iter()
+                .map(|item| Value::String(item.to_string()))
+                .collect(),
+        )
+    }
+}
+
+/// An `alarm.NAME` setting as a table of `alarms`.
+fn alarm(name: &str, value: &str) -> Value {
+    let fields: Vec<&str> = value.split_whitespace().collect();
+    let mut alarm = Table::new();
+    alarm.insert("name".to_string(), Value::String(name.to_string()));
+    alarm.insert(
+        "schedule".to_string(),
+        Value::String(fields[..fields.len().min(5)].join(" ")),
+    );
+    let rest = ["uri", "volume", "fade_in"];
+    for (&key, &field) in rest.iter().zip(fields.iter().skip(5)) {
+        alarm.insert(key.to_string(), to_value(key, field));
+    }
+    Value::Table(alarm)
+}
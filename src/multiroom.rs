@@ -0,0 +1,340 @@
+use crate::backends::{RecoveringSink, SinkBuilder};
+use lazy_static::lazy_static;
+use librespot::playback::audio_backend::Sink;
+use log::{info, warn};
+use std::{
+    collections::VecDeque,
+    io::{self, BufReader, Read},
+    net::{TcpStream, UdpSocket},
+    sync::{mpsc::channel, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_PORT: u16 = 5030;
+/// How far ahead of playing it the leader sends audio, unless
+/// `multiroom_latency_ms` says otherwise.
+pub const DEFAULT_LATENCY: Duration = Duration::from_millis(300);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+/// The clock offset is taken from the exchange with the shortest round trip
+/// among this many, which is the least skewed by the network.
+const SYNC_EXCHANGES: usize = 8;
+/// How far a follower may be off before it drops audio or plays silence.
+const TOLERANCE: i64 = 5_000;
+/// How often a follower checks whether its device drifted from the clock,
+/// in microseconds.
+const DRIFT_INTERVAL: i64 = 10_000_000;
+/// librespot always plays 44.1 kHz stereo.
+const SAMPLES_PER_SECOND: i64 = 44100 * 2;
+/// The most samples a packet may have, a second's worth, far more than
+/// librespot writes at once.
+const MAX_PACKET_SAMPLES: usize = SAMPLES_PER_SECOND as usize;
+
+lazy_static! {
+    static ref EPOCH: Instant = Instant::now();
+}
+
+/// Microseconds on this instance's clock, which only the exchange with the
+/// leader relates to the leader's.
+pub fn now() -> i64 {
+    let elapsed = EPOCH.elapsed();
+    elapsed.as_secs() as i64 * 1_000_000 + i64::from(elapsed.subsec_micros())
+}
+
+/// Adds the default port to an address without one.
+pub fn with_port(address: &str) -> String {
+    if address.contains(':') {
+        address.to_string()
+    } else {
+        format!("{}:{}", address, DEFAULT_PORT)
+    }
+}
+
+/// How many samples, in whole frames, play in `micros`.
+fn samples_in(micros: i64) -> usize {
+    (micros * SAMPLES_PER_SECOND / 1_000_000 / 2 * 2) as usize
+}
+
+fn duration_of(samples: usize) -> i64 {
+    samples as i64 * 1_000_000 / SAMPLES_PER_SECOND
+}
+
+/// What the leader sends its followers over TCP: a byte for the kind, and
+/// for audio the time it plays at on the leader's clock, in microseconds,
+/// the number of samples, and the samples as S16LE.
+pub enum Message {
+    Start,
+    Stop,
+    Data { play_at: i64, samples: Vec<i16> },
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        match *self {
+            Message::Start => vec![0],
+            Message::Stop => vec![1],
+            Message::Data {
+                play_at,
+                ref samples,
+            } => {
+                let mut bytes = Vec::with_capacity(13 + samples.len() * 2);
+                bytes.push(2);
+                bytes.extend_from_slice(&play_at.to_be_bytes());
+                bytes.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+                for sample in samples {
+                    bytes.extend_from_slice(&sample.to_le_bytes());
+                }
+                bytes
+            },
+        }
+    }
+
+    fn read<R: Read>(reader: &mut R) -> io::Result<Message> {
+        let mut kind = [0];
+        reader.read_exact(&mut kind)?;
+        match kind[0] {
+            0 => Ok(Message::Start),
+            1 => Ok(Message::Stop),
+            2 => {
+                let mut header = [0; 12];
+                reader.read_exact(&mut header)?;
+                let mut play_at = [0; 8];
+                play_at.copy_from_slice(&header[..8]);
+                let mut count = [0; 4];
+                count.copy_from_slice(&header[8..]);
+                let count = u32::from_be_bytes(count) as usize;
+                if count > MAX_PACKET_SAMPLES {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "too large a packet from the leader",
+                    ));
+                }
+                let mut bytes = vec![0; count * 2];
+                reader.read_exact(&mut bytes)?;
+                Ok(Message::Data {
+                    play_at: i64::from_be_bytes(play_at),
+                    samples: bytes
+                        .chunks(2)
+                        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                        .collect(),
+                })
+            },
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown message from the leader",
+            )),
+        }
+    }
+}
+
+/// Answers the clock requests of followers on `socket`: each sends the time
+/// on its clock, and gets it back with the time on the leader's.
+pub fn answer_clock_requests(socket: &UdpSocket) {
+    let mut request = [0; 8];
+    while let Ok((8, follower)) = socket.recv_from(&mut request) {
+        let mut answer = request.to_vec();
+        answer.extend_from_slice(&now().to_be_bytes());
+        let _ = socket.send_to(&answer, follower);
+    }
+}
+
+/// The leader's clock minus this instance's, once known.
+#[derive(Clone)]
+struct Clock(Arc<Mutex<Option<i64>>>);
+
+impl Clock {
+    fn offset(&self) -> Option<i64> {
+        *self.0.lock().unwrap()
+    }
+
+    /// Asks the leader for its time every second, NTP-style: the leader's
+    /// time is taken to be halfway through the round trip.
+    fn sync(&self, leader: &str) {
+        let socket = match UdpSocket::bind("0.0.0.0:0") {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!(
+                    "Couldn't open a socket to sync clocks with the leader: {}",
+                    e
+                );
+                return;
+            },
+        };
+        let _ = socket.set_read_timeout(Some(SYNC_INTERVAL));
+        let mut exchanges = VecDeque::new();
+        loop {
+            let sent = now();
+            let mut answer = [0; 16];
+            let received = socket
+                .send_to(&sent.to_be_bytes(), leader)
+                .and_then(|_| socket.recv(&mut answer));
+            let returned = now();
+            let mut echoed = [0; 8];
+            echoed.copy_from_slice(&answer[..8]);
+            if let (Ok(16), true) = (received, i64::from_be_bytes(echoed) == sent) {
+                let mut leader_time = [0; 8];
+                leader_time.copy_from_slice(&answer[8..]);
+                let offset = i64::from_be_bytes(leader_time) - (sent + returned) / 2;
+                exchanges.push_back((returned - sent, offset));
+                if exchanges.len() > SYNC_EXCHANGES {
+                    exchanges.pop_front();
+                }
+                *self.0.lock().unwrap() = exchanges
+                    .iter()
+                    .min_by_key(|&&(round_trip, _)| round_trip)
+                    .map(|&(_, offset)| offset);
+            }
+            thread::sleep(SYNC_INTERVAL);
+        }
+    }
+}
+
+/// Runs spotifyd as a follower of another instance, for
+/// `multiroom_leader`: instead of being a Connect device, it plays what the
+/// leader plays, at the same time, through its own backend. `offset_ms`
+/// shifts it like `latency_offset_ms` does.
+pub fn follow(leader: &str, backend: SinkBuilder, device: Option<String>, offset_ms: i64) -> ! {
+    let leader = with_port(leader);
+    let clock = Clock(Arc::new(Mutex::new(None)));
+    let syncing = clock.clone();
+    let address = leader.clone();
+    thread::spawn(move || syncing.sync(&address));
+
+    // Nothing reacts to the device coming and going, it is just tried again.
+    let (events, _events) = channel();
+    let mut sink = RecoveringSink::new(backend, device, events);
+    let mut connected = true;
+    loop {
+        match TcpStream::connect(&*leader) {
+            Ok(stream) => {
+                info!("Following {}", leader);
+                connected = true;
+                play(BufReader::new(stream), &mut sink, &clock, offset_ms * 1000);
+                warn!("Lost the connection to the leader {}", leader);
+            },
+            Err(e) => {
+                // Only the first of a series of failures is worth logging.
+                if connected {
+                    warn!("Couldn't connect to the leader {}: {}", leader, e);
+                }
+                connected = false;
+            },
+        }
+        thread::sleep(RECONNECT_INTERVAL);
+    }
+}
+
+/// How far the device drifted from the clock. Once the device's buffer has
+/// filled up, what was written stays the same time ahead of the clock while
+/// the device keeps its pace, and that changes as much as the device drifts.
+struct Drift {
+    /// Whether the device's buffer is still filling up, in the first
+    /// interval after starting.
+    filling: bool,
+    /// How far ahead it was in the interval after that.
+    baseline: Option<i64>,
+    /// The least it was ahead in this interval, which is the least skewed
+    /// by when writing returned.
+    least: Option<i64>,
+    /// When this interval started.
+    since: i64,
+}
+
+impl Drift {
+    fn new() -> Drift {
+        Drift {
+            filling: true,
+            baseline: None,
+            least: None,
+            since: now(),
+        }
+    }
+
+    /// Takes note of when what is written next plays, and once an interval
+    /// tells how much the device ran ahead of the clock since starting.
+    fn check(&mut self, next: i64) -> i64 {
+        let now = now();
+        let ahead = next - now;
+        let least = self.least.map_or(ahead, |least| least.min(ahead));
+        if now - self.since < DRIFT_INTERVAL {
+            self.least = Some(least);
+            return 0;
+        }
+        self.least = None;
+        self.since = now;
+        if self.filling {
+            self.filling = false;
+            return 0;
+        }
+        match self.baseline {
+            Some(baseline) => least - baseline,
+            None => {
+                self.baseline = Some(least);
+                0
+            },
+        }
+    }
+}
+
+/// Plays the leader's audio, each packet when the leader says, until the
+/// connection drops. Every `DRIFT_INTERVAL`, how far the device drifted from
+/// the clock is taken into account, and audio that is off by more than
+/// `TOLERANCE` is dropped or waited for.
+fn play<R: Read>(mut reader: R, sink: &mut RecoveringSink, clock: &Clock, offset: i64) {
+    let mut playing = false;
+    // When, on this clock, what is written next plays.
+    let mut next: Option<i64> = None;
+    let mut drift = Drift::new();
+    while let Ok(message) = Message::read(&mut reader) {
+        let (play_at, samples) = match message {
+            Message::Start => {
+                playing = true;
+                next = None;
+                drift = Drift::new();
+                let _ = sink.start();
+                continue;
+            },
+            Message::Stop => {
+                playing = false;
+                let _ = sink.stop();
+                continue;
+            },
+            Message::Data { play_at, samples } => (play_at, samples),
+        };
+        let leader_offset = match (playing, clock.offset()) {
+            (true, Some(leader_offset)) => leader_offset,
+            // Until the clocks are synced, the time to play at is unknown.
+            _ => continue,
+        };
+        let due = play_at - leader_offset + offset;
+        let expected = next.unwrap_or_else(|| {
+            let now = now();
+            if due > now {
+                thread::sleep(Duration::from_micros((due - now) as u64));
+            }
+            now.max(due)
+        });
+        // Dropping audio or playing silence makes up for being off, and the
+        // next packet plays after all that was written.
+        let behind = expected - due;
+        let mut data = &samples[..];
+        let mut written = 0;
+        if behind > TOLERANCE {
+            data = &data[samples_in(behind).min(data.len())..];
+        } else if behind < -TOLERANCE {
+            let silence = vec![0; samples_in(-behind)];
+            written += silence.len();
+            let _ = sink.write(&silence);
+        }
+        if !data.is_empty() {
+            written += data.len();
+            let _ = sink.write(data);
+        }
+        let written_until = expected + duration_of(written);
+        next = Some(written_until - drift.check(written_until));
+    }
+    if playing {
+        let _ = sink.stop();
+    }
+}
@@ -0,0 +1,266 @@
+use crate::errors::{self, ErrorCode};
+use crypto::{digest::Digest, sha2::Sha256};
+use librespot::{core::authentication::Credentials, protocol::authentication::AuthenticationType};
+use log::{error, warn};
+use serde_json::Value;
+use std::{
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+use url::Url;
+
+const AUTHORIZE_URL: &str = "https://accounts.spotify.com/authorize";
+const DEVICE_AUTHORIZE_URL: &str = "https://accounts.spotify.com/oauth2/device/authorize";
+const TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// The client librespot logs in as, which may stream.
+const CLIENT_ID: &str = "65b708073fc0480ea92a077233ca87bd";
+const REDIRECT_ADDRESS: &str = "127.0.0.1:8898";
+const REDIRECT_URI: &str = "http://127.0.0.1:8898/login";
+const SCOPE: &str = "streaming";
+/// The file in the cache directory that keeps the refresh token.
+const REFRESH_TOKEN_FILE: &str = "oauth_refresh_token";
+
+/// What the token endpoint hands out.
+pub struct Tokens {
+    pub access_token: String,
+    /// Gets a new access token once this one expired, without logging in
+    /// again.
+    pub refresh_token: Option<String>,
+}
+
+/// Credentials logging in with an OAuth access token instead of a password.
+pub fn credentials(access_token: String) -> Credentials {
+    Credentials {
+        username: String::new(),
+        auth_type: AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN,
+        auth_data: access_token.into_bytes(),
+    }
+}
+
+/// Gets tokens through the authorization code flow with PKCE: the user logs
+/// in on Spotify's page in a browser, which then redirects to a server
+/// listening on localhost for the outcome.
+pub fn authorize() -> Result<Tokens, Box<Error>> {
+    let verifier = random_string()?;
+    let mut sha = Sha256::new();
+    sha.input_str(&verifier);
+    let mut challenge = [0; 32];
+    sha.result(&mut challenge);
+    let state = random_string()?;
+
+    let url = Url::parse_with_params(
+        AUTHORIZE_URL,
+        &[
+            ("client_id", CLIENT_ID),
+            ("response_type", "code"),
+            ("redirect_uri", REDIRECT_URI),
+            ("scope", SCOPE),
+            ("state", state.as_str()),
+            ("code_challenge_method", "S256"),
+            (
+                "code_challenge",
+                base64::encode_config(&challenge, base64::URL_SAFE_NO_PAD).as_str(),
+            ),
+        ],
+    )?;
+    let listener = TcpListener::bind(REDIRECT_ADDRESS)?;
+    println!("To log in, open this address in a browser:\n\n{}\n", url);
+    println!(
+        "On another machine, forward the port first: ssh -L 8898:{} HOST",
+        REDIRECT_ADDRESS
+    );
+
+    let (mut stream, _) = listener.accept()?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let redirect = Url::parse(&format!("http://{}{}", REDIRECT_ADDRESS, path))?;
+    let query = |key| {
+        redirect
+            .query_pairs()
+            .find(|&(ref k, _)| k == key)
+            .map(|(_, v)| v.into_owned())
+    };
+    let (code, returned_state) = (query("code"), query("state"));
+    let body = if code.is_some() {
+        "Logged in, you can close this page."
+    } else {
+        "Logging in failed."
+    };
+    let _ = write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    if returned_state.as_ref() != Some(&state) {
+        return Err(From::from("the login page returned an unexpected state"));
+    }
+    let code = match code {
+        Some(code) => code,
+        None => {
+            let error = query("error").unwrap_or_else(|| "no code returned".to_string());
+            return Err(From::from(error));
+        },
+    };
+
+    request_tokens(&[
+        ("grant_type", "authorization_code"),
+        ("code", code.as_str()),
+        ("redirect_uri", REDIRECT_URI),
+        ("client_id", CLIENT_ID),
+        ("code_verifier", verifier.as_str()),
+    ])
+}
+
+/// Gets tokens through the device authorization flow: the user enters a code
+/// on Spotify's page on any device with a browser, while this asks whether
+/// that happened yet.
+pub fn authorize_device() -> Result<Tokens, Box<Error>> {
+    let client = reqwest::Client::new();
+    let response: Value = client
+        .post(DEVICE_AUTHORIZE_URL)
+        .form(&[("client_id", CLIENT_ID), ("scope", SCOPE)])
+        .send()?
+        .json()?;
+    let field = |key: &str| {
+        response[key]
+            .as_str()
+            .ok_or_else(|| format!("no {} in {}", key, response))
+    };
+    let device_code = field("device_code")?;
+    let user_code = field("user_code")?;
+    let address = match response["verification_uri_complete"].as_str() {
+        Some(address) => address,
+        None => field("verification_uri")?,
+    };
+    let mut interval = response["interval"].as_u64().unwrap_or(5);
+    let expires_in = response["expires_in"].as_u64().unwrap_or(600);
+    println!(
+        "To log in, open this address on any device and enter the code {}:\n\n{}\n",
+        user_code, address
+    );
+
+    let deadline = Instant::now() + Duration::from_secs(expires_in);
+    while Instant::now() < deadline {
+        thread::sleep(Duration::from_secs(interval));
+        let response: Value = client
+            .post(TOKEN_URL)
+            .form(&[
+                ("grant_type", DEVICE_CODE_GRANT),
+                ("device_code", device_code),
+                ("client_id", CLIENT_ID),
+            ])
+            .send()?
+            .json()?;
+        match response["error"].as_str() {
+            Some("authorization_pending") => (),
+            Some("slow_down") => interval += 5,
+            Some(error) => return Err(From::from(error.to_string())),
+            None => return tokens(&response),
+        }
+    }
+    Err(From::from("the code expired before it was entered"))
+}
+
+/// Gets a new access token for a refresh token. Spotify may hand out a new
+/// refresh token with it, which replaces the old one.
+pub fn refresh(refresh_token: &str) -> Result<Tokens, Box<Error>> {
+    let tokens = request_tokens(&[
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+        ("client_id", CLIENT_ID),
+    ])?;
+    Ok(Tokens {
+        refresh_token: tokens
+            .refresh_token
+            .or_else(|| Some(refresh_token.to_string())),
+        ..tokens
+    })
+}
+
+/// Credentials from a new access token for the refresh token, keeping the
+/// refresh token that replaces it in the cache, if there is one.
+pub fn refreshed_credentials(
+    refresh_token: &str,
+    cache_path: Option<&Path>,
+) -> Option<Credentials> {
+    match refresh(refresh_token) {
+        Ok(tokens) => {
+            if let (Some(cache_path), Some(ref new)) = (cache_path, tokens.refresh_token) {
+                if new != refresh_token {
+                    store_refresh_token(cache_path, new);
+                }
+            }
+            Some(credentials(tokens.access_token))
+        },
+        Err(e) => {
+            error!("{}", errors::message(ErrorCode::OAuthFailed, &[&e]));
+            None
+        },
+    }
+}
+
+/// The refresh token kept in the cache directory, if there is one.
+pub fn stored_refresh_token(cache_path: &Path) -> Option<String> {
+    fs::read_to_string(cache_path.join(REFRESH_TOKEN_FILE))
+        .ok()
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+}
+
+/// Keeps the refresh token in the cache directory, readable only by the
+/// user spotifyd runs as.
+pub fn store_refresh_token(cache_path: &Path, refresh_token: &str) {
+    let path = cache_path.join(REFRESH_TOKEN_FILE);
+    let stored = fs::create_dir_all(cache_path)
+        .and_then(|_| {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&path)
+        })
+        .and_then(|mut file| file.write_all(refresh_token.as_bytes()));
+    if let Err(e) = stored {
+        warn!("Couldn't keep the refresh token in {:?}: {}", path, e);
+    }
+}
+
+fn request_tokens(form: &[(&str, &str)]) -> Result<Tokens, Box<Error>> {
+    let response: Value = reqwest::Client::new()
+        .post(TOKEN_URL)
+        .form(form)
+        .send()?
+        .json()?;
+    tokens(&response)
+}
+
+fn tokens(response: &Value) -> Result<Tokens, Box<Error>> {
+    let access_token =
+        response["access_token"]
+            .as_str()
+            .ok_or_else(|| match response["error"].as_str() {
+                Some(error) => error.to_string(),
+                None => format!("no access token in {}", response),
+            })?;
+    Ok(Tokens {
+        access_token: access_token.to_string(),
+        refresh_token: response["refresh_token"].as_str().map(String::from),
+    })
+}
+
+/// 32 random bytes, as URL safe characters.
+fn random_string() -> Result<String, Box<Error>> {
+    let mut bytes = [0; 32];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD))
+}
@@ -0,0 +1,185 @@
+use crate::{
+    logging::{LogFile, RotatingFile},
+    track_metadata::TrackMetadata,
+};
+use chrono::{DateTime, Local};
+use librespot::{core::spotify_id::SpotifyId, playback::player::PlayerEvent};
+use log::{error, warn};
+use serde_json::json;
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// How the play history is written.
+#[derive(Clone, Copy, PartialEq)]
+pub enum HistoryFormat {
+    /// Comma separated values, without a header.
+    Csv,
+    /// One JSON object per line.
+    Jsonl,
+}
+
+impl FromStr for HistoryFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "csv" => Ok(HistoryFormat::Csv),
+            "jsonl" | "json" => Ok(HistoryFormat::Jsonl),
+            _ => Err(()),
+        }
+    }
+}
+
+pub struct PlayHistoryConfig {
+    pub file: LogFile,
+    pub format: HistoryFormat,
+}
+
+/// The track playing, and how long it has played so far.
+struct Listening {
+    track_id: SpotifyId,
+    started: DateTime<Local>,
+    /// Since when it plays, unless it's paused.
+    playing_since: Option<Instant>,
+    listened: Duration,
+    metadata: Option<TrackMetadata>,
+}
+
+/// Writes a line for every track played to a file: when it started, its ID,
+/// title and artists, and for how long it was listened to.
+pub struct PlayHistory {
+    file: Option<RotatingFile>,
+    format: HistoryFormat,
+    current: Option<Listening>,
+}
+
+impl PlayHistory {
+    pub fn new(config: PlayHistoryConfig) -> PlayHistory {
+        let file = match RotatingFile::open(&config.file) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                error!(
+                    "Couldn't open the play history {:?}: {}",
+                    config.file.path, e
+                );
+                None
+            },
+        };
+        PlayHistory {
+            file,
+            format: config.format,
+            current: None,
+        }
+    }
+
+    pub fn handle_event(&mut self, event: &PlayerEvent) {
+        match *event {
+            PlayerEvent::Started { track_id } => {
+                if let Some(ref mut current) = self.current {
+                    if current.track_id == track_id {
+                        if current.playing_since.is_none() {
+                            current.playing_since = Some(Instant::now());
+                        }
+                        return;
+                    }
+                }
+                self.finish();
+                self.start(track_id);
+            },
+            PlayerEvent::Changed { new_track_id, .. } => {
+                self.finish();
+                self.start(new_track_id);
+            },
+            PlayerEvent::Stopped { .. } => {
+                if let Some(ref mut current) = self.current {
+                    if let Some(since) = current.playing_since.take() {
+                        current.listened += since.elapsed();
+                    }
+                }
+            },
+        }
+    }
+
+    /// Takes the title and artists of the track playing, once known.
+    pub fn handle_metadata(&mut self, metadata: &TrackMetadata) {
+        if let Some(ref mut current) = self.current {
+            if current.track_id == metadata.track_id {
+                current.metadata = Some(metadata.clone());
+            }
+        }
+    }
+
+    fn start(&mut self, track_id: SpotifyId) {
+        self.current = Some(Listening {
+            track_id,
+            started: Local::now(),
+            playing_since: Some(Instant::now()),
+            listened: Duration::from_secs(0),
+            metadata: None,
+        });
+    }
+
+    /// Writes the track played so far to the history.
+    fn finish(&mut self) {
+        let current = match self.current.take() {
+            Some(current) => current,
+            None => return,
+        };
+        let file = match self.file {
+            Some(ref file) => file,
+            None => return,
+        };
+        let listened = current.listened
+            + current
+                .playing_since
+                .map_or_else(Duration::default, |s| s.elapsed());
+        let (title, artists) = match current.metadata {
+            Some(ref m) => (m.title.as_str(), m.artists.join(", ")),
+            None => ("", String::new()),
+        };
+        let timestamp = current.started.to_rfc3339();
+        let track_uri = format!("spotify:track:{}", current.track_id.to_base62());
+        let listened_ms = listened.as_millis() as u64;
+        let line = match self.format {
+            HistoryFormat::Csv => format!(
+                "{},{},{},{},{}\n",
+                timestamp,
+                track_uri,
+                csv_field(title),
+                csv_field(&artists),
+                listened_ms
+            ),
+            HistoryFormat::Jsonl => format!(
+                "{}\n",
+                json!({
+                    "timestamp": timestamp,
+                    "track_id": track_uri,
+                    "title": title,
+                    "artist": artists,
+                    "listened_ms": listened_ms,
+                })
+            ),
+        };
+        if let Err(e) = file.append(&line) {
+            warn!("Couldn't write the play history {:?}: {}", file.path(), e);
+        }
+    }
+}
+
+/// The track playing when spotifyd exits counts as played, too.
+impl Drop for PlayHistory {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Quotes a field for CSV if it needs it.
+fn csv_field(field: &str) -> String {
+    if field.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
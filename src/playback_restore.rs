@@ -0,0 +1,185 @@
+use crate::web_api;
+use futures::{Async, Stream};
+use librespot::playback::player::PlayerEvent;
+use log::{info, warn};
+use rspotify::spotify::{client::Spotify, model::offset, oauth2::TokenInfo as RspotifyToken};
+use serde_json::{json, Value};
+use std::{fs, path::PathBuf, thread, time::Duration};
+use tokio_core::reactor::{Handle, Interval};
+
+/// Power can go at any time, so playback is recorded while it goes on rather
+/// than when spotifyd shuts down.
+const SAVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Records what plays on this device, the context, the track and the
+/// position in it, and brings it back, paused, when spotifyd starts again.
+pub struct PlaybackRestore {
+    path: PathBuf,
+    device_name: String,
+    interval: Interval,
+    playing: bool,
+    /// Whether the position is to be recorded once more, after pausing.
+    save_pending: bool,
+    /// Whether the recorded playback is still to be restored.
+    restore_pending: bool,
+}
+
+impl PlaybackRestore {
+    pub fn new(path: PathBuf, device_name: String, handle: &Handle) -> PlaybackRestore {
+        let restore_pending = path.exists();
+        PlaybackRestore {
+            path,
+            device_name,
+            interval: Interval::new(SAVE_INTERVAL, handle).unwrap(),
+            playing: false,
+            save_pending: false,
+            restore_pending,
+        }
+    }
+
+    /// Whether a Web API token is needed to restore or record playback.
+    pub fn is_pending(&self) -> bool {
+        self.restore_pending || self.playing || self.save_pending
+    }
+
+    pub fn handle_event(&mut self, event: &PlayerEvent) {
+        // Once something else plays, there is nothing to restore anymore.
+        self.restore_pending = false;
+        match *event {
+            PlayerEvent::Started { .. } | PlayerEvent::Changed { .. } => self.playing = true,
+            PlayerEvent::Stopped { .. } => {
+                self.playing = false;
+                self.save_pending = true;
+            },
+        }
+    }
+
+    pub fn poll(&mut self, token: Option<RspotifyToken>) {
+        let mut elapsed = false;
+        while let Ok(Async::Ready(Some(()))) = self.interval.poll() {
+            elapsed = true;
+        }
+        let token = match token {
+            Some(token) => token,
+            None => return,
+        };
+        if self.restore_pending {
+            self.restore_pending = false;
+            self.restore(token);
+        } else if self.save_pending || (elapsed && self.playing) {
+            self.save_pending = false;
+            self.save(token);
+        }
+    }
+
+    fn save(&self, token: RspotifyToken) {
+        let path = self.path.clone();
+        let device_name = self.device_name.clone();
+        thread::spawn(move || {
+            let playback = match Playback::current(&web_api::client(&token), &device_name) {
+                Some(playback) => playback,
+                None => return,
+            };
+            if let Err(e) = fs::write(&path, playback.to_json().to_string()) {
+                warn!("Couldn't record playback in {:?}: {}", path, e);
+            }
+        });
+    }
+
+    fn restore(&self, token: RspotifyToken) {
+        let playback = match fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .and_then(|saved| Playback::from_json(&saved))
+        {
+            Some(playback) => playback,
+            None => return,
+        };
+        let device_name = self.device_name.clone();
+        thread::spawn(move || {
+            let client = web_api::client(&token);
+            let device_id = match web_api::find_device(&client, &device_name) {
+                Some(id) => id,
+                None => return,
+            };
+            info!(
+                "Restoring {} at {} ms",
+                playback.track_uri, playback.position_ms
+            );
+            if playback.start(&client, &device_id) {
+                if let Err(e) = client.pause_playback(Some(device_id)) {
+                    warn!("Couldn't pause the restored playback: {}", e);
+                }
+            }
+        });
+    }
+}
+
+/// What played on a device: the context, the track and the position in it.
+#[derive(Clone)]
+pub struct Playback {
+    pub context_uri: Option<String>,
+    pub track_uri: String,
+    pub position_ms: u32,
+}
+
+impl Playback {
+    /// What the named device plays right now, if anything.
+    pub fn current(client: &Spotify, device_name: &str) -> Option<Playback> {
+        let playback = client.current_playback(None).ok()??;
+        let track = match playback.item {
+            Some(ref track) if playback.device.name == device_name => track,
+            _ => return None,
+        };
+        Some(Playback {
+            context_uri: playback.context.as_ref().map(|c| c.uri.clone()),
+            track_uri: track.uri.clone(),
+            position_ms: playback.progress_ms.unwrap_or(0),
+        })
+    }
+
+    fn from_json(saved: &Value) -> Option<Playback> {
+        Some(Playback {
+            context_uri: saved["context_uri"].as_str().map(str::to_string),
+            track_uri: saved["track_uri"].as_str()?.to_string(),
+            position_ms: saved["position_ms"].as_u64().unwrap_or(0) as u32,
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "context_uri": self.context_uri,
+            "track_uri": self.track_uri,
+            "position_ms": self.position_ms,
+        })
+    }
+
+    /// Plays the track again on the device, from the recorded position.
+    /// Returns whether that worked.
+    pub fn start(&self, client: &Spotify, device_id: &str) -> bool {
+        // Tracks can only be started in their context by their URI.
+        let started = match self.context_uri {
+            Some(ref context_uri) => client.start_playback(
+                Some(device_id.to_string()),
+                Some(context_uri.clone()),
+                None,
+                offset::for_uri(self.track_uri.clone()),
+            ),
+            None => client.start_playback(
+                Some(device_id.to_string()),
+                None,
+                Some(vec![self.track_uri.clone()]),
+                None,
+            ),
+        };
+        let started =
+            started.and_then(|_| client.seek_track(self.position_ms, Some(device_id.to_string())));
+        match started {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Couldn't play {} again: {}", self.track_uri, e);
+                false
+            },
+        }
+    }
+}
@@ -1,22 +1,267 @@
+use crate::{backends::UnderrunCause, errors::ErrorCode, web_api};
+use futures::{sync::oneshot, Async, Future};
 use librespot::playback::player::PlayerEvent;
-use log::info;
+use log::{error, info};
+use rspotify::spotify::oauth2::TokenInfo as RspotifyToken;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    io,
     process::{Child, Command},
+    thread,
+    time::Duration,
 };
+use tokio_core::reactor::{Handle, Timeout};
 
-fn run_program(program: &str, env_vars: HashMap<&str, String>) -> Child {
-    let mut v: Vec<&str> = program.split_whitespace().collect();
+/// How often the Web API is asked for the details of an event, since it
+/// takes a moment to know about a track that just started.
+const DETAILS_ATTEMPTS: u32 = 4;
+const DETAILS_RETRY: Duration = Duration::from_millis(500);
+/// How long an event waits for its details before it is passed on without.
+const DETAILS_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Where playback was at a player event, for progress bars and scrobblers.
+pub struct PlaybackDetails {
+    position_ms: u32,
+    duration_ms: u32,
+    /// The playlist, album or the like it plays from.
+    context_uri: Option<String>,
+}
+
+/// The details of a player event, which the Web API is asked for.
+pub enum Details {
+    Pending(oneshot::Receiver<Option<PlaybackDetails>>, Timeout),
+    Known(PlaybackDetails),
+    Unknown,
+}
+
+impl Details {
+    /// Asks the Web API where playback of the event's track is on this
+    /// device, if there is a token to ask with. Details of another track,
+    /// which the Web API may still report right after a change, aren't
+    /// taken.
+    pub fn fetch(
+        token: Option<RspotifyToken>,
+        device_name: &str,
+        event: &PlayerEvent,
+        handle: &Handle,
+    ) -> Details {
+        let token = match token {
+            Some(token) => token,
+            None => return Details::Unknown,
+        };
+        let track_id = match *event {
+            PlayerEvent::Changed { new_track_id, .. } => new_track_id,
+            PlayerEvent::Started { track_id } | PlayerEvent::Stopped { track_id } => track_id,
+        };
+        let track_uri = format!("spotify:track:{}", track_id.to_base62());
+        let device_name = device_name.to_string();
+        let (sender, receiver) = oneshot::channel();
+        thread::spawn(move || {
+            let client = web_api::client(&token);
+            for attempt in 0..DETAILS_ATTEMPTS {
+                if attempt > 0 {
+                    thread::sleep(DETAILS_RETRY);
+                }
+                match client.current_playback(None) {
+                    Ok(Some(ref playback)) if playback.device.name == device_name => {
+                        match playback.item {
+                            Some(ref track) if track.uri == track_uri => {
+                                let _ = sender.send(Some(PlaybackDetails {
+                                    position_ms: playback.progress_ms.unwrap_or(0),
+                                    duration_ms: track.duration_ms,
+                                    context_uri: playback.context.as_ref().map(|c| c.uri.clone()),
+                                }));
+                                return;
+                            },
+                            _ => (),
+                        }
+                    },
+                    _ => break,
+                }
+            }
+            let _ = sender.send(None);
+        });
+        Details::Pending(receiver, Timeout::new(DETAILS_TIMEOUT, handle).unwrap())
+    }
+}
+
+/// Events passed to the onevent program: librespot's player events and the
+/// ones spotifyd raises itself.
+pub enum Event {
+    Player(PlayerEvent, Details),
+    /// Logging in failed because the account isn't a Premium account.
+    PremiumRequired,
+    /// Another Connect device, by its name, took playback over.
+    TakenOver(String),
+    /// The connection to Spotify was lost, e.g. because the account logged
+    /// in elsewhere.
+    SessionLost,
+    /// The audio device ran out of audio, and how often it did for that
+    /// cause so far.
+    Underrun(UnderrunCause, u64),
+}
+
+impl Event {
+    /// Whether the event can be passed on, which a player event can once the
+    /// Web API answered where playback is.
+    pub fn poll_ready(&mut self) -> bool {
+        if let Event::Player(_, ref mut details) = *self {
+            if let Details::Pending(ref mut receiver, ref mut timeout) = *details {
+                match receiver.poll() {
+                    Ok(Async::NotReady) => match timeout.poll() {
+                        Ok(Async::NotReady) => return false,
+                        _ => *details = Details::Unknown,
+                    },
+                    Ok(Async::Ready(Some(known))) => *details = Details::Known(known),
+                    _ => *details = Details::Unknown,
+                }
+            }
+        }
+        true
+    }
+}
+
+/// The onevent program, run on one event at a time and in the order they
+/// happened. Events wait here while it runs and while their details are
+/// fetched, so nothing else handling them has to.
+pub struct EventProgram {
+    pub program: Option<String>,
+    pending: VecDeque<Event>,
+    running: Option<Child>,
+}
+
+impl EventProgram {
+    pub fn new(program: Option<String>) -> EventProgram {
+        EventProgram {
+            program,
+            pending: VecDeque::new(),
+            running: None,
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.program.is_some()
+    }
+
+    /// Queues `event` for the program, if there is one.
+    pub fn push(&mut self, event: Event) {
+        if self.is_set() {
+            self.pending.push_back(event);
+        }
+    }
+
+    /// Runs the program on the next event once the last run exited and the
+    /// event is ready.
+    pub fn poll(&mut self) {
+        if let Some(mut child) = self.running.take() {
+            if let Ok(None) = child.try_wait() {
+                self.running = Some(child);
+                return;
+            }
+        }
+        let ready = self.pending.front_mut().map_or(false, Event::poll_ready);
+        if !ready {
+            return;
+        }
+        if let (Some(event), Some(program)) = (self.pending.pop_front(), self.program.as_ref()) {
+            match run_program_on_events(event, program) {
+                Ok(child) => self.running = Some(child),
+                Err(e) => error!("Couldn't run {}: {}", program, e),
+            }
+        }
+    }
+}
+
+/// Quotes an argument of a command for `split_command`, if it needs it.
+pub fn quote(argument: &str) -> String {
+    if argument.is_empty()
+        || argument.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\')
+    {
+        format!(
+            "\"{}\"",
+            argument.replace('\\', "\\\\").replace('"', "\\\"")
+        )
+    } else {
+        argument.to_string()
+    }
+}
+
+/// Splits a command at whitespace into the program and its arguments, where
+/// double quotes keep an argument together and a backslash escapes the
+/// character after it within them.
+pub fn split_command(command: &str) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let mut argument: Option<String> = None;
+    let mut quoted = false;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                argument.get_or_insert_with(String::new);
+            },
+            '\\' if quoted => {
+                if let Some(c) = chars.next() {
+                    argument.get_or_insert_with(String::new).push(c);
+                }
+            },
+            c if c.is_whitespace() && !quoted => arguments.extend(argument.take()),
+            c => argument.get_or_insert_with(String::new).push(c),
+        }
+    }
+    arguments.extend(argument);
+    arguments
+}
+
+pub fn run_program(program: &str, env_vars: HashMap<&str, String>) -> io::Result<Child> {
+    let mut v = split_command(program);
+    if v.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the command is empty",
+        ));
+    }
     info!("Running {:?} with environment variables {:?}", v, env_vars);
     Command::new(&v.remove(0))
         .args(&v)
         .envs(env_vars.iter())
         .spawn()
-        .expect("program failed to start")
 }
 
-pub fn run_program_on_events(event: PlayerEvent, onevent: &str) -> Child {
+pub fn run_program_on_events(event: Event, onevent: &str) -> io::Result<Child> {
     let mut env_vars = HashMap::new();
+    let event = match event {
+        Event::Player(event, details) => {
+            if let Details::Known(details) = details {
+                env_vars.insert("POSITION_MS", details.position_ms.to_string());
+                env_vars.insert("DURATION_MS", details.duration_ms.to_string());
+                if let Some(uri) = details.context_uri {
+                    env_vars.insert("CONTEXT_URI", uri);
+                }
+            }
+            event
+        },
+        Event::PremiumRequired => {
+            env_vars.insert("PLAYER_EVENT", "premium_required".to_string());
+            env_vars.insert("ERROR_CODE", ErrorCode::PremiumRequired.code().to_string());
+            return run_program(onevent, env_vars);
+        },
+        Event::TakenOver(device_name) => {
+            env_vars.insert("PLAYER_EVENT", "taken_over".to_string());
+            env_vars.insert("DEVICE_NAME", device_name);
+            return run_program(onevent, env_vars);
+        },
+        Event::SessionLost => {
+            env_vars.insert("PLAYER_EVENT", "session_lost".to_string());
+            return run_program(onevent, env_vars);
+        },
+        Event::Underrun(cause, count) => {
+            env_vars.insert("PLAYER_EVENT", "underrun".to_string());
+            env_vars.insert("UNDERRUN_CAUSE", cause.name().to_string());
+            env_vars.insert("UNDERRUNS", count.to_string());
+            return run_program(onevent, env_vars);
+        },
+    };
     match event {
         PlayerEvent::Changed {
             old_track_id,
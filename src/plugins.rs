@@ -0,0 +1,212 @@
+use crate::{lirc::RemoteAction, web_api};
+use futures::{
+    sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    task, Async, Stream,
+};
+use librespot::{connect::spirc::Spirc, playback::player::PlayerEvent};
+use log::{error, info, warn};
+use rlua::{Context, Function, Lua, Table};
+use rspotify::spotify::oauth2::TokenInfo as RspotifyToken;
+use std::{
+    fs,
+    path::PathBuf,
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
+
+/// What a plugin asks spotifyd to do.
+enum PluginCommand {
+    Action(RemoteAction),
+    /// To this many percent.
+    Volume(u8),
+}
+
+/// Lua scripts that are told about player events through their `on_event`
+/// function, and control playback through the `spotifyd` table. They all run
+/// in a thread of their own, so a slow one holds up the other plugins but not
+/// playback.
+pub struct Plugins {
+    events: Sender<PlayerEvent>,
+    commands: UnboundedReceiver<PluginCommand>,
+    /// A volume waiting for a Web API token, as Spirc can't set it.
+    pending_volume: Option<u8>,
+}
+
+impl Plugins {
+    pub fn new(scripts: Vec<PathBuf>) -> Plugins {
+        let (events, receiver) = channel();
+        let (sender, commands) = unbounded();
+        thread::spawn(move || run(&scripts, &receiver, &sender));
+        Plugins {
+            events,
+            commands,
+            pending_volume: None,
+        }
+    }
+
+    pub fn handle_event(&self, event: &PlayerEvent) {
+        let _ = self.events.send(event.clone());
+    }
+
+    /// Whether a Web API token is needed to carry out a command.
+    pub fn is_pending(&self) -> bool {
+        self.pending_volume.is_some()
+    }
+
+    pub fn poll(&mut self, spirc: Option<&Spirc>, token: Option<RspotifyToken>, device_name: &str) {
+        while let Ok(Async::Ready(Some(command))) = self.commands.poll() {
+            match command {
+                PluginCommand::Action(action) => {
+                    if let Some(spirc) = spirc {
+                        action.apply(spirc);
+                    }
+                },
+                PluginCommand::Volume(volume) => {
+                    // The main loop asks for a token on its next turn.
+                    if self.pending_volume.is_none() {
+                        task::current().notify();
+                    }
+                    self.pending_volume = Some(volume);
+                },
+            }
+        }
+        if let (Some(token), Some(volume)) = (token, self.pending_volume) {
+            self.pending_volume = None;
+            web_api::set_volume(token, device_name.to_string(), volume);
+        }
+    }
+}
+
+fn run(
+    scripts: &[PathBuf],
+    events: &Receiver<PlayerEvent>,
+    commands: &UnboundedSender<PluginCommand>,
+) {
+    let plugins: Vec<(&PathBuf, Lua)> = scripts
+        .iter()
+        .filter_map(|script| match load(script, commands) {
+            Ok(lua) => {
+                info!("Loaded plugin {:?}", script);
+                Some((script, lua))
+            },
+            Err(e) => {
+                error!("Couldn't load plugin {:?}: {}", script, e);
+                None
+            },
+        })
+        .collect();
+    for event in events.iter() {
+        for &(script, ref lua) in &plugins {
+            if let Err(e) = lua.context(|ctx| on_event(ctx, &event)) {
+                warn!("Plugin {:?} failed on an event: {}", script, e);
+            }
+        }
+    }
+}
+
+fn load(script: &PathBuf, commands: &UnboundedSender<PluginCommand>) -> rlua::Result<Lua> {
+    let source = fs::read(script).map_err(rlua::Error::external)?;
+    let lua = Lua::new();
+    lua.context(|ctx| {
+        ctx.globals().set("spotifyd", api(ctx, commands)?)?;
+        ctx.load(&source)
+            .set_name(script.to_string_lossy().as_bytes())?
+            .exec()
+    })?;
+    Ok(lua)
+}
+
+/// The `spotifyd` table: playback control, plain HTTP requests for talking
+/// to other devices, and spotifyd's log.
+fn api<'lua>(
+    ctx: Context<'lua>,
+    commands: &UnboundedSender<PluginCommand>,
+) -> rlua::Result<Table<'lua>> {
+    let api = ctx.create_table()?;
+    let actions = [
+        ("play", RemoteAction::Play),
+        ("pause", RemoteAction::Pause),
+        ("play_pause", RemoteAction::PlayPause),
+        ("next", RemoteAction::Next),
+        ("previous", RemoteAction::Previous),
+        ("volume_up", RemoteAction::VolumeUp),
+        ("volume_down", RemoteAction::VolumeDown),
+    ];
+    for &(name, action) in &actions {
+        let commands = commands.clone();
+        api.set(
+            name,
+            ctx.create_function(move |_, ()| {
+                let _ = commands.unbounded_send(PluginCommand::Action(action));
+                Ok(())
+            })?,
+        )?;
+    }
+    let commands = commands.clone();
+    api.set(
+        "set_volume",
+        ctx.create_function(move |_, percent: f64| {
+            let volume = percent.max(0.0).min(100.0).round() as u8;
+            let _ = commands.unbounded_send(PluginCommand::Volume(volume));
+            Ok(())
+        })?,
+    )?;
+    // Both return the status code and the body of the response.
+    api.set(
+        "http_get",
+        ctx.create_function(|_, url: String| {
+            reqwest::get(&url)
+                .and_then(|mut response| Ok((response.status().as_u16(), response.text()?)))
+                .map_err(rlua::Error::external)
+        })?,
+    )?;
+    api.set(
+        "http_post",
+        ctx.create_function(|_, (url, body): (String, Option<String>)| {
+            reqwest::Client::new()
+                .post(&url)
+                .body(body.unwrap_or_default())
+                .send()
+                .and_then(|mut response| Ok((response.status().as_u16(), response.text()?)))
+                .map_err(rlua::Error::external)
+        })?,
+    )?;
+    api.set(
+        "log",
+        ctx.create_function(|_, message: String| {
+            info!("{}", message);
+            Ok(())
+        })?,
+    )?;
+    Ok(api)
+}
+
+/// Calls the plugin's `on_event` with a table like the environment of the
+/// onevent program: `event`, `track_id` and, on changes, `old_track_id`.
+fn on_event(ctx: Context, event: &PlayerEvent) -> rlua::Result<()> {
+    let on_event: Option<Function> = ctx.globals().get("on_event")?;
+    let on_event = match on_event {
+        Some(on_event) => on_event,
+        None => return Ok(()),
+    };
+    let table = ctx.create_table()?;
+    match *event {
+        PlayerEvent::Changed {
+            old_track_id,
+            new_track_id,
+        } => {
+            table.set("event", "change")?;
+            table.set("old_track_id", old_track_id.to_base62())?;
+            table.set("track_id", new_track_id.to_base62())?;
+        },
+        PlayerEvent::Started { track_id } => {
+            table.set("event", "start")?;
+            table.set("track_id", track_id.to_base62())?;
+        },
+        PlayerEvent::Stopped { track_id } => {
+            table.set("event", "stop")?;
+            table.set("track_id", track_id.to_base62())?;
+        },
+    }
+    on_event.call(table)
+}
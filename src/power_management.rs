@@ -0,0 +1,184 @@
+use crate::{config::PowerConfig, player_event_handler::run_program};
+use librespot::playback::player::PlayerEvent;
+use log::{error, info};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::Path,
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+const GPIO_ROOT: &str = "/sys/class/gpio";
+
+/// Switches connected equipment on when playback starts and off again after
+/// a period of silence, either through commands or a GPIO pin driving a relay
+/// or 12V trigger.
+///
+/// Whether the equipment is powered is kept in a state file, so that after a
+/// restart spotifyd still knows it has to switch it off.
+pub struct PowerManager {
+    sender: Sender<PowerEvent<()>>,
+}
+
+impl PowerManager {
+    pub fn new(config: PowerConfig) -> PowerManager {
+        let (sender, receiver) = channel();
+        thread::spawn(move || run(&config, &receiver));
+        PowerManager { sender }
+    }
+
+    pub fn handle_event(&self, event: &PlayerEvent) {
+        let event = match *event {
+            PlayerEvent::Started { .. } | PlayerEvent::Changed { .. } => PowerEvent::Playing,
+            PlayerEvent::Stopped { .. } => PowerEvent::Stopped,
+        };
+        let _ = self.sender.send(event);
+    }
+}
+
+fn read_state(config: &PowerConfig) -> bool {
+    config
+        .state_file
+        .as_ref()
+        .and_then(|f| fs::read_to_string(f).ok())
+        .map_or(false, |s| s.trim() == "on")
+}
+
+fn write_state(config: &PowerConfig, powered: bool) {
+    if let Some(ref f) = config.state_file {
+        if let Err(e) = fs::write(f, if powered { "on\n" } else { "off\n" }) {
+            error!("Couldn't save power state to {:?}: {}", f, e);
+        }
+    }
+}
+
+fn set_gpio(pin: u32, value: bool) -> io::Result<()> {
+    let root = Path::new(GPIO_ROOT);
+    let pin_dir = root.join(format!("gpio{}", pin));
+    if !pin_dir.exists() {
+        fs::write(root.join("export"), pin.to_string())?;
+    }
+    fs::write(pin_dir.join("direction"), "out")?;
+    fs::write(pin_dir.join("value"), if value { "1" } else { "0" })
+}
+
+fn switch(config: &PowerConfig, on: bool) {
+    info!("Switching power {}.", if on { "on" } else { "off" });
+    if let Some(pin) = config.gpio {
+        if let Err(e) = set_gpio(pin, on) {
+            error!("Couldn't set GPIO {}: {}", pin, e);
+        }
+    }
+    let command = if on {
+        &config.on_command
+    } else {
+        &config.off_command
+    };
+    if let Some(ref command) = *command {
+        if let Err(e) = run_program(command, HashMap::new()).and_then(|mut child| child.wait()) {
+            error!("Couldn't run {}: {}", command, e);
+        }
+    }
+    write_state(config, on);
+}
+
+/// The equipment `power_*` settings switch.
+struct Power<'a>(&'a PowerConfig);
+
+impl<'a> Switched for Power<'a> {
+    type Event = ();
+
+    fn switch_on(&mut self) {
+        switch(self.0, true);
+    }
+
+    fn switch_off(&mut self) {
+        switch(self.0, false);
+    }
+}
+
+fn run(config: &PowerConfig, receiver: &Receiver<PowerEvent<()>>) {
+    // Nothing is playing right after startup, so anything left on by a
+    // previous run gets switched off after the usual delay.
+    switch_when_idle(
+        &mut Power(config),
+        receiver,
+        read_state(config),
+        config.off_delay,
+    );
+}
+
+/// Equipment that is switched on when playback starts and off again once it
+/// has been stopped for a while.
+pub trait Switched {
+    /// What the equipment is told besides that playback started or stopped.
+    type Event;
+
+    fn switch_on(&mut self);
+    fn switch_off(&mut self);
+    fn handle(&mut self, _event: Self::Event) {}
+}
+
+pub enum PowerEvent<T> {
+    Playing,
+    Stopped,
+    // Only the serial amplifier is told anything else.
+    #[cfg_attr(not(feature = "serial_amp"), allow(dead_code))]
+    Other(T),
+}
+
+/// Switches the equipment as the events come in, until the sender hangs up.
+/// It is switched off `off_delay` after playback stopped, unless playback
+/// starts again before. Equipment that is `powered` to begin with is
+/// switched off after that delay too.
+pub fn switch_when_idle<S: Switched>(
+    equipment: &mut S,
+    receiver: &Receiver<PowerEvent<S::Event>>,
+    mut powered: bool,
+    off_delay: Duration,
+) {
+    let mut power_off_at = if powered {
+        Some(Instant::now() + off_delay)
+    } else {
+        None
+    };
+
+    loop {
+        let event = match power_off_at {
+            Some(deadline) => {
+                let now = Instant::now();
+                let timeout = if deadline > now {
+                    deadline - now
+                } else {
+                    Duration::from_secs(0)
+                };
+                receiver.recv_timeout(timeout)
+            },
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match event {
+            Ok(PowerEvent::Playing) => {
+                power_off_at = None;
+                if !powered {
+                    equipment.switch_on();
+                    powered = true;
+                }
+            },
+            Ok(PowerEvent::Stopped) => {
+                if powered && power_off_at.is_none() {
+                    power_off_at = Some(Instant::now() + off_delay);
+                }
+            },
+            Ok(PowerEvent::Other(event)) => equipment.handle(event),
+            Err(RecvTimeoutError::Timeout) => {
+                equipment.switch_off();
+                powered = false;
+                power_off_at = None;
+            },
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use log::{info, warn};
+use std::{
+    ffi::{CStr, CString},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+/// Switches from root to `user` and `group`, once the sockets are bound and
+/// the log file is open, after handing the cache over to them. The user's
+/// supplementary groups, e.g. `audio`, come along. Returns why it couldn't,
+/// in which case spotifyd must not go on as root.
+pub fn drop_to(user: Option<&str>, group: Option<&str>, owned: &[&Path]) -> Result<(), String> {
+    if user.is_none() && group.is_none() {
+        return Ok(());
+    }
+    if unsafe { libc::geteuid() } != 0 {
+        warn!("user and group are ignored, spotifyd isn't running as root.");
+        return Ok(());
+    }
+    let (uid, user_gid, name) = match user {
+        Some(user) => {
+            let name = CString::new(user).map_err(|e| e.to_string())?;
+            let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+            if passwd.is_null() {
+                return Err(format!("there is no user {}", user));
+            }
+            let passwd = unsafe { &*passwd };
+            (passwd.pw_uid, Some(passwd.pw_gid), Some(name))
+        },
+        None => (0, None, None),
+    };
+    let gid = match group {
+        Some(group) => {
+            let name = CString::new(group).map_err(|e| e.to_string())?;
+            let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+            if entry.is_null() {
+                return Err(format!("there is no group {}", group));
+            }
+            unsafe { (*entry).gr_gid }
+        },
+        None => user_gid.unwrap_or(0),
+    };
+
+    for path in owned {
+        if let Err(e) = chown_all(path, uid, gid) {
+            warn!("Couldn't hand {:?} over to user {}: {}", path, uid, e);
+        }
+    }
+
+    check(match name {
+        Some(ref name) => unsafe { libc::initgroups(name.as_ptr(), gid as _) },
+        None => unsafe { libc::setgroups(0, std::ptr::null()) },
+    })?;
+    check(unsafe { libc::setgid(gid) })?;
+    if user.is_some() {
+        check(unsafe { libc::setuid(uid) })?;
+    }
+    info!(
+        "Running as user {} and group {}",
+        user.map_or_else(|| "root".to_string(), str::to_string),
+        group_name(gid)
+    );
+    Ok(())
+}
+
+/// The ID of `user`, if there is such a user.
+pub fn uid_of(user: &str) -> Option<libc::uid_t> {
+    let name = CString::new(user).ok()?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        None
+    } else {
+        Some(unsafe { (*passwd).pw_uid })
+    }
+}
+
+fn check(result: libc::c_int) -> Result<(), String> {
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error().to_string())
+    }
+}
+
+/// Changes the owner of `path`, which is configured, and everything below
+/// it. This runs as root in directories the user owns, so below `path`
+/// links are never followed, as they could point anywhere, e.g. at `/etc`.
+/// Directories are opened relative to their parent without following links,
+/// so none can be swapped for a link midway.
+fn chown_all(path: &Path, uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let dir = unsafe {
+        libc::open(
+            c_path.as_ptr(),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )
+    };
+    if dir < 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENOENT) => Ok(()),
+            _ => Err(e),
+        };
+    }
+    if unsafe { libc::fchown(dir, uid, gid) } != 0 {
+        let e = io::Error::last_os_error();
+        unsafe { libc::close(dir) };
+        return Err(e);
+    }
+    chown_below(dir, uid, gid)
+}
+
+/// Changes the owner of what is in the open directory `dir`, which it
+/// closes, and below.
+fn chown_below(dir: libc::c_int, uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    let stream = unsafe { libc::fdopendir(dir) };
+    if stream.is_null() {
+        let e = io::Error::last_os_error();
+        unsafe { libc::close(dir) };
+        return Err(e);
+    }
+    let result = chown_entries(stream, uid, gid);
+    unsafe { libc::closedir(stream) };
+    result
+}
+
+fn chown_entries(stream: *mut libc::DIR, uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    let dir = unsafe { libc::dirfd(stream) };
+    loop {
+        let entry = unsafe { libc::readdir(stream) };
+        if entry.is_null() {
+            return Ok(());
+        }
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        if name.to_bytes() == b"." || name.to_bytes() == b".." {
+            continue;
+        }
+        if unsafe { libc::fchownat(dir, name.as_ptr(), uid, gid, libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Fails for anything but a directory, links to one included.
+        let child = unsafe {
+            libc::openat(
+                dir,
+                name.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            )
+        };
+        if child >= 0 {
+            chown_below(child, uid, gid)?;
+        }
+    }
+}
+
+fn group_name(gid: libc::gid_t) -> String {
+    let entry = unsafe { libc::getgrgid(gid) };
+    if entry.is_null() {
+        gid.to_string()
+    } else {
+        unsafe { CStr::from_ptr((*entry).gr_name) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
@@ -0,0 +1,340 @@
+use crate::{ap_policy::SharedApPolicy, data_usage::SharedDataUsage};
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::{
+    error::Error,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    thread,
+};
+use url::Url;
+
+/// The domain of the access point resolver and the access points, the only
+/// hosts the relay passes connections on to.
+const SPOTIFY_DOMAIN: &str = "spotify.com";
+
+/// Makes `proxy` usable as librespot's proxy, which can only tunnel through
+/// HTTP proxies that don't ask for credentials. For anything else, a relay
+/// on localhost takes librespot's requests and passes them on through the
+/// proxy. The relay also applies the access point policy, if there is one,
+/// and counts the data received, if asked to, with or without a proxy.
+/// Returns the address librespot should use.
+pub fn librespot_proxy(
+    proxy: Option<Url>,
+    policy: Option<SharedApPolicy>,
+    usage: Option<SharedDataUsage>,
+) -> io::Result<Option<Url>> {
+    let relayed = match proxy {
+        Some(ref proxy) => proxy.scheme() != "http" || !proxy.username().is_empty(),
+        None => false,
+    };
+    if !relayed && policy.is_none() && usage.is_none() {
+        return Ok(proxy);
+    }
+    // The system picks a free port at random.
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let local = Url::parse(&format!("http://{}", listener.local_addr()?)).unwrap();
+    if let Some(ref proxy) = proxy {
+        info!(
+            "Relaying connections through {}",
+            proxy.host_str().unwrap_or("")
+        );
+    }
+    thread::spawn(move || {
+        for client in listener.incoming().filter_map(Result::ok) {
+            let proxy = proxy.clone();
+            let policy = policy.clone();
+            let usage = usage.clone();
+            thread::spawn(move || {
+                if let Err(e) = relay(client, proxy.as_ref(), policy.as_ref(), usage.as_ref()) {
+                    warn!("Couldn't relay a connection: {}", e);
+                }
+            });
+        }
+    });
+    Ok(Some(local))
+}
+
+/// Passes one connection from librespot on, through the proxy if there is
+/// one. librespot either asks to `CONNECT` to an access point, or sends a
+/// plain HTTP request to resolve the access points.
+fn relay(
+    mut client: TcpStream,
+    proxy: Option<&Url>,
+    policy: Option<&SharedApPolicy>,
+    usage: Option<&SharedDataUsage>,
+) -> io::Result<()> {
+    let head = read_head(&mut client)?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or("");
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    // Any local program can connect to the relay, which mustn't take it,
+    // with the proxy's credentials, anywhere but to Spotify.
+    let host = if method == "CONNECT" {
+        split_host_port(target, 443)?.0
+    } else {
+        Url::parse(target)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default()
+    };
+    if !is_spotify_host(&host) {
+        return refuse(client, target);
+    }
+    let resolving = method != "CONNECT" && policy.is_some();
+    if let (Some(policy), "CONNECT") = (policy, method) {
+        // The relay has the resolver's answer, so it only lets librespot
+        // through to the access points in it.
+        if !policy.is_access_point(target) {
+            return refuse(client, target);
+        }
+        policy.connecting_to(target);
+    }
+    // Without keep-alive, the resolver closes the connection once it has
+    // answered, so the answer can be read whole.
+    let version = if resolving { "HTTP/1.0" } else { "HTTP/1.1" };
+    let headers: Vec<&str> = lines
+        .filter(|l| !l.is_empty())
+        .filter(|l| !resolving || !l.to_lowercase().starts_with("connection:"))
+        .collect();
+
+    let server = match proxy {
+        Some(proxy) if proxy.scheme() == "http" => {
+            // The proxy takes the request as it is, only with credentials.
+            let mut server = connect(proxy, 8080)?;
+            write!(server, "{} {} {}\r\n", method, target, version)?;
+            if !proxy.username().is_empty() {
+                let credentials = format!(
+                    "{}:{}",
+                    decode(proxy.username()),
+                    decode(proxy.password().unwrap_or(""))
+                );
+                write!(
+                    server,
+                    "Proxy-Authorization: Basic {}\r\n",
+                    base64::encode(&credentials)
+                )?;
+            }
+            for header in headers {
+                write!(server, "{}\r\n", header)?;
+            }
+            server.write_all(b"\r\n")?;
+            server
+        },
+        _ => {
+            if method == "CONNECT" {
+                let (host, port) = split_host_port(target, 443)?;
+                let server = match proxy {
+                    Some(proxy) => socks5_connect(proxy, &host, port)?,
+                    None => TcpStream::connect((&*host, port))?,
+                };
+                client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+                server
+            } else {
+                let url = Url::parse(target).map_err(invalid)?;
+                let host = url.host_str().ok_or_else(|| invalid("no host"))?;
+                let port = url.port().unwrap_or(80);
+                let mut server = match proxy {
+                    Some(proxy) => socks5_connect(proxy, host, port)?,
+                    None => TcpStream::connect((host, port))?,
+                };
+                let path = &url[url::Position::BeforePath..];
+                write!(server, "{} {} {}\r\n", method, path, version)?;
+                for header in headers {
+                    write!(server, "{}\r\n", header)?;
+                }
+                server.write_all(b"\r\n")?;
+                server
+            }
+        },
+    };
+    match policy {
+        Some(policy) if resolving => answer_resolve(client, server, policy, usage),
+        _ => pipe(client, server, usage),
+    }
+}
+
+fn refuse(mut client: TcpStream, target: &str) -> io::Result<()> {
+    client.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")?;
+    Err(invalid(format!("refused to relay to {}", target)))
+}
+
+/// Passes the resolver's answer on with only the access points the policy
+/// allows, in its order.
+fn answer_resolve(
+    mut client: TcpStream,
+    mut server: TcpStream,
+    policy: &SharedApPolicy,
+    usage: Option<&SharedDataUsage>,
+) -> io::Result<()> {
+    let mut response = Vec::new();
+    server.read_to_end(&mut response)?;
+    if let Some(usage) = usage {
+        usage.received(response.len() as u64);
+    }
+    let body_start = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map_or(response.len(), |i| i + 4);
+    let mut answer: Value = match serde_json::from_slice(&response[body_start..]) {
+        Ok(answer) => answer,
+        Err(_) => return client.write_all(&response),
+    };
+    let access_points = answer["ap_list"]
+        .as_array()
+        .map(|aps| {
+            aps.iter()
+                .filter_map(|ap| ap.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default();
+    answer["ap_list"] = json!(policy.apply(access_points));
+    let body = answer.to_string();
+    let status_line = String::from_utf8_lossy(&response)
+        .lines()
+        .next()
+        .unwrap_or("HTTP/1.0 200 OK")
+        .to_string();
+    write!(
+        client,
+        "{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status_line,
+        body.len(),
+        body
+    )
+}
+
+/// Reads a request up to the empty line after its headers.
+fn read_head(client: &mut TcpStream) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+        if client.read(&mut byte)? == 0 {
+            return Err(invalid("connection closed"));
+        }
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).map_err(invalid)
+}
+
+fn connect(proxy: &Url, default_port: u16) -> io::Result<TcpStream> {
+    let host = proxy.host_str().ok_or_else(|| invalid("no proxy host"))?;
+    TcpStream::connect((host, proxy.port().unwrap_or(default_port)))
+}
+
+/// Connects to `host` through a SOCKS5 proxy, as in RFC 1928 and, for
+/// credentials, RFC 1929. The proxy resolves the host name.
+fn socks5_connect(proxy: &Url, host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut server = connect(proxy, 1080)?;
+    let (username, password) = (
+        decode(proxy.username()),
+        decode(proxy.password().unwrap_or("")),
+    );
+    // Each of these is preceded by its length in a byte.
+    for field in &[username.as_str(), password.as_str(), host] {
+        if field.len() > 255 {
+            return Err(invalid("the proxy credentials or host name are too long"));
+        }
+    }
+    let method = if username.is_empty() { 0 } else { 2 };
+    server.write_all(&[5, 1, method])?;
+    let mut reply = [0; 2];
+    server.read_exact(&mut reply)?;
+    if reply[1] != method {
+        return Err(invalid("the proxy refused the authentication method"));
+    }
+    if method == 2 {
+        let mut request = vec![1, username.len() as u8];
+        request.extend(username.as_bytes());
+        request.push(password.len() as u8);
+        request.extend(password.as_bytes());
+        server.write_all(&request)?;
+        server.read_exact(&mut reply)?;
+        if reply[1] != 0 {
+            return Err(invalid("the proxy refused the credentials"));
+        }
+    }
+
+    let mut request = vec![5, 1, 0, 3, host.len() as u8];
+    request.extend(host.as_bytes());
+    request.extend(&port.to_be_bytes());
+    server.write_all(&request)?;
+    let mut reply = [0; 4];
+    server.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        return Err(invalid(format!(
+            "the proxy failed to connect, code {}",
+            reply[1]
+        )));
+    }
+    // Skip the address the proxy connected from.
+    let address_length = match reply[3] {
+        1 => 4,
+        4 => 16,
+        _ => {
+            let mut length = [0];
+            server.read_exact(&mut length)?;
+            usize::from(length[0])
+        },
+    };
+    let mut address = vec![0; address_length + 2];
+    server.read_exact(&mut address)?;
+    Ok(server)
+}
+
+/// Copies data both ways until either side closes the connection, counting
+/// what comes from the server.
+fn pipe(client: TcpStream, server: TcpStream, usage: Option<&SharedDataUsage>) -> io::Result<()> {
+    let (mut client_reader, mut server_writer) = (client.try_clone()?, server.try_clone()?);
+    let upstream = thread::spawn(move || {
+        let _ = io::copy(&mut client_reader, &mut server_writer);
+        let _ = server_writer.shutdown(Shutdown::Write);
+    });
+    let (mut server_reader, mut client_writer) = (server, client);
+    let mut buffer = [0; 16 * 1024];
+    loop {
+        match server_reader.read(&mut buffer) {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                if let Some(usage) = usage {
+                    usage.received(n as u64);
+                }
+                if client_writer.write_all(&buffer[..n]).is_err() {
+                    break;
+                }
+            },
+        }
+    }
+    let _ = client_writer.shutdown(Shutdown::Write);
+    let _ = upstream.join();
+    Ok(())
+}
+
+/// Whether `host` is Spotify's, and so one librespot connects to.
+fn is_spotify_host(host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_lowercase();
+    host == SPOTIFY_DOMAIN || host.ends_with(&format!(".{}", SPOTIFY_DOMAIN))
+}
+
+fn split_host_port(target: &str, default_port: u16) -> io::Result<(String, u16)> {
+    match target.rfind(':') {
+        Some(i) => {
+            let port = target[i + 1..].parse().map_err(invalid)?;
+            Ok((target[..i].to_string(), port))
+        },
+        None => Ok((target.to_string(), default_port)),
+    }
+}
+
+/// Credentials in URLs are percent-encoded.
+fn decode(s: &str) -> String {
+    url::percent_encoding::percent_decode(s.as_bytes())
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+fn invalid<E: Into<Box<Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
@@ -0,0 +1,160 @@
+use crate::{config::RecentlyPlayedConfig, track_metadata::TrackMetadata};
+use chrono::Local;
+use librespot::{core::spotify_id::SpotifyId, playback::player::PlayerEvent};
+use log::error;
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// A track that played, as listed by `recently_played`.
+#[derive(Clone)]
+pub struct PlayedTrack {
+    pub uri: String,
+    pub title: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    /// When it started, in RFC 3339.
+    pub played_at: String,
+}
+
+impl PlayedTrack {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.uri,
+            "title": self.title,
+            "artists": self.artists,
+            "album": self.album,
+            "played_at": self.played_at,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<PlayedTrack> {
+        let string = |key: &str| value[key].as_str().map(str::to_string);
+        Some(PlayedTrack {
+            uri: string("id")?,
+            title: string("title").unwrap_or_default(),
+            artists: value["artists"]
+                .as_array()
+                .map(|artists| {
+                    artists
+                        .iter()
+                        .filter_map(|a| a.as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            album: string("album").unwrap_or_default(),
+            played_at: string("played_at").unwrap_or_default(),
+        })
+    }
+
+    /// The track as D-Bus hands it out, a dictionary of strings.
+    pub fn to_map(&self) -> HashMap<String, String> {
+        let mut map = HashMap::new();
+        map.insert("id".to_string(), self.uri.clone());
+        map.insert("title".to_string(), self.title.clone());
+        map.insert("artist".to_string(), self.artists.join(", "));
+        map.insert("album".to_string(), self.album.clone());
+        map.insert("played_at".to_string(), self.played_at.clone());
+        map
+    }
+}
+
+struct RecentlyPlayed {
+    /// The most recent first.
+    tracks: VecDeque<PlayedTrack>,
+    count: usize,
+    file: Option<PathBuf>,
+    last: Option<SpotifyId>,
+    /// When the last track started, until it is listed.
+    played_at: Option<String>,
+}
+
+impl RecentlyPlayed {
+    fn save(&self) {
+        let file = match self.file {
+            Some(ref file) => file,
+            None => return,
+        };
+        let tracks: Vec<Value> = self.tracks.iter().map(PlayedTrack::to_json).collect();
+        if let Err(e) = fs::write(file, Value::Array(tracks).to_string()) {
+            error!(
+                "Couldn't save the recently played tracks to {:?}: {}",
+                file, e
+            );
+        }
+    }
+}
+
+/// The last tracks that played, kept in the cache so that they are still
+/// known after playback stopped or spotifyd restarted. Shared with the
+/// control socket and D-Bus, which list them.
+#[derive(Clone)]
+pub struct SharedRecentlyPlayed(Arc<Mutex<RecentlyPlayed>>);
+
+impl SharedRecentlyPlayed {
+    pub fn new(config: RecentlyPlayedConfig) -> SharedRecentlyPlayed {
+        let tracks = config
+            .file
+            .as_ref()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .and_then(|s| serde_json::from_str::<Value>(&s).ok())
+            .and_then(|value| {
+                value
+                    .as_array()
+                    .map(|tracks| tracks.iter().filter_map(PlayedTrack::from_json).collect())
+            })
+            .unwrap_or_else(VecDeque::new);
+        SharedRecentlyPlayed(Arc::new(Mutex::new(RecentlyPlayed {
+            tracks,
+            count: config.count,
+            file: config.file,
+            last: None,
+            played_at: None,
+        })))
+    }
+
+    /// The most recent first, the one playing included.
+    pub fn tracks(&self) -> Vec<PlayedTrack> {
+        self.0.lock().unwrap().tracks.iter().cloned().collect()
+    }
+
+    pub fn handle_event(&self, event: &PlayerEvent) {
+        let track_id = match *event {
+            PlayerEvent::Started { track_id } => track_id,
+            PlayerEvent::Changed { new_track_id, .. } => new_track_id,
+            PlayerEvent::Stopped { .. } => return,
+        };
+        let mut recent = self.0.lock().unwrap();
+        // Resuming after a pause starts the same track again.
+        if recent.last != Some(track_id) {
+            recent.last = Some(track_id);
+            recent.played_at = Some(Local::now().to_rfc3339());
+        }
+    }
+
+    /// Lists the track that started last, once its title is known.
+    pub fn handle_metadata(&self, metadata: &TrackMetadata) {
+        let mut recent = self.0.lock().unwrap();
+        if recent.last != Some(metadata.track_id) {
+            return;
+        }
+        let played_at = match recent.played_at.take() {
+            Some(played_at) => played_at,
+            None => return,
+        };
+        recent.tracks.push_front(PlayedTrack {
+            uri: format!("spotify:track:{}", metadata.track_id.to_base62()),
+            title: metadata.title.clone(),
+            artists: metadata.artists.clone(),
+            album: metadata.album.clone(),
+            played_at,
+        });
+        let count = recent.count;
+        recent.tracks.truncate(count);
+        recent.save();
+    }
+}
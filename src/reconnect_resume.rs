@@ -0,0 +1,101 @@
+use crate::{playback_restore::Playback, web_api};
+use futures::{Async, Stream};
+use librespot::playback::player::PlayerEvent;
+use log::info;
+use rspotify::spotify::oauth2::TokenInfo as RspotifyToken;
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio_core::reactor::{Handle, Interval};
+
+/// The Web API can't be asked anymore once the connection is gone, so
+/// playback is recorded while it goes on.
+const RECORD_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Picks playback up again where it was when the connection to Spotify was
+/// lost during playback, as soon as spotifyd is connected again.
+pub struct ReconnectResume {
+    device_name: String,
+    interval: Interval,
+    playing: bool,
+    /// The playback recorded last, and when.
+    last: Arc<Mutex<Option<(Playback, Instant)>>>,
+    /// What to resume once connected again.
+    resume: Option<Playback>,
+}
+
+impl ReconnectResume {
+    pub fn new(device_name: String, handle: &Handle) -> ReconnectResume {
+        ReconnectResume {
+            device_name,
+            interval: Interval::new(RECORD_INTERVAL, handle).unwrap(),
+            playing: false,
+            last: Arc::new(Mutex::new(None)),
+            resume: None,
+        }
+    }
+
+    /// Whether a Web API token is needed to record or resume playback.
+    pub fn is_pending(&self) -> bool {
+        self.playing || self.resume.is_some()
+    }
+
+    pub fn handle_event(&mut self, event: &PlayerEvent) {
+        match *event {
+            PlayerEvent::Started { .. } | PlayerEvent::Changed { .. } => {
+                // Something else was played in the meantime.
+                self.resume = None;
+                self.playing = true;
+            },
+            PlayerEvent::Stopped { .. } => self.playing = false,
+        }
+    }
+
+    /// Remembers what played, if anything did, to resume it later.
+    pub fn connection_lost(&mut self) {
+        if !self.playing {
+            return;
+        }
+        let last = self.last.lock().unwrap().take();
+        self.resume = last.map(|(mut playback, recorded)| {
+            // Recording fails once the network is down, so what played after
+            // the last recording is only known up to the next one.
+            let since = recorded.elapsed().min(RECORD_INTERVAL);
+            playback.position_ms += since.as_millis() as u32;
+            playback
+        });
+    }
+
+    pub fn poll(&mut self, token: Option<RspotifyToken>) {
+        let mut elapsed = false;
+        while let Ok(Async::Ready(Some(()))) = self.interval.poll() {
+            elapsed = true;
+        }
+        let token = match token {
+            Some(token) => token,
+            None => return,
+        };
+        let device_name = self.device_name.clone();
+        if let Some(playback) = self.resume.take() {
+            thread::spawn(move || {
+                let client = web_api::client(&token);
+                if let Some(device_id) = web_api::find_device(&client, &device_name) {
+                    info!(
+                        "Connected again, resuming {} at {} ms",
+                        playback.track_uri, playback.position_ms
+                    );
+                    playback.start(&client, &device_id);
+                }
+            });
+        } else if elapsed && self.playing {
+            let last = self.last.clone();
+            thread::spawn(move || {
+                if let Some(playback) = Playback::current(&web_api::client(&token), &device_name) {
+                    *last.lock().unwrap() = Some((playback, Instant::now()));
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use crate::config::RotaryEncoderConfig;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use librespot::connect::spirc::Spirc;
+use log::{error, info};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::io::AsRawFd,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+const GPIO_ROOT: &str = "/sys/class/gpio";
+const BUTTON_DEBOUNCE: Duration = Duration::from_millis(50);
+/// Detents turned faster than this apart speed up the volume change.
+const ACCELERATION_WINDOW: Duration = Duration::from_millis(60);
+const MAX_ACCELERATION: i32 = 4;
+/// Position change for each transition of the two encoder pins, indexed by
+/// the previous and current state. Impossible transitions, as produced by
+/// contact bounce, count as 0.
+const TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+pub enum Command {
+    /// Volume steps, positive for clockwise.
+    Volume(i32),
+    PlayPause,
+}
+
+impl Command {
+    pub fn apply(&self, spirc: &Spirc) {
+        // Going through spirc applies the change to the mixer and reports the
+        // new volume to Connect.
+        match *self {
+            Command::Volume(steps) if steps > 0 => (0..steps).for_each(|_| spirc.volume_up()),
+            Command::Volume(steps) => (0..-steps).for_each(|_| spirc.volume_down()),
+            Command::PlayPause => spirc.play_pause(),
+        }
+    }
+}
+
+/// Reads a rotary encoder on GPIO pins through sysfs, turning it into volume
+/// changes and its push button into play/pause.
+pub struct RotaryEncoder {
+    pub commands: UnboundedReceiver<Command>,
+}
+
+impl RotaryEncoder {
+    pub fn new(config: RotaryEncoderConfig) -> RotaryEncoder {
+        let (sender, commands) = unbounded();
+        thread::spawn(move || {
+            if let Err(e) = run(&config, &sender) {
+                error!("Couldn't read rotary encoder: {}", e);
+            }
+        });
+        RotaryEncoder { commands }
+    }
+}
+
+/// Sets the pin up as an input that reports both edges.
+pub fn open_input(pin: u32) -> io::Result<File> {
+    let root = Path::new(GPIO_ROOT);
+    let pin_dir = root.join(format!("gpio{}", pin));
+    if !pin_dir.exists() {
+        fs::write(root.join("export"), pin.to_string())?;
+    }
+    fs::write(pin_dir.join("direction"), "in")?;
+    fs::write(pin_dir.join("edge"), "both")?;
+    File::open(pin_dir.join("value"))
+}
+
+pub fn read_value(file: &mut File) -> io::Result<bool> {
+    let mut value = [0; 1];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut value)?;
+    Ok(value[0] == b'1')
+}
+
+fn run(config: &RotaryEncoderConfig, sender: &UnboundedSender<Command>) -> io::Result<()> {
+    let mut pins = vec![open_input(config.pin_a)?, open_input(config.pin_b)?];
+    if let Some(button) = config.button {
+        pins.push(open_input(button)?);
+    }
+    info!(
+        "Reading rotary encoder on GPIO {} and {}",
+        config.pin_a, config.pin_b
+    );
+
+    let mut fds: Vec<libc::pollfd> = pins
+        .iter()
+        .map(|f| libc::pollfd {
+            fd: f.as_raw_fd(),
+            events: libc::POLLPRI | libc::POLLERR,
+            revents: 0,
+        })
+        .collect();
+
+    let mut state = (read_value(&mut pins[0])? as usize) << 1 | read_value(&mut pins[1])? as usize;
+    let mut position = 0;
+    let mut last_detent = Instant::now();
+    let mut acceleration = 1;
+    let mut button_down = false;
+    let mut button_changed = Instant::now();
+    loop {
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let a = read_value(&mut pins[0])?;
+        let b = read_value(&mut pins[1])?;
+        let new_state = (a as usize) << 1 | b as usize;
+        position += i32::from(TRANSITIONS[state << 2 | new_state]);
+        state = new_state;
+
+        if position.abs() >= config.steps_per_detent {
+            let direction = position.signum();
+            position = 0;
+            acceleration = if last_detent.elapsed() < ACCELERATION_WINDOW {
+                (acceleration + 1).min(MAX_ACCELERATION)
+            } else {
+                1
+            };
+            last_detent = Instant::now();
+            let steps = direction * acceleration;
+            if sender.unbounded_send(Command::Volume(steps)).is_err() {
+                return Ok(());
+            }
+        }
+
+        if pins.len() > 2 {
+            // The button pulls the pin low.
+            let down = !read_value(&mut pins[2])?;
+            if down != button_down && button_changed.elapsed() >= BUTTON_DEBOUNCE {
+                button_down = down;
+                button_changed = Instant::now();
+                if down && sender.unbounded_send(Command::PlayPause).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use crate::config::SpotifydConfig;
+use log::{info, warn};
+use std::{
+    ffi::CString,
+    io, mem,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+// Landlock, ABI version 1. The syscall numbers are the same on all
+// architectures.
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+const ACCESS_EXECUTE: u64 = 1;
+const ACCESS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_READ_FILE: u64 = 1 << 2;
+const ACCESS_READ_DIR: u64 = 1 << 3;
+/// Everything ABI version 1 knows, from executing to making links.
+const ACCESS_ALL: u64 = (1 << 13) - 1;
+const ACCESS_READ: u64 = ACCESS_EXECUTE | ACCESS_READ_FILE | ACCESS_READ_DIR;
+
+// seccomp, which libc doesn't have the structures of.
+const SECCOMP_SET_MODE_FILTER: libc::c_uint = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: libc::c_uint = 1;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(target_arch = "arm")]
+const AUDIT_ARCH: Option<u32> = Some(0x4000_0028);
+#[cfg(target_arch = "x86")]
+const AUDIT_ARCH: Option<u32> = Some(0x4000_0003);
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "arm",
+    target_arch = "x86"
+)))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// Syscalls spotifyd never makes, which would let a compromised spotifyd
+/// reach beyond itself.
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_reboot,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_acct,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_open_by_handle_at,
+];
+
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: libc::c_ushort,
+    filter: *const SockFilter,
+}
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Where spotifyd writes besides the cache: the directories of the files
+/// it is configured to write, which may be created or rotated in them.
+pub fn writable_paths(config: &SpotifydConfig) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = config
+        .cache_path
+        .iter()
+        .chain(config.audio_cache_path.iter())
+        .cloned()
+        .collect();
+    let files = vec![
+        config.pid.as_ref(),
+        config.log_file.as_ref().map(|l| &l.path),
+        config.play_history.as_ref().map(|h| &h.file.path),
+        config.metadata_pipe.as_ref(),
+        config.control_socket.as_ref(),
+        config.cover_art_file.as_ref().map(|c| &c.path),
+        config.restore_playback.as_ref(),
+        config.reconnect_marker.as_ref(),
+    ];
+    for file in files.into_iter().filter_map(|f| f) {
+        if let Some(dir) = file.parent() {
+            paths.push(dir.to_path_buf());
+        }
+    }
+    paths
+}
+
+/// Confines spotifyd before it sets up the player: Landlock keeps it to
+/// reading the system directories and `config_file`, and to writing
+/// `writable`, audio devices and temporary files, and a seccomp filter denies
+/// the syscalls it has no business making. Where the kernel lacks either,
+/// spotifyd runs without it.
+///
+/// Both only confine the thread they are set up in and the threads it starts
+/// later, so this has to run before the components start theirs. The seccomp
+/// filter is synchronised to any thread there already is.
+pub fn apply(config_file: Option<&Path>, writable: &[PathBuf]) {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        warn!("Couldn't sandbox spotifyd: {}", io::Error::last_os_error());
+        return;
+    }
+    let mut rules: Vec<(&Path, u64)> = [
+        "/usr", "/bin", "/sbin", "/lib", "/lib64", "/opt", "/etc", "/proc", "/sys", "/var/lib",
+    ]
+    .iter()
+    .map(|&dir| (Path::new(dir), ACCESS_READ))
+    .collect();
+    for &dir in &["/dev", "/tmp", "/run", "/var/run"] {
+        rules.push((Path::new(dir), ACCESS_ALL));
+    }
+    if let Some(config_file) = config_file {
+        rules.push((config_file, ACCESS_READ_FILE));
+    }
+    rules.extend(writable.iter().map(|p| (p.as_path(), ACCESS_ALL)));
+    match landlock(&rules) {
+        Ok(()) => info!("Restricted file access with Landlock"),
+        Err(e) => warn!("Couldn't restrict file access with Landlock: {}", e),
+    }
+    match seccomp() {
+        Ok(()) => info!("Restricted syscalls with seccomp"),
+        Err(e) => warn!("Couldn't restrict syscalls with seccomp: {}", e),
+    }
+}
+
+fn landlock(rules: &[(&Path, u64)]) -> io::Result<()> {
+    let attr = RulesetAttr {
+        handled_access_fs: ACCESS_ALL,
+    };
+    let ruleset = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            &attr as *const RulesetAttr,
+            mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if ruleset < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ruleset = ruleset as libc::c_int;
+    for &(path, access) in rules {
+        let c_path = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(c_path) => c_path,
+            Err(_) => continue,
+        };
+        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        // Directories that don't exist here have nothing to allow.
+        if fd < 0 {
+            continue;
+        }
+        // Directories get all the rights, files only those for files.
+        let is_dir = path.is_dir();
+        let rule = PathBeneathAttr {
+            allowed_access: if is_dir {
+                access
+            } else {
+                access & (ACCESS_EXECUTE | ACCESS_WRITE_FILE | ACCESS_READ_FILE)
+            },
+            parent_fd: fd,
+        };
+        let added = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_ADD_RULE,
+                ruleset,
+                LANDLOCK_RULE_PATH_BENEATH,
+                &rule as *const PathBeneathAttr,
+                0,
+            )
+        };
+        let error = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        if added != 0 {
+            warn!("Couldn't allow access to {:?}: {}", path, error);
+        }
+    }
+    let restricted = unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset, 0) };
+    let error = io::Error::last_os_error();
+    unsafe { libc::close(ruleset) };
+    if restricted != 0 {
+        return Err(error);
+    }
+    Ok(())
+}
+
+fn seccomp() -> io::Result<()> {
+    let arch = AUDIT_ARCH
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "unsupported architecture"))?;
+    let deny = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    let statement = |code, k, jt, jf| SockFilter { code, jt, jf, k };
+    // Syscalls of other architectures have other numbers, so they are denied
+    // altogether.
+    let mut filter = vec![
+        statement(BPF_LD_W_ABS, 4, 0, 0),
+        statement(BPF_JEQ_K, arch, 1, 0),
+        statement(BPF_RET_K, deny, 0, 0),
+        statement(BPF_LD_W_ABS, 0, 0, 0),
+    ];
+    for &syscall in DENIED_SYSCALLS {
+        filter.push(statement(BPF_JEQ_K, syscall as u32, 0, 1));
+        filter.push(statement(BPF_RET_K, deny, 0, 0));
+    }
+    filter.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW, 0, 0));
+    let program = SockFprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_ptr(),
+    };
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_TSYNC,
+            &program as *const SockFprog,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // With TSYNC, the ID of a thread that couldn't take the filter on.
+    if result > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("thread {} has a filter of its own", result),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use crate::web_api;
+use futures::{Async, Stream};
+use librespot::{core::spotify_id::SpotifyId, playback::player::PlayerEvent};
+use log::{error, info};
+use rspotify::spotify::oauth2::TokenInfo as RspotifyToken;
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use tokio_core::reactor::{Handle, Interval};
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(15);
+/// Positions this close to the end count as finished.
+const END_MARGIN_MS: u32 = 30_000;
+
+/// Playback positions of long tracks, such as audiobook chapters, by URI.
+pub struct SavePoints {
+    path: PathBuf,
+    positions: HashMap<String, u32>,
+}
+
+pub type SharedSavePoints = Arc<Mutex<SavePoints>>;
+
+impl SavePoints {
+    pub fn load(path: PathBuf) -> SavePoints {
+        let positions = fs::read_to_string(&path)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default();
+        SavePoints { path, positions }
+    }
+
+    fn save(&self) {
+        let result = serde_json::to_string(&self.positions)
+            .map_err(|e| e.to_string())
+            .and_then(|s| fs::write(&self.path, s).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Couldn't save positions to {:?}: {}", self.path, e);
+        }
+    }
+
+    pub fn get(&self, uri: &str) -> Option<u32> {
+        self.positions.get(uri).cloned()
+    }
+
+    pub fn all(&self) -> HashMap<String, u32> {
+        self.positions.clone()
+    }
+
+    fn update(&mut self, uri: String, position_ms: u32, duration_ms: u32) {
+        if position_ms + END_MARGIN_MS >= duration_ms {
+            if self.positions.remove(&uri).is_some() {
+                self.save();
+            }
+        } else if self.positions.insert(uri, position_ms) != Some(position_ms) {
+            self.save();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.positions.clear();
+        self.save();
+    }
+}
+
+/// Periodically records the position of long tracks while they play on this
+/// device, and seeks to the recorded position when such a track is started
+/// again.
+pub struct SavePointTracker {
+    save_points: SharedSavePoints,
+    device_name: String,
+    min_duration_ms: u32,
+    interval: Interval,
+    playing: bool,
+    last_track: Option<SpotifyId>,
+}
+
+impl SavePointTracker {
+    pub fn new(
+        save_points: SharedSavePoints,
+        device_name: String,
+        min_duration: Duration,
+        handle: &Handle,
+    ) -> SavePointTracker {
+        SavePointTracker {
+            save_points,
+            device_name,
+            min_duration_ms: min_duration.as_secs() as u32 * 1000,
+            interval: Interval::new(SAVE_INTERVAL, handle).unwrap(),
+            playing: false,
+            last_track: None,
+        }
+    }
+
+    pub fn handle_event(&mut self, event: &PlayerEvent, token: Option<RspotifyToken>) {
+        let track_id = match *event {
+            PlayerEvent::Started { track_id } => track_id,
+            PlayerEvent::Changed { new_track_id, .. } => new_track_id,
+            PlayerEvent::Stopped { .. } => {
+                self.playing = false;
+                return;
+            },
+        };
+        self.playing = true;
+        // Resuming after a pause continues where it was anyway.
+        if self.last_track == Some(track_id) {
+            return;
+        }
+        self.last_track = Some(track_id);
+
+        let uri = format!("spotify:track:{}", track_id.to_base62());
+        let position = self.save_points.lock().unwrap().get(&uri);
+        if let (Some(position), Some(token)) = (position, token) {
+            info!("Resuming {} at {} ms", uri, position);
+            thread::spawn(move || {
+                // Give the new playback state a moment to reach the Web API.
+                thread::sleep(Duration::from_secs(1));
+                let _ = web_api::client(&token).seek_track(position, None);
+            });
+        }
+    }
+
+    /// Records the current position whenever the save interval elapsed.
+    pub fn poll(&mut self, token: Option<RspotifyToken>) {
+        let mut elapsed = false;
+        while let Ok(Async::Ready(Some(()))) = self.interval.poll() {
+            elapsed = true;
+        }
+        if !elapsed || !self.playing {
+            return;
+        }
+        let token = match token {
+            Some(t) => t,
+            None => return,
+        };
+
+        let save_points = self.save_points.clone();
+        let device_name = self.device_name.clone();
+        let min_duration_ms = self.min_duration_ms;
+        thread::spawn(move || {
+            let playback = match web_api::client(&token).current_playback(None) {
+                Ok(Some(playback)) => playback,
+                _ => return,
+            };
+            if playback.device.name != device_name {
+                return;
+            }
+            if let (Some(track), Some(progress)) = (playback.item, playback.progress_ms) {
+                if track.duration_ms >= min_duration_ms {
+                    save_points
+                        .lock()
+                        .unwrap()
+                        .update(track.uri, progress, track.duration_ms);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,164 @@
+use crate::{
+    config::{unescape, SerialAmpConfig},
+    power_management::{switch_when_idle, PowerEvent, Switched},
+};
+use librespot::playback::{
+    mixer::{softmixer::SoftMixer, AudioFilter, Mixer},
+    player::PlayerEvent,
+};
+use log::{error, info};
+use serialport::{DataBits, FlowControl, Parity, SerialPort, SerialPortSettings, StopBits};
+use std::{
+    io::Write,
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+    time::Duration,
+};
+
+/// Drives an amplifier or AV receiver over a serial port: powers it on and
+/// selects the input when playback starts, powers it off a while after
+/// playback stopped, and forwards volume changes.
+#[derive(Clone)]
+pub struct SerialAmp {
+    sender: Sender<PowerEvent<u16>>,
+}
+
+impl SerialAmp {
+    pub fn new(config: SerialAmpConfig) -> SerialAmp {
+        let (sender, receiver) = channel();
+        thread::spawn(move || run(&config, &receiver));
+        SerialAmp { sender }
+    }
+
+    pub fn handle_event(&self, event: &PlayerEvent) {
+        let event = match *event {
+            PlayerEvent::Started { .. } | PlayerEvent::Changed { .. } => PowerEvent::Playing,
+            PlayerEvent::Stopped { .. } => PowerEvent::Stopped,
+        };
+        let _ = self.sender.send(event);
+    }
+
+    /// Wraps `mixer` so that volume changes are also sent to the amplifier.
+    pub fn mixer(&self, mixer: Box<Mixer>) -> Box<Mixer> {
+        Box::new(AmpMixer {
+            inner: mixer,
+            amp: Some(self.sender.clone()),
+        })
+    }
+}
+
+struct AmpMixer {
+    inner: Box<Mixer>,
+    amp: Option<Sender<PowerEvent<u16>>>,
+}
+
+impl Mixer for AmpMixer {
+    fn open() -> AmpMixer {
+        AmpMixer {
+            inner: Box::new(SoftMixer::open()),
+            amp: None,
+        }
+    }
+
+    fn start(&self) {
+        self.inner.start()
+    }
+
+    fn stop(&self) {
+        self.inner.stop()
+    }
+
+    fn volume(&self) -> u16 {
+        self.inner.volume()
+    }
+
+    fn set_volume(&self, volume: u16) {
+        self.inner.set_volume(volume);
+        if let Some(ref amp) = self.amp {
+            let _ = amp.send(PowerEvent::Other(volume));
+        }
+    }
+
+    fn get_audio_filter(&self) -> Option<Box<AudioFilter + Send>> {
+        self.inner.get_audio_filter()
+    }
+}
+
+fn open_port(config: &SerialAmpConfig) -> Option<Box<SerialPort>> {
+    let settings = SerialPortSettings {
+        baud_rate: config.baud_rate,
+        data_bits: DataBits::Eight,
+        flow_control: FlowControl::None,
+        parity: Parity::None,
+        stop_bits: StopBits::One,
+        timeout: Duration::from_secs(1),
+    };
+    match serialport::open_with_settings(&config.port, &settings) {
+        Ok(port) => Some(port),
+        Err(e) => {
+            error!("Couldn't open serial port {}: {}", config.port, e);
+            None
+        },
+    }
+}
+
+fn send(port: &mut Option<Box<SerialPort>>, config: &SerialAmpConfig, command: &[u8]) {
+    if port.is_none() {
+        *port = open_port(config);
+    }
+    let result = match port.as_mut() {
+        Some(p) => p.write_all(command).and_then(|_| p.flush()),
+        None => return,
+    };
+    if let Err(e) = result {
+        error!("Couldn't write to serial port {}: {}", config.port, e);
+        // Reopen on the next command, the adapter may have been replugged.
+        *port = None;
+    }
+}
+
+/// The amplifier and the port it is driven through.
+struct Amp<'a> {
+    config: &'a SerialAmpConfig,
+    port: Option<Box<SerialPort>>,
+}
+
+impl<'a> Switched for Amp<'a> {
+    type Event = u16;
+
+    fn switch_on(&mut self) {
+        info!("Powering on amplifier.");
+        if let Some(ref command) = self.config.power_on {
+            send(&mut self.port, self.config, command);
+        }
+        if let Some(ref command) = self.config.input {
+            send(&mut self.port, self.config, command);
+        }
+    }
+
+    fn switch_off(&mut self) {
+        info!("Powering off amplifier.");
+        if let Some(ref command) = self.config.power_off {
+            send(&mut self.port, self.config, command);
+        }
+    }
+
+    /// Sets the volume.
+    fn handle(&mut self, volume: u16) {
+        if let Some(ref template) = self.config.volume {
+            // In u64, which any amp_volume_max times the volume fits in.
+            let scaled =
+                u64::from(volume) * u64::from(self.config.volume_max) / u64::from(u16::max_value());
+            let command = unescape(&template.replace("{volume}", &scaled.to_string()));
+            send(&mut self.port, self.config, &command);
+        }
+    }
+}
+
+fn run(config: &SerialAmpConfig, receiver: &Receiver<PowerEvent<u16>>) {
+    let mut amp = Amp {
+        config,
+        port: open_port(config),
+    };
+    switch_when_idle(&mut amp, receiver, false, config.power_off_delay);
+}
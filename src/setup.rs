@@ -1,7 +1,66 @@
 #[cfg(feature = "alsa_backend")]
 use crate::alsa_mixer;
-use crate::{config, main_loop};
-use futures::{self, Future};
+#[cfg(feature = "desktop_notifications")]
+use crate::desktop_notification::DesktopNotifier;
+#[cfg(feature = "display")]
+use crate::display::Display;
+#[cfg(feature = "gpio_buttons")]
+use crate::gpio_buttons::GpioButtons;
+#[cfg(feature = "lua_plugins")]
+use crate::plugins::Plugins;
+#[cfg(feature = "serial_amp")]
+use crate::serial_amp::SerialAmp;
+#[cfg(feature = "dbus_mpris")]
+use crate::sleep::SleepInhibitor;
+use crate::{
+    adaptive_bitrate::AdaptiveBitrate,
+    alarm::Alarms,
+    ap_policy::SharedApPolicy,
+    art::ArtProviders,
+    autoplay::Autoplay,
+    backends,
+    backoff::Backoff,
+    balance::SharedBalance,
+    cache_limit::CacheLimit,
+    config,
+    control::{Control, SharedStatus},
+    controller_reconnect::ControllerReconnect,
+    cover_art_file::CoverArtFile,
+    data_usage::SharedDataUsage,
+    device_loss::DeviceLoss,
+    device_state::{DeviceState, SharedDeviceState},
+    dither,
+    equalizer::SharedEqualizer,
+    errors::{self, ErrorCode},
+    fixed_mixer::FixedMixer,
+    health::HealthCheck,
+    idle_exit::IdleExit,
+    lirc::Lirc,
+    main_loop,
+    metadata_pipe::MetadataPipe,
+    oauth,
+    play_history::PlayHistory,
+    playback_restore::PlaybackRestore,
+    player_event_handler::EventProgram,
+    power_management::PowerManager,
+    proxy,
+    recently_played::SharedRecentlyPlayed,
+    reconnect_resume::ReconnectResume,
+    rotary_encoder::RotaryEncoder,
+    save_points::{SavePointTracker, SavePoints},
+    signals::PlaybackSignals,
+    simulation::Simulator,
+    sleep_timer::{SharedSleepTimer, SleepTimer},
+    snapcast::Snapcast,
+    socket_activation,
+    systemd::Notifier,
+    takeover::Takeover,
+    track_metadata::MetadataFetcher,
+    underruns::Underruns,
+    volume_curve::VolumeSettings,
+    wait_for_network,
+};
+use futures::{self, Future, Stream};
 #[cfg(feature = "dbus_keyring")]
 use keyring::Keyring;
 use librespot::{
@@ -13,22 +72,41 @@ use librespot::{
         session::Session,
     },
     playback::{
-        audio_backend::{Sink, BACKENDS},
+        audio_backend::Sink,
         mixer::{self, Mixer},
     },
 };
-use log::{error, info};
-use std::{io, process::exit};
+use log::{error, info, warn};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
 use tokio_core::reactor::Handle;
-use tokio_signal::ctrl_c;
+use tokio_signal::{ctrl_c, unix::Signal};
 
-pub fn initial_state(handle: Handle, config: config::SpotifydConfig) -> main_loop::MainLoopState {
-    let local_audio_device = config.audio_device.clone();
+pub fn initial_state(
+    handle: Handle,
+    config: config::SpotifydConfig,
+    read_config: Box<Fn() -> config::SpotifydConfig>,
+) -> main_loop::MainLoopState {
+    let local_audio_device = config
+        .mixer_card
+        .clone()
+        .or_else(|| config.audio_device.clone());
     let local_mixer = config.mixer.clone();
+    let local_mixer_index = config.mixer_index;
+
+    #[cfg(feature = "alsa_backend")]
+    let alsa_scaling = match config.volume_controller {
+        config::VolumeController::Alsa { linear: false } => Some(alsa_mixer::Scaling::Logarithmic),
+        config::VolumeController::Alsa { linear: true } => Some(alsa_mixer::Scaling::Linear),
+        config::VolumeController::AlsaDb => Some(alsa_mixer::Scaling::Db),
+        config::VolumeController::SoftVol | config::VolumeController::Fixed => None,
+    };
 
     #[cfg(feature = "alsa_backend")]
-    let mut mixer = match config.volume_controller {
-        config::VolumeController::Alsa { linear } => {
+    let mut mixer = match alsa_scaling {
+        Some(scaling) => {
             info!("Using alsa volume controller.");
             Box::new(move || {
                 Box::new(alsa_mixer::AlsaMixer {
@@ -36,52 +114,97 @@ pub fn initial_state(handle: Handle, config: config::SpotifydConfig) -> main_loo
                         .clone()
                         .unwrap_or_else(|| "default".to_string()),
                     mixer: local_mixer.clone().unwrap_or_else(|| "Master".to_string()),
-                    linear_scaling: linear,
+                    index: local_mixer_index,
+                    scaling,
                 }) as Box<mixer::Mixer>
             }) as Box<FnMut() -> Box<Mixer>>
         },
-        config::VolumeController::SoftVol => {
-            info!("Using software volume controller.");
-            Box::new(|| Box::new(mixer::softmixer::SoftMixer::open()) as Box<Mixer>)
-                as Box<FnMut() -> Box<Mixer>>
-        },
+        None => software_mixer(&config.volume_controller),
     };
 
     #[cfg(not(feature = "alsa_backend"))]
+    let mut mixer = software_mixer(&config.volume_controller);
+
+    // Before the stages that dither are set up.
+    dither::set_enabled(config.dither);
+    let equalizer = config.equalizer.map(SharedEqualizer::new);
+    let mut mixer = match equalizer.clone() {
+        Some(equalizer) => Box::new(move || equalizer.mixer(mixer())) as Box<FnMut() -> Box<Mixer>>,
+        None => mixer,
+    };
+    let balance = SharedBalance::new(config.balance);
     let mut mixer = {
-        info!("Using software volume controller.");
-        Box::new(|| Box::new(mixer::softmixer::SoftMixer::open()) as Box<Mixer>)
-            as Box<FnMut() -> Box<Mixer>>
+        let balance = balance.clone();
+        Box::new(move || balance.mixer(mixer())) as Box<FnMut() -> Box<Mixer>>
+    };
+    let sleep_timer = SharedSleepTimer::new();
+    let mut mixer = {
+        let sleep_timer = sleep_timer.clone();
+        Box::new(move || sleep_timer.mixer(mixer())) as Box<FnMut() -> Box<Mixer>>
     };
 
-    let cache = config.cache;
+    if cfg!(not(feature = "dbus_mpris")) && config.inhibit_sleep {
+        warn!("inhibit_sleep is set, but spotifyd was built without D-Bus support.");
+    }
+    if cfg!(not(feature = "serial_amp")) && config.serial_amp.is_some() {
+        warn!("amp_serial_port is set, but spotifyd was built without serial_amp support.");
+    }
+    #[cfg(feature = "serial_amp")]
+    let serial_amp = config.serial_amp.map(SerialAmp::new);
+    #[cfg(feature = "serial_amp")]
+    let mut mixer = match serial_amp.clone() {
+        // The amplifier keeps its own volume.
+        Some(_) if is_fixed(&config.volume_controller) => mixer,
+        Some(amp) => Box::new(move || amp.mixer(mixer())) as Box<FnMut() -> Box<Mixer>>,
+        None => mixer,
+    };
+
+    let volume = VolumeSettings::new(&config);
+
+    let initial_volume = config
+        .initial_volume
+        .map(|v| (u32::from(v) * u32::from(u16::max_value()) / 100) as u16);
+
+    let cache = config::open_cache(&config);
     let player_config = config.player_config;
-    let session_config = config.session_config;
+    let mut session_config = config.session_config;
+    let ap_policy = config.ap_policy.map(SharedApPolicy::new);
+    let data_usage = config
+        .data_usage
+        .map(|usage| SharedDataUsage::new(usage.file));
+    if config.wait_for_network {
+        wait_for_network::wait(session_config.proxy.as_ref());
+    }
+    session_config.proxy = proxy::librespot_proxy(
+        session_config.proxy.take(),
+        ap_policy.clone(),
+        data_usage.clone(),
+    )
+    .expect("Couldn't start the proxy relay");
     let backend = config.backend.clone();
     let device_id = session_config.device_id.clone();
 
-    #[cfg(feature = "alsa_backend")]
-    let linear_volume = match config.volume_controller {
-        config::VolumeController::Alsa { linear } => linear,
-        _ => false,
-    };
-
-    #[cfg(not(feature = "alsa_backend"))]
-    let linear_volume = false;
-
     #[allow(clippy::or_fun_call)]
-    let discovery_stream = discovery(
-        &handle,
-        ConnectConfig {
-            name: config.device_name.clone(),
-            device_type: DeviceType::default(),
-            volume: mixer().volume(),
-            linear_volume,
-        },
-        device_id,
-        0,
-    )
-    .unwrap();
+    let discovery_stream = if config.discovery {
+        Some(
+            discovery(
+                &handle,
+                ConnectConfig {
+                    name: config.device_name.clone(),
+                    device_type: DeviceType::default(),
+                    volume: initial_volume.unwrap_or_else(|| volume.apply(mixer()).volume()),
+                    // The mixer applies the volume curve.
+                    linear_volume: true,
+                },
+                device_id,
+                config.zeroconf_port,
+            )
+            .unwrap(),
+        )
+    } else {
+        info!("Discovery is off, the device isn't announced on the network.");
+        None
+    };
 
     let username = config.username;
     #[allow(unused_mut)] // mut is needed behind the dbus_keyring flag.
@@ -91,22 +214,78 @@ pub fn initial_state(handle: Handle, config: config::SpotifydConfig) -> main_loo
         // We only need to check if an actual user has been specified as
         // spotifyd can run without being signed in too.
         if username.is_some() && config.use_keyring {
-            info!("Checking keyring for password");
             let keyring = Keyring::new("spotifyd", username.as_ref().unwrap());
-            let retrieved_password = keyring.get_password();
-            password = password.or_else(|| retrieved_password.ok());
+            match password {
+                Some(ref password) if config.keyring_store => {
+                    match keyring.set_password(password) {
+                        Ok(()) => info!(
+                            "Stored the password in the keyring, it can be removed from the \
+                             configuration now."
+                        ),
+                        Err(e) => warn!("Couldn't store the password in the keyring: {}", e),
+                    }
+                },
+                Some(_) => (),
+                None => {
+                    info!("Checking keyring for password");
+                    password = keyring.get_password().ok();
+                },
+            }
         }
     }
+    if cfg!(not(feature = "dbus_keyring")) && config.use_keyring {
+        warn!("use-keyring is set, but spotifyd was built without keyring support.");
+    }
 
-    let connection = if let Some(credentials) = get_credentials(
-        username,
-        password,
-        cache.as_ref().and_then(Cache::credentials),
-        |_| {
-            error!("No password found.");
-            exit(1);
+    let (simulator, player_event_channel) = if config.simulate {
+        let (simulator, events) = Simulator::new(&handle);
+        (Some(simulator), Some(events))
+    } else {
+        (None, None)
+    };
+
+    let cached_credentials = cache.as_ref().and_then(Cache::credentials);
+    // The stored refresh token replaced the configured one once it was used.
+    let refresh_token = config
+        .cache_path
+        .as_ref()
+        .and_then(|path| oauth::stored_refresh_token(path))
+        .or_else(|| config.oauth_refresh_token.clone());
+    let cache_path = config.cache_path.clone();
+    let credentials = match config.oauth_token {
+        _ if config.simulate => None,
+        Some(token) => Some(oauth::credentials(token)),
+        None if password.is_none() && cached_credentials.is_none() && refresh_token.is_some() => {
+            refresh_token.and_then(|token| {
+                oauth::refreshed_credentials(&token, cache_path.as_ref().map(|p| p.as_path()))
+            })
         },
-    ) {
+        None => get_credentials(username, password, cached_credentials, |_| {
+            error!("{}", errors::message(ErrorCode::NoPassword, &[]));
+            ErrorCode::NoPassword.failure().exit();
+        }),
+    };
+    for name in socket_activation::names() {
+        match name {
+            "snapcast" | "tcp" | "http" | "health" | "control" => (),
+            "zeroconf" => warn!(
+                "The zeroconf socket from systemd can't be used, discovery opens its own; set \
+                 zeroconf_port instead."
+            ),
+            _ => warn!("Ignoring the socket {} from systemd.", name),
+        }
+    }
+    if credentials.is_none() && discovery_stream.is_none() && !config.simulate {
+        warn!("Discovery is off and there are no credentials, so nothing can log in.");
+    }
+    let mut state = DeviceState::Disconnected;
+    let connection = if config.simulate {
+        // The simulator plays as if logged in.
+        state = DeviceState::Registered;
+        Box::new(futures::future::empty())
+            as Box<futures::Future<Item = Session, Error = io::Error>>
+    } else if let Some(credentials) = credentials.clone() {
+        state = DeviceState::Connecting;
         Session::connect(
             session_config.clone(),
             credentials,
@@ -118,44 +297,283 @@ pub fn initial_state(handle: Handle, config: config::SpotifydConfig) -> main_loo
             as Box<futures::Future<Item = Session, Error = io::Error>>
     };
 
+    let save_points = config
+        .save_points
+        .as_ref()
+        .map(|c| Arc::new(Mutex::new(SavePoints::load(c.file.clone()))));
+    let save_point_tracker = config.save_points.as_ref().map(|c| {
+        SavePointTracker::new(
+            save_points.clone().unwrap(),
+            config.device_name.clone(),
+            c.min_duration,
+            &handle,
+        )
+    });
+
+    let cache_limit = match (config.cache_path.as_ref(), config.cache_size) {
+        (Some(cache_path), Some(size)) => Some(CacheLimit::new(cache_path, size, &handle)),
+        _ => None,
+    };
+
+    let controller_reconnect = config
+        .reconnect_marker
+        .map(|marker| ControllerReconnect::new(marker, config.device_name.clone()));
+
+    let art = ArtProviders::new(&config.art_providers);
+    if cfg!(not(feature = "display")) && config.display.is_some() {
+        warn!("display_driver is set, but spotifyd was built without display support.");
+    }
+    #[cfg(feature = "display")]
+    let display = config.display.map(Display::new);
+    if cfg!(not(feature = "gpio_buttons")) && !config.gpio_buttons.is_empty() {
+        warn!("gpio_buttons is set, but spotifyd was built without GPIO button support.");
+    }
+    if cfg!(not(feature = "desktop_notifications")) && config.desktop_notifications {
+        warn!(
+            "desktop_notifications is set, but spotifyd was built without desktop notification \
+             support."
+        );
+    }
+    #[cfg(feature = "desktop_notifications")]
+    let desktop_notifier = if config.desktop_notifications {
+        Some(DesktopNotifier::new())
+    } else {
+        None
+    };
+    if cfg!(not(feature = "lua_plugins")) && !config.plugins.is_empty() {
+        warn!("plugins is set, but spotifyd was built without plugin support.");
+    }
+    #[cfg(feature = "lua_plugins")]
+    let plugins = if config.plugins.is_empty() {
+        None
+    } else {
+        Some(Plugins::new(config.plugins.clone()))
+    };
+    backends::set_pulseaudio_properties();
+    if config.audio_format != config::AudioFormat::S16
+        || config.sample_rate != 44100
+        || config.channel_map.is_some()
+    {
+        let names = config
+            .tee_backends
+            .iter()
+            .map(|b| Some(&b.0))
+            .chain(Some(backend.as_ref()));
+        for name in names {
+            let name = name.map_or("the default backend", String::as_str);
+            if !backends::CONVERTING.contains(&name) {
+                warn!(
+                    "{} doesn't support audio_format, sample_rate and channel_map, it plays \
+                     stereo S16 at 44.1 kHz.",
+                    name
+                );
+            }
+        }
+    }
+    backends::set_output(config.audio_format, config.sample_rate, config.channel_map);
+    let pulse_sink = match backend.as_ref().map(String::as_str) {
+        Some("pulseaudio") => config.audio_device.clone(),
+        _ => None,
+    };
     let backend = find_backend(backend.as_ref().map(String::as_ref));
+    let device_loss = DeviceLoss::new(
+        config.pause_on_device_loss,
+        config.resume_on_device_return,
+        backend,
+        config.audio_device.clone(),
+        pulse_sink,
+        &handle,
+    );
+    let mut notifier = Notifier::from_env(&handle);
+    if let Some(ref mut notifier) = notifier {
+        // Without logging in first, spotifyd is ready as soon as discovery
+        // runs.
+        match state {
+            DeviceState::Disconnected => notifier.ready("Waiting for a client to connect"),
+            DeviceState::Registered => notifier.ready("Simulating playback"),
+            _ => notifier.status("Logging in"),
+        }
+    }
+    let tee = config
+        .tee_backends
+        .iter()
+        .map(|&(ref name, ref device)| (find_backend(Some(name)), device.clone()))
+        .collect();
+    let device_state = SharedDeviceState::new(state);
+    let recently_played = config.recently_played.map(SharedRecentlyPlayed::new);
+    let status = config.control_socket.as_ref().map(|_| {
+        SharedStatus::new(
+            config.device_name.clone(),
+            device_state.clone(),
+            recently_played.clone(),
+            data_usage.clone(),
+        )
+    });
+    let control = match (config.control_socket, status.clone()) {
+        (Some(socket), Some(status)) => Some(Control::new(socket, status)),
+        _ => None,
+    };
     main_loop::MainLoopState {
-        librespot_connection: main_loop::LibreSpotConnection::new(connection, discovery_stream),
+        librespot_connection: main_loop::LibreSpotConnection::new(
+            connection,
+            credentials,
+            discovery_stream,
+        ),
         audio_setup: main_loop::AudioSetup {
             mixer,
             backend,
             audio_device: config.audio_device.clone(),
+            tee,
+            start_delay: config.playback_start_delay,
+            start_command: config.playback_start_command,
+            fade: config.fade,
+            trim_silence: config.trim_silence,
+            limiter: config.limiter,
+            buffer: config.audio_buffer,
+            latency_offset_ms: config.latency_offset_ms,
+            multiroom: config
+                .multiroom_listen
+                .as_ref()
+                .map(|address| backends::MultiroomLeader::new(address, config.multiroom_latency)),
+            initial_volume,
+            volume,
+            status,
         },
         spotifyd_state: main_loop::SpotifydState {
-            ctrl_c_stream: Box::new(ctrl_c(&handle).flatten_stream()),
-            shutting_down: false,
+            shutdown_stream: Box::new(
+                ctrl_c(&handle).flatten_stream().select(
+                    Signal::new(libc::SIGTERM, &handle)
+                        .flatten_stream()
+                        .map(|_| ()),
+                ),
+            ),
+            hangup_stream: Box::new(
+                Signal::new(libc::SIGHUP, &handle)
+                    .flatten_stream()
+                    .map(|_| ()),
+            ),
+            playback_signals: PlaybackSignals::new(&config.signal_actions, &handle),
+            state: device_state,
             cache,
+            cache_limit,
             device_name: config.device_name,
-            player_event_channel: None,
-            player_event_program: config.onevent,
+            player_event_channel,
+            event_program: EventProgram::new(config.onevent),
+            metadata_pipe: config.metadata_pipe.map(MetadataPipe::new),
+            control,
+            cover_art_file: config.cover_art_file.map(CoverArtFile::new),
+            play_history: config.play_history.map(PlayHistory::new),
+            recently_played,
+            power_manager: config.power.map(PowerManager::new),
+            snapcast: config.snapcast_socket.map(Snapcast::new),
+            save_points,
+            save_point_tracker,
+            equalizer,
+            balance,
+            sleep_timer: SleepTimer::new(sleep_timer, &handle),
+            takeover: Takeover::new(config.device_name.clone()),
+            track_changes: None,
+            track_metadata: MetadataFetcher::new(art),
+            rotary_encoder: config.rotary_encoder.map(RotaryEncoder::new),
+            #[cfg(feature = "gpio_buttons")]
+            gpio_buttons: if config.gpio_buttons.is_empty() {
+                None
+            } else {
+                Some(GpioButtons::new(config.gpio_buttons))
+            },
+            lirc: config.lirc.map(Lirc::new),
+            controller_reconnect,
+            autoplay: if config.autoplay {
+                Some(Autoplay::new(config.device_name.clone()))
+            } else {
+                None
+            },
+            alarms: if config.alarms.is_empty() {
+                None
+            } else {
+                Some(Alarms::new(
+                    config.alarms,
+                    config.device_name.clone(),
+                    &handle,
+                ))
+            },
+            playback_restore: config
+                .restore_playback
+                .map(|path| PlaybackRestore::new(path, config.device_name.clone(), &handle)),
+            reconnect_resume: if config.resume_after_reconnect {
+                Some(ReconnectResume::new(config.device_name.clone(), &handle))
+            } else {
+                None
+            },
+            adaptive_bitrate: if config.adaptive_bitrate {
+                Some(AdaptiveBitrate::new(player_config.bitrate))
+            } else {
+                None
+            },
+            device_loss,
+            underruns: Underruns::new(),
+            data_usage,
+            idle_exit: config
+                .exit_after_idle
+                .map(|limit| IdleExit::new(limit, &handle)),
+            health_check: config
+                .health_listen
+                .as_ref()
+                .map(|address| HealthCheck::new(address)),
+            notifier,
+            simulator,
+            #[cfg(feature = "serial_amp")]
+            serial_amp,
+            #[cfg(feature = "dbus_mpris")]
+            sleep_inhibitor: if config.inhibit_sleep {
+                Some(SleepInhibitor::new(false))
+            } else {
+                None
+            },
+            #[cfg(feature = "display")]
+            display,
+            #[cfg(feature = "desktop_notifications")]
+            desktop_notifier,
+            #[cfg(feature = "lua_plugins")]
+            plugins,
             dbus_mpris_server: None,
         },
         player_config,
         session_config,
+        ap_ports: config.ap_ports,
+        ap_policy,
+        reconnect: Backoff::new(handle.clone()),
         handle,
-        linear_volume,
-        running_event_program: None,
+        read_config,
+    }
+}
+
+/// The mixers that work without ALSA.
+fn software_mixer(controller: &config::VolumeController) -> Box<FnMut() -> Box<Mixer>> {
+    if is_fixed(controller) {
+        info!("Volume control is disabled, playing at full volume.");
+        Box::new(|| Box::new(FixedMixer) as Box<Mixer>)
+    } else {
+        info!("Using software volume controller.");
+        Box::new(|| Box::new(mixer::softmixer::SoftMixer::open()) as Box<Mixer>)
     }
 }
 
-fn find_backend(name: Option<&str>) -> fn(Option<String>) -> Box<Sink> {
+fn is_fixed(controller: &config::VolumeController) -> bool {
+    if let config::VolumeController::Fixed = *controller {
+        true
+    } else {
+        false
+    }
+}
+
+pub fn find_backend(name: Option<&str>) -> fn(Option<String>) -> Box<Sink> {
     match name {
-        Some(name) => {
-            BACKENDS
-                .iter()
-                .find(|backend| name == backend.0)
-                .unwrap_or_else(|| panic!("Unknown backend: {}.", name))
-                .1
-        },
+        Some(name) => backends::find(name)
+            .unwrap_or_else(|| panic!("{}", errors::message(ErrorCode::UnknownBackend, &[&name]))),
         None => {
-            let &(name, back) = BACKENDS
-                .first()
-                .expect("No backends were enabled at build time");
+            let (name, back) = backends::default()
+                .unwrap_or_else(|| panic!("{}", errors::message(ErrorCode::NoBackends, &[])));
             info!("No backend specified, defaulting to: {}.", name);
             back
         },
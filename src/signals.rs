@@ -0,0 +1,67 @@
+use futures::{Async, Future, Stream};
+use librespot::connect::spirc::Spirc;
+use std::str::FromStr;
+use tokio_core::reactor::Handle;
+use tokio_io::IoStream;
+use tokio_signal::unix::Signal;
+
+/// What a signal does to playback.
+#[derive(Clone, Copy, Debug)]
+pub enum SignalAction {
+    PlayPause,
+    Play,
+    Pause,
+    Next,
+    Previous,
+}
+
+impl FromStr for SignalAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "play_pause" | "playpause" => Ok(SignalAction::PlayPause),
+            "play" => Ok(SignalAction::Play),
+            "pause" => Ok(SignalAction::Pause),
+            "next" => Ok(SignalAction::Next),
+            "previous" | "prev" => Ok(SignalAction::Previous),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Controls playback with signals, e.g. `kill -USR1 $(pidof spotifyd)` from a
+/// hotkey or a button's script.
+pub struct PlaybackSignals {
+    signals: Vec<(IoStream<()>, SignalAction)>,
+}
+
+impl PlaybackSignals {
+    pub fn new(actions: &[(i32, SignalAction)], handle: &Handle) -> PlaybackSignals {
+        let signals = actions
+            .iter()
+            .map(|&(signal, action)| {
+                let stream: IoStream<()> =
+                    Box::new(Signal::new(signal, handle).flatten_stream().map(|_| ()));
+                (stream, action)
+            })
+            .collect();
+        PlaybackSignals { signals }
+    }
+
+    pub fn poll(&mut self, spirc: Option<&Spirc>) {
+        for &mut (ref mut stream, action) in &mut self.signals {
+            while let Ok(Async::Ready(Some(()))) = stream.poll() {
+                if let Some(spirc) = spirc {
+                    match action {
+                        SignalAction::PlayPause => spirc.play_pause(),
+                        SignalAction::Play => spirc.play(),
+                        SignalAction::Pause => spirc.pause(),
+                        SignalAction::Next => spirc.next(),
+                        SignalAction::Previous => spirc.prev(),
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use futures::{
+    sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    Async, Stream,
+};
+use librespot::{core::spotify_id::SpotifyId, playback::player::PlayerEvent};
+use log::info;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Interval};
+
+/// How long each simulated track plays.
+pub const TRACK_DURATION: Duration = Duration::from_secs(30);
+/// Playback pauses for one track length after this many tracks.
+const TRACKS_BETWEEN_PAUSES: u64 = 5;
+const FIRST_TRACK: u64 = 1;
+
+/// Fakes a player for `--simulate`: plays synthetic tracks one after another
+/// and raises the same events a real player would, so hooks, pipes and
+/// displays can be developed without an account or network access.
+pub struct Simulator {
+    interval: Interval,
+    sender: UnboundedSender<PlayerEvent>,
+    track: u64,
+    playing: bool,
+}
+
+impl Simulator {
+    pub fn new(handle: &Handle) -> (Simulator, UnboundedReceiver<PlayerEvent>) {
+        info!("Simulating playback, nothing is sent to Spotify.");
+        let (sender, receiver) = unbounded();
+        let _ = sender.unbounded_send(PlayerEvent::Started {
+            track_id: track_id(FIRST_TRACK),
+        });
+        let simulator = Simulator {
+            interval: Interval::new(TRACK_DURATION, handle).unwrap(),
+            sender,
+            track: FIRST_TRACK,
+            playing: true,
+        };
+        (simulator, receiver)
+    }
+
+    pub fn poll(&mut self) {
+        while let Ok(Async::Ready(Some(()))) = self.interval.poll() {
+            let event = if !self.playing {
+                self.playing = true;
+                self.track += 1;
+                PlayerEvent::Started {
+                    track_id: track_id(self.track),
+                }
+            } else if self.track % TRACKS_BETWEEN_PAUSES == 0 {
+                self.playing = false;
+                PlayerEvent::Stopped {
+                    track_id: track_id(self.track),
+                }
+            } else {
+                self.track += 1;
+                PlayerEvent::Changed {
+                    old_track_id: track_id(self.track - 1),
+                    new_track_id: track_id(self.track),
+                }
+            };
+            let _ = self.sender.unbounded_send(event);
+        }
+    }
+}
+
+/// Simulated tracks have IDs counting up from 1.
+fn track_id(number: u64) -> SpotifyId {
+    SpotifyId::from_base16(&format!("{:032x}", number)).unwrap()
+}
+
+/// The number of a simulated track.
+pub fn track_number(track_id: SpotifyId) -> u64 {
+    u64::from_str_radix(&track_id.to_base16(), 16).unwrap_or(0)
+}
@@ -0,0 +1,140 @@
+use dbus::{BusType, Connection, Message, OwnedFd};
+use futures::{
+    sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    Async, Stream,
+};
+use log::{info, warn};
+use std::{
+    collections::HashMap,
+    sync::mpsc::{channel, Receiver, Sender, TryRecvError},
+    thread,
+    time::Duration,
+};
+
+const PREPARE_FOR_SLEEP: &str = "type='signal',sender='org.freedesktop.login1',interface='org.\
+                                 freedesktop.login1.Manager',member='PrepareForSleep'";
+/// How long the player gets to pause before the system goes to sleep.
+const PAUSE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SleepEvent {
+    /// The system is about to go to sleep.
+    Suspending,
+    /// The system woke up again.
+    Resumed,
+}
+
+/// Keeps the system awake while playing, with a logind inhibitor lock, and
+/// tells when it goes to sleep anyway, e.g. when the lid is closed, or wakes
+/// up. A delay lock holds off sleep until playback is paused.
+///
+/// Talking to logind blocks, so a thread does it.
+pub struct SleepInhibitor {
+    blocking: Sender<bool>,
+    events: UnboundedReceiver<SleepEvent>,
+    playing: bool,
+}
+
+impl SleepInhibitor {
+    pub fn new(playing: bool) -> SleepInhibitor {
+        let (blocking, blocking_receiver) = channel();
+        let (events_sender, events) = unbounded();
+        let _ = blocking.send(playing);
+        thread::spawn(move || run(&blocking_receiver, &events_sender));
+        SleepInhibitor {
+            blocking,
+            events,
+            playing,
+        }
+    }
+
+    /// Blocks sleep while `playing`, and returns what happened to the system.
+    pub fn poll(&mut self, playing: bool) -> Option<SleepEvent> {
+        if playing != self.playing {
+            self.playing = playing;
+            let _ = self.blocking.send(playing);
+        }
+        match self.events.poll() {
+            Ok(Async::Ready(Some(event))) => Some(event),
+            _ => None,
+        }
+    }
+}
+
+fn run(blocking: &Receiver<bool>, events: &UnboundedSender<SleepEvent>) {
+    let connection = match Connection::get_private(BusType::System) {
+        Ok(connection) => connection,
+        Err(e) => {
+            warn!("Couldn't connect to logind, sleep isn't inhibited: {}", e);
+            return;
+        },
+    };
+    if let Err(e) = connection.add_match(PREPARE_FOR_SLEEP) {
+        warn!("Couldn't watch for the system going to sleep: {}", e);
+    }
+    // The inhibitor locks held, by mode. Closing a lock releases it.
+    let mut locks = HashMap::new();
+    inhibit(&connection, "delay", &mut locks);
+    loop {
+        loop {
+            match blocking.try_recv() {
+                Ok(true) => inhibit(&connection, "block", &mut locks),
+                Ok(false) => drop(locks.remove("block")),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        for message in connection.incoming(1000) {
+            let (_, _, _, member) = message.headers();
+            if member.as_ref().map(String::as_str) != Some("PrepareForSleep") {
+                continue;
+            }
+            match message.get1::<bool>() {
+                Some(true) => {
+                    info!("The system is going to sleep");
+                    let _ = events.unbounded_send(SleepEvent::Suspending);
+                    thread::sleep(PAUSE_DELAY);
+                    locks.remove("delay");
+                },
+                Some(false) => {
+                    info!("The system woke up");
+                    inhibit(&connection, "delay", &mut locks);
+                    let _ = events.unbounded_send(SleepEvent::Resumed);
+                },
+                None => (),
+            }
+        }
+    }
+}
+
+/// Takes an inhibitor lock on sleep, held until its descriptor is closed.
+fn inhibit(
+    connection: &Connection,
+    mode: &'static str,
+    locks: &mut HashMap<&'static str, OwnedFd>,
+) {
+    let reason = if mode == "block" {
+        "Playing music"
+    } else {
+        "Pausing playback"
+    };
+    let result = Message::new_method_call(
+        "org.freedesktop.login1",
+        "/org/freedesktop/login1",
+        "org.freedesktop.login1.Manager",
+        "Inhibit",
+    )
+    .map(|m| m.append3("sleep", "Spotifyd", reason).append1(mode))
+    .and_then(|m| {
+        connection
+            .send_with_reply_and_block(m, 2000)
+            .map_err(|e| e.to_string())
+    })
+    .and_then(|reply| reply.read1::<OwnedFd>().map_err(|e| format!("{:?}", e)));
+    match result {
+        Ok(fd) => {
+            locks.insert(mode, fd);
+        },
+        Err(e) => warn!("Couldn't inhibit sleep: {}", e),
+    }
+}
@@ -0,0 +1,187 @@
+use futures::{Async, Stream};
+use librespot::playback::{
+    mixer::{softmixer::SoftMixer, AudioFilter, Mixer},
+    player::PlayerEvent,
+};
+use log::info;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio_core::reactor::{Handle, Interval};
+
+/// How long playback fades out before the sleep timer pauses it.
+const FADE_OUT: Duration = Duration::from_secs(30);
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Timer {
+    Off,
+    Running(Instant),
+    /// Ran out, silent until playback starts again.
+    Expired,
+}
+
+/// A sleep timer, which fades playback out and pauses it once it runs out.
+/// It is shared so that it can be set and cancelled, e.g. over D-Bus, while
+/// the audio fades through the mixer's audio filter.
+#[derive(Clone)]
+pub struct SharedSleepTimer(Arc<Mutex<Timer>>);
+
+// Only D-Bus sets the timer so far.
+#[cfg_attr(not(feature = "dbus_mpris"), allow(dead_code))]
+impl SharedSleepTimer {
+    pub fn new() -> SharedSleepTimer {
+        SharedSleepTimer(Arc::new(Mutex::new(Timer::Off)))
+    }
+
+    pub fn set(&self, duration: Duration) {
+        info!("Sleep timer set to {} seconds.", duration.as_secs());
+        *self.0.lock().unwrap() = Timer::Running(Instant::now() + duration);
+    }
+
+    pub fn cancel(&self) {
+        let mut timer = self.0.lock().unwrap();
+        if let Timer::Running(_) = *timer {
+            info!("Sleep timer cancelled.");
+        }
+        *timer = Timer::Off;
+    }
+
+    /// The time left until playback pauses, if the timer runs.
+    pub fn remaining(&self) -> Option<Duration> {
+        match *self.0.lock().unwrap() {
+            Timer::Running(deadline) => Some(time_left(deadline)),
+            _ => None,
+        }
+    }
+
+    fn gain(&self) -> f32 {
+        match *self.0.lock().unwrap() {
+            Timer::Off => 1.0,
+            Timer::Running(deadline) => {
+                let left = time_left(deadline);
+                (left.as_millis() as f32 / FADE_OUT.as_millis() as f32).min(1.0)
+            },
+            Timer::Expired => 0.0,
+        }
+    }
+
+    pub fn mixer(&self, mixer: Box<Mixer>) -> Box<Mixer> {
+        Box::new(SleepTimerMixer {
+            inner: mixer,
+            timer: Some(self.clone()),
+        })
+    }
+}
+
+fn time_left(deadline: Instant) -> Duration {
+    let now = Instant::now();
+    if deadline > now {
+        deadline - now
+    } else {
+        Duration::from_secs(0)
+    }
+}
+
+/// Pauses playback for the main loop once the sleep timer ran out.
+pub struct SleepTimer {
+    timer: SharedSleepTimer,
+    interval: Interval,
+}
+
+impl SleepTimer {
+    pub fn new(timer: SharedSleepTimer, handle: &Handle) -> SleepTimer {
+        SleepTimer {
+            timer,
+            interval: Interval::new(CHECK_INTERVAL, handle).unwrap(),
+        }
+    }
+
+    pub fn shared(&self) -> SharedSleepTimer {
+        self.timer.clone()
+    }
+
+    pub fn handle_event(&self, event: &PlayerEvent) {
+        if let PlayerEvent::Started { .. } = *event {
+            let mut timer = self.timer.0.lock().unwrap();
+            if *timer == Timer::Expired {
+                *timer = Timer::Off;
+            }
+        }
+    }
+
+    /// Returns true once the timer ran out, when playback is to be paused.
+    pub fn poll(&mut self) -> bool {
+        while let Ok(Async::Ready(Some(()))) = self.interval.poll() {}
+        let mut timer = self.timer.0.lock().unwrap();
+        match *timer {
+            Timer::Running(deadline) if deadline <= Instant::now() => {
+                info!("Sleep timer ran out, pausing.");
+                *timer = Timer::Expired;
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+struct SleepTimerMixer {
+    inner: Box<Mixer>,
+    timer: Option<SharedSleepTimer>,
+}
+
+impl Mixer for SleepTimerMixer {
+    fn open() -> SleepTimerMixer {
+        SleepTimerMixer {
+            inner: Box::new(SoftMixer::open()),
+            timer: None,
+        }
+    }
+
+    fn start(&self) {
+        self.inner.start()
+    }
+
+    fn stop(&self) {
+        self.inner.stop()
+    }
+
+    fn volume(&self) -> u16 {
+        self.inner.volume()
+    }
+
+    fn set_volume(&self, volume: u16) {
+        self.inner.set_volume(volume)
+    }
+
+    fn get_audio_filter(&self) -> Option<Box<AudioFilter + Send>> {
+        let inner = self.inner.get_audio_filter();
+        match self.timer {
+            Some(ref timer) => Some(Box::new(SleepTimerAudioFilter {
+                timer: timer.clone(),
+                inner,
+            })),
+            None => inner,
+        }
+    }
+}
+
+struct SleepTimerAudioFilter {
+    timer: SharedSleepTimer,
+    inner: Option<Box<AudioFilter + Send>>,
+}
+
+impl AudioFilter for SleepTimerAudioFilter {
+    fn modify_stream(&self, data: &mut [i16]) {
+        if let Some(ref inner) = self.inner {
+            inner.modify_stream(data);
+        }
+        let gain = self.timer.gain();
+        if gain < 1.0 {
+            for sample in data.iter_mut() {
+                *sample = (f32::from(*sample) * gain) as i16;
+            }
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use crate::{art, socket_activation, track_metadata::TrackMetadata};
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use librespot::{connect::spirc::Spirc, playback::player::PlayerEvent};
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    process::exit,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+pub const DEFAULT_SOCKET: &str = "/tmp/spotifyd-snapcast.sock";
+
+/// Player commands received from Snapcast.
+pub enum Command {
+    Play,
+    Pause,
+    PlayPause,
+    Next,
+    Previous,
+}
+
+impl Command {
+    pub fn apply(&self, spirc: &Spirc) {
+        match *self {
+            Command::Play => spirc.play(),
+            Command::Pause => spirc.pause(),
+            Command::PlayPause => spirc.play_pause(),
+            Command::Next => spirc.next(),
+            Command::Previous => spirc.prev(),
+        }
+    }
+}
+
+/// Implements Snapcast's stream plugin API on a Unix socket. Snapserver runs
+/// `spotifyd snapcast-control` as the stream's control script, which relays
+/// between the script's standard input and output and this socket.
+pub struct Snapcast {
+    pub commands: UnboundedReceiver<Command>,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    clients: Mutex<Vec<UnixStream>>,
+    properties: Mutex<Value>,
+}
+
+impl Snapcast {
+    pub fn new(socket: PathBuf) -> Snapcast {
+        let (sender, commands) = unbounded();
+        let shared = Arc::new(Shared {
+            clients: Mutex::new(Vec::new()),
+            properties: Mutex::new(properties("stopped", None)),
+        });
+
+        let listener = match socket_activation::unix_listener("snapcast") {
+            Some(listener) => Ok(listener),
+            None => {
+                let _ = fs::remove_file(&socket);
+                UnixListener::bind(&socket)
+            },
+        };
+        match listener {
+            Ok(listener) => {
+                info!("Listening for Snapcast on {:?}", socket);
+                let shared = shared.clone();
+                thread::spawn(move || accept(&listener, &sender, &shared));
+            },
+            Err(e) => error!("Couldn't listen for Snapcast on {:?}: {}", socket, e),
+        }
+
+        Snapcast { commands, shared }
+    }
+
+    pub fn handle_event(&self, event: &PlayerEvent) {
+        if let PlayerEvent::Stopped { .. } = *event {
+            let mut properties = self.shared.properties.lock().unwrap();
+            properties["playbackStatus"] = json!("paused");
+            self.shared.notify_properties(&properties);
+        }
+    }
+
+    /// Tells Snapcast what plays, once a track started and is known.
+    pub fn handle_metadata(&self, metadata: &TrackMetadata) {
+        let mut current = self.shared.properties.lock().unwrap();
+        *current = properties("playing", Some(metadata));
+        self.shared.notify_properties(&current);
+    }
+}
+
+impl Shared {
+    fn notify_properties(&self, properties: &Value) {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "Plugin.Stream.Player.Properties",
+            "params": properties,
+        });
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|client| writeln!(&*client, "{}", notification).is_ok());
+    }
+}
+
+fn properties(status: &str, metadata: Option<&TrackMetadata>) -> Value {
+    let mut properties = json!({
+        "playbackStatus": status,
+        "loopStatus": "none",
+        "shuffle": false,
+        "canGoNext": true,
+        "canGoPrevious": true,
+        "canPlay": true,
+        "canPause": true,
+        "canSeek": false,
+        "canControl": true,
+    });
+    if let Some(metadata) = metadata {
+        properties["metadata"] = json!({
+            "trackId": metadata.track_id.to_base62(),
+            "title": metadata.title,
+            "artist": metadata.artists,
+            "album": metadata.album,
+            "artUrl": art::shared_url(metadata),
+            "duration": f64::from(metadata.duration_ms) / 1000.0,
+        });
+    }
+    properties
+}
+
+fn accept(listener: &UnixListener, sender: &UnboundedSender<Command>, shared: &Arc<Shared>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Couldn't accept Snapcast connection: {}", e);
+                continue;
+            },
+        };
+        info!("Snapcast control script connected.");
+        let ready = json!({"jsonrpc": "2.0", "method": "Plugin.Stream.Ready"});
+        if writeln!(&stream, "{}", ready).is_err() {
+            continue;
+        }
+        if let Ok(s) = stream.try_clone() {
+            shared.clients.lock().unwrap().push(s);
+        }
+        let sender = sender.clone();
+        let shared = shared.clone();
+        thread::spawn(move || serve(stream, &sender, &shared));
+    }
+}
+
+fn serve(stream: UnixStream, sender: &UnboundedSender<Command>, shared: &Shared) {
+    let reader = match stream.try_clone() {
+        Ok(s) => BufReader::new(s),
+        Err(_) => return,
+    };
+    for line in reader.lines() {
+        let request: Value = match line.map(|l| serde_json::from_str(&l)) {
+            Ok(Ok(request)) => request,
+            Ok(Err(e)) => {
+                warn!("Invalid request from Snapcast: {}", e);
+                continue;
+            },
+            Err(_) => break,
+        };
+
+        let result = match request["method"].as_str() {
+            Some("Plugin.Stream.Player.Control") => {
+                let command = match request["params"]["command"].as_str() {
+                    Some("play") => Some(Command::Play),
+                    Some("pause") | Some("stop") => Some(Command::Pause),
+                    Some("playPause") => Some(Command::PlayPause),
+                    Some("next") => Some(Command::Next),
+                    Some("previous") => Some(Command::Previous),
+                    _ => None,
+                };
+                match command {
+                    Some(command) => {
+                        let _ = sender.unbounded_send(command);
+                        Ok(json!("ok"))
+                    },
+                    None => Err("Unsupported command"),
+                }
+            },
+            Some("Plugin.Stream.Player.GetProperties") => {
+                Ok(shared.properties.lock().unwrap().clone())
+            },
+            _ => Err("Method not found"),
+        };
+
+        if request["id"].is_null() {
+            continue;
+        }
+        let response = match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": request["id"], "result": result}),
+            Err(message) => json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "error": {"code": -32601, "message": message},
+            }),
+        };
+        if writeln!(&stream, "{}", response).is_err() {
+            break;
+        }
+    }
+}
+
+/// Entry point of `spotifyd snapcast-control`: copies lines between standard
+/// input/output and the daemon's Snapcast socket. Arguments passed by
+/// snapserver are ignored, except for `--socket=PATH`.
+pub fn run_control_script(args: &[String]) -> ! {
+    let socket = args
+        .iter()
+        .find(|a| a.starts_with("--socket="))
+        .map(|a| a["--socket=".len()..].to_string())
+        .unwrap_or_else(|| DEFAULT_SOCKET.to_string());
+
+    let stream = match UnixStream::connect(Path::new(&socket)) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Couldn't connect to spotifyd at {}: {}", socket, e);
+            exit(1);
+        },
+    };
+
+    let mut to_daemon = stream.try_clone().expect("Couldn't clone socket");
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) => {
+                    if writeln!(to_daemon, "{}", line).is_err() {
+                        break;
+                    }
+                },
+                Err(_) => break,
+            }
+        }
+        exit(0);
+    });
+
+    let stdout = io::stdout();
+    for line in BufReader::new(stream).lines() {
+        match line {
+            Ok(line) => {
+                let mut out = stdout.lock();
+                if writeln!(out, "{}", line).and_then(|_| out.flush()).is_err() {
+                    break;
+                }
+            },
+            Err(_) => break,
+        }
+    }
+    exit(0);
+}
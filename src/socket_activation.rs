@@ -0,0 +1,78 @@
+use lazy_static::lazy_static;
+use std::{
+    env,
+    net::TcpListener,
+    os::unix::{
+        io::{FromRawFd, RawFd},
+        net::UnixListener,
+    },
+    process,
+};
+
+/// The first file descriptor systemd passes, as in `sd_listen_fds`.
+const LISTEN_FDS_START: RawFd = 3;
+
+lazy_static! {
+    /// The sockets systemd passed, by their `FileDescriptorName=`.
+    static ref SOCKETS: Vec<(String, RawFd)> = from_env();
+}
+
+/// Takes the sockets systemd passes to a socket activated service. This
+/// has to happen before detaching, which changes the process ID they are
+/// passed to.
+pub fn init() {
+    lazy_static::initialize(&SOCKETS);
+}
+
+/// The names of the sockets systemd passed.
+pub fn names() -> Vec<&'static str> {
+    SOCKETS.iter().map(|&(ref name, _)| name.as_str()).collect()
+}
+
+/// The TCP listener systemd passed with the given name, if any. Every call
+/// returns a duplicate of it, so sinks opened again keep listening on it.
+pub fn tcp_listener(name: &str) -> Option<TcpListener> {
+    duplicate(name).map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+}
+
+/// The Unix socket listener systemd passed with the given name, if any.
+pub fn unix_listener(name: &str) -> Option<UnixListener> {
+    duplicate(name).map(|fd| unsafe { UnixListener::from_raw_fd(fd) })
+}
+
+fn duplicate(name: &str) -> Option<RawFd> {
+    let &(_, fd) = SOCKETS.iter().find(|&&(ref n, _)| n == name)?;
+    match unsafe { libc::dup(fd) } {
+        -1 => None,
+        fd => Some(fd),
+    }
+}
+
+fn from_env() -> Vec<(String, RawFd)> {
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(process::id());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    // Programs spotifyd runs mustn't take the sockets for theirs.
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    if !for_us {
+        return Vec::new();
+    }
+    let mut names = names.split(':');
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            unsafe {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+            let name = names.next().unwrap_or("unknown").to_string();
+            (name, fd)
+        })
+        .collect()
+}
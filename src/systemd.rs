@@ -0,0 +1,106 @@
+use crate::track_metadata::TrackMetadata;
+use futures::{Async, Stream};
+use librespot::playback::player::PlayerEvent;
+use log::warn;
+use std::{env, os::unix::net::UnixDatagram, path::PathBuf, process, time::Duration};
+use tokio_core::reactor::{Handle, Interval};
+
+/// Tells systemd how spotifyd is doing, as `sd_notify` does, when run by a
+/// unit with `Type=notify`: that it is ready once the Connect device is
+/// registered, what it is playing, and, with `WatchdogSec=`, that the main
+/// loop still runs. A main loop that stops answering the watchdog gets
+/// spotifyd restarted.
+pub struct Notifier {
+    socket: (UnixDatagram, PathBuf),
+    watchdog: Option<Interval>,
+    ready: bool,
+}
+
+impl Notifier {
+    /// The notifier for the socket systemd passes in `$NOTIFY_SOCKET`, if
+    /// any.
+    pub fn from_env(handle: &Handle) -> Option<Notifier> {
+        let path = env::var_os("NOTIFY_SOCKET")?;
+        if path.to_string_lossy().starts_with('@') {
+            warn!("NOTIFY_SOCKET is an abstract socket, which isn't supported.");
+            return None;
+        }
+        let socket = match UnixDatagram::unbound() {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("Couldn't create a socket to notify systemd: {}", e);
+                return None;
+            },
+        };
+        let for_us = env::var("WATCHDOG_PID")
+            .ok()
+            .map_or(true, |pid| pid.parse::<u32>().ok() == Some(process::id()));
+        // Pinging twice as often as systemd expects leaves some slack.
+        let watchdog = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|&usec| usec > 0 && for_us)
+            .map(|usec| Interval::new(Duration::from_micros(usec / 2), handle).unwrap());
+        Some(Notifier {
+            socket: (socket, PathBuf::from(path)),
+            watchdog,
+            ready: false,
+        })
+    }
+
+    /// Reports that the Connect device is registered, with `status`.
+    pub fn ready(&mut self, status: &str) {
+        if self.ready {
+            self.status(status);
+        } else {
+            self.ready = true;
+            notify(&self.socket, &format!("READY=1\nSTATUS={}", status));
+        }
+    }
+
+    /// Reports that spotifyd is shutting down.
+    pub fn stopping(&self) {
+        notify(&self.socket, "STOPPING=1\nSTATUS=Shutting down");
+    }
+
+    pub fn status(&self, status: &str) {
+        notify(&self.socket, &format!("STATUS={}", status));
+    }
+
+    pub fn handle_event(&self, event: &PlayerEvent) {
+        if let PlayerEvent::Stopped { .. } = *event {
+            self.status("Stopped");
+        }
+    }
+
+    pub fn handle_metadata(&self, metadata: &TrackMetadata) {
+        notify(&self.socket, &playing(metadata));
+    }
+
+    /// Answers the watchdog when it is time to.
+    pub fn poll(&mut self) {
+        let mut elapsed = false;
+        if let Some(ref mut watchdog) = self.watchdog {
+            while let Ok(Async::Ready(Some(()))) = watchdog.poll() {
+                elapsed = true;
+            }
+        }
+        if elapsed {
+            notify(&self.socket, "WATCHDOG=1");
+        }
+    }
+}
+
+fn playing(metadata: &TrackMetadata) -> String {
+    format!(
+        "STATUS=Playing {} by {}",
+        metadata.title,
+        metadata.artists.join(", ")
+    )
+}
+
+fn notify(socket: &(UnixDatagram, PathBuf), message: &str) {
+    if let Err(e) = socket.0.send_to(message.as_bytes(), &socket.1) {
+        warn!("Couldn't notify systemd: {}", e);
+    }
+}
@@ -0,0 +1,90 @@
+use crate::web_api;
+use futures::{
+    sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    Async, Stream,
+};
+use librespot::playback::player::PlayerEvent;
+use log::info;
+use rspotify::spotify::oauth2::TokenInfo as RspotifyToken;
+use std::{thread, time::Duration};
+
+/// How long Connect takes to list the new device as the playing one.
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Notices another Connect device taking playback over from this one, which
+/// to the player looks like any other stop. After every stop, the Web API is
+/// asked where playback went.
+pub struct Takeover {
+    device_name: String,
+    /// Whether playback stopped and the Web API wasn't asked yet.
+    pending: bool,
+    sender: UnboundedSender<String>,
+    takeovers: UnboundedReceiver<String>,
+    /// Those told the name of every device that takes over.
+    subscribers: Vec<UnboundedSender<String>>,
+}
+
+impl Takeover {
+    pub fn new(device_name: String) -> Takeover {
+        let (sender, takeovers) = unbounded();
+        Takeover {
+            device_name,
+            pending: false,
+            sender,
+            takeovers,
+            subscribers: Vec::new(),
+        }
+    }
+
+    pub fn set_device_name(&mut self, name: &str) {
+        self.device_name = name.to_string();
+    }
+
+    pub fn subscribe(&mut self) -> UnboundedReceiver<String> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Whether a Web API token is needed to find out where playback went.
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    pub fn handle_event(&mut self, event: &PlayerEvent) {
+        self.pending = match *event {
+            PlayerEvent::Stopped { .. } => true,
+            _ => false,
+        };
+    }
+
+    /// Returns the names of the devices that took playback over since the
+    /// last call.
+    pub fn poll(&mut self, token: Option<RspotifyToken>) -> Vec<String> {
+        if let Some(token) = token {
+            if self.pending {
+                self.pending = false;
+                let device_name = self.device_name.clone();
+                let sender = self.sender.clone();
+                thread::spawn(move || {
+                    thread::sleep(SETTLE_TIME);
+                    let playback = web_api::client(&token).current_playback(None);
+                    if let Ok(Some(playback)) = playback {
+                        if playback.is_playing && playback.device.name != device_name {
+                            let _ = sender.unbounded_send(playback.device.name);
+                        }
+                    }
+                });
+            }
+        }
+
+        let mut devices = Vec::new();
+        while let Ok(Async::Ready(Some(device))) = self.takeovers.poll() {
+            info!("{} took playback over", device);
+            self.subscribers
+                .retain(|s| s.unbounded_send(device.clone()).is_ok());
+            devices.push(device);
+        }
+        devices
+    }
+}
@@ -0,0 +1,225 @@
+use crate::{
+    config::{ALARM_PREFIX, EQUALIZER_PRESET_PREFIX, INSTANCE_SECTION_PREFIX},
+    player_event_handler::quote,
+};
+use ini::Ini;
+use std::{collections::HashMap, fmt, path::Path};
+use toml::{value::Table, Value};
+
+/// The array of alarm tables in a section, `[[spotifyd.alarms]]`.
+pub const ALARMS: &str = "alarms";
+/// The table of equalizer presets in a section, `[spotifyd.equalizer_presets]`.
+pub const EQUALIZER_PRESETS: &str = "equalizer_presets";
+/// Settings that are a program and its arguments, which may contain spaces
+/// in an array.
+const COMMANDS: &[&str] = &["onevent"];
+
+/// What is wrong with a TOML config file, and on which line if known.
+pub struct TomlError {
+    /// Counted from 1, or 0 if unknown.
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for TomlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.msg)
+    }
+}
+
+fn error(msg: String) -> TomlError {
+    TomlError { line: 0, msg }
+}
+
+/// Whether the config file is TOML rather than INI, by its extension.
+pub fn is_toml(path: &Path) -> bool {
+    path.extension().map_or(false, |e| e == "toml")
+}
+
+/// Reads a TOML config file into the sections of an INI one, which is what
+/// the settings are read from. Settings outside of any table belong to
+/// `[global]`, and the tables in `[spotifyd]` are instances. Numbers and
+/// booleans become the text the INI file would have, and arrays of values
+/// lists separated by commas.
+pub fn to_ini(contents: &str) -> Result<Ini, TomlError> {
+    let root: Table = toml::from_str(contents).map_err(|e| TomlError {
+        line: e.line_col().map_or(0, |(line, _)| line + 1),
+        msg: e.to_string(),
+    })?;
+    let mut ini = Ini::new();
+    for (key, value) in &root {
+        match *value {
+            Value::Table(ref table) => add_section(&mut ini, key, table)?,
+            ref value => set(&mut ini, "global", key, value)?,
+        }
+    }
+    Ok(ini)
+}
+
+/// The lines of the settings in a TOML config file, by the section and name
+/// they have in the INI file `to_ini` makes of it, as
+/// `check_config::line_numbers` finds them in an INI file. Sections are on
+/// the line of their header, and alarms on that of their `name`.
+pub fn line_numbers(contents: &str) -> HashMap<(String, String), usize> {
+    let mut lines = HashMap::new();
+    // The section of the table the lines are in, what its keys are prefixed
+    // with, and whether it is one of the alarms.
+    let mut section = "global".to_string();
+    let mut prefix = "";
+    let mut alarm = false;
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            let end = line.rfind(']').unwrap_or(line.len() - 1);
+            let header = line[..=end].trim_matches(|c| c == '[' || c == ']');
+            let mut keys: Vec<String> = header.split('.').map(unquote).collect();
+            alarm = line.starts_with("[[") && keys.last().map(String::as_str) == Some(ALARMS);
+            prefix = match keys.last().map(String::as_str) {
+                Some(ALARMS) if alarm => ALARM_PREFIX,
+                Some(EQUALIZER_PRESETS) => EQUALIZER_PRESET_PREFIX,
+                Some("alarm") => "alarm.",
+                Some("equalizer") => "equalizer.",
+                _ => "",
+            };
+            if alarm || !prefix.is_empty() {
+                keys.pop();
+            }
+            section = keys.join(".");
+            lines
+                .entry((section.clone(), String::new()))
+                .or_insert(i + 1);
+        } else if let Some(end) = line.find('=') {
+            let key = unquote(&line[..end]);
+            if !alarm {
+                lines
+                    .entry((section.clone(), format!("{}{}", prefix, key)))
+                    .or_insert(i + 1);
+            } else if key == "name" {
+                let name = unquote(&line[end + 1..]);
+                lines
+                    .entry((section.clone(), format!("{}{}", prefix, name)))
+                    .or_insert(i + 1);
+            }
+        }
+    }
+    lines
+}
+
+/// A key or string of a line as written, without its quotes or a comment.
+fn unquote(s: &str) -> String {
+    let s = s.trim();
+    for &quote in &['"', '\''] {
+        if s.starts_with(quote) {
+            return s[1..].split(quote).next().unwrap_or("").to_string();
+        }
+    }
+    s.split('#').next().unwrap_or("").trim().to_string()
+}
+
+fn add_section(ini: &mut Ini, section: &str, table: &Table) -> Result<(), TomlError> {
+    for (key, value) in table {
+        match *value {
+            Value::Array(ref alarms) if key == ALARMS => {
+                for alarm in alarms {
+                    let (name, alarm) = alarm_setting(alarm)?;
+                    set_string(ini, section, &format!("{}{}", ALARM_PREFIX, name), alarm);
+                }
+            },
+            Value::Table(ref presets) if key == EQUALIZER_PRESETS => {
+                for (name, bands) in presets {
+                    set(
+                        ini,
+                        section,
+                        &format!("{}{}", EQUALIZER_PRESET_PREFIX, name),
+                        bands,
+                    )?;
+                }
+            },
+            // Dotted keys like in the INI file, `alarm.NAME = ...`.
+            Value::Table(ref settings) if key == "alarm" || key == "equalizer" => {
+                for (name, value) in settings {
+                    set(ini, section, &format!("{}.{}", key, name), value)?;
+                }
+            },
+            Value::Table(ref instance) if section == "spotifyd" => add_section(
+                ini,
+                &format!("{}{}", INSTANCE_SECTION_PREFIX, key),
+                instance,
+            )?,
+            Value::Table(_) => {
+                return Err(error(format!(
+                    "[{}.{}] is no table spotifyd knows.",
+                    section, key
+                )))
+            },
+            ref value => set(ini, section, key, value)?,
+        }
+    }
+    Ok(())
+}
+
+fn set(ini: &mut Ini, section: &str, key: &str, value: &Value) -> Result<(), TomlError> {
+    let value = match *value {
+        Value::Array(ref items) => {
+            let items = items
+                .iter()
+                .map(|item| {
+                    scalar(item).ok_or_else(|| {
+                        error(format!(
+                            "{} can only list strings, numbers and booleans.",
+                            key
+                        ))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if COMMANDS.contains(&key) {
+                items.iter().map(|a| quote(a)).collect::<Vec<_>>().join(" ")
+            } else {
+                items.join(",")
+            }
+        },
+        ref value => scalar(value).ok_or_else(|| error(format!("{} can't be a table.", key)))?,
+    };
+    set_string(ini, section, key, value);
+    Ok(())
+}
+
+fn set_string(ini: &mut Ini, section: &str, key: &str, value: String) {
+    ini.with_section(Some(section.to_string()))
+        .set(key.to_string(), value);
+}
+
+/// A value as the INI file would have it, unless it is an array or table.
+fn scalar(value: &Value) -> Option<String> {
+    match *value {
+        Value::String(ref s) => Some(s.clone()),
+        Value::Array(_) | Value::Table(_) => None,
+        ref value => Some(value.to_string()),
+    }
+}
+
+/// An alarm of `alarms`, as the name and value of its `alarm.NAME` setting.
+fn alarm_setting(alarm: &Value) -> Result<(String, String), TomlError> {
+    let field = |name: &str| alarm.get(name).and_then(scalar);
+    let required = |name: &str| {
+        field(name).ok_or_else(|| error(format!("Each of {} needs a {}.", ALARMS, name)))
+    };
+    let name = required("name")?;
+    let mut setting = format!("{} {}", required("schedule")?, required("uri")?);
+    match (field("volume"), field("fade_in")) {
+        (Some(volume), fade_in) => {
+            setting.push_str(&format!(" {}", volume));
+            if let Some(fade_in) = fade_in {
+                setting.push_str(&format!(" {}", fade_in));
+            }
+        },
+        (None, Some(_)) => {
+            return Err(error(format!(
+                "The alarm {} needs a volume to fade in to.",
+                name
+            )))
+        },
+        (None, None) => (),
+    }
+    Ok((name, setting))
+}
@@ -0,0 +1,149 @@
+use crate::{art::ArtProviders, simulation};
+use futures::{
+    future,
+    sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    Async, Future, Poll, Stream,
+};
+use librespot::{
+    core::{mercury::MercuryError, session::Session, spotify_id::SpotifyId},
+    metadata::{Album, Artist, Metadata, Track},
+    playback::player::PlayerEvent,
+};
+use log::warn;
+use tokio_core::reactor::Handle;
+
+const COVER_URL: &str = "https://i.scdn.co/image/";
+
+#[derive(Clone, Debug)]
+pub struct TrackMetadata {
+    pub track_id: SpotifyId,
+    pub title: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    pub duration_ms: u32,
+    pub cover_url: Option<String>,
+    /// The cover on Spotify's servers, whatever `cover_url` was resolved to.
+    pub spotify_cover_url: Option<String>,
+}
+
+impl TrackMetadata {
+    pub fn artist(&self) -> &str {
+        self.artists.first().map_or("", String::as_str)
+    }
+}
+
+/// Fetches the metadata of every track that starts once, for all that show
+/// it, which get it by polling this. Resuming the track fetched last hands
+/// out what is known of it again.
+pub struct MetadataFetcher {
+    art: ArtProviders,
+    sender: UnboundedSender<TrackMetadata>,
+    fetched: UnboundedReceiver<TrackMetadata>,
+    last: Option<TrackMetadata>,
+}
+
+impl MetadataFetcher {
+    pub fn new(art: ArtProviders) -> MetadataFetcher {
+        let (sender, fetched) = unbounded();
+        MetadataFetcher {
+            art,
+            sender,
+            fetched,
+            last: None,
+        }
+    }
+
+    pub fn handle_event(&self, event: &PlayerEvent, session: Option<&Session>, handle: &Handle) {
+        let track_id = match *event {
+            PlayerEvent::Started { track_id } => track_id,
+            PlayerEvent::Changed { new_track_id, .. } => new_track_id,
+            PlayerEvent::Stopped { .. } => return,
+        };
+        if let Some(ref last) = self.last {
+            if last.track_id == track_id {
+                let _ = self.sender.unbounded_send(last.clone());
+                return;
+            }
+        }
+
+        let sender = self.sender.clone();
+        handle.spawn(
+            fetch(session, track_id, &self.art)
+                .map(move |metadata| {
+                    let _ = sender.unbounded_send(metadata);
+                })
+                .map_err(|e| warn!("Couldn't fetch track metadata: {:?}", e)),
+        );
+    }
+}
+
+impl Stream for MetadataFetcher {
+    type Error = ();
+    type Item = TrackMetadata;
+
+    fn poll(&mut self) -> Poll<Option<TrackMetadata>, ()> {
+        match self.fetched.poll()? {
+            Async::Ready(Some(metadata)) => {
+                self.last = Some(metadata.clone());
+                Ok(Async::Ready(Some(metadata)))
+            },
+            other => Ok(other),
+        }
+    }
+}
+
+/// Resolves the title, artists and album of a track through the session's
+/// mercury connection, and its cover through the art providers. Without a
+/// session, the track is one of the simulator's.
+fn fetch(
+    session: Option<&Session>,
+    track_id: SpotifyId,
+    art: &ArtProviders,
+) -> Box<Future<Item = TrackMetadata, Error = MercuryError>> {
+    let art = art.clone();
+    let session = match session {
+        Some(session) => session.clone(),
+        None => return art.resolve(simulated(track_id)),
+    };
+    Box::new(Track::get(&session, track_id).and_then(move |track| {
+        let album = Album::get(&session, track.album);
+        let artists = future::join_all(
+            track
+                .artists
+                .iter()
+                .map(|id| Artist::get(&session, *id))
+                .collect::<Vec<_>>(),
+        );
+        album
+            .join(artists)
+            .map(move |(album, artists)| {
+                let cover_url = album
+                    .covers
+                    .first()
+                    .map(|id| format!("{}{}", COVER_URL, id.to_base16()));
+                TrackMetadata {
+                    track_id,
+                    title: track.name,
+                    artists: artists.into_iter().map(|a| a.name).collect(),
+                    album: album.name,
+                    duration_ms: track.duration as u32,
+                    spotify_cover_url: cover_url.clone(),
+                    cover_url,
+                }
+            })
+            .and_then(move |metadata| art.resolve(metadata))
+    }))
+}
+
+fn simulated(track_id: SpotifyId) -> TrackMetadata {
+    let number = simulation::track_number(track_id);
+    TrackMetadata {
+        track_id,
+        title: format!("Simulated Track {}", number),
+        artists: vec!["Spotifyd Simulator".to_string()],
+        album: format!("Simulation {}", (number + 4) / 5),
+        duration_ms: simulation::TRACK_DURATION.as_secs() as u32 * 1000,
+        cover_url: None,
+        spotify_cover_url: None,
+    }
+}
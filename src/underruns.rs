@@ -0,0 +1,54 @@
+use crate::backends::UnderrunCause;
+use log::warn;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// Counts the underruns the player's `UnderrunSink` reports, by cause, to
+/// tell network trouble from a struggling audio device.
+pub struct Underruns {
+    sender: Sender<UnderrunCause>,
+    receiver: Receiver<UnderrunCause>,
+    network: u64,
+    device: u64,
+}
+
+impl Underruns {
+    pub fn new() -> Underruns {
+        let (sender, receiver) = channel();
+        Underruns {
+            sender,
+            receiver,
+            network: 0,
+            device: 0,
+        }
+    }
+
+    /// Where sinks report underruns.
+    pub fn reports(&self) -> Sender<UnderrunCause> {
+        self.sender.clone()
+    }
+
+    /// The underruns so far, caused by the network and by the device.
+    pub fn counts(&self) -> (u64, u64) {
+        (self.network, self.device)
+    }
+
+    /// The underruns reported since the last call, with the count of their
+    /// cause so far.
+    pub fn poll(&mut self) -> Vec<(UnderrunCause, u64)> {
+        let mut new = Vec::new();
+        while let Ok(cause) = self.receiver.try_recv() {
+            let count = match cause {
+                UnderrunCause::Network => &mut self.network,
+                UnderrunCause::Device => &mut self.device,
+            };
+            *count += 1;
+            warn!(
+                "Audio underrun, the {} is too slow ({} so far)",
+                cause.name(),
+                count
+            );
+            new.push((cause, *count));
+        }
+        new
+    }
+}
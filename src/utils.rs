@@ -0,0 +1,14 @@
+use std::{ffi::CString, io, os::unix::ffi::OsStrExt, path::Path};
+
+/// Creates a named pipe at `path` unless something already exists there.
+pub fn create_fifo(path: &Path) -> io::Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use crate::config::{SpotifydConfig, VolumeCurve};
+use librespot::playback::mixer::{softmixer::SoftMixer, AudioFilter, Mixer};
+
+/// Shapes the volume Connect asks for before it reaches the mixer, so the
+/// slider on the phone can be made to feel natural with any DAC or amp.
+pub struct CurveMixer {
+    inner: Box<Mixer>,
+    curve: VolumeCurve,
+    /// The range, in dB, the logarithmic curve spans.
+    db_range: f64,
+    /// The slider is kept within these, whatever Connect asks for.
+    min: u16,
+    max: u16,
+}
+
+impl CurveMixer {
+    pub fn new(inner: Box<Mixer>, curve: VolumeCurve, db_range: f64) -> CurveMixer {
+        CurveMixer {
+            inner,
+            curve,
+            db_range,
+            min: 0,
+            max: u16::max_value(),
+        }
+    }
+
+    /// Limits the volume to `min..=max` percent of the slider.
+    pub fn limit(mut self, min: u8, max: u8) -> CurveMixer {
+        self.min = from_unit(f64::from(min) / 100.0);
+        self.max = from_unit(f64::from(max) / 100.0);
+        self
+    }
+
+    /// Maps a slider position in `0..=1` to the mixer's volume in `0..=1`.
+    fn mixer_volume(&self, volume: f64) -> f64 {
+        match self.curve {
+            VolumeCurve::Linear => volume,
+            VolumeCurve::Logarithmic if volume <= 0.0 => 0.0,
+            VolumeCurve::Logarithmic => 10f64.powf((volume - 1.0) * self.db_range / 20.0),
+            VolumeCurve::Cubic => volume.powi(3),
+        }
+    }
+
+    /// The inverse of `mixer_volume`.
+    fn slider_volume(&self, volume: f64) -> f64 {
+        match self.curve {
+            VolumeCurve::Linear => volume,
+            VolumeCurve::Logarithmic if volume <= 0.0 => 0.0,
+            VolumeCurve::Logarithmic => 1.0 + 20.0 * volume.log10() / self.db_range,
+            VolumeCurve::Cubic => volume.cbrt(),
+        }
+    }
+}
+
+/// How the volume from Connect is shaped and limited, as configured.
+#[derive(Clone, Copy, PartialEq)]
+pub struct VolumeSettings {
+    pub curve: VolumeCurve,
+    pub db_range: f64,
+    pub min: u8,
+    pub max: u8,
+}
+
+impl VolumeSettings {
+    pub fn new(config: &SpotifydConfig) -> VolumeSettings {
+        VolumeSettings {
+            curve: config.volume_curve,
+            db_range: config.volume_db_range,
+            min: config.volume_min,
+            max: config.volume_max,
+        }
+    }
+
+    pub fn apply(&self, mixer: Box<Mixer>) -> Box<Mixer> {
+        Box::new(CurveMixer::new(mixer, self.curve, self.db_range).limit(self.min, self.max))
+    }
+}
+
+fn to_unit(volume: u16) -> f64 {
+    f64::from(volume) / f64::from(u16::max_value())
+}
+
+fn from_unit(volume: f64) -> u16 {
+    (volume.max(0.0).min(1.0) * f64::from(u16::max_value())).round() as u16
+}
+
+impl Mixer for CurveMixer {
+    fn open() -> CurveMixer {
+        CurveMixer::new(Box::new(SoftMixer::open()), VolumeCurve::Linear, 0.0)
+    }
+
+    fn start(&self) {
+        self.inner.start()
+    }
+
+    fn stop(&self) {
+        self.inner.stop()
+    }
+
+    fn volume(&self) -> u16 {
+        from_unit(self.slider_volume(to_unit(self.inner.volume())))
+    }
+
+    fn set_volume(&self, volume: u16) {
+        let volume = volume.max(self.min).min(self.max);
+        self.inner
+            .set_volume(from_unit(self.mixer_volume(to_unit(volume))))
+    }
+
+    fn get_audio_filter(&self) -> Option<Box<AudioFilter + Send>> {
+        self.inner.get_audio_filter()
+    }
+}
@@ -0,0 +1,49 @@
+use log::info;
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    thread,
+    time::Duration,
+};
+use url::Url;
+
+/// Where librespot looks the access points up, the first host it talks to.
+const AP_RESOLVER: (&str, u16) = ("apresolve.spotify.com", 443);
+const FIRST_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Blocks until the first host spotifyd talks to, the proxy or else the
+/// access point resolver, can be looked up and connected to, for
+/// `wait_for_network`. Network managers often report the network online
+/// before DHCP and DNS are done, and nothing can log in or be discovered
+/// before they are.
+pub fn wait(proxy: Option<&Url>) {
+    // SOCKS has no well-known port in the URL crate.
+    let (host, port) = match proxy.and_then(|p| p.host_str().map(|host| (host, p))) {
+        Some((host, p)) => (host.to_string(), p.port_or_known_default().unwrap_or(1080)),
+        None => (AP_RESOLVER.0.to_string(), AP_RESOLVER.1),
+    };
+    let mut delay = FIRST_DELAY;
+    let mut waiting = false;
+    loop {
+        let reached = (&*host, port)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addresses| {
+                addresses
+                    .find(|address| TcpStream::connect_timeout(address, CONNECT_TIMEOUT).is_ok())
+            });
+        if reached.is_some() {
+            if waiting {
+                info!("The network is up.");
+            }
+            return;
+        }
+        if !waiting {
+            info!("Waiting for the network, {} isn't reachable yet.", host);
+            waiting = true;
+        }
+        thread::sleep(delay);
+        delay = (delay * 2).min(MAX_DELAY);
+    }
+}
@@ -0,0 +1,166 @@
+use chrono::prelude::*;
+use futures::{Async, Future};
+use librespot::core::{
+    keymaster::{get_token, Token as LibrespotToken},
+    mercury::MercuryError,
+    session::Session,
+};
+use log::{info, warn};
+use rspotify::spotify::{
+    client::Spotify, model::track::FullTrack, oauth2::TokenInfo as RspotifyToken,
+    util::datetime_to_timestamp,
+};
+use std::{thread, time::Duration};
+
+const TRANSFER_ATTEMPTS: u32 = 5;
+const TRANSFER_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+pub const CLIENT_ID: &str = "2c1ea588dfbc4a989e2426f8385297c3";
+pub const SCOPE: &str = "user-read-playback-state,user-read-private,user-read-birthdate,\
+                         user-read-email,playlist-read-private,user-library-read,\
+                         user-library-modify,user-top-read,playlist-read-collaborative,\
+                         playlist-modify-public,playlist-modify-private,user-follow-read,\
+                         user-follow-modify,user-read-currently-playing,\
+                         user-modify-playback-state,user-read-recently-played";
+
+/// Keeps a Web API token for the session, requesting a new one through the
+/// keymaster whenever the current one is about to expire.
+pub struct TokenProvider {
+    session: Session,
+    token: RspotifyToken,
+    request: Option<Box<Future<Item = LibrespotToken, Error = MercuryError>>>,
+}
+
+impl TokenProvider {
+    pub fn new(session: Session) -> TokenProvider {
+        TokenProvider {
+            session,
+            token: RspotifyToken::default(),
+            request: None,
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        let now: DateTime<Utc> = Utc::now();
+        match self.token.expires_at {
+            Some(expires_at) => now.timestamp() > expires_at - 100,
+            None => true,
+        }
+    }
+
+    /// Drives the token request and returns a valid token, if there is one.
+    /// Has to be called from within a task, like any other poll.
+    pub fn poll(&mut self) -> Option<RspotifyToken> {
+        if !self.is_expired() {
+            return Some(self.token.clone());
+        }
+
+        let session = &self.session;
+        let result = self
+            .request
+            .get_or_insert_with(|| get_token(session, CLIENT_ID, SCOPE))
+            .poll();
+        match result {
+            Ok(Async::Ready(token)) => {
+                self.request = None;
+                self.token = RspotifyToken::default()
+                    .access_token(&token.access_token)
+                    .expires_in(token.expires_in)
+                    .expires_at(datetime_to_timestamp(token.expires_in));
+                Some(self.token.clone())
+            },
+            Ok(Async::NotReady) => None,
+            Err(e) => {
+                warn!("Couldn't get a Web API token: {:?}", e);
+                self.request = None;
+                None
+            },
+        }
+    }
+}
+
+pub fn client(token: &RspotifyToken) -> Spotify {
+    Spotify::default().access_token(&token.access_token).build()
+}
+
+/// The ID of the named device. A device that was just registered only shows
+/// up once Connect has taken notice of it, so this retries for a while.
+pub fn find_device(client: &Spotify, device_name: &str) -> Option<String> {
+    for _ in 0..TRANSFER_ATTEMPTS {
+        let device = client
+            .device()
+            .ok()
+            .and_then(|payload| payload.devices.into_iter().find(|d| d.name == device_name));
+        if let Some(device) = device {
+            return Some(device.id);
+        }
+        thread::sleep(TRANSFER_RETRY_INTERVAL);
+    }
+    warn!("{} isn't listed as a Connect device", device_name);
+    None
+}
+
+/// Makes the named device the active one, in a thread of its own, and starts
+/// playing there if `play` is set.
+pub fn transfer_playback(token: RspotifyToken, device_name: String, play: bool) {
+    thread::spawn(move || {
+        let client = client(&token);
+        if let Some(id) = find_device(&client, &device_name) {
+            info!("Making {} the active device", device_name);
+            if let Err(e) = client.transfer_playback(&id, play) {
+                warn!("Couldn't make {} the active device: {}", device_name, e);
+            }
+        }
+    });
+}
+
+/// Sets the volume of the named device, in a thread of its own.
+pub fn set_volume(token: RspotifyToken, device_name: String, percent: u8) {
+    thread::spawn(move || {
+        let client = client(&token);
+        if let Some(id) = find_device(&client, &device_name) {
+            if let Err(e) = client.volume(percent, Some(id)) {
+                warn!("Couldn't set the volume of {}: {}", device_name, e);
+            }
+        }
+    });
+}
+
+/// The name of the playlist, album or artist a playback context refers to.
+pub fn context_name(client: &Spotify, uri: &str) -> Option<String> {
+    // Older playlist URIs carry the owner, as in spotify:user:NAME:playlist:ID.
+    let mut fields = uri.rsplit(':');
+    let id = fields.next()?;
+    let name = match fields.next()? {
+        "playlist" => client.playlist(id, None, None).map(|p| p.name),
+        "album" => client.album(id).map(|a| a.name),
+        "artist" => client.artist(id).map(|a| a.name),
+        _ => return None,
+    };
+    name.map_err(|e| warn!("Couldn't look up {}: {}", uri, e))
+        .ok()
+}
+
+/// The genres of a track. Spotify only reliably assigns genres to artists,
+/// so these are the genres of all of the track's artists.
+pub fn genres(client: &Spotify, track: &FullTrack) -> Vec<String> {
+    let uris = track.artists.iter().map(|a| a.uri.clone()).collect();
+    let mut genres = Vec::new();
+    for artist in client.artists(uris).map(|a| a.artists).unwrap_or_default() {
+        for genre in artist.genres {
+            if !genres.contains(&genre) {
+                genres.push(genre);
+            }
+        }
+    }
+    genres
+}
+
+/// Whether the track is among the user's saved tracks.
+pub fn is_saved(client: &Spotify, track_uri: &str) -> bool {
+    client
+        .current_user_saved_tracks_contains(&[track_uri.to_string()])
+        .ok()
+        .and_then(|saved| saved.first().cloned())
+        .unwrap_or(false)
+}